@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+pub const SPU_SAMPLE_RATE: u32 = 44100;
+
+// How much audio we try to keep queued for the device, in stereo frames. The frame loop waits
+// while there's more than this, anything past the maximum is dropped.
+const TARGET_LATENCY_FRAMES: usize = 2048;
+const MAX_LATENCY_FRAMES: usize = TARGET_LATENCY_FRAMES * 4;
+
+// Longest the frame loop waits for the device, in case it stopped pulling samples.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+// Maximum deviation from the nominal rate the rate control is allowed to apply.
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+struct OutputCallback {
+    buffer: Arc<Mutex<VecDeque<(i16, i16)>>>,
+    last_frame: (i16, i16),
+}
+
+impl AudioCallback for OutputCallback {
+    type Channel = i16;
+
+    fn callback(&mut self, output: &mut [i16]) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        for frame in output.chunks_mut(2) {
+            // On underruns hold the last frame instead of dropping to zero, which clicks.
+            if let Some(next) = buffer.pop_front() {
+                self.last_frame = next;
+            }
+
+            frame[0] = self.last_frame.0;
+            if frame.len() > 1 {
+                frame[1] = self.last_frame.1;
+            }
+        }
+    }
+}
+
+// Plays SPU output through SDL, and paces emulation: the frame loop waits for the device to
+// play the queue down before running the next frame. Samples go through a linear resampler whose
+// ratio is nudged depending on how full the ring buffer is, which soaks up the small drift between
+// the two clocks.
+pub struct AudioOutput {
+    _device: AudioDevice<OutputCallback>,
+    buffer: Arc<Mutex<VecDeque<(i16, i16)>>>,
+
+    // Nominal ratio between the device rate and the SPU rate.
+    base_ratio: f64,
+    position: f64,
+    previous: (i16, i16),
+}
+
+impl AudioOutput {
+    pub fn new(audio: &AudioSubsystem) -> Result<AudioOutput, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(SPU_SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+
+        let buffer = Arc::new(Mutex::new(VecDeque::with_capacity(MAX_LATENCY_FRAMES)));
+        let callback_buffer = buffer.clone();
+
+        let device = audio.open_playback(None, &desired, |_spec| OutputCallback {
+            buffer: callback_buffer,
+            last_frame: (0, 0),
+        })?;
+
+        let base_ratio = device.spec().freq as f64 / SPU_SAMPLE_RATE as f64;
+        device.resume();
+
+        Ok(AudioOutput {
+            _device: device,
+            buffer,
+
+            base_ratio,
+            position: 0.0,
+            previous: (0, 0),
+        })
+    }
+
+    // Blocks until the queue is at or below the target.
+    pub fn wait_for_room(&self) {
+        let deadline = Instant::now() + MAX_WAIT;
+
+        while self.buffer.lock().unwrap().len() > TARGET_LATENCY_FRAMES && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    pub fn queue(&mut self, samples: &[(i16, i16)]) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        // Below the target the ratio goes up to produce more frames, above it goes down.
+        let fill = buffer.len() as f64 / TARGET_LATENCY_FRAMES as f64;
+        let adjustment = ((1.0 - fill) * MAX_RATE_ADJUSTMENT).clamp(-MAX_RATE_ADJUSTMENT, MAX_RATE_ADJUSTMENT);
+        let step = 1.0 / (self.base_ratio * (1.0 + adjustment));
+
+        for sample in samples.iter() {
+            while self.position < 1.0 {
+                let left = lerp(self.previous.0, sample.0, self.position);
+                let right = lerp(self.previous.1, sample.1, self.position);
+
+                if buffer.len() < MAX_LATENCY_FRAMES {
+                    buffer.push_back((left, right));
+                }
+
+                self.position += step;
+            }
+
+            self.position -= 1.0;
+            self.previous = *sample;
+        }
+    }
+}
+
+fn lerp(from: i16, to: i16, position: f64) -> i16 {
+    (from as f64 + (to as f64 - from as f64) * position).round() as i16
+}
@@ -0,0 +1,210 @@
+use imgui::*;
+
+use super::cpu::breakpoints;
+use super::cpu::breakpoints::Breakpoints;
+use super::watchpoints::{WatchKind, Watchpoints};
+
+// Lists the execution breakpoints and memory watchpoints, and adds new ones.
+pub struct BreakpointsWindow {
+    address: ImString,
+    condition: ImString,
+    status: String,
+
+    watch_start: ImString,
+    // Empty watches the word at the start address.
+    watch_end: ImString,
+    watch_kind: WatchKind,
+}
+
+impl BreakpointsWindow {
+    pub fn new() -> BreakpointsWindow {
+        BreakpointsWindow {
+            address: ImString::with_capacity(8),
+            condition: ImString::with_capacity(128),
+            status: String::new(),
+
+            watch_start: ImString::with_capacity(8),
+            watch_end: ImString::with_capacity(8),
+            watch_kind: WatchKind::Write,
+        }
+    }
+
+    fn add(&mut self, breakpoints: &mut Breakpoints) {
+        let address = match u32::from_str_radix(self.address.to_str(), 16) {
+            Ok(address) => address & !3,
+            Err(_) => {
+                self.status = format!("\"{}\" isn't a hexadecimal address", self.address.to_str());
+                return;
+            },
+        };
+
+        let condition = if self.condition.to_str().trim().is_empty() {
+            None
+        }
+        else {
+            match breakpoints::Condition::parse(self.condition.to_str()) {
+                Ok(condition) => Some(condition),
+                Err(error) => {
+                    self.status = format!("Couldn't read the condition: {}", error);
+                    return;
+                },
+            }
+        };
+
+        breakpoints.add(address, condition);
+        self.address.clear();
+        self.condition.clear();
+        self.status.clear();
+    }
+
+    fn add_watchpoint(&mut self, watchpoints: &mut Watchpoints) {
+        let start = match u32::from_str_radix(self.watch_start.to_str(), 16) {
+            Ok(start) => start,
+            Err(_) => {
+                self.status = format!("\"{}\" isn't a hexadecimal address", self.watch_start.to_str());
+                return;
+            },
+        };
+
+        let end = if self.watch_end.to_str().is_empty() {
+            (start & !3) + 3
+        }
+        else {
+            match u32::from_str_radix(self.watch_end.to_str(), 16) {
+                Ok(end) => end,
+                Err(_) => {
+                    self.status = format!("\"{}\" isn't a hexadecimal address", self.watch_end.to_str());
+                    return;
+                },
+            }
+        };
+
+        watchpoints.add(start, end, self.watch_kind);
+        self.watch_start.clear();
+        self.watch_end.clear();
+        self.status.clear();
+    }
+
+    pub fn build(&mut self, ui: &Ui, breakpoints: &mut Breakpoints, watchpoints: &mut Watchpoints) {
+        Window::new(im_str!("Rusty PSX - Breakpoints")).size([460.0, 300.0], Condition::FirstUseEver).build(ui, || {
+            let mut add = ui.input_text(im_str!("Address"), &mut self.address).chars_hexadecimal(true).enter_returns_true(true).build();
+            add |= ui.input_text(im_str!("Condition"), &mut self.condition).enter_returns_true(true).build();
+            ui.text_disabled("For example: a0 == 0x1F801810 && [sp+0x10] != 0");
+            add |= ui.button(im_str!("Add breakpoint"), [120.0, 20.0]);
+            ui.same_line(0.0);
+            if ui.button(im_str!("Remove all"), [120.0, 20.0]) {
+                breakpoints.clear();
+            }
+
+            if add {
+                self.add(breakpoints);
+            }
+
+            if !self.status.is_empty() {
+                ui.text_colored([1.0, 0.3, 0.3, 1.0], &self.status);
+            }
+            ui.separator();
+
+            if breakpoints.is_empty() {
+                ui.text("No breakpoints set.");
+            }
+
+            let addresses: Vec<u32> = breakpoints.list().iter().map(|breakpoint| breakpoint.address).collect();
+            let mut removed = None;
+
+            for address in addresses {
+                let breakpoint = match breakpoints.get_mut(address) {
+                    Some(breakpoint) => breakpoint,
+                    None => continue,
+                };
+
+                let id = ui.push_id(address as i32);
+
+                ui.checkbox(&ImString::new(format!("{:08X}", address)), &mut breakpoint.enabled);
+                ui.same_line(0.0);
+                ui.text(format!("hits {}", breakpoint.hit_count));
+
+                ui.same_line(0.0);
+                let mut ignore_count = breakpoint.ignore_count as i32;
+                let width = ui.push_item_width(80.0);
+                if ui.input_int(im_str!("ignore"), &mut ignore_count).build() {
+                    breakpoint.ignore_count = ignore_count.max(0) as u32;
+                }
+                width.pop(ui);
+
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Reset")) {
+                    breakpoint.hit_count = 0;
+                }
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Remove")) {
+                    removed = Some(address);
+                }
+
+                if let Some(condition) = &breakpoint.condition {
+                    ui.text_disabled(format!("    if {}", condition.text));
+                }
+
+                id.pop(ui);
+            }
+
+            if let Some(address) = removed {
+                breakpoints.remove(address);
+            }
+
+            ui.spacing();
+            ui.text("Watchpoints");
+            ui.separator();
+
+            let mut add = ui.input_text(im_str!("Start"), &mut self.watch_start).chars_hexadecimal(true).enter_returns_true(true).build();
+            add |= ui.input_text(im_str!("End"), &mut self.watch_end).chars_hexadecimal(true).enter_returns_true(true).build();
+            ui.radio_button(im_str!("Read"), &mut self.watch_kind, WatchKind::Read);
+            ui.same_line(0.0);
+            ui.radio_button(im_str!("Write"), &mut self.watch_kind, WatchKind::Write);
+            ui.same_line(0.0);
+            ui.radio_button(im_str!("Access"), &mut self.watch_kind, WatchKind::Access);
+            add |= ui.button(im_str!("Add watchpoint"), [120.0, 20.0]);
+            ui.same_line(0.0);
+            if ui.button(im_str!("Remove all##watchpoints"), [120.0, 20.0]) {
+                watchpoints.clear();
+            }
+
+            if add {
+                self.add_watchpoint(watchpoints);
+            }
+
+            let mut removed = None;
+            let mut toggled = None;
+
+            for (index, watchpoint) in watchpoints.list().iter().enumerate() {
+                let id = ui.push_id(index as i32);
+
+                let kind = match watchpoint.kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access",
+                };
+
+                let mut enabled = watchpoint.enabled;
+                if ui.checkbox(&ImString::new(format!("{:08X}-{:08X} {}", watchpoint.start, watchpoint.end, kind)), &mut enabled) {
+                    toggled = Some((index, enabled));
+                }
+                ui.same_line(0.0);
+                ui.text(format!("hits {}", watchpoint.hit_count));
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Remove")) {
+                    removed = Some(index);
+                }
+
+                id.pop(ui);
+            }
+
+            if let Some((index, enabled)) = toggled {
+                watchpoints.set_enabled(index, enabled);
+            }
+            if let Some(index) = removed {
+                watchpoints.remove(index);
+            }
+        });
+    }
+}
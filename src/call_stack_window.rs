@@ -0,0 +1,41 @@
+use imgui::*;
+
+use super::cpu::Cpu;
+
+// The shadow call stack, innermost frame first. Clicking a frame shows its call site.
+// Returns the address to show in the disassembly when a frame is clicked.
+pub fn build(ui: &Ui, cpu: &Cpu) -> Option<u32> {
+    let mut show = None;
+
+    Window::new(im_str!("Rusty PSX - Call Stack")).size([420.0, 250.0], Condition::FirstUseEver).build(ui, || {
+        if Selectable::new(&ImString::new(format!("PC {:08X}", cpu.pc))).build(ui) {
+            show = Some(cpu.pc);
+        }
+        ui.separator();
+
+        let frames = cpu.call_stack.frames();
+        if frames.is_empty() {
+            ui.text("No calls seen yet.");
+        }
+
+        for (depth, frame) in frames.iter().enumerate().rev() {
+            let id = ui.push_id(depth as i32);
+            let text = format!("#{:<3} {:08X}  called from {:08X}  sp {:08X}", depth, frame.target, frame.call_site, frame.stack_pointer);
+
+            if Selectable::new(&ImString::new(text)).build(ui) {
+                show = Some(frame.call_site);
+            }
+            if ui.is_item_hovered() {
+                ui.tooltip_text(format!("Returns to {:08X}", frame.return_address));
+            }
+            id.pop(ui);
+        }
+
+        if cpu.call_stack.unmatched_returns != 0 {
+            ui.spacing();
+            ui.text_disabled(format!("{} returns from calls made before tracking started", cpu.call_stack.unmatched_returns));
+        }
+    });
+
+    show
+}
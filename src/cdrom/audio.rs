@@ -0,0 +1,490 @@
+use std::io;
+use std::collections::VecDeque;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::super::savestate::{StateReader, StateWriter};
+
+// Offsets within a raw sector.
+const XA_DATA_OFFSET: usize = 24;
+const SUBHEADER_FILE: usize = 16;
+const SUBHEADER_CHANNEL: usize = 17;
+const SUBHEADER_SUBMODE: usize = 18;
+const SUBHEADER_CODING: usize = 19;
+
+const SUBMODE_AUDIO: u8 = 0x04;
+const SUBMODE_FORM2: u8 = 0x20;
+
+const CODING_STEREO: u8 = 0x01;
+const CODING_HALF_RATE: u8 = 0x04;
+const CODING_8BIT: u8 = 0x10;
+
+const SOUND_GROUPS: usize = 18;
+const SOUND_GROUP_SIZE: usize = 128;
+const SAMPLES_PER_BLOCK: usize = 28;
+
+const POS_XA_ADPCM_TABLE: [i32; 4] = [0, 60, 115, 98];
+const NEG_XA_ADPCM_TABLE: [i32; 4] = [0, 0, -52, -55];
+
+// Coefficients used to upsample 37.8kHz to 44.1kHz, 6 input samples become 7 output samples.
+const ZIGZAG_TABLE: [[i32; 29]; 7] = [
+    [
+        0, 0, 0, 0, 0, -0x0002, 0x000A, -0x0022, 0x0041, -0x0054, 0x0034, 0x0009, -0x010A, 0x0400, -0x0A78,
+        0x234C, 0x6794, -0x1780, 0x0BCD, -0x0623, 0x0350, -0x016D, 0x006B, 0x000A, -0x0010, 0x0011, -0x0008, 0x0003, -0x0001,
+    ],
+    [
+        0, 0, 0, -0x0002, 0, 0x0003, -0x0013, 0x003C, -0x004B, 0x00A2, -0x00E3, 0x0132, -0x0043, -0x0267, 0x0C9D,
+        0x74BB, -0x11B4, 0x09B8, -0x05BF, 0x0372, -0x01A8, 0x00A6, -0x001B, 0x0005, 0x0006, -0x0008, 0x0003, -0x0001, 0,
+    ],
+    [
+        0, 0, -0x0001, 0x0003, -0x0002, -0x0005, 0x001F, -0x004A, 0x00B3, -0x0192, 0x02B1, -0x039E, 0x04F8, -0x05A6, 0x7939,
+        -0x05A6, 0x04F8, -0x039E, 0x02B1, -0x0192, 0x00B3, -0x004A, 0x001F, -0x0005, -0x0002, 0x0003, -0x0001, 0, 0,
+    ],
+    [
+        0, -0x0001, 0x0003, -0x0008, 0x0006, 0x0005, -0x001B, 0x00A6, -0x01A8, 0x0372, -0x05BF, 0x09B8, -0x11B4, 0x74BB, 0x0C9D,
+        -0x0267, -0x0043, 0x0132, -0x00E3, 0x00A2, -0x004B, 0x003C, -0x0013, 0x0003, 0, -0x0002, 0, 0, 0,
+    ],
+    [
+        -0x0001, 0x0003, -0x0008, 0x0011, -0x0010, 0x000A, 0x006B, -0x016D, 0x0350, -0x0623, 0x0BCD, -0x1780, 0x6794, 0x234C, -0x0A78,
+        0x0400, -0x010A, 0x0009, 0x0034, -0x0054, 0x0041, -0x0022, 0x000A, -0x0001, 0, 0x0001, 0, 0, 0,
+    ],
+    [
+        0x0002, -0x0008, 0x0010, -0x0023, 0x002B, 0x001A, -0x00EB, 0x027B, -0x0548, 0x0AFA, -0x16FA, 0x53E0, 0x3C07, -0x1249, 0x080E,
+        -0x0347, 0x015B, -0x0044, -0x0017, 0x0046, -0x0023, 0x0011, -0x0005, 0, 0, 0, 0, 0, 0,
+    ],
+    [
+        -0x0005, 0x0011, -0x0023, 0x0046, -0x0017, -0x0044, 0x015B, -0x0347, 0x080E, -0x1249, 0x3C07, 0x53E0, -0x16FA, 0x0AFA, -0x0548,
+        0x027B, -0x00EB, 0x001A, 0x002B, -0x0023, 0x0010, -0x0008, 0x0002, 0, 0, 0, 0, 0, 0,
+    ],
+];
+
+// Never let the queue grow past a second of audio if nobody is pulling samples.
+const MAX_QUEUED_SAMPLES: usize = 44100;
+
+#[derive(Clone, Copy, Default)]
+struct AdpcmHistory {
+    old: i32,
+    older: i32,
+}
+
+struct Resampler {
+    ring: [i16; 32],
+    position: usize,
+    sixstep: u8,
+}
+
+impl Resampler {
+    fn new() -> Resampler {
+        Resampler {
+            ring: [0; 32],
+            position: 0,
+            sixstep: 6,
+        }
+    }
+
+    fn push(&mut self, sample: i16, output: &mut Vec<i16>) {
+        self.ring[self.position & 0x1F] = sample;
+        self.position = self.position.wrapping_add(1);
+        self.sixstep -= 1;
+
+        if self.sixstep == 0 {
+            self.sixstep = 6;
+
+            for table in ZIGZAG_TABLE.iter() {
+                let mut sum = 0;
+
+                for (index, coefficient) in table.iter().enumerate() {
+                    let sample = self.ring[self.position.wrapping_sub(index + 1) & 0x1F] as i32;
+                    sum += (sample * coefficient) >> 15;
+                }
+
+                output.push(clamp16(sum));
+            }
+        }
+    }
+}
+
+// The CD audio volume matrix, 0x80 is 100%.
+#[derive(Clone, Copy)]
+pub struct VolumeMatrix {
+    pub left_to_left: u8,
+    pub left_to_right: u8,
+    pub right_to_left: u8,
+    pub right_to_right: u8,
+}
+
+impl VolumeMatrix {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.left_to_left);
+        state.write_u8(self.left_to_right);
+        state.write_u8(self.right_to_left);
+        state.write_u8(self.right_to_right);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.left_to_left = state.read_u8()?;
+        self.left_to_right = state.read_u8()?;
+        self.right_to_left = state.read_u8()?;
+        self.right_to_right = state.read_u8()?;
+        Ok(())
+    }
+
+    pub fn new() -> VolumeMatrix {
+        VolumeMatrix {
+            left_to_left: 0x80,
+            left_to_right: 0,
+            right_to_left: 0,
+            right_to_right: 0x80,
+        }
+    }
+
+    fn apply(&self, left: i16, right: i16) -> (i16, i16) {
+        let left = left as i32;
+        let right = right as i32;
+
+        let out_left = (left * self.left_to_left as i32 + right * self.right_to_left as i32) >> 7;
+        let out_right = (left * self.left_to_right as i32 + right * self.right_to_right as i32) >> 7;
+
+        (clamp16(out_left), clamp16(out_right))
+    }
+}
+
+pub struct CdAudio {
+    history: [AdpcmHistory; 2],
+    resamplers: [Resampler; 2],
+
+    // Setfilter parameters, only honored when the filter is enabled in the mode.
+    pub filter_file: u8,
+    pub filter_channel: u8,
+    pub filter_enabled: bool,
+
+    pub muted: bool,
+    pub adpcm_muted: bool,
+
+    // Volumes written by the CPU only take effect once they're applied through ADPCTL.
+    pub pending_volume: VolumeMatrix,
+    pub volume: VolumeMatrix,
+
+    // 44.1kHz stereo samples waiting for the SPU to mix them.
+    pub output: VecDeque<(i16, i16)>,
+}
+
+impl CdAudio {
+    pub fn new() -> CdAudio {
+        CdAudio {
+            history: [AdpcmHistory::default(); 2],
+            resamplers: [Resampler::new(), Resampler::new()],
+
+            filter_file: 0,
+            filter_channel: 0,
+            filter_enabled: false,
+
+            muted: false,
+            adpcm_muted: false,
+
+            pending_volume: VolumeMatrix::new(),
+            volume: VolumeMatrix::new(),
+
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for history in self.history.iter() {
+            state.write_i32(history.old);
+            state.write_i32(history.older);
+        }
+        for resampler in self.resamplers.iter() {
+            state.write_i16s(&resampler.ring);
+            state.write_u32(resampler.position as u32);
+            state.write_u8(resampler.sixstep);
+        }
+
+        state.write_u8(self.filter_file);
+        state.write_u8(self.filter_channel);
+        state.write_bool(self.filter_enabled);
+
+        state.write_bool(self.muted);
+        state.write_bool(self.adpcm_muted);
+
+        self.pending_volume.save_state(state);
+        self.volume.save_state(state);
+
+        state.write_u32(self.output.len() as u32);
+        for (left, right) in self.output.iter() {
+            state.write_i16(*left);
+            state.write_i16(*right);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for history in self.history.iter_mut() {
+            history.old = state.read_i32()?;
+            history.older = state.read_i32()?;
+        }
+        for resampler in self.resamplers.iter_mut() {
+            state.read_i16s_into(&mut resampler.ring)?;
+            resampler.position = state.read_u32()? as usize;
+            resampler.sixstep = state.read_u8()?;
+        }
+
+        self.filter_file = state.read_u8()?;
+        self.filter_channel = state.read_u8()?;
+        self.filter_enabled = state.read_bool()?;
+
+        self.muted = state.read_bool()?;
+        self.adpcm_muted = state.read_bool()?;
+
+        self.pending_volume.load_state(state)?;
+        self.volume.load_state(state)?;
+
+        self.output.clear();
+        for _ in 0..state.read_u32()? {
+            let left = state.read_i16()?;
+            let right = state.read_i16()?;
+            self.output.push_back((left, right));
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_volume(&mut self) {
+        self.volume = self.pending_volume;
+    }
+
+    pub fn reset_decoder(&mut self) {
+        self.history = [AdpcmHistory::default(); 2];
+        self.resamplers = [Resampler::new(), Resampler::new()];
+    }
+
+    pub fn next_sample(&mut self) -> (i16, i16) {
+        self.output.pop_front().unwrap_or((0, 0))
+    }
+
+    // Returns whether a raw sector is a real-time XA audio sector.
+    pub fn is_xa_sector(sector: &[u8]) -> bool {
+        sector[15] == 2 && sector[SUBHEADER_SUBMODE] & (SUBMODE_AUDIO | SUBMODE_FORM2) == (SUBMODE_AUDIO | SUBMODE_FORM2)
+    }
+
+    // Decodes an XA-ADPCM sector. Returns false if the sector was dropped by the file/channel filter.
+    pub fn decode_xa_sector(&mut self, sector: &[u8]) -> bool {
+        if self.filter_enabled && (sector[SUBHEADER_FILE] != self.filter_file || sector[SUBHEADER_CHANNEL] != self.filter_channel) {
+            return false;
+        }
+
+        let coding = sector[SUBHEADER_CODING];
+        let stereo = coding & CODING_STEREO != 0;
+        let half_rate = coding & CODING_HALF_RATE != 0;
+        let eight_bit = coding & CODING_8BIT != 0;
+
+        let mut channels = [Vec::new(), Vec::new()];
+
+        for group in 0..SOUND_GROUPS {
+            let start = XA_DATA_OFFSET + group * SOUND_GROUP_SIZE;
+            let group = &sector[start..start + SOUND_GROUP_SIZE];
+            let blocks = if eight_bit { 4 } else { 8 };
+
+            for block in 0..blocks {
+                let channel = if stereo { block & 1 } else { 0 };
+                let decoded = decode_block(group, block, eight_bit, &mut self.history[channel]);
+                channels[channel].extend_from_slice(&decoded);
+            }
+        }
+
+        let mut resampled = [Vec::new(), Vec::new()];
+        let channel_count = if stereo { 2 } else { 1 };
+
+        for channel in 0..channel_count {
+            for sample in channels[channel].iter() {
+                self.resamplers[channel].push(*sample, &mut resampled[channel]);
+
+                // 18.9kHz gets every sample fed twice to reach the same output rate.
+                if half_rate {
+                    self.resamplers[channel].push(*sample, &mut resampled[channel]);
+                }
+            }
+        }
+
+        if self.muted || self.adpcm_muted {
+            return true;
+        }
+
+        for index in 0..resampled[0].len() {
+            let left = resampled[0][index];
+            let right = if stereo { resampled[1].get(index).cloned().unwrap_or(0) } else { left };
+            self.queue_sample(left, right);
+        }
+
+        true
+    }
+
+    // Queues a red-book audio sector, 588 stereo samples at 44.1kHz.
+    pub fn decode_cdda_sector(&mut self, sector: &[u8]) {
+        if self.muted {
+            return;
+        }
+
+        for frame in sector.chunks(4) {
+            let left = LittleEndian::read_i16(&frame[0..2]);
+            let right = LittleEndian::read_i16(&frame[2..4]);
+            self.queue_sample(left, right);
+        }
+    }
+
+    fn queue_sample(&mut self, left: i16, right: i16) {
+        if self.output.len() >= MAX_QUEUED_SAMPLES {
+            self.output.pop_front();
+        }
+
+        self.output.push_back(self.volume.apply(left, right));
+    }
+}
+
+fn decode_block(group: &[u8], block: usize, eight_bit: bool, history: &mut AdpcmHistory) -> [i16; SAMPLES_PER_BLOCK] {
+    let header = group[4 + block];
+    let mut shift = (header & 0x0F) as u32;
+    let filter = ((header >> 4) & 0x03) as usize;
+
+    // Reserved shift values behave like 9.
+    if shift > 12 {
+        shift = 9;
+    }
+
+    let mut samples = [0; SAMPLES_PER_BLOCK];
+
+    for (index, sample) in samples.iter_mut().enumerate() {
+        let data = group[16 + index * 4 + if eight_bit { block } else { block / 2 }];
+
+        let raw = if eight_bit {
+            ((data as i8 as i16) << 8) as i32
+        }
+        else {
+            let nibble = if block & 1 != 0 { data >> 4 } else { data & 0x0F };
+            ((nibble as i16) << 12) as i32
+        };
+
+        let value = (raw >> shift) + ((history.old * POS_XA_ADPCM_TABLE[filter] + history.older * NEG_XA_ADPCM_TABLE[filter] + 32) >> 6);
+        let value = clamp16(value);
+
+        history.older = history.old;
+        history.old = value as i32;
+        *sample = value;
+    }
+
+    samples
+}
+
+fn clamp16(value: i32) -> i16 {
+    value.clamp(-0x8000, 0x7FFF) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected samples worked out by hand from the decoding formula in the nocash documentation.
+    const FILTER_1_SHIFT_8: [i16; SAMPLES_PER_BLOCK] = [
+        0, 48, 141, 20, -45, -58, -22, 59, -73, -148, -171, -144, -71, 45,
+        -54, -99, -93, -39, 59, -57, -117, -126, -86, -1, -129, -201, -220, -190,
+    ];
+    const FILTER_2_SHIFT_0: [i16; SAMPLES_PER_BLOCK] = [
+        28672, 32767, 32767, 32767, 32255, 31335, 30098, 28623, 26977, 25218, 23395, 21548, 19711, 17910,
+        16167, 14498, 12915, 11427, 10039, 8754, 7573, 6495, 5518, 4638, 3851, 3151, 2533, 1991,
+    ];
+    const EIGHT_BIT_FILTER_3_SHIFT_4: [i16; SAMPLES_PER_BLOCK] = [
+        80, 795, 2413, 4868, 3732, 475, -2944, -4788, -4082, -824, 4150, 5463, 3791, 694,
+        -2019, -2920, -1376, 2354, 3235, 1971, -130, -1669, -1628, 349, 3933, 4218, 2167, -627,
+    ];
+
+    // Block 0 counts up in steps of 3 nibbles, block 1 saturates with four 7s.
+    fn four_bit_group() -> Vec<u8> {
+        let mut group = vec![0; SOUND_GROUP_SIZE];
+        group[4] = 0x18;
+        group[5] = 0x20;
+
+        for index in 0..SAMPLES_PER_BLOCK {
+            let low = (index * 3) as u8 & 0x0F;
+            let high = if index < 4 { 7 } else { 0 };
+            group[16 + index * 4] = low | (high << 4);
+        }
+
+        group
+    }
+
+    #[test]
+    fn four_bit_blocks() {
+        let group = four_bit_group();
+        let mut history = AdpcmHistory::default();
+
+        assert_eq!(decode_block(&group, 0, false, &mut history), FILTER_1_SHIFT_8);
+        assert_eq!(history.old, -190);
+        assert_eq!(history.older, -220);
+
+        let mut history = AdpcmHistory::default();
+        assert_eq!(decode_block(&group, 1, false, &mut history), FILTER_2_SHIFT_0);
+    }
+
+    #[test]
+    fn eight_bit_block() {
+        let mut group = vec![0; SOUND_GROUP_SIZE];
+        group[6] = 0x34;
+        for index in 0..SAMPLES_PER_BLOCK {
+            group[16 + index * 4 + 2] = (index * 37 + 5) as u8;
+        }
+
+        let mut history = AdpcmHistory::default();
+        assert_eq!(decode_block(&group, 2, true, &mut history), EIGHT_BIT_FILTER_3_SHIFT_4);
+    }
+
+    fn xa_sector(coding: u8, group: &[u8]) -> Vec<u8> {
+        let mut sector = vec![0; 2352];
+        sector[15] = 2;
+        sector[SUBHEADER_FILE] = 1;
+        sector[SUBHEADER_CHANNEL] = 3;
+        sector[SUBHEADER_SUBMODE] = SUBMODE_AUDIO | SUBMODE_FORM2;
+        sector[SUBHEADER_CODING] = coding;
+
+        for index in 0..SOUND_GROUPS {
+            let start = XA_DATA_OFFSET + index * SOUND_GROUP_SIZE;
+            sector[start..start + SOUND_GROUP_SIZE].copy_from_slice(group);
+        }
+
+        sector
+    }
+
+    // 37.8kHz goes up to 44.1kHz by turning every 6 samples into 7.
+    #[test]
+    fn sector_sample_counts() {
+        let group = four_bit_group();
+        let mut audio = CdAudio::new();
+
+        assert!(audio.decode_xa_sector(&xa_sector(0, &group)));
+        assert_eq!(audio.output.len(), SOUND_GROUPS * 8 * SAMPLES_PER_BLOCK * 7 / 6);
+        assert!(audio.output.iter().all(|(left, right)| left == right));
+
+        audio.output.clear();
+        audio.reset_decoder();
+        assert!(audio.decode_xa_sector(&xa_sector(CODING_STEREO | CODING_HALF_RATE, &group)));
+        assert_eq!(audio.output.len(), SOUND_GROUPS * 4 * SAMPLES_PER_BLOCK * 2 * 7 / 6);
+    }
+
+    #[test]
+    fn filter_and_mute() {
+        let sector = xa_sector(0, &four_bit_group());
+        let mut audio = CdAudio::new();
+
+        audio.filter_enabled = true;
+        audio.filter_file = 1;
+        audio.filter_channel = 2;
+        assert!(!audio.decode_xa_sector(&sector));
+        assert!(audio.output.is_empty());
+
+        audio.filter_channel = 3;
+        audio.muted = true;
+        assert!(audio.decode_xa_sector(&sector));
+        assert!(audio.output.is_empty());
+
+        audio.decode_cdda_sector(&[0x34; 2352]);
+        assert!(audio.output.is_empty());
+    }
+}
@@ -0,0 +1,472 @@
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::fs::File;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const SECTOR_SIZE: usize = 2352;
+pub const DATA_SIZE: usize = 2048;
+
+// Sector header (sync + address + mode) and the Mode 2 subheader.
+const SYNC_SIZE: usize = 12;
+const HEADER_SIZE: usize = 16;
+const SUBHEADER_SIZE: usize = 8;
+
+// Fixed part of an ISO9660 directory record, up to the name.
+const RECORD_HEADER_SIZE: usize = 33;
+
+// Discs start with a two second pregap, which raw images leave out.
+pub const PREGAP_SECTORS: u32 = 150;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    // Full 2352 byte sectors (.bin/.img).
+    Raw,
+    // Cooked 2048 byte user data sectors (.iso).
+    Cooked,
+}
+
+pub struct Disc {
+    file: File,
+    format: ImageFormat,
+    sector_count: u32,
+}
+
+impl Disc {
+    pub fn open(path: &Path) -> io::Result<Disc> {
+        let mut file = File::open(path)?;
+        let length = file.metadata()?.len();
+
+        // Plenty of sizes are multiples of both sector sizes, so look at how the image starts.
+        // Raw images of data discs begin with the sync pattern of sector 0, cooked ones with
+        // the 2048 bytes of user data that follow it.
+        let mut start = [0; SYNC_SIZE];
+        let synced = length >= SYNC_SIZE as u64 && file.read_exact(&mut start).is_ok() && has_sync_pattern(&start);
+
+        let raw = length % SECTOR_SIZE as u64 == 0;
+        let cooked = length % DATA_SIZE as u64 == 0;
+
+        let format = match (raw, cooked) {
+            (true, false) => ImageFormat::Raw,
+            (false, true) => ImageFormat::Cooked,
+            (true, true) if synced => ImageFormat::Raw,
+            (true, true) => ImageFormat::Cooked,
+            (false, false) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Image size isn't a multiple of a sector size")),
+        };
+
+        let sector_count = match format {
+            ImageFormat::Raw => length / SECTOR_SIZE as u64,
+            ImageFormat::Cooked => length / DATA_SIZE as u64,
+        } as u32;
+
+        Ok(Disc {
+            file,
+            format,
+            sector_count,
+        })
+    }
+
+    // Reads a full 2352 byte sector at the given image LBA.
+    // Cooked images get a synthesized Mode 2 Form 1 header around the user data.
+    pub fn read_raw_sector(&mut self, lba: u32) -> io::Result<Vec<u8>> {
+        if lba >= self.sector_count {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Sector {} is past the end of the disc", lba)));
+        }
+
+        let mut sector = vec![0; SECTOR_SIZE];
+
+        match self.format {
+            ImageFormat::Raw => {
+                self.file.seek(SeekFrom::Start(lba as u64 * SECTOR_SIZE as u64))?;
+                self.file.read_exact(&mut sector)?;
+            },
+            ImageFormat::Cooked => {
+                let (minute, second, frame) = lba_to_msf(lba + PREGAP_SECTORS);
+
+                sector[1..11].copy_from_slice(&[0xFF; 10]);
+                sector[12] = to_bcd(minute);
+                sector[13] = to_bcd(second);
+                sector[14] = to_bcd(frame);
+                sector[15] = 2;
+
+                // Form 1 data, submode 0x08 in both copies of the subheader.
+                sector[18] = 0x08;
+                sector[22] = 0x08;
+
+                let start = HEADER_SIZE + SUBHEADER_SIZE;
+                self.file.seek(SeekFrom::Start(lba as u64 * DATA_SIZE as u64))?;
+                self.file.read_exact(&mut sector[start..start + DATA_SIZE])?;
+            },
+        }
+
+        Ok(sector)
+    }
+
+    // Reads the 2048 bytes of user data of a data sector.
+    pub fn read_data_sector(&mut self, lba: u32) -> io::Result<Vec<u8>> {
+        match self.format {
+            ImageFormat::Raw => {
+                let sector = self.read_raw_sector(lba)?;
+
+                // Mode 1 sectors have the data right after the header,
+                // Mode 2 ones have the subheader in between.
+                let start = if sector[SYNC_SIZE + 3] == 1 {
+                    HEADER_SIZE
+                }
+                else {
+                    HEADER_SIZE + SUBHEADER_SIZE
+                };
+
+                Ok(sector[start..start + DATA_SIZE].to_vec())
+            },
+            ImageFormat::Cooked => {
+                if lba >= self.sector_count {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Sector {} is past the end of the disc", lba)));
+                }
+
+                let mut data = vec![0; DATA_SIZE];
+                self.file.seek(SeekFrom::Start(lba as u64 * DATA_SIZE as u64))?;
+                self.file.read_exact(&mut data)?;
+                Ok(data)
+            },
+        }
+    }
+
+    // Reads the contents of a file or directory, spanning as many sectors as needed.
+    pub fn read_file(&mut self, entry: &DirectoryEntry) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(entry.size as usize);
+        let mut lba = entry.lba;
+
+        while data.len() < entry.size as usize {
+            let sector = self.read_data_sector(lba)?;
+            let remaining = entry.size as usize - data.len();
+            data.extend_from_slice(&sector[..remaining.min(DATA_SIZE)]);
+            lba += 1;
+        }
+
+        Ok(data)
+    }
+
+    // Looks up a file in the ISO9660 filesystem of the disc.
+    // Paths are case-insensitive, use either kind of slash, and can omit the ";1" version suffix.
+    pub fn find_file(&mut self, path: &str) -> io::Result<DirectoryEntry> {
+        let pvd = self.read_data_sector(16)?;
+
+        if pvd[0] != 1 || &pvd[1..6] != b"CD001" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Disc doesn't have an ISO9660 primary volume descriptor"));
+        }
+
+        let mut current = DirectoryEntry::parse(&pvd[156..190])?;
+        let components: Vec<&str> = path.split(['\\', '/']).filter(|c| !c.is_empty()).collect();
+
+        for component in components {
+            if !current.is_directory {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a directory", current.name)));
+            }
+
+            let wanted = strip_version(component).to_uppercase();
+            let found = self.read_directory(&current)?.into_iter().find(|entry| strip_version(&entry.name).to_uppercase() == wanted);
+
+            current = match found {
+                Some(entry) => entry,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Couldn't find {} on the disc", path))),
+            };
+        }
+
+        Ok(current)
+    }
+
+    pub fn read_directory(&mut self, directory: &DirectoryEntry) -> io::Result<Vec<DirectoryEntry>> {
+        let data = self.read_file(directory)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let length = data[offset] as usize;
+
+            // Records never cross sector boundaries, a zero length means skip to the next sector.
+            if length == 0 {
+                offset = (offset / DATA_SIZE + 1) * DATA_SIZE;
+                continue;
+            }
+
+            if offset + length > data.len() {
+                break;
+            }
+
+            let entry = DirectoryEntry::parse(&data[offset..offset + length])?;
+
+            // Skip the "." and ".." entries.
+            if entry.name != "\u{0}" && entry.name != "\u{1}" {
+                entries.push(entry);
+            }
+
+            offset += length;
+        }
+
+        Ok(entries)
+    }
+
+    // Parses SYSTEM.CNF to find the path of the boot executable, falling back to PSX.EXE.
+    pub fn boot_executable_path(&mut self) -> io::Result<String> {
+        let entry = match self.find_file("SYSTEM.CNF") {
+            Ok(entry) => entry,
+            Err(_) => return Ok(String::from("PSX.EXE")),
+        };
+
+        let contents = self.read_file(&entry)?;
+        let contents = String::from_utf8_lossy(&contents);
+
+        for line in contents.lines() {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+
+            if key.eq_ignore_ascii_case("BOOT") {
+                let value = parts.next().unwrap_or("").trim();
+                let value = value.trim_start_matches("cdrom:").trim_start_matches("CDROM:");
+                return Ok(String::from(value.trim_start_matches(['\\', '/'])));
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::InvalidData, "SYSTEM.CNF doesn't have a BOOT line"))
+    }
+}
+
+pub struct DirectoryEntry {
+    pub name: String,
+    pub lba: u32,
+    pub size: u32,
+    pub is_directory: bool,
+}
+
+impl DirectoryEntry {
+    fn parse(record: &[u8]) -> io::Result<DirectoryEntry> {
+        if record.len() < RECORD_HEADER_SIZE || RECORD_HEADER_SIZE + record[32] as usize > record.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed ISO9660 directory record"));
+        }
+
+        let name_length = record[32] as usize;
+
+        Ok(DirectoryEntry {
+            name: String::from_utf8_lossy(&record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + name_length]).into_owned(),
+            lba: LittleEndian::read_u32(&record[2..6]),
+            size: LittleEndian::read_u32(&record[10..14]),
+            is_directory: record[25] & 0x02 != 0,
+        })
+    }
+}
+
+fn strip_version(name: &str) -> &str {
+    match name.find(';') {
+        Some(index) => &name[..index],
+        None => name,
+    }
+}
+
+// Data sectors start with 00 FF*10 00, audio sectors don't have any header at all.
+pub fn has_sync_pattern(sector: &[u8]) -> bool {
+    sector[0] == 0 && sector[1..11].iter().all(|byte| *byte == 0xFF) && sector[11] == 0
+}
+
+pub fn lba_to_msf(lba: u32) -> (u8, u8, u8) {
+    let minute = lba / (60 * 75);
+    let second = (lba / 75) % 60;
+    let frame = lba % 75;
+
+    (minute as u8, second as u8, frame as u8)
+}
+
+pub fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+    use super::*;
+
+    const ROOT_LBA: u32 = 18;
+
+    // A cooked ISO9660 image with the given files in its root directory, padded to `sectors`.
+    pub fn build_image(files: &[(&str, &[u8])], sectors: usize) -> Vec<u8> {
+        let mut image = vec![0; sectors * DATA_SIZE];
+        let mut lba = ROOT_LBA + 1;
+
+        let mut root = record(b"\0", ROOT_LBA, DATA_SIZE as u32, true);
+        root.extend(record(b"\x01", ROOT_LBA, DATA_SIZE as u32, true));
+
+        for (name, contents) in files {
+            root.extend(record(name.as_bytes(), lba, contents.len() as u32, false));
+
+            let start = lba as usize * DATA_SIZE;
+            image[start..start + contents.len()].copy_from_slice(contents);
+            lba += contents.len().div_ceil(DATA_SIZE) as u32;
+        }
+
+        let pvd = 16 * DATA_SIZE;
+        image[pvd] = 1;
+        image[pvd + 1..pvd + 6].copy_from_slice(b"CD001");
+        image[pvd + 6] = 1;
+        let root_record = record(b"\0", ROOT_LBA, DATA_SIZE as u32, true);
+        image[pvd + 156..pvd + 156 + root_record.len()].copy_from_slice(&root_record);
+
+        let terminator = 17 * DATA_SIZE;
+        image[terminator] = 0xFF;
+        image[terminator + 1..terminator + 6].copy_from_slice(b"CD001");
+
+        let directory = ROOT_LBA as usize * DATA_SIZE;
+        image[directory..directory + root.len()].copy_from_slice(&root);
+
+        image
+    }
+
+    pub fn record(name: &[u8], lba: u32, size: u32, is_directory: bool) -> Vec<u8> {
+        let length = (RECORD_HEADER_SIZE + name.len() + 1) & !1;
+        let mut record = vec![0; length];
+
+        record[0] = length as u8;
+        LittleEndian::write_u32(&mut record[2..6], lba);
+        BigEndian::write_u32(&mut record[6..10], lba);
+        LittleEndian::write_u32(&mut record[10..14], size);
+        BigEndian::write_u32(&mut record[14..18], size);
+        record[25] = if is_directory { 0x02 } else { 0x00 };
+        record[28] = 1;
+        record[32] = name.len() as u8;
+        record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + name.len()].copy_from_slice(name);
+
+        record
+    }
+
+    // The same image as full Mode 2 Form 1 sectors, padded to `sectors`.
+    pub fn to_raw(cooked: &[u8], sectors: usize) -> Vec<u8> {
+        let mut raw = vec![0; sectors * SECTOR_SIZE];
+
+        for (lba, data) in cooked.chunks(DATA_SIZE).enumerate() {
+            let sector = &mut raw[lba * SECTOR_SIZE..(lba + 1) * SECTOR_SIZE];
+            let (minute, second, frame) = lba_to_msf(lba as u32 + PREGAP_SECTORS);
+
+            sector[1..11].copy_from_slice(&[0xFF; 10]);
+            sector[12] = to_bcd(minute);
+            sector[13] = to_bcd(second);
+            sector[14] = to_bcd(frame);
+            sector[15] = 2;
+            sector[18] = 0x08;
+            sector[22] = 0x08;
+            sector[HEADER_SIZE + SUBHEADER_SIZE..HEADER_SIZE + SUBHEADER_SIZE + DATA_SIZE].copy_from_slice(data);
+        }
+
+        raw
+    }
+
+    pub fn write_image(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rusty_psx_{}_{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn error_kind<T>(result: io::Result<T>) -> io::ErrorKind {
+        result.err().unwrap().kind()
+    }
+
+    const SYSTEM_CNF: &[u8] = b"BOOT = cdrom:\\GAME\\MAIN.EXE;1\r\nTCB = 4\r\n";
+
+    // 147 cooked sectors take exactly as much space as 128 raw ones.
+    #[test]
+    fn cooked_image_with_raw_multiple_size() {
+        let image = build_image(&[("SYSTEM.CNF;1", SYSTEM_CNF), ("DATA.BIN;1", &[0x5A; 3000])], 147);
+        assert_eq!(image.len() % SECTOR_SIZE, 0);
+
+        let path = write_image("cooked.iso", &image);
+        let mut disc = Disc::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(disc.format == ImageFormat::Cooked);
+        assert_eq!(disc.sector_count, 147);
+
+        let entry = disc.find_file("/data.bin").unwrap();
+        assert_eq!(entry.size, 3000);
+        assert_eq!(disc.read_file(&entry).unwrap(), vec![0x5A; 3000]);
+    }
+
+    #[test]
+    fn raw_image_with_cooked_multiple_size() {
+        let cooked = build_image(&[("SYSTEM.CNF;1", SYSTEM_CNF)], 20);
+        let raw = to_raw(&cooked, 128);
+        assert_eq!(raw.len() % DATA_SIZE, 0);
+
+        let path = write_image("raw.bin", &raw);
+        let mut disc = Disc::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(disc.format == ImageFormat::Raw);
+        assert_eq!(disc.sector_count, 128);
+        assert_eq!(disc.read_data_sector(16).unwrap(), &cooked[16 * DATA_SIZE..17 * DATA_SIZE]);
+        assert_eq!(disc.boot_executable_path().unwrap(), "GAME\\MAIN.EXE;1");
+    }
+
+    #[test]
+    fn cooked_sectors_get_a_header() {
+        let cooked = build_image(&[], 20);
+        let path = write_image("header.iso", &cooked);
+        let mut disc = Disc::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(disc.read_raw_sector(16).unwrap(), &to_raw(&cooked, 20)[16 * SECTOR_SIZE..17 * SECTOR_SIZE]);
+        assert_eq!(error_kind(disc.read_raw_sector(20)), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn find_file_ignores_case_and_version() {
+        let image = build_image(&[("SYSTEM.CNF;1", SYSTEM_CNF)], 20);
+        let path = write_image("find.iso", &image);
+        let mut disc = Disc::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(disc.find_file("system.cnf").unwrap().name, "SYSTEM.CNF;1");
+        assert_eq!(disc.find_file("\\SYSTEM.CNF;1").unwrap().size, SYSTEM_CNF.len() as u32);
+        assert_eq!(error_kind(disc.find_file("MISSING.EXE")), io::ErrorKind::NotFound);
+        assert_eq!(error_kind(disc.find_file("SYSTEM.CNF/INNER")), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn boot_path_defaults_to_psx_exe() {
+        let image = build_image(&[("PSX.EXE;1", &[0; 16])], 20);
+        let path = write_image("default.iso", &image);
+        let mut disc = Disc::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(disc.boot_executable_path().unwrap(), "PSX.EXE");
+    }
+
+    #[test]
+    fn malformed_directory_records() {
+        assert!(DirectoryEntry::parse(&record(b"A.BIN;1", 20, 1, false)).is_ok());
+        assert_eq!(error_kind(DirectoryEntry::parse(&[1])), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind(DirectoryEntry::parse(&record(b"A.BIN;1", 20, 1, false)[..38])), io::ErrorKind::InvalidData);
+
+        // A record that claims a single byte, inside an otherwise valid root directory.
+        let mut image = build_image(&[], 20);
+        image[ROOT_LBA as usize * DATA_SIZE + 68] = 1;
+
+        let path = write_image("malformed.iso", &image);
+        let mut disc = Disc::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(error_kind(disc.find_file("A.BIN")), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn odd_sizes_are_rejected() {
+        let path = write_image("odd.iso", &[0; 1000]);
+        let result = Disc::open(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(error_kind(result), io::ErrorKind::InvalidData);
+    }
+}
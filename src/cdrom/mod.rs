@@ -0,0 +1,669 @@
+pub mod disc;
+pub mod audio;
+
+use std::io;
+use std::io::Write;
+use std::fs::File;
+use std::path::Path;
+use std::collections::VecDeque;
+
+use super::savestate::{StateReader, StateWriter};
+
+// Setmode bits.
+pub const MODE_CDDA: u8 = 0x01;
+pub const MODE_XA_FILTER: u8 = 0x08;
+pub const MODE_WHOLE_SECTOR: u8 = 0x20;
+pub const MODE_XA_ADPCM: u8 = 0x40;
+pub const MODE_DOUBLE_SPEED: u8 = 0x80;
+
+// Bits of the status byte that starts most responses.
+const STAT_ERROR: u8 = 0x01;
+const STAT_MOTOR_ON: u8 = 0x02;
+const STAT_READING: u8 = 0x20;
+const STAT_PLAYING: u8 = 0x80;
+
+// INT1 to INT5, in the interrupt flag register.
+const INT_DATA_READY: u8 = 1;
+const INT_COMPLETE: u8 = 2;
+const INT_ACKNOWLEDGE: u8 = 3;
+const INT_ERROR: u8 = 5;
+
+// CPU cycles at 33.8688MHz.
+const FIRST_RESPONSE_DELAY: u32 = 50_000;
+const SECOND_RESPONSE_DELAY: u32 = 500_000;
+const SECTOR_CYCLES: u32 = 33_868_800 / 75;
+
+const PARAMETER_FIFO_SIZE: usize = 16;
+const DATA_OFFSET: usize = 24;
+const WHOLE_SECTOR_OFFSET: usize = 12;
+
+// What the drive does on its own between commands.
+#[derive(Clone, Copy, PartialEq)]
+enum Activity {
+    Idle,
+    Reading,
+    Playing,
+}
+
+impl Activity {
+    fn from_u8(value: u8) -> Activity {
+        match value {
+            1 => Activity::Reading,
+            2 => Activity::Playing,
+            _ => Activity::Idle,
+        }
+    }
+}
+
+// A response waiting for its delay to run out and for the previous interrupt to be acknowledged.
+struct Response {
+    delay: u32,
+    interrupt: u8,
+    bytes: Vec<u8>,
+}
+
+pub struct Cdrom {
+    pub disc: Option<disc::Disc>,
+    pub audio: audio::CdAudio,
+
+    pub index: u8,
+    pub mode: u8,
+    pub parameters: Vec<u8>,
+
+    interrupt_enable: u8,
+    interrupt_flag: u8,
+    response: VecDeque<u8>,
+    pending: VecDeque<Response>,
+
+    activity: Activity,
+    // Image LBA of the next sector to read or play, and the target of the last Setloc.
+    position: u32,
+    location: u32,
+    sector_timer: u32,
+    // The last sector read, moved to the data FIFO when the CPU asks for it.
+    sector: Vec<u8>,
+    data: VecDeque<u8>,
+
+    // Set when an interrupt was raised, cleared by the memory bus once it's been passed on.
+    pub irq_pending: bool,
+}
+
+impl Cdrom {
+    pub fn new() -> Cdrom {
+        Cdrom {
+            disc: None,
+            audio: audio::CdAudio::new(),
+
+            index: 0,
+            mode: 0,
+            parameters: Vec::new(),
+
+            interrupt_enable: 0,
+            interrupt_flag: 0,
+            response: VecDeque::new(),
+            pending: VecDeque::new(),
+
+            activity: Activity::Idle,
+            position: 0,
+            location: 0,
+            sector_timer: 0,
+            sector: Vec::new(),
+            data: VecDeque::new(),
+
+            irq_pending: false,
+        }
+    }
+
+    pub fn insert_disc(&mut self, path: &Path) -> io::Result<()> {
+        self.disc = Some(disc::Disc::open(path)?);
+        Ok(())
+    }
+
+    // The disc itself isn't part of the state, save states are tied to it through a hash instead.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"CDRM");
+        state.write_u8(self.index);
+        state.write_u8(self.mode);
+        state.write_bytes(&self.parameters);
+
+        state.write_u8(self.interrupt_enable);
+        state.write_u8(self.interrupt_flag);
+        state.write_bytes(&self.response.iter().cloned().collect::<Vec<u8>>());
+        state.write_u32(self.pending.len() as u32);
+        for response in self.pending.iter() {
+            state.write_u32(response.delay);
+            state.write_u8(response.interrupt);
+            state.write_bytes(&response.bytes);
+        }
+
+        state.write_u8(self.activity as u8);
+        state.write_u32(self.position);
+        state.write_u32(self.location);
+        state.write_u32(self.sector_timer);
+        state.write_bytes(&self.sector);
+        state.write_bytes(&self.data.iter().cloned().collect::<Vec<u8>>());
+
+        self.audio.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"CDRM")?;
+        self.index = state.read_u8()?;
+        self.mode = state.read_u8()?;
+        self.parameters = state.read_bytes()?;
+
+        self.interrupt_enable = state.read_u8()?;
+        self.interrupt_flag = state.read_u8()?;
+        self.response = state.read_bytes()?.into_iter().collect();
+        self.pending.clear();
+        for _ in 0..state.read_u32()? {
+            let delay = state.read_u32()?;
+            let interrupt = state.read_u8()?;
+            let bytes = state.read_bytes()?;
+            self.pending.push_back(Response { delay, interrupt, bytes });
+        }
+
+        self.activity = Activity::from_u8(state.read_u8()?);
+        self.position = state.read_u32()?;
+        self.location = state.read_u32()?;
+        self.sector_timer = state.read_u32()?;
+        self.sector = state.read_bytes()?;
+        self.data = state.read_bytes()?.into_iter().collect();
+
+        self.audio.load_state(state)
+    }
+
+    pub fn set_mode(&mut self, mode: u8) {
+        self.mode = mode;
+        self.audio.filter_enabled = mode & MODE_XA_FILTER != 0;
+    }
+
+    pub fn set_filter(&mut self, file: u8, channel: u8) {
+        self.audio.filter_file = file;
+        self.audio.filter_channel = channel;
+    }
+
+    // Feeds a sector read from a data track through the audio path.
+    // Returns true if the sector was consumed as XA audio and shouldn't be delivered to the CPU.
+    pub fn process_data_sector(&mut self, sector: &[u8]) -> bool {
+        if self.mode & MODE_XA_ADPCM == 0 || !audio::CdAudio::is_xa_sector(sector) {
+            return false;
+        }
+
+        self.audio.decode_xa_sector(sector);
+        true
+    }
+
+    pub fn process_audio_sector(&mut self, sector: &[u8]) {
+        if self.mode & MODE_CDDA != 0 {
+            self.audio.decode_cdda_sector(sector);
+        }
+    }
+
+    // Decodes a run of sectors straight to a raw 44.1kHz s16le stereo file, with the XA filter disabled.
+    // Used to check the audio path against reference PCM dumps without running the CPU.
+    pub fn dump_audio(&mut self, start_lba: u32, sector_count: u32, output_path: &Path) -> io::Result<()> {
+        let mut output = File::create(output_path)?;
+
+        self.set_mode(MODE_CDDA | MODE_XA_ADPCM);
+        self.audio.reset_decoder();
+
+        for lba in start_lba..start_lba + sector_count {
+            let sector = match self.disc.as_mut() {
+                Some(disc) => disc.read_raw_sector(lba)?,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "No disc inserted")),
+            };
+
+            if disc::has_sync_pattern(&sector) {
+                self.process_data_sector(&sector);
+            }
+            else {
+                self.process_audio_sector(&sector);
+            }
+
+            let mut samples = Vec::with_capacity(self.audio.output.len() * 4);
+            for (left, right) in self.audio.output.drain(..) {
+                samples.extend_from_slice(&left.to_le_bytes());
+                samples.extend_from_slice(&right.to_le_bytes());
+            }
+            output.write_all(&samples)?;
+        }
+
+        Ok(())
+    }
+
+    fn stat(&self) -> u8 {
+        let activity = match self.activity {
+            Activity::Idle => 0,
+            Activity::Reading => STAT_READING,
+            Activity::Playing => STAT_PLAYING,
+        };
+
+        if self.disc.is_some() { STAT_MOTOR_ON | activity } else { activity }
+    }
+
+    fn respond(&mut self, delay: u32, interrupt: u8, bytes: Vec<u8>) {
+        self.pending.push_back(Response { delay, interrupt, bytes });
+    }
+
+    fn acknowledge(&mut self) {
+        let stat = self.stat();
+        self.respond(FIRST_RESPONSE_DELAY, INT_ACKNOWLEDGE, vec![stat]);
+    }
+
+    fn error(&mut self, code: u8) {
+        let stat = self.stat() | STAT_ERROR;
+        self.respond(FIRST_RESPONSE_DELAY, INT_ERROR, vec![stat, code]);
+    }
+
+    // Starts reading or playing from the last Setloc. Both need a disc.
+    fn start(&mut self, activity: Activity) {
+        if self.disc.is_none() {
+            self.error(0x80);
+            return;
+        }
+
+        self.activity = activity;
+        self.position = self.location;
+        self.sector_timer = 0;
+        self.audio.reset_decoder();
+        self.acknowledge();
+    }
+
+    fn command(&mut self, command: u8) {
+        match command {
+            // Getstat
+            0x01 => self.acknowledge(),
+            // Setloc, as BCD minutes, seconds and sectors including the two second pregap.
+            0x02 if self.parameters.len() >= 3 => {
+                let [minute, second, sector] = [from_bcd(self.parameters[0]), from_bcd(self.parameters[1]), from_bcd(self.parameters[2])];
+                self.location = ((minute * 60 + second) * 75 + sector).saturating_sub(150);
+                self.acknowledge();
+            },
+            // Play. The track parameter would need a table of contents, so it always plays from Setloc.
+            0x03 => self.start(Activity::Playing),
+            // ReadN and ReadS.
+            0x06 | 0x1B => self.start(Activity::Reading),
+            // Stop and Pause.
+            0x08 | 0x09 => {
+                self.acknowledge();
+                self.activity = Activity::Idle;
+                let stat = self.stat();
+                self.respond(SECOND_RESPONSE_DELAY, INT_COMPLETE, vec![stat]);
+            },
+            // Init
+            0x0A => {
+                self.acknowledge();
+                self.activity = Activity::Idle;
+                self.set_mode(0);
+                let stat = self.stat();
+                self.respond(SECOND_RESPONSE_DELAY, INT_COMPLETE, vec![stat]);
+            },
+            0x0B => {
+                self.audio.muted = true;
+                self.acknowledge();
+            },
+            0x0C => {
+                self.audio.muted = false;
+                self.acknowledge();
+            },
+            0x0D if self.parameters.len() >= 2 => {
+                self.set_filter(self.parameters[0], self.parameters[1]);
+                self.acknowledge();
+            },
+            0x0E if !self.parameters.is_empty() => {
+                self.set_mode(self.parameters[0]);
+                self.acknowledge();
+            },
+            // SeekL and SeekP, which only move the head.
+            0x15 | 0x16 => {
+                self.position = self.location;
+                self.activity = Activity::Idle;
+                self.acknowledge();
+                let stat = self.stat();
+                self.respond(SECOND_RESPONSE_DELAY, INT_COMPLETE, vec![stat]);
+            },
+            // Test 20h, the controller's version and date.
+            0x19 if self.parameters.first() == Some(&0x20) => self.respond(FIRST_RESPONSE_DELAY, INT_ACKNOWLEDGE, vec![0x94, 0x09, 0x19, 0xC0]),
+            // GetID, reporting a licensed NTSC-U data disc.
+            0x1A => {
+                if self.disc.is_some() {
+                    self.acknowledge();
+                    self.respond(SECOND_RESPONSE_DELAY, INT_COMPLETE, vec![STAT_MOTOR_ON, 0x00, 0x20, 0x00, b'S', b'C', b'E', b'A']);
+                }
+                else {
+                    self.acknowledge();
+                    self.respond(SECOND_RESPONSE_DELAY, INT_ERROR, vec![0x08, 0x40, 0, 0, 0, 0, 0, 0]);
+                }
+            },
+            _ => {
+                println!("Unhandled CD-ROM command {:02X}", command);
+                self.error(0x40);
+            },
+        }
+
+        self.parameters.clear();
+    }
+
+    // Advances the drive. Sectors come in at 75 or 150 a second, and responses come out one at
+    // a time, each waiting for the previous interrupt to be acknowledged.
+    pub fn tick(&mut self, cycles: u32) {
+        if self.activity != Activity::Idle {
+            self.advance_head(cycles);
+        }
+
+        if let Some(response) = self.pending.front_mut() {
+            response.delay = response.delay.saturating_sub(cycles);
+        }
+
+        if self.interrupt_flag == 0 && self.pending.front().map(|response| response.delay == 0).unwrap_or(false) {
+            let response = self.pending.pop_front().unwrap();
+            self.interrupt_flag = response.interrupt;
+            self.response = response.bytes.into_iter().collect();
+
+            if self.interrupt_flag & self.interrupt_enable != 0 {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn advance_head(&mut self, cycles: u32) {
+        let period = if self.mode & MODE_DOUBLE_SPEED != 0 { SECTOR_CYCLES / 2 } else { SECTOR_CYCLES };
+        self.sector_timer += cycles;
+        if self.sector_timer < period {
+            return;
+        }
+        self.sector_timer -= period;
+
+        let position = self.position;
+        let sector = match self.disc.as_mut().map(|disc| disc.read_raw_sector(position)) {
+            Some(Ok(sector)) => sector,
+            _ => {
+                self.activity = Activity::Idle;
+                self.error(0x04);
+                return;
+            },
+        };
+        self.position += 1;
+
+        if self.activity == Activity::Playing {
+            self.process_audio_sector(&sector);
+            return;
+        }
+
+        if self.process_data_sector(&sector) {
+            return;
+        }
+
+        let (start, length) = if self.mode & MODE_WHOLE_SECTOR != 0 { (WHOLE_SECTOR_OFFSET, 0x924) } else { (DATA_OFFSET, 0x800) };
+        self.sector = sector[start..start + length].to_vec();
+
+        // A sector the CPU didn't get to in time is overwritten by the next one.
+        self.pending.retain(|response| response.interrupt != INT_DATA_READY);
+        let stat = self.stat();
+        self.respond(0, INT_DATA_READY, vec![stat]);
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.data.pop_front().unwrap_or(0)
+    }
+
+    // Data FIFO reads through DMA channel 3.
+    pub fn read_data_word(&mut self) -> u32 {
+        (0..4).fold(0, |word, byte| word | (self.read_data() as u32) << (byte * 8))
+    }
+
+    pub fn read_register(&mut self, offset: u32) -> u8 {
+        match (offset, self.index) {
+            (0, _) => {
+                let mut status = self.index;
+                if self.parameters.is_empty() { status |= 0x08; }
+                if self.parameters.len() < PARAMETER_FIFO_SIZE { status |= 0x10; }
+                if !self.response.is_empty() { status |= 0x20; }
+                if !self.data.is_empty() { status |= 0x40; }
+                if self.pending.front().map(|response| response.interrupt == INT_ACKNOWLEDGE).unwrap_or(false) { status |= 0x80; }
+                status
+            },
+            (1, _) => self.response.pop_front().unwrap_or(0),
+            (2, _) => self.read_data(),
+            (3, 0) | (3, 2) => self.interrupt_enable | 0xE0,
+            (3, 1) | (3, 3) => self.interrupt_flag | 0xE0,
+            _ => {
+                println!("Unhandled CD-ROM register read at offset {}, index {}", offset, self.index);
+                0
+            },
+        }
+    }
+
+    pub fn write_register(&mut self, offset: u32, value: u8) {
+        match (offset, self.index) {
+            (0, _) => self.index = value & 0x03,
+            (1, 0) => self.command(value),
+            (2, 0) => {
+                if self.parameters.len() < PARAMETER_FIFO_SIZE {
+                    self.parameters.push(value);
+                }
+            },
+            // Request register, bit 7 asks for the sector just read.
+            (3, 0) => {
+                if value & 0x80 != 0 {
+                    if self.data.is_empty() {
+                        self.data = self.sector.iter().cloned().collect();
+                    }
+                }
+                else {
+                    self.data.clear();
+                }
+            },
+            (2, 1) => self.interrupt_enable = value & 0x1F,
+            (3, 1) => {
+                self.interrupt_flag &= !(value & 0x1F);
+                if value & 0x40 != 0 {
+                    self.parameters.clear();
+                }
+            },
+
+            (2, 2) => self.audio.pending_volume.left_to_left = value,
+            (3, 2) => self.audio.pending_volume.left_to_right = value,
+            (1, 3) => self.audio.pending_volume.right_to_right = value,
+            (2, 3) => self.audio.pending_volume.right_to_left = value,
+            (3, 3) => {
+                self.audio.adpcm_muted = value & 0x01 != 0;
+                if value & 0x20 != 0 {
+                    self.audio.apply_volume();
+                }
+            },
+
+            _ => println!("Unhandled CD-ROM register write {:02X} at offset {}, index {}", value, offset, self.index),
+        }
+    }
+}
+
+fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0x0F) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::disc::tests::{build_image, to_raw, write_image};
+
+    fn send(cdrom: &mut Cdrom, command: u8, parameters: &[u8]) {
+        cdrom.write_register(0, 0);
+        for parameter in parameters {
+            cdrom.write_register(2, *parameter);
+        }
+        cdrom.write_register(1, command);
+    }
+
+    #[test]
+    fn mute_and_demute() {
+        let mut cdrom = Cdrom::new();
+
+        send(&mut cdrom, 0x0B, &[]);
+        assert!(cdrom.audio.muted);
+
+        send(&mut cdrom, 0x0E, &[MODE_CDDA]);
+        cdrom.process_audio_sector(&[0x12; 2352]);
+        assert!(cdrom.audio.output.is_empty());
+
+        send(&mut cdrom, 0x0C, &[]);
+        assert!(!cdrom.audio.muted);
+
+        cdrom.process_audio_sector(&[0x12; 2352]);
+        assert_eq!(cdrom.audio.output.len(), 588);
+    }
+
+    #[test]
+    fn setfilter_and_setmode() {
+        let mut cdrom = Cdrom::new();
+
+        send(&mut cdrom, 0x0D, &[1, 5]);
+        send(&mut cdrom, 0x0E, &[MODE_XA_ADPCM | MODE_XA_FILTER]);
+
+        assert_eq!((cdrom.audio.filter_file, cdrom.audio.filter_channel), (1, 5));
+        assert!(cdrom.audio.filter_enabled);
+        assert!(cdrom.parameters.is_empty());
+    }
+
+    fn enable_interrupts(cdrom: &mut Cdrom) {
+        cdrom.write_register(0, 1);
+        cdrom.write_register(2, 0x1F);
+        cdrom.write_register(0, 0);
+    }
+
+    fn acknowledge(cdrom: &mut Cdrom) {
+        cdrom.write_register(0, 1);
+        cdrom.write_register(3, 0x1F);
+        cdrom.write_register(0, 0);
+    }
+
+    fn interrupt_flag(cdrom: &mut Cdrom) -> u8 {
+        cdrom.write_register(0, 1);
+        let flag = cdrom.read_register(3) & 0x07;
+        cdrom.write_register(0, 0);
+        flag
+    }
+
+    // A raw image whose sector 19 holds a file of `fill` bytes, edited by `patch`.
+    fn drive_with_sector<F: Fn(&mut [u8])>(name: &str, fill: u8, patch: F) -> Cdrom {
+        let file = vec![fill; disc::DATA_SIZE];
+        let mut image = to_raw(&build_image(&[("DATA.BIN;1", &file)], 24), 24);
+        patch(&mut image[19 * disc::SECTOR_SIZE..20 * disc::SECTOR_SIZE]);
+
+        let path = write_image(name, &image);
+        let mut cdrom = Cdrom::new();
+        cdrom.insert_disc(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        enable_interrupts(&mut cdrom);
+        send(&mut cdrom, 0x02, &[0x00, 0x02, 0x19]);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        acknowledge(&mut cdrom);
+        cdrom
+    }
+
+    #[test]
+    fn responses_wait_for_their_delay_and_the_acknowledge() {
+        let mut cdrom = Cdrom::new();
+        enable_interrupts(&mut cdrom);
+
+        send(&mut cdrom, 0x01, &[]);
+        send(&mut cdrom, 0x19, &[0x20]);
+        assert_eq!(cdrom.read_register(0) & 0x80, 0x80);
+
+        cdrom.tick(FIRST_RESPONSE_DELAY - 1);
+        assert_eq!(interrupt_flag(&mut cdrom), 0);
+
+        cdrom.tick(1);
+        assert!(cdrom.irq_pending);
+        assert_eq!(interrupt_flag(&mut cdrom), INT_ACKNOWLEDGE);
+        assert_eq!(cdrom.read_register(0) & 0x20, 0x20);
+        assert_eq!(cdrom.read_register(1), 0);
+        assert_eq!(cdrom.read_register(0) & 0x20, 0);
+
+        // The version has to wait for the Getstat interrupt to be acknowledged.
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        assert_eq!(cdrom.read_register(0) & 0x20, 0);
+        acknowledge(&mut cdrom);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        assert_eq!(interrupt_flag(&mut cdrom), INT_ACKNOWLEDGE);
+        assert_eq!((0..4).map(|_| cdrom.read_register(1)).collect::<Vec<u8>>(), vec![0x94, 0x09, 0x19, 0xC0]);
+    }
+
+    #[test]
+    fn reading_without_a_disc_fails() {
+        let mut cdrom = Cdrom::new();
+        send(&mut cdrom, 0x06, &[]);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+
+        assert_eq!(interrupt_flag(&mut cdrom), INT_ERROR);
+        assert_eq!(cdrom.read_register(1), STAT_ERROR);
+        assert_eq!(cdrom.read_register(1), 0x80);
+    }
+
+    #[test]
+    fn readn_delivers_sectors() {
+        let mut cdrom = drive_with_sector("readn.bin", 0x5A, |_| {});
+
+        send(&mut cdrom, 0x06, &[]);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        assert_eq!(interrupt_flag(&mut cdrom), INT_ACKNOWLEDGE);
+        assert_eq!(cdrom.read_register(1), STAT_MOTOR_ON | STAT_READING);
+        acknowledge(&mut cdrom);
+
+        cdrom.tick(SECTOR_CYCLES);
+        assert_eq!(interrupt_flag(&mut cdrom), INT_DATA_READY);
+        acknowledge(&mut cdrom);
+
+        cdrom.write_register(3, 0x80);
+        assert_eq!(cdrom.read_register(0) & 0x40, 0x40);
+        let data: Vec<u8> = (0..0x800).map(|_| cdrom.read_register(2)).collect();
+        assert!(data.iter().all(|byte| *byte == 0x5A));
+        assert_eq!(cdrom.read_register(0) & 0x40, 0);
+    }
+
+    #[test]
+    fn reads_send_xa_sectors_to_the_audio_path() {
+        let mut cdrom = drive_with_sector("reads.bin", 0, |sector| {
+            sector[16..24].copy_from_slice(&[1, 0, 0x24, 0x00, 1, 0, 0x24, 0x00]);
+        });
+
+        send(&mut cdrom, 0x0E, &[MODE_XA_ADPCM]);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        acknowledge(&mut cdrom);
+        send(&mut cdrom, 0x1B, &[]);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        acknowledge(&mut cdrom);
+
+        cdrom.tick(SECTOR_CYCLES);
+        assert_eq!(interrupt_flag(&mut cdrom), 0);
+        // 18 sound groups of 8 blocks of 28 samples, upsampled from 37.8kHz by 7/6.
+        assert_eq!(cdrom.audio.output.len(), 18 * 8 * 28 * 7 / 6);
+    }
+
+    #[test]
+    fn play_sends_cd_audio_to_the_spu() {
+        let mut cdrom = drive_with_sector("play.bin", 0, |_| {});
+
+        send(&mut cdrom, 0x0E, &[MODE_CDDA | MODE_DOUBLE_SPEED]);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        acknowledge(&mut cdrom);
+        send(&mut cdrom, 0x03, &[]);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        assert_eq!(cdrom.read_register(1), STAT_MOTOR_ON | STAT_PLAYING);
+        acknowledge(&mut cdrom);
+
+        cdrom.tick(SECTOR_CYCLES / 2);
+        assert_eq!(cdrom.audio.output.len(), 588);
+        assert_eq!(interrupt_flag(&mut cdrom), 0);
+
+        send(&mut cdrom, 0x09, &[]);
+        cdrom.tick(FIRST_RESPONSE_DELAY);
+        acknowledge(&mut cdrom);
+        cdrom.tick(SECOND_RESPONSE_DELAY);
+        assert_eq!(interrupt_flag(&mut cdrom), INT_COMPLETE);
+        assert_eq!(cdrom.read_register(1), STAT_MOTOR_ON);
+        assert_eq!(cdrom.audio.output.len(), 588);
+    }
+}
@@ -0,0 +1,457 @@
+// Execution breakpoints for the debugger.
+// They stay set after firing, can be switched off without losing them, and can skip a number
+// of hits or only stop when a condition over the registers and memory holds.
+
+use std::collections::HashMap;
+
+use super::super::instructions_decoder::REGISTER_NAMES;
+use super::super::memory::CpuMemory;
+
+pub struct Breakpoint {
+    pub address: u32,
+    pub enabled: bool,
+    // Times execution reached the address with the condition holding.
+    pub hit_count: u32,
+    // Hits to let through before stopping.
+    pub ignore_count: u32,
+    pub condition: Option<Condition>,
+}
+
+// What a condition can look at when a breakpoint is reached.
+pub struct Context<'a> {
+    pub pc: u32,
+    pub registers: &'a [u32],
+    pub hi: u32,
+    pub lo: u32,
+    pub memory: &'a CpuMemory,
+}
+
+pub struct Breakpoints {
+    // Keyed by address, so checking the PC doesn't go through every breakpoint.
+    breakpoints: HashMap<u32, Breakpoint>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints {
+            breakpoints: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    pub fn get(&self, address: u32) -> Option<&Breakpoint> {
+        self.breakpoints.get(&address)
+    }
+
+    pub fn get_mut(&mut self, address: u32) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&address)
+    }
+
+    // Sorted by address, for listing.
+    pub fn list(&self) -> Vec<&Breakpoint> {
+        let mut list: Vec<&Breakpoint> = self.breakpoints.values().collect();
+        list.sort_by_key(|breakpoint| breakpoint.address);
+        list
+    }
+
+    // Adds an enabled breakpoint, or replaces the condition of the one already there.
+    pub fn add(&mut self, address: u32, condition: Option<Condition>) {
+        let breakpoint = self.breakpoints.entry(address).or_insert(Breakpoint {
+            address,
+            enabled: true,
+            hit_count: 0,
+            ignore_count: 0,
+            condition: None,
+        });
+
+        breakpoint.enabled = true;
+        breakpoint.condition = condition;
+    }
+
+    pub fn remove(&mut self, address: u32) {
+        self.breakpoints.remove(&address);
+    }
+
+    pub fn toggle(&mut self, address: u32) {
+        if self.breakpoints.contains_key(&address) {
+            self.remove(address);
+        }
+        else {
+            self.add(address, None);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // Called with the PC of the instruction about to run. Counts the hit and returns true
+    // if execution should stop there.
+    pub fn hit(&mut self, context: &Context) -> bool {
+        let breakpoint = match self.breakpoints.get_mut(&context.pc) {
+            Some(breakpoint) if breakpoint.enabled => breakpoint,
+            _ => return false,
+        };
+
+        if let Some(condition) = &breakpoint.condition {
+            if !condition.evaluate(context) {
+                return false;
+            }
+        }
+
+        breakpoint.hit_count += 1;
+        breakpoint.hit_count > breakpoint.ignore_count
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+enum Value {
+    Constant(u32),
+    Register(usize),
+    Pc,
+    Hi,
+    Lo,
+    // Word in memory at the address.
+    Memory(Box<Value>),
+    Add(Box<Value>, Box<Value>),
+    Subtract(Box<Value>, Box<Value>),
+}
+
+impl Value {
+    // None when it reads memory that isn't plain memory.
+    fn evaluate(&self, context: &Context) -> Option<u32> {
+        match self {
+            Value::Constant(value) => Some(*value),
+            Value::Register(index) => Some(context.registers[*index]),
+            Value::Pc => Some(context.pc),
+            Value::Hi => Some(context.hi),
+            Value::Lo => Some(context.lo),
+            Value::Memory(address) => context.memory.peek_word(address.evaluate(context)?),
+            Value::Add(left, right) => Some(left.evaluate(context)?.wrapping_add(right.evaluate(context)?)),
+            Value::Subtract(left, right) => Some(left.evaluate(context)?.wrapping_sub(right.evaluate(context)?)),
+        }
+    }
+}
+
+// Comparisons of registers, memory words and constants, joined with && and ||,
+// like `a0 == 0x1F801810 && [sp+0x10] != 0`. Comparisons are unsigned.
+pub struct Condition {
+    pub text: String,
+    // Any of the groups has to hold, and all of the comparisons in a group.
+    groups: Vec<Vec<(Value, Operator, Value)>>,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let mut groups = Vec::new();
+
+        loop {
+            let mut group = Vec::new();
+
+            loop {
+                let left = parser.value()?;
+                let operator = match parser.next() {
+                    Some(Token::Operator(operator)) => *operator,
+                    _ => return Err(String::from("Expected a comparison like ==, !=, <, <=, > or >=")),
+                };
+                let right = parser.value()?;
+                group.push((left, operator, right));
+
+                if !parser.accept(&Token::And) {
+                    break;
+                }
+            }
+
+            groups.push(group);
+
+            if !parser.accept(&Token::Or) {
+                break;
+            }
+        }
+
+        if parser.position != tokens.len() {
+            return Err(String::from("Unexpected text after the condition"));
+        }
+
+        Ok(Condition {
+            text: String::from(text.trim()),
+            groups,
+        })
+    }
+
+    pub fn evaluate(&self, context: &Context) -> bool {
+        self.groups.iter().any(|group| group.iter().all(|(left, operator, right)| {
+            let (left, right) = match (left.evaluate(context), right.evaluate(context)) {
+                (Some(left), Some(right)) => (left, right),
+                _ => return false,
+            };
+
+            match operator {
+                Operator::Equal => left == right,
+                Operator::NotEqual => left != right,
+                Operator::Less => left < right,
+                Operator::LessOrEqual => left <= right,
+                Operator::Greater => left > right,
+                Operator::GreaterOrEqual => left >= right,
+            }
+        }))
+    }
+}
+
+#[derive(PartialEq)]
+enum Token {
+    Number(u32),
+    Name(String),
+    Operator(Operator),
+    Plus,
+    Minus,
+    OpenBracket,
+    CloseBracket,
+    And,
+    Or,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let characters: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+
+    while position < characters.len() {
+        let character = characters[position];
+        let next = characters.get(position + 1).cloned();
+
+        let (token, length) = match (character, next) {
+            (' ', _) | ('\t', _) => {
+                position += 1;
+                continue;
+            },
+            ('=', Some('=')) => (Token::Operator(Operator::Equal), 2),
+            ('!', Some('=')) => (Token::Operator(Operator::NotEqual), 2),
+            ('<', Some('=')) => (Token::Operator(Operator::LessOrEqual), 2),
+            ('>', Some('=')) => (Token::Operator(Operator::GreaterOrEqual), 2),
+            ('<', _) => (Token::Operator(Operator::Less), 1),
+            ('>', _) => (Token::Operator(Operator::Greater), 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('+', _) => (Token::Plus, 1),
+            ('-', _) => (Token::Minus, 1),
+            ('[', _) => (Token::OpenBracket, 1),
+            (']', _) => (Token::CloseBracket, 1),
+            _ if character.is_ascii_alphanumeric() || character == '$' || character == '_' => {
+                let end = characters[position..].iter()
+                    .position(|character| !(character.is_ascii_alphanumeric() || *character == '$' || *character == '_'))
+                    .map(|length| position + length)
+                    .unwrap_or(characters.len());
+                let word: String = characters[position..end].iter().collect();
+
+                let token = if character.is_ascii_digit() {
+                    Token::Number(parse_number(&word)?)
+                }
+                else {
+                    Token::Name(word.to_lowercase())
+                };
+                (token, end - position)
+            },
+            _ => return Err(format!("Unexpected '{}' in the condition", character)),
+        };
+
+        tokens.push(token);
+        position += length;
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Result<u32, String> {
+    let word = word.to_lowercase();
+    let result = match word.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => word.parse::<u32>(),
+    };
+
+    result.map_err(|_| format!("\"{}\" isn't a number", word))
+}
+
+fn parse_register(name: &str) -> Option<Value> {
+    let name = name.trim_start_matches('$');
+
+    match name {
+        "pc" => return Some(Value::Pc),
+        "hi" => return Some(Value::Hi),
+        "lo" => return Some(Value::Lo),
+        _ => {},
+    }
+
+    if let Some(index) = REGISTER_NAMES.iter().position(|register| &register[1..] == name) {
+        return Some(Value::Register(index));
+    }
+
+    // r0 to r31, as the register view shows them.
+    name.strip_prefix('r')
+        .and_then(|index| index.parse::<usize>().ok())
+        .filter(|index| *index < 32)
+        .map(Value::Register)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn accept(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.position) == Some(token) {
+            self.position += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    // Terms added or subtracted together.
+    fn value(&mut self) -> Result<Value, String> {
+        let mut value = self.term()?;
+
+        loop {
+            if self.accept(&Token::Plus) {
+                value = Value::Add(Box::new(value), Box::new(self.term()?));
+            }
+            else if self.accept(&Token::Minus) {
+                value = Value::Subtract(Box::new(value), Box::new(self.term()?));
+            }
+            else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Value::Constant(*value)),
+            Some(Token::Name(name)) => parse_register(name).ok_or_else(|| format!("\"{}\" isn't a register", name)),
+            Some(Token::OpenBracket) => {
+                let address = self.value()?;
+                if !self.accept(&Token::CloseBracket) {
+                    return Err(String::from("Missing ]"));
+                }
+                Ok(Value::Memory(Box::new(address)))
+            },
+            _ => Err(String::from("Expected a number, a register or [address]")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context<'a>(registers: &'a [u32], memory: &'a CpuMemory) -> Context<'a> {
+        Context {
+            pc: 0x80010000,
+            registers,
+            hi: 0x10,
+            lo: 0x20,
+            memory,
+        }
+    }
+
+    fn holds(text: &str, context: &Context) -> bool {
+        Condition::parse(text).unwrap().evaluate(context)
+    }
+
+    #[test]
+    fn conditions_compare_registers_and_memory() {
+        let mut memory = CpuMemory::new(vec![0; 512 * 1024]);
+        memory.write_word(0x801FFF10, 0xCAFE);
+
+        let mut registers = vec![0; 32];
+        registers[4] = 0x1F801810;
+        registers[8] = 0xFFFFFFFF;
+        registers[29] = 0x801FFF00;
+        let context = context(&registers, &memory);
+
+        assert!(holds("a0 == 0x1F801810 && [sp+0x10] != 0", &context));
+        assert!(holds("$a0 == 0x1F801810", &context));
+        assert!(holds("r4 == 528488464", &context));
+        assert!(holds("[sp + 0x20 - 0x10] == 0xCAFE", &context));
+        assert!(holds("pc == 0x80010000 && hi < lo && lo <= 0x20 && hi >= 0x10", &context));
+        assert!(!holds("a0 == 0 && pc == 0x80010000", &context));
+
+        // Any group holding is enough.
+        assert!(holds("a0 == 0 || t0 == 0xFFFFFFFF", &context));
+        assert!(!holds("a0 == 0 || t0 == 0", &context));
+
+        // Comparisons are unsigned.
+        assert!(holds("t0 > 0x7FFFFFFF", &context));
+
+        // I/O registers can't be peeked, so comparisons against them never hold.
+        assert!(!holds("[0x1F801810] == 0", &context));
+        assert!(!holds("[0x1F801810] != 0", &context));
+    }
+
+    #[test]
+    fn malformed_conditions_are_rejected() {
+        for text in ["", "a0", "a0 ==", "a0 = 1", "foo == 1", "r32 == 0", "[a0 == 1", "a0 == 1 &&", "a0 == 1 ||", "a0 == 1 2", "0xZZ == 1", "a0 == 1;", "99999999999 == 0"].iter() {
+            assert!(Condition::parse(text).is_err(), "{}", text);
+        }
+
+        assert_eq!(Condition::parse("  a0 == 1 ").unwrap().text, "a0 == 1");
+    }
+
+    #[test]
+    fn hits_ignore_counts_and_conditions() {
+        let memory = CpuMemory::new(vec![0; 512 * 1024]);
+        let mut registers = vec![0; 32];
+        let mut breakpoints = Breakpoints::new();
+
+        breakpoints.add(0x80010000, None);
+        breakpoints.get_mut(0x80010000).unwrap().ignore_count = 2;
+
+        assert!(!breakpoints.hit(&context(&registers, &memory)));
+        assert!(!breakpoints.hit(&context(&registers, &memory)));
+        assert!(breakpoints.hit(&context(&registers, &memory)));
+        assert!(breakpoints.hit(&context(&registers, &memory)));
+        assert_eq!(breakpoints.get(0x80010000).unwrap().hit_count, 4);
+
+        // Other addresses and disabled breakpoints don't count.
+        let mut elsewhere = context(&registers, &memory);
+        elsewhere.pc = 0x80010004;
+        assert!(!breakpoints.hit(&elsewhere));
+        breakpoints.get_mut(0x80010000).unwrap().enabled = false;
+        assert!(!breakpoints.hit(&context(&registers, &memory)));
+        assert_eq!(breakpoints.get(0x80010000).unwrap().hit_count, 4);
+
+        // Adding again enables it and replaces the condition, keeping the counts.
+        breakpoints.add(0x80010000, Some(Condition::parse("v0 == 1").unwrap()));
+        assert!(!breakpoints.hit(&context(&registers, &memory)));
+        assert_eq!(breakpoints.get(0x80010000).unwrap().hit_count, 4);
+
+        registers[2] = 1;
+        assert!(breakpoints.hit(&context(&registers, &memory)));
+        assert_eq!(breakpoints.get(0x80010000).unwrap().hit_count, 5);
+
+        breakpoints.toggle(0x80010000);
+        assert!(breakpoints.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+// Shadow call stack, rebuilt from the jal, jalr and jr $ra instructions the CPU runs, and the
+// stepping targets the debugger builds on it.
+
+use super::Cpu;
+use super::super::instructions_decoder;
+
+// Code that calls without ever returning, like a main loop entered with jal, would otherwise
+// grow the stack forever. The oldest frames go first.
+const MAX_FRAMES: usize = 1024;
+
+pub struct Frame {
+    // The jal or jalr.
+    pub call_site: u32,
+    pub target: u32,
+    // After the delay slot.
+    pub return_address: u32,
+    // $sp at the time of the call.
+    pub stack_pointer: u32,
+}
+
+pub struct CallStack {
+    // Outermost first.
+    frames: Vec<Frame>,
+    // jr $ra instructions that didn't match any frame, like the return of a function entered
+    // before the CPU started or the state was loaded.
+    pub unmatched_returns: u32,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack {
+            frames: Vec::new(),
+            unmatched_returns: 0,
+        }
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.unmatched_returns = 0;
+    }
+
+    // Called after each instruction outside a delay slot has run. `jump_register` is the value rs
+    // had before it ran.
+    pub fn update(&mut self, pc: u32, value: u32, jump_register: u32, stack_pointer: u32) {
+        let op = value >> 26;
+        let funct = value & 0x3F;
+        let rs = (value >> 21) & 0x1F;
+
+        let target = match (op, funct) {
+            // jal
+            (0x03, _) => instructions_decoder::decode(value).target(pc),
+            // jalr
+            (0x00, 0x09) => Some(jump_register),
+            // jr $ra
+            (0x00, 0x08) if rs == 31 => {
+                match self.frames.iter().rposition(|frame| frame.return_address == jump_register) {
+                    Some(index) => self.frames.truncate(index),
+                    None => self.unmatched_returns = self.unmatched_returns.wrapping_add(1),
+                }
+                return;
+            },
+            _ => return,
+        };
+
+        if let Some(target) = target {
+            if self.frames.len() == MAX_FRAMES {
+                self.frames.remove(0);
+            }
+
+            self.frames.push(Frame {
+                call_site: pc,
+                target,
+                return_address: pc.wrapping_add(8),
+                stack_pointer,
+            });
+        }
+    }
+}
+
+// What the debugger runs the CPU until. Checked after every instruction; delay slots always
+// run along with their branch except when stepping a single instruction.
+#[derive(Clone, Copy, PartialEq)]
+pub enum StepTarget {
+    Instruction,
+    // Back at this depth or above. Step Over.
+    Depth(usize),
+    // Out of the frame at this depth, or past a return without a frame when there's none. Step Out.
+    Return { depth: usize, unmatched_returns: u32 },
+    // About to run this address. Run To Cursor.
+    Address(u32),
+}
+
+impl StepTarget {
+    pub fn step_over(cpu: &Cpu) -> StepTarget {
+        StepTarget::Depth(cpu.call_stack.depth())
+    }
+
+    pub fn step_out(cpu: &Cpu) -> StepTarget {
+        StepTarget::Return {
+            depth: cpu.call_stack.depth(),
+            unmatched_returns: cpu.call_stack.unmatched_returns,
+        }
+    }
+
+    pub fn reached(&self, cpu: &Cpu) -> bool {
+        match *self {
+            StepTarget::Instruction => true,
+            StepTarget::Address(address) => cpu.current_pc() == address,
+            _ if cpu.branch_delay => false,
+            StepTarget::Depth(depth) => cpu.call_stack.depth() <= depth,
+            StepTarget::Return { depth, unmatched_returns } => cpu.call_stack.depth() < depth || cpu.call_stack.unmatched_returns != unmatched_returns,
+        }
+    }
+}
@@ -0,0 +1,798 @@
+// Geometry Transformation Engine, the R3000A's coprocessor 2.
+
+use std::io;
+
+use super::super::savestate::{StateReader, StateWriter};
+
+// FLAG register bits.
+const FLAG_MAC_POSITIVE: [u32; 3] = [1 << 30, 1 << 29, 1 << 28];
+const FLAG_MAC_NEGATIVE: [u32; 3] = [1 << 27, 1 << 26, 1 << 25];
+const FLAG_IR_SATURATED: [u32; 3] = [1 << 24, 1 << 23, 1 << 22];
+const FLAG_COLOR_SATURATED: [u32; 3] = [1 << 21, 1 << 20, 1 << 19];
+const FLAG_SZ3_OTZ_SATURATED: u32 = 1 << 18;
+const FLAG_DIVIDE_OVERFLOW: u32 = 1 << 17;
+const FLAG_MAC0_POSITIVE: u32 = 1 << 16;
+const FLAG_MAC0_NEGATIVE: u32 = 1 << 15;
+const FLAG_SX2_SATURATED: u32 = 1 << 14;
+const FLAG_SY2_SATURATED: u32 = 1 << 13;
+const FLAG_IR0_SATURATED: u32 = 1 << 12;
+
+// Bits that get summarized into bit 31.
+const FLAG_ERROR_MASK: u32 = 0x7F87E000;
+
+type Matrix = [[i16; 3]; 3];
+type Vector = [i16; 3];
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    value: u32,
+}
+
+impl Command {
+    pub fn new(value: u32) -> Command {
+        Command {
+            value,
+        }
+    }
+
+    pub fn opcode(&self) -> u32 {
+        self.value & 0x3F
+    }
+
+    // Fraction bits to drop from the results, 0 or 12.
+    pub fn shift(&self) -> u32 {
+        if self.value & (1 << 19) != 0 { 12 } else { 0 }
+    }
+
+    // Saturate IR1-3 to 0 instead of -0x8000.
+    pub fn lm(&self) -> bool {
+        self.value & (1 << 10) != 0
+    }
+
+    pub fn matrix(&self) -> u32 {
+        (self.value >> 17) & 0x03
+    }
+
+    pub fn vector(&self) -> u32 {
+        (self.value >> 15) & 0x03
+    }
+
+    pub fn translation(&self) -> u32 {
+        (self.value >> 13) & 0x03
+    }
+}
+
+pub struct Gte {
+    // Data registers.
+    pub vectors: [Vector; 3],
+    pub rgbc: [u8; 4],
+    pub otz: u16,
+    pub ir: [i16; 4],
+    pub sxy: [(i16, i16); 3],
+    pub sz: [u16; 4],
+    pub rgb: [[u8; 4]; 3],
+    pub res1: u32,
+    pub mac: [i32; 4],
+    pub lzcs: u32,
+    pub lzcr: u32,
+
+    // Control registers.
+    pub rotation: Matrix,
+    pub translation: [i32; 3],
+    pub light: Matrix,
+    pub background_color: [i32; 3],
+    pub light_color: Matrix,
+    pub far_color: [i32; 3],
+    pub screen_offset: [i32; 2],
+    pub projection_distance: u16,
+    pub depth_queue_a: i16,
+    pub depth_queue_b: i32,
+    pub zsf3: i16,
+    pub zsf4: i16,
+    pub flag: u32,
+
+    unr_table: [u8; 257],
+}
+
+impl Gte {
+    pub fn new() -> Gte {
+        let mut unr_table = [0; 257];
+        for (index, entry) in unr_table.iter_mut().enumerate() {
+            *entry = ((0x40000 / (index as i32 + 0x100) + 1) / 2 - 0x101).max(0) as u8;
+        }
+
+        Gte {
+            vectors: [[0; 3]; 3],
+            rgbc: [0; 4],
+            otz: 0,
+            ir: [0; 4],
+            sxy: [(0, 0); 3],
+            sz: [0; 4],
+            rgb: [[0; 4]; 3],
+            res1: 0,
+            mac: [0; 4],
+            lzcs: 0,
+            lzcr: 32,
+
+            rotation: [[0; 3]; 3],
+            translation: [0; 3],
+            light: [[0; 3]; 3],
+            background_color: [0; 3],
+            light_color: [[0; 3]; 3],
+            far_color: [0; 3],
+            screen_offset: [0; 2],
+            projection_distance: 0,
+            depth_queue_a: 0,
+            depth_queue_b: 0,
+            zsf3: 0,
+            zsf4: 0,
+            flag: 0,
+
+            unr_table,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"GTE ");
+
+        for vector in self.vectors.iter() {
+            state.write_i16s(vector);
+        }
+        state.write_bytes(&self.rgbc);
+        state.write_u16(self.otz);
+        state.write_i16s(&self.ir);
+        for (x, y) in self.sxy.iter() {
+            state.write_i16(*x);
+            state.write_i16(*y);
+        }
+        state.write_u16s(&self.sz);
+        for color in self.rgb.iter() {
+            state.write_bytes(color);
+        }
+        state.write_u32(self.res1);
+        state.write_i32s(&self.mac);
+        state.write_u32(self.lzcs);
+        state.write_u32(self.lzcr);
+
+        for matrix in [&self.rotation, &self.light, &self.light_color].iter() {
+            for row in matrix.iter() {
+                state.write_i16s(row);
+            }
+        }
+        state.write_i32s(&self.translation);
+        state.write_i32s(&self.background_color);
+        state.write_i32s(&self.far_color);
+        state.write_i32s(&self.screen_offset);
+        state.write_u16(self.projection_distance);
+        state.write_i16(self.depth_queue_a);
+        state.write_i32(self.depth_queue_b);
+        state.write_i16(self.zsf3);
+        state.write_i16(self.zsf4);
+        state.write_u32(self.flag);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"GTE ")?;
+
+        for vector in self.vectors.iter_mut() {
+            state.read_i16s_into(vector)?;
+        }
+        state.read_bytes_into(&mut self.rgbc)?;
+        self.otz = state.read_u16()?;
+        state.read_i16s_into(&mut self.ir)?;
+        for (x, y) in self.sxy.iter_mut() {
+            *x = state.read_i16()?;
+            *y = state.read_i16()?;
+        }
+        state.read_u16s_into(&mut self.sz)?;
+        for color in self.rgb.iter_mut() {
+            state.read_bytes_into(color)?;
+        }
+        self.res1 = state.read_u32()?;
+        state.read_i32s_into(&mut self.mac)?;
+        self.lzcs = state.read_u32()?;
+        self.lzcr = state.read_u32()?;
+
+        for matrix in [&mut self.rotation, &mut self.light, &mut self.light_color].iter_mut() {
+            for row in matrix.iter_mut() {
+                state.read_i16s_into(row)?;
+            }
+        }
+        state.read_i32s_into(&mut self.translation)?;
+        state.read_i32s_into(&mut self.background_color)?;
+        state.read_i32s_into(&mut self.far_color)?;
+        state.read_i32s_into(&mut self.screen_offset)?;
+        self.projection_distance = state.read_u16()?;
+        self.depth_queue_a = state.read_i16()?;
+        self.depth_queue_b = state.read_i32()?;
+        self.zsf3 = state.read_i16()?;
+        self.zsf4 = state.read_i16()?;
+        self.flag = state.read_u32()?;
+        Ok(())
+    }
+
+    pub fn read_data(&self, index: u32) -> u32 {
+        match index {
+            0 | 2 | 4 => pack(self.vectors[index as usize / 2][0], self.vectors[index as usize / 2][1]),
+            1 | 3 | 5 => self.vectors[index as usize / 2][2] as i32 as u32,
+            6 => u32::from_le_bytes(self.rgbc),
+            7 => self.otz as u32,
+            8..=11 => self.ir[index as usize - 8] as i32 as u32,
+            12..=14 => pack(self.sxy[index as usize - 12].0, self.sxy[index as usize - 12].1),
+            15 => pack(self.sxy[2].0, self.sxy[2].1),
+            16..=19 => self.sz[index as usize - 16] as u32,
+            20..=22 => u32::from_le_bytes(self.rgb[index as usize - 20]),
+            23 => self.res1,
+            24..=27 => self.mac[index as usize - 24] as u32,
+            28 | 29 => {
+                let component = |value: i16| ((value >> 7).clamp(0, 0x1F)) as u32;
+                component(self.ir[1]) | component(self.ir[2]) << 5 | component(self.ir[3]) << 10
+            },
+            30 => self.lzcs,
+            _ => self.lzcr,
+        }
+    }
+
+    pub fn write_data(&mut self, index: u32, value: u32) {
+        let (low, high) = unpack(value);
+
+        match index {
+            0 | 2 | 4 => {
+                self.vectors[index as usize / 2][0] = low;
+                self.vectors[index as usize / 2][1] = high;
+            },
+            1 | 3 | 5 => self.vectors[index as usize / 2][2] = low,
+            6 => self.rgbc = value.to_le_bytes(),
+            7 => self.otz = value as u16,
+            8..=11 => self.ir[index as usize - 8] = low,
+            12..=14 => self.sxy[index as usize - 12] = (low, high),
+            15 => {
+                self.sxy[0] = self.sxy[1];
+                self.sxy[1] = self.sxy[2];
+                self.sxy[2] = (low, high);
+            },
+            16..=19 => self.sz[index as usize - 16] = value as u16,
+            20..=22 => self.rgb[index as usize - 20] = value.to_le_bytes(),
+            23 => self.res1 = value,
+            24..=27 => self.mac[index as usize - 24] = value as i32,
+            28 => {
+                self.ir[1] = ((value & 0x1F) << 7) as i16;
+                self.ir[2] = (((value >> 5) & 0x1F) << 7) as i16;
+                self.ir[3] = (((value >> 10) & 0x1F) << 7) as i16;
+            },
+            30 => {
+                self.lzcs = value;
+                self.lzcr = if (value as i32) < 0 { (!value).leading_zeros() } else { value.leading_zeros() };
+            },
+            // ORGB and LZCR are read-only.
+            _ => {},
+        }
+    }
+
+    pub fn read_control(&self, index: u32) -> u32 {
+        match index {
+            0..=4 => read_matrix(&self.rotation, index),
+            5..=7 => self.translation[index as usize - 5] as u32,
+            8..=12 => read_matrix(&self.light, index - 8),
+            13..=15 => self.background_color[index as usize - 13] as u32,
+            16..=20 => read_matrix(&self.light_color, index - 16),
+            21..=23 => self.far_color[index as usize - 21] as u32,
+            24 | 25 => self.screen_offset[index as usize - 24] as u32,
+            // H is unsigned, but reads sign-extend it anyway.
+            26 => self.projection_distance as i16 as i32 as u32,
+            27 => self.depth_queue_a as i32 as u32,
+            28 => self.depth_queue_b as u32,
+            29 => self.zsf3 as i32 as u32,
+            30 => self.zsf4 as i32 as u32,
+            _ => self.flag,
+        }
+    }
+
+    pub fn write_control(&mut self, index: u32, value: u32) {
+        match index {
+            0..=4 => write_matrix(&mut self.rotation, index, value),
+            5..=7 => self.translation[index as usize - 5] = value as i32,
+            8..=12 => write_matrix(&mut self.light, index - 8, value),
+            13..=15 => self.background_color[index as usize - 13] = value as i32,
+            16..=20 => write_matrix(&mut self.light_color, index - 16, value),
+            21..=23 => self.far_color[index as usize - 21] = value as i32,
+            24 | 25 => self.screen_offset[index as usize - 24] = value as i32,
+            26 => self.projection_distance = value as u16,
+            27 => self.depth_queue_a = value as i16,
+            28 => self.depth_queue_b = value as i32,
+            29 => self.zsf3 = value as i16,
+            30 => self.zsf4 = value as i16,
+            _ => {
+                self.flag = value & 0x7FFFF000;
+                self.update_error_flag();
+            },
+        }
+    }
+
+    // Runs a GTE command. Returns false for unknown opcodes.
+    pub fn execute(&mut self, value: u32) -> bool {
+        let command = Command::new(value);
+        self.flag = 0;
+
+        match command.opcode() {
+            0x01 => self.rtps(command, 0, true),
+            0x06 => self.nclip(),
+            0x0C => self.op(command),
+            0x10 => self.dpcs(command, false),
+            0x11 => self.intpl(command),
+            0x12 => self.mvmva(command),
+            0x13 => self.ncds(command, 0),
+            0x14 => self.cdp(command),
+            0x16 => {
+                self.ncds(command, 0);
+                self.ncds(command, 1);
+                self.ncds(command, 2);
+            },
+            0x1B => self.nccs(command, 0),
+            0x1C => self.cc(command),
+            0x1E => self.ncs(command, 0),
+            0x20 => {
+                self.ncs(command, 0);
+                self.ncs(command, 1);
+                self.ncs(command, 2);
+            },
+            0x28 => self.sqr(command),
+            0x29 => self.dcpl(command),
+            0x2A => {
+                self.dpcs(command, true);
+                self.dpcs(command, true);
+                self.dpcs(command, true);
+            },
+            0x2D => self.avsz3(),
+            0x2E => self.avsz4(),
+            0x30 => {
+                self.rtps(command, 0, false);
+                self.rtps(command, 1, false);
+                self.rtps(command, 2, true);
+            },
+            0x3D => self.gpf(command),
+            0x3E => self.gpl(command),
+            0x3F => {
+                self.nccs(command, 0);
+                self.nccs(command, 1);
+                self.nccs(command, 2);
+            },
+            _ => return false,
+        }
+
+        self.update_error_flag();
+        true
+    }
+
+    fn update_error_flag(&mut self) {
+        if self.flag & FLAG_ERROR_MASK != 0 {
+            self.flag |= 0x80000000;
+        }
+        else {
+            self.flag &= !0x80000000;
+        }
+    }
+
+    // Flags overflows of the 44 bit MAC1-3 accumulators and wraps the value like the hardware does.
+    fn check_mac(&mut self, index: usize, value: i64) -> i64 {
+        if value > 0x7FF_FFFF_FFFF {
+            self.flag |= FLAG_MAC_POSITIVE[index];
+        }
+        else if value < -0x800_0000_0000 {
+            self.flag |= FLAG_MAC_NEGATIVE[index];
+        }
+
+        (value << 20) >> 20
+    }
+
+    fn set_mac(&mut self, index: usize, value: i64, shift: u32) -> i64 {
+        let value = self.check_mac(index, value) >> shift;
+        self.mac[index + 1] = value as i32;
+        value
+    }
+
+    fn set_mac0(&mut self, value: i64) -> i64 {
+        if value > 0x7FFFFFFF {
+            self.flag |= FLAG_MAC0_POSITIVE;
+        }
+        else if value < -0x80000000 {
+            self.flag |= FLAG_MAC0_NEGATIVE;
+        }
+
+        self.mac[0] = value as i32;
+        value
+    }
+
+    fn set_ir(&mut self, index: usize, value: i64, lm: bool) {
+        let minimum = if lm { 0 } else { -0x8000 };
+
+        if value < minimum || value > 0x7FFF {
+            self.flag |= FLAG_IR_SATURATED[index];
+        }
+
+        self.ir[index + 1] = value.clamp(minimum, 0x7FFF) as i16;
+    }
+
+    fn set_ir0(&mut self, value: i64) {
+        if !(0..=0x1000).contains(&value) {
+            self.flag |= FLAG_IR0_SATURATED;
+        }
+
+        self.ir[0] = value.clamp(0, 0x1000) as i16;
+    }
+
+    fn set_mac_and_ir(&mut self, index: usize, value: i64, shift: u32, lm: bool) {
+        let value = self.set_mac(index, value, shift);
+        self.set_ir(index, value, lm);
+    }
+
+    fn push_sz(&mut self, value: i64) {
+        if !(0..=0xFFFF).contains(&value) {
+            self.flag |= FLAG_SZ3_OTZ_SATURATED;
+        }
+
+        self.sz[0] = self.sz[1];
+        self.sz[1] = self.sz[2];
+        self.sz[2] = self.sz[3];
+        self.sz[3] = value.clamp(0, 0xFFFF) as u16;
+    }
+
+    fn push_sxy(&mut self, x: i64, y: i64) {
+        if !(-0x400..=0x3FF).contains(&x) {
+            self.flag |= FLAG_SX2_SATURATED;
+        }
+        if !(-0x400..=0x3FF).contains(&y) {
+            self.flag |= FLAG_SY2_SATURATED;
+        }
+
+        self.sxy[0] = self.sxy[1];
+        self.sxy[1] = self.sxy[2];
+        self.sxy[2] = (x.clamp(-0x400, 0x3FF) as i16, y.clamp(-0x400, 0x3FF) as i16);
+    }
+
+    fn push_color(&mut self) {
+        let mut color = [0; 4];
+
+        for (index, component) in color.iter_mut().take(3).enumerate() {
+            let value = self.mac[index + 1] >> 4;
+
+            if !(0..=0xFF).contains(&value) {
+                self.flag |= FLAG_COLOR_SATURATED[index];
+            }
+
+            *component = value.clamp(0, 0xFF) as u8;
+        }
+
+        color[3] = self.rgbc[3];
+
+        self.rgb[0] = self.rgb[1];
+        self.rgb[1] = self.rgb[2];
+        self.rgb[2] = color;
+    }
+
+    fn set_otz(&mut self, value: i64) {
+        let value = value >> 12;
+
+        if !(0..=0xFFFF).contains(&value) {
+            self.flag |= FLAG_SZ3_OTZ_SATURATED;
+        }
+
+        self.otz = value.clamp(0, 0xFFFF) as u16;
+    }
+
+    // Unsigned Newton-Raphson division used for the perspective transform, H / SZ3.
+    fn divide(&mut self) -> i64 {
+        let numerator = self.projection_distance as u32;
+        let divisor = self.sz[3] as u32;
+
+        if numerator >= divisor * 2 {
+            self.flag |= FLAG_DIVIDE_OVERFLOW;
+            return 0x1FFFF;
+        }
+
+        let shift = (divisor as u16).leading_zeros();
+        let n = (numerator as u64) << shift;
+        let d = (divisor as u64) << shift;
+
+        let u = self.unr_table[((d - 0x7FC0) >> 7) as usize] as u64 + 0x101;
+        let d = (0x2000080 - d * u) >> 8;
+        let d = (0x0000080 + d * u) >> 8;
+
+        (((n * d) + 0x8000) >> 16).min(0x1FFFF) as i64
+    }
+
+    // Computes translation + matrix * vector into MAC1-3 and IR1-3.
+    fn multiply_matrix(&mut self, matrix: &Matrix, vector: &Vector, translation: [i32; 3], shift: u32, lm: bool) {
+        for (row, coefficients) in matrix.iter().enumerate() {
+            let mut value = (translation[row] as i64) << 12;
+
+            for (coefficient, component) in coefficients.iter().zip(vector.iter()) {
+                value = self.check_mac(row, value + *coefficient as i64 * *component as i64);
+            }
+
+            self.set_mac_and_ir(row, value, shift, lm);
+        }
+    }
+
+    fn rtps(&mut self, command: Command, vertex: usize, last: bool) {
+        let shift = command.shift();
+        let lm = command.lm();
+        let vector = self.vectors[vertex];
+        let rotation = self.rotation;
+
+        let mut z = 0;
+
+        for (row, coefficients) in rotation.iter().enumerate() {
+            let mut value = (self.translation[row] as i64) << 12;
+
+            for (coefficient, component) in coefficients.iter().zip(vector.iter()) {
+                value = self.check_mac(row, value + *coefficient as i64 * *component as i64);
+            }
+
+            let shifted = self.set_mac(row, value, shift);
+
+            if row < 2 {
+                self.set_ir(row, shifted, lm);
+            }
+            else {
+                // IR3 is clamped from the shifted value, but its flag is checked against the value >> 12.
+                z = value >> 12;
+
+                if !(-0x8000..=0x7FFF).contains(&z) {
+                    self.flag |= FLAG_IR_SATURATED[2];
+                }
+
+                self.ir[3] = shifted.clamp(if lm { 0 } else { -0x8000 }, 0x7FFF) as i16;
+            }
+        }
+
+        self.push_sz(z);
+
+        let quotient = self.divide();
+
+        let x = self.set_mac0(quotient * self.ir[1] as i64 + self.screen_offset[0] as i64);
+        let y = self.set_mac0(quotient * self.ir[2] as i64 + self.screen_offset[1] as i64);
+        self.push_sxy(x >> 16, y >> 16);
+
+        if last {
+            let depth = self.set_mac0(quotient * self.depth_queue_a as i64 + self.depth_queue_b as i64);
+            self.set_ir0(depth >> 12);
+        }
+    }
+
+    fn nclip(&mut self) {
+        let [(x0, y0), (x1, y1), (x2, y2)] = self.sxy;
+        let (x0, y0, x1, y1, x2, y2) = (x0 as i64, y0 as i64, x1 as i64, y1 as i64, x2 as i64, y2 as i64);
+
+        self.set_mac0(x0 * y1 + x1 * y2 + x2 * y0 - x0 * y2 - x1 * y0 - x2 * y1);
+    }
+
+    fn op(&mut self, command: Command) {
+        let shift = command.shift();
+        let lm = command.lm();
+
+        let d1 = self.rotation[0][0] as i64;
+        let d2 = self.rotation[1][1] as i64;
+        let d3 = self.rotation[2][2] as i64;
+        let ir1 = self.ir[1] as i64;
+        let ir2 = self.ir[2] as i64;
+        let ir3 = self.ir[3] as i64;
+
+        self.set_mac_and_ir(0, ir3 * d2 - ir2 * d3, shift, lm);
+        self.set_mac_and_ir(1, ir1 * d3 - ir3 * d1, shift, lm);
+        self.set_mac_and_ir(2, ir2 * d1 - ir1 * d2, shift, lm);
+    }
+
+    // Interpolates MAC values (with 12 fraction bits) towards the far color by IR0, then pushes the color.
+    fn interpolate_far_color(&mut self, values: [i64; 3], shift: u32, lm: bool) {
+        for (index, value) in values.iter().enumerate() {
+            let difference = self.check_mac(index, ((self.far_color[index] as i64) << 12) - value) >> shift;
+            self.set_ir(index, difference, false);
+        }
+
+        for (index, value) in values.iter().enumerate() {
+            let result = self.ir[index + 1] as i64 * self.ir[0] as i64 + value;
+            self.set_mac_and_ir(index, result, shift, lm);
+        }
+
+        self.push_color();
+    }
+
+    fn dpcs(&mut self, command: Command, use_fifo: bool) {
+        let color = if use_fifo { self.rgb[0] } else { self.rgbc };
+        let values = [(color[0] as i64) << 16, (color[1] as i64) << 16, (color[2] as i64) << 16];
+
+        self.interpolate_far_color(values, command.shift(), command.lm());
+    }
+
+    fn intpl(&mut self, command: Command) {
+        let values = [(self.ir[1] as i64) << 12, (self.ir[2] as i64) << 12, (self.ir[3] as i64) << 12];
+        self.interpolate_far_color(values, command.shift(), command.lm());
+    }
+
+    fn dcpl(&mut self, command: Command) {
+        let values = self.color_times_ir();
+        self.interpolate_far_color(values, command.shift(), command.lm());
+    }
+
+    // [R*IR1, G*IR2, B*IR3] << 4, using the RGBC register.
+    fn color_times_ir(&self) -> [i64; 3] {
+        [
+            ((self.rgbc[0] as i64) * self.ir[1] as i64) << 4,
+            ((self.rgbc[1] as i64) * self.ir[2] as i64) << 4,
+            ((self.rgbc[2] as i64) * self.ir[3] as i64) << 4,
+        ]
+    }
+
+    fn mvmva(&mut self, command: Command) {
+        let shift = command.shift();
+        let lm = command.lm();
+
+        let matrix = match command.matrix() {
+            0 => self.rotation,
+            1 => self.light,
+            2 => self.light_color,
+            // Selecting the reserved matrix gives a mix of RGBC, IR0 and rotation entries.
+            _ => {
+                let red = (self.rgbc[0] as i16) << 4;
+                [
+                    [-red, red, self.ir[0]],
+                    [self.rotation[0][2]; 3],
+                    [self.rotation[1][1]; 3],
+                ]
+            },
+        };
+
+        let vector = match command.vector() {
+            0 => self.vectors[0],
+            1 => self.vectors[1],
+            2 => self.vectors[2],
+            _ => [self.ir[1], self.ir[2], self.ir[3]],
+        };
+
+        match command.translation() {
+            0 => self.multiply_matrix(&matrix, &vector, self.translation, shift, lm),
+            1 => self.multiply_matrix(&matrix, &vector, self.background_color, shift, lm),
+            // The far color translation is bugged, the first column only affects the flags.
+            2 => {
+                for (row, coefficients) in matrix.iter().enumerate() {
+                    let first = ((self.far_color[row] as i64) << 12) + coefficients[0] as i64 * vector[0] as i64;
+                    let first = self.check_mac(row, first);
+                    self.set_ir(row, first >> shift, false);
+
+                    let mut value = self.check_mac(row, coefficients[1] as i64 * vector[1] as i64);
+                    value = self.check_mac(row, value + coefficients[2] as i64 * vector[2] as i64);
+                    self.set_mac_and_ir(row, value, shift, lm);
+                }
+            },
+            _ => self.multiply_matrix(&matrix, &vector, [0; 3], shift, lm),
+        }
+    }
+
+    // Light source matrix times the normal vector, then light color matrix plus background color.
+    fn light_vertex(&mut self, command: Command, vertex: usize) {
+        let shift = command.shift();
+        let lm = command.lm();
+        let vector = self.vectors[vertex];
+        let light = self.light;
+        let light_color = self.light_color;
+
+        self.multiply_matrix(&light, &vector, [0; 3], shift, lm);
+
+        let intensity = [self.ir[1], self.ir[2], self.ir[3]];
+        self.multiply_matrix(&light_color, &intensity, self.background_color, shift, lm);
+    }
+
+    fn ncs(&mut self, command: Command, vertex: usize) {
+        self.light_vertex(command, vertex);
+        self.push_color();
+    }
+
+    fn nccs(&mut self, command: Command, vertex: usize) {
+        self.light_vertex(command, vertex);
+        self.color_multiply(command);
+    }
+
+    fn ncds(&mut self, command: Command, vertex: usize) {
+        self.light_vertex(command, vertex);
+
+        let values = self.color_times_ir();
+        self.interpolate_far_color(values, command.shift(), command.lm());
+    }
+
+    fn cc(&mut self, command: Command) {
+        let intensity = [self.ir[1], self.ir[2], self.ir[3]];
+        let light_color = self.light_color;
+        self.multiply_matrix(&light_color, &intensity, self.background_color, command.shift(), command.lm());
+        self.color_multiply(command);
+    }
+
+    fn cdp(&mut self, command: Command) {
+        let intensity = [self.ir[1], self.ir[2], self.ir[3]];
+        let light_color = self.light_color;
+        self.multiply_matrix(&light_color, &intensity, self.background_color, command.shift(), command.lm());
+
+        let values = self.color_times_ir();
+        self.interpolate_far_color(values, command.shift(), command.lm());
+    }
+
+    // MAC = [R*IR1, G*IR2, B*IR3] << 4 >> shift, then pushes the color.
+    fn color_multiply(&mut self, command: Command) {
+        let values = self.color_times_ir();
+
+        for (index, value) in values.iter().enumerate() {
+            self.set_mac_and_ir(index, *value, command.shift(), command.lm());
+        }
+
+        self.push_color();
+    }
+
+    fn sqr(&mut self, command: Command) {
+        for index in 0..3 {
+            let value = self.ir[index + 1] as i64;
+            self.set_mac_and_ir(index, value * value, command.shift(), command.lm());
+        }
+    }
+
+    fn avsz3(&mut self) {
+        let sum = self.sz[1] as i64 + self.sz[2] as i64 + self.sz[3] as i64;
+        let value = self.set_mac0(self.zsf3 as i64 * sum);
+        self.set_otz(value);
+    }
+
+    fn avsz4(&mut self) {
+        let sum = self.sz[0] as i64 + self.sz[1] as i64 + self.sz[2] as i64 + self.sz[3] as i64;
+        let value = self.set_mac0(self.zsf4 as i64 * sum);
+        self.set_otz(value);
+    }
+
+    fn gpf(&mut self, command: Command) {
+        for index in 0..3 {
+            let value = self.ir[0] as i64 * self.ir[index + 1] as i64;
+            self.set_mac_and_ir(index, value, command.shift(), command.lm());
+        }
+
+        self.push_color();
+    }
+
+    fn gpl(&mut self, command: Command) {
+        let shift = command.shift();
+
+        for index in 0..3 {
+            let value = ((self.mac[index + 1] as i64) << shift) + self.ir[0] as i64 * self.ir[index + 1] as i64;
+            self.set_mac_and_ir(index, value, shift, command.lm());
+        }
+
+        self.push_color();
+    }
+}
+
+fn pack(low: i16, high: i16) -> u32 {
+    (low as u16 as u32) | (high as u16 as u32) << 16
+}
+
+fn unpack(value: u32) -> (i16, i16) {
+    (value as i16, (value >> 16) as i16)
+}
+
+// Matrices are stored two entries per register, with the last one alone and sign-extended.
+fn read_matrix(matrix: &Matrix, register: u32) -> u32 {
+    let index = register as usize * 2;
+
+    if index == 8 {
+        matrix[2][2] as i32 as u32
+    }
+    else {
+        pack(matrix[index / 3][index % 3], matrix[(index + 1) / 3][(index + 1) % 3])
+    }
+}
+
+fn write_matrix(matrix: &mut Matrix, register: u32, value: u32) {
+    let index = register as usize * 2;
+    let (low, high) = unpack(value);
+
+    matrix[index / 3][index % 3] = low;
+    if index < 8 {
+        matrix[(index + 1) / 3][(index + 1) % 3] = high;
+    }
+}
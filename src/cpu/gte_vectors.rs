@@ -0,0 +1,206 @@
+use std::io;
+use std::fs;
+use std::path::Path;
+
+use super::gte::Gte;
+
+// Data registers that can't be loaded directly: SXYP mirrors SXY2, IRGB/ORGB mirror IR1-3 and LZCR follows LZCS.
+const DERIVED_DATA_REGISTERS: [u32; 4] = [15, 28, 29, 31];
+
+pub const REGISTER_COUNT: usize = 64;
+
+// A GTE test vector: every data and control register before and after it ran.
+// Registers 0-31 are the data registers, 32-63 the control ones, FLAG is 63.
+pub struct TestVector {
+    pub name: String,
+    pub command: u32,
+    pub input: Vec<u32>,
+    pub output: Vec<u32>,
+}
+
+pub struct Mismatch {
+    pub vector: String,
+    pub register: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+// Parses a vector file. Each vector looks like
+//
+//   vector <name>
+//   command <hex>
+//   input <64 hex words>
+//   output <64 hex words>
+//
+// Words can span as many lines as needed, and everything after a '#' is a comment.
+pub fn parse_vectors(contents: &str) -> io::Result<Vec<TestVector>> {
+    let mut vectors = Vec::new();
+    let mut tokens = contents.lines().flat_map(|line| line.split('#').next().unwrap_or("").split_whitespace()).peekable();
+
+    while let Some(token) = tokens.next() {
+        if token != "vector" {
+            return Err(invalid(format!("Expected a vector, found {}", token)));
+        }
+
+        let name = String::from(tokens.next().ok_or_else(|| invalid(String::from("Missing vector name")))?);
+        let mut command = None;
+        let mut input = Vec::new();
+        let mut output = Vec::new();
+
+        while let Some(&field) = tokens.peek() {
+            if field == "vector" {
+                break;
+            }
+            tokens.next();
+
+            match field {
+                "command" => command = Some(parse_word(tokens.next(), &name)?),
+                "input" | "output" => {
+                    let registers = if field == "input" { &mut input } else { &mut output };
+
+                    for _ in 0..REGISTER_COUNT {
+                        registers.push(parse_word(tokens.next(), &name)?);
+                    }
+                },
+                _ => return Err(invalid(format!("Unknown field {} in vector {}", field, name))),
+            }
+        }
+
+        if input.is_empty() || output.is_empty() {
+            return Err(invalid(format!("Vector {} needs both input and output registers", name)));
+        }
+
+        vectors.push(TestVector {
+            command: command.ok_or_else(|| invalid(format!("Vector {} has no command", name)))?,
+            name,
+            input,
+            output,
+        });
+    }
+
+    Ok(vectors)
+}
+
+fn parse_word(token: Option<&str>, name: &str) -> io::Result<u32> {
+    let token = token.ok_or_else(|| invalid(format!("Vector {} ended early", name)))?;
+    let digits = token.trim_start_matches("0x").trim_start_matches("0X");
+
+    u32::from_str_radix(digits, 16).map_err(|_| invalid(format!("Invalid hex value {} in vector {}", token, name)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn load_state(gte: &mut Gte, registers: &[u32]) {
+    for index in 0..32 {
+        gte.write_control(index, registers[32 + index as usize]);
+    }
+
+    for index in 0..32 {
+        if !DERIVED_DATA_REGISTERS.contains(&index) {
+            gte.write_data(index, registers[index as usize]);
+        }
+    }
+
+    // Loading LZCS recomputes LZCR, writing the data registers in order takes care of that.
+}
+
+pub fn dump_state(gte: &Gte) -> Vec<u32> {
+    let mut registers = Vec::with_capacity(REGISTER_COUNT);
+
+    for index in 0..32 {
+        registers.push(gte.read_data(index));
+    }
+    for index in 0..32 {
+        registers.push(gte.read_control(index));
+    }
+
+    registers
+}
+
+pub fn run_vector(vector: &TestVector) -> Vec<Mismatch> {
+    let mut gte = Gte::new();
+
+    load_state(&mut gte, &vector.input);
+    gte.execute(vector.command);
+
+    dump_state(&gte).into_iter().zip(vector.output.iter()).enumerate().filter(|(_, (actual, expected))| actual != *expected).map(|(register, (actual, expected))| Mismatch {
+        vector: vector.name.clone(),
+        register,
+        expected: *expected,
+        actual,
+    }).collect()
+}
+
+// Runs every vector in a file, or in every file of a directory.
+pub fn run_path(path: &Path) -> io::Result<(usize, Vec<Mismatch>)> {
+    let mut files = Vec::new();
+
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            files.push(entry?.path());
+        }
+        files.sort();
+    }
+    else {
+        files.push(path.to_path_buf());
+    }
+
+    let mut count = 0;
+    let mut mismatches = Vec::new();
+
+    for file in files {
+        for vector in parse_vectors(&fs::read_to_string(&file)?)? {
+            count += 1;
+            mismatches.extend(run_vector(&vector));
+        }
+    }
+
+    Ok((count, mismatches))
+}
+
+pub fn register_name(register: usize) -> String {
+    const DATA: [&str; 32] = [
+        "VXY0", "VZ0", "VXY1", "VZ1", "VXY2", "VZ2", "RGBC", "OTZ", "IR0", "IR1", "IR2", "IR3", "SXY0", "SXY1", "SXY2", "SXYP",
+        "SZ0", "SZ1", "SZ2", "SZ3", "RGB0", "RGB1", "RGB2", "RES1", "MAC0", "MAC1", "MAC2", "MAC3", "IRGB", "ORGB", "LZCS", "LZCR",
+    ];
+    const CONTROL: [&str; 32] = [
+        "RT11RT12", "RT13RT21", "RT22RT23", "RT31RT32", "RT33", "TRX", "TRY", "TRZ", "L11L12", "L13L21", "L22L23", "L31L32", "L33", "RBK", "GBK", "BBK",
+        "LR1LR2", "LR3LG1", "LG2LG3", "LB1LB2", "LB3", "RFC", "GFC", "BFC", "OFX", "OFY", "H", "DQA", "DQB", "ZSF3", "ZSF4", "FLAG",
+    ];
+
+    if register < 32 {
+        String::from(DATA[register])
+    }
+    else {
+        String::from(CONTROL[register - 32])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn reference_vectors() {
+        let (count, mismatches) = super::run_path(Path::new("tests/gte")).unwrap();
+
+        for mismatch in mismatches.iter() {
+            println!("{}: {} expected {:08X}, got {:08X}", mismatch.vector, super::register_name(mismatch.register), mismatch.expected, mismatch.actual);
+        }
+
+        assert!(count > 0);
+        assert!(mismatches.is_empty());
+    }
+
+    #[test]
+    fn vectors_cover_every_command() {
+        let contents = std::fs::read_to_string("tests/gte/model_commands.txt").unwrap();
+        let mut opcodes: Vec<u32> = super::parse_vectors(&contents).unwrap().iter().map(|vector| vector.command & 0x3F).collect();
+        opcodes.sort();
+        opcodes.dedup();
+
+        assert_eq!(opcodes, [0x01, 0x06, 0x0C, 0x10, 0x11, 0x12, 0x13, 0x14, 0x16, 0x1B, 0x1C, 0x1E, 0x20, 0x28, 0x29, 0x2A, 0x2D, 0x2E, 0x30, 0x3D, 0x3E, 0x3F]);
+    }
+}
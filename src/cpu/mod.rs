@@ -1,8 +1,27 @@
+use std::io;
 use std::io::Read;
 use std::fs::File;
 use std::path::PathBuf;
 
+pub mod gte;
+pub mod gte_vectors;
+pub mod breakpoints;
+pub mod call_stack;
+pub mod trace;
+pub mod trace_diff;
+
+use super::exe;
 use super::memory;
+use super::watchpoints::WatchHit;
+use super::savestate::{StateReader, StateWriter};
+
+pub const CLOCK_RATE: u32 = 33_868_800;
+
+// Rough average until instruction timings are emulated.
+pub const CYCLES_PER_INSTRUCTION: u32 = 2;
+
+// Address the BIOS jumps to after copying the shell to RAM.
+const SHELL_ENTRY: u32 = 0x80030000;
 
 #[derive(PartialEq)]
 pub enum CycleResult {
@@ -10,6 +29,8 @@ pub enum CycleResult {
     Error,
     Success,
     Breakpoint,
+    // A memory access hit a watchpoint, see Cpu::watch_hit.
+    Watchpoint,
 }
 
 #[derive(Clone, Copy)]
@@ -24,6 +45,10 @@ impl Instruction {
         }
     }
 
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
     pub fn op(&self) -> u32 {
         (self.value >> 26) & 0x3F
     }
@@ -64,6 +89,7 @@ pub struct Cpu {
     
     pub registers: Vec<u32>,
     pub cop0_registers: Vec<u32>,
+    pub gte: gte::Gte,
 
     pub memory: memory::CpuMemory,
 
@@ -71,10 +97,18 @@ pub struct Cpu {
     pub current_instruction: Instruction,
 
     pub branch_delay: bool,
+    // Address of the instruction in the delay slot. While it runs, `pc` already holds the target.
+    pub delay_slot_pc: u32,
 
     pub cpu_paused: bool,
     pub cpu_result: CycleResult,
-    pub debugger_breakpoints: Vec<u32>,
+    pub breakpoints: breakpoints::Breakpoints,
+    // The access behind the last CycleResult::Watchpoint.
+    pub watch_hit: Option<WatchHit>,
+    pub tracer: Option<trace::Tracer>,
+    pub call_stack: call_stack::CallStack,
+
+    pub fast_boot: bool,
 }
 
 impl Cpu {
@@ -85,6 +119,10 @@ impl Cpu {
 
         bios_file.read_to_end(&mut bios_data).unwrap();
 
+        Cpu::with_bios(bios_data)
+    }
+
+    pub fn with_bios(bios_data: Vec<u8>) -> Cpu {
         let mut memory = memory::CpuMemory::new(bios_data);
         let first_op = memory.read_word(0xBFC00000);
 
@@ -101,6 +139,7 @@ impl Cpu {
             registers: vec![0; 32],
 
             cop0_registers: vec![0; 16],
+            gte: gte::Gte::new(),
 
             memory: memory,
 
@@ -108,13 +147,92 @@ impl Cpu {
             current_instruction: Instruction::new(0),
 
             branch_delay: false,
+            delay_slot_pc: 0,
 
             cpu_paused: true,
             cpu_result: CycleResult::None,
-            debugger_breakpoints: Vec::new(),
+            breakpoints: breakpoints::Breakpoints::new(),
+            watch_hit: None,
+            tracer: None,
+            call_stack: call_stack::CallStack::new(),
+
+            fast_boot: false,
+        }
+    }
+
+    // Everything needed to pick up where the machine left off, down to an instruction in a branch delay slot.
+    // Debugger state like breakpoints and the paused flag stays with the running session.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"CPU ");
+        state.write_u32(self.pc);
+        state.write_u32(self.hi);
+        state.write_u32(self.lo);
+        state.write_u32s(&self.registers);
+        state.write_u32s(&self.cop0_registers);
+
+        state.write_u32(self.next_instruction.value);
+        state.write_u32(self.current_instruction.value);
+        state.write_bool(self.branch_delay);
+        state.write_u32(self.delay_slot_pc);
+        state.write_bool(self.fast_boot);
+
+        self.gte.save_state(state);
+        self.memory.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"CPU ")?;
+        self.pc = state.read_u32()?;
+        self.hi = state.read_u32()?;
+        self.lo = state.read_u32()?;
+        state.read_u32s_into(&mut self.registers)?;
+        state.read_u32s_into(&mut self.cop0_registers)?;
+
+        self.next_instruction = Instruction::new(state.read_u32()?);
+        self.current_instruction = Instruction::new(state.read_u32()?);
+        self.branch_delay = state.read_bool()?;
+        self.delay_slot_pc = state.read_u32()?;
+        self.fast_boot = state.read_bool()?;
+        // Whatever was on it belongs to the code that was running before.
+        self.call_stack.clear();
+
+        self.gte.load_state(state)?;
+        self.memory.load_state(state)
+    }
+
+    // Loads the disc's boot executable straight into RAM and jumps to it.
+    // Called once the BIOS is done setting up the kernel and is about to start the shell.
+    fn boot_disc_executable(&mut self) {
+        let executable = match self.memory.cdrom.disc.as_mut() {
+            Some(disc) => disc.boot_executable_path().and_then(|path| {
+                let entry = disc.find_file(&path)?;
+                let file = disc.read_file(&entry)?;
+                exe::Executable::parse(&file)
+            }),
+            None => return,
+        };
+
+        match executable {
+            Ok(executable) => {
+                executable.load(&mut self.memory);
+
+                self.pc = executable.pc;
+                self.set_register(28, executable.gp);
+
+                if executable.sp != 0 {
+                    self.set_register(29, executable.sp);
+                    self.set_register(30, executable.sp);
+                }
+            },
+            Err(error) => println!("Fast boot failed, continuing to the shell: {}", error),
         }
     }
 
+    // Address of the instruction that runs next, the delay slot's own address included.
+    pub fn current_pc(&self) -> u32 {
+        if self.branch_delay { self.delay_slot_pc } else { self.pc }
+    }
+
     fn set_register(&mut self, idx: usize, value: u32) {
         self.registers[idx] = value;
         self.registers[0] = 0;
@@ -128,16 +246,46 @@ impl Cpu {
     }
 
     fn fetch_instruction(&mut self) {
-        self.current_instruction = Instruction::new(self.memory.read_word(self.pc));
-        self.next_instruction = Instruction::new(self.memory.read_word(self.pc + 4));
+        self.current_instruction = Instruction::new(self.memory.fetch_word(self.pc));
+        self.next_instruction = Instruction::new(self.memory.fetch_word(self.pc + 4));
     }
 
     pub fn run_instruction(&mut self) -> CycleResult {
 
-        if self.cpu_result == CycleResult::Breakpoint {
+        // Resuming from a breakpoint runs the instruction it stopped on.
+        let resuming = self.cpu_result == CycleResult::Breakpoint;
+        if resuming {
             self.cpu_result = CycleResult::Success;
         }
 
+        if self.fast_boot && !self.branch_delay && self.pc == SHELL_ENTRY {
+            self.fast_boot = false;
+            self.boot_disc_executable();
+        }
+
+        let in_delay_slot = self.branch_delay;
+        let instruction_pc = self.current_pc();
+
+        if !resuming && !self.breakpoints.is_empty() {
+            let context = breakpoints::Context {
+                pc: instruction_pc,
+                registers: &self.registers,
+                hi: self.hi,
+                lo: self.lo,
+                memory: &self.memory,
+            };
+
+            if self.breakpoints.hit(&context) {
+                self.cpu_result = CycleResult::Breakpoint;
+                return CycleResult::Breakpoint;
+            }
+        }
+
+        let traced_pc = match self.tracer.as_mut() {
+            Some(tracer) => tracer.begin(self.pc, self.branch_delay, &self.registers, self.hi, self.lo),
+            None => None,
+        };
+
         if self.branch_delay {
             self.current_instruction = self.next_instruction;
             self.branch_delay = false;
@@ -146,6 +294,8 @@ impl Cpu {
             self.fetch_instruction();
         }
 
+        let jump_register = self.registers[self.current_instruction.rs() as usize];
+
         match self.current_instruction.op() {
 
             0x00 => match self.current_instruction.function() {
@@ -248,7 +398,7 @@ impl Cpu {
 
             0x10 => self.cop0(),
             0x11 => {},
-            0x12 => {},
+            0x12 => self.cop2(),
             0x13 => {},
             0x14 => {},
             0x15 => {},
@@ -284,7 +434,7 @@ impl Cpu {
 
             0x30 => {},
             0x31 => {},
-            0x32 => {},
+            0x32 => self.lwc2(),
             0x33 => {},
             0x34 => {},
             0x35 => {},
@@ -293,7 +443,7 @@ impl Cpu {
 
             0x38 => {},
             0x39 => {},
-            0x3A => {},
+            0x3A => self.swc2(),
             0x3B => {},
             0x3C => {},
             0x3D => {},
@@ -303,19 +453,30 @@ impl Cpu {
             _=> {}
         }
 
-        for index in 0..self.debugger_breakpoints.len() {
-            if self.pc == self.debugger_breakpoints[index] {
-                self.cpu_result = CycleResult::Breakpoint;
-                self.debugger_breakpoints.remove(index);
-                return CycleResult::Breakpoint;
+        if let (Some(pc), Some(tracer)) = (traced_pc, self.tracer.as_mut()) {
+            tracer.end(pc, self.current_instruction.value(), &self.registers, self.hi, self.lo);
+        }
+
+        if !in_delay_slot {
+            self.call_stack.update(instruction_pc, self.current_instruction.value(), jump_register, self.registers[29]);
+
+            if self.branch_delay {
+                self.delay_slot_pc = instruction_pc.wrapping_add(4);
             }
         }
 
+        let watch_hit = if self.memory.watchpoints.armed() { self.memory.watchpoints.take_hit(instruction_pc) } else { None };
+
         self.pc = self.pc.wrapping_add(4);
+        self.memory.tick(CYCLES_PER_INSTRUCTION);
 
         if self.cpu_result == CycleResult::Error {
             CycleResult::Error
         }
+        else if watch_hit.is_some() {
+            self.watch_hit = watch_hit;
+            CycleResult::Watchpoint
+        }
         else {
             CycleResult::Success
         }
@@ -724,4 +885,181 @@ impl Cpu {
     fn mtc0(&mut self) {
         self.cop0_registers[self.current_instruction.rd() as usize] = self.registers[self.current_instruction.rt() as usize];
     }
-}
\ No newline at end of file
+
+
+
+    // COP2 (GTE) Instructions
+
+    fn cop2(&mut self) {
+        // Bit 25 set means the rest of the instruction is a GTE command.
+        if self.current_instruction.value & (1 << 25) != 0 {
+            if !self.gte.execute(self.current_instruction.value & 0x1FFFFFF) {
+                self.cpu_result = CycleResult::Error;
+                println!("Unimplemented GTE command: {:02X} at PC {:08X}", self.current_instruction.function(), self.pc);
+            }
+            return;
+        }
+
+        match self.current_instruction.rs() {
+            0x00 => self.mfc2(),
+            0x02 => self.cfc2(),
+            0x04 => self.mtc2(),
+            0x06 => self.ctc2(),
+            _ => {
+                self.cpu_result = CycleResult::Error;
+                println!("Unimplemented COP2 instruction: {:X}({:b})", self.current_instruction.rs(), self.current_instruction.rs());
+            },
+        }
+    }
+
+    fn mfc2(&mut self) {
+        let value = self.gte.read_data(self.current_instruction.rd());
+        self.set_register(self.current_instruction.rt() as usize, value);
+    }
+
+    fn cfc2(&mut self) {
+        let value = self.gte.read_control(self.current_instruction.rd());
+        self.set_register(self.current_instruction.rt() as usize, value);
+    }
+
+    fn mtc2(&mut self) {
+        self.gte.write_data(self.current_instruction.rd(), self.registers[self.current_instruction.rt() as usize]);
+    }
+
+    fn ctc2(&mut self) {
+        self.gte.write_control(self.current_instruction.rd(), self.registers[self.current_instruction.rt() as usize]);
+    }
+
+    fn lwc2(&mut self) {
+        let immediate = self.current_instruction.immediate() as i16 as i32;
+        let address = self.registers[self.current_instruction.rs() as usize].wrapping_add(immediate as u32);
+        let value = self.memory.read_word(address);
+        self.gte.write_data(self.current_instruction.rt(), value);
+    }
+
+    fn swc2(&mut self) {
+        let immediate = self.current_instruction.immediate() as i16 as i32;
+        let address = self.registers[self.current_instruction.rs() as usize].wrapping_add(immediate as u32);
+        let value = self.gte.read_data(self.current_instruction.rt());
+        self.memory.write_word(address, value);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::fs;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::*;
+    use super::super::cdrom::disc::tests::{build_image, write_image};
+
+    pub fn test_cpu() -> Cpu {
+        Cpu::with_bios(vec![0; 512 * 1024])
+    }
+
+    fn executable(pc: u32, gp: u32, sp: u32, code: &[u32]) -> Vec<u8> {
+        let mut file = vec![0; exe::HEADER_SIZE + code.len() * 4];
+
+        file[0..8].copy_from_slice(b"PS-X EXE");
+        LittleEndian::write_u32(&mut file[0x10..0x14], pc);
+        LittleEndian::write_u32(&mut file[0x14..0x18], gp);
+        LittleEndian::write_u32(&mut file[0x18..0x1C], pc);
+        LittleEndian::write_u32(&mut file[0x1C..0x20], code.len() as u32 * 4);
+        LittleEndian::write_u32(&mut file[0x30..0x34], sp);
+
+        for (index, word) in code.iter().enumerate() {
+            LittleEndian::write_u32(&mut file[exe::HEADER_SIZE + index * 4..], *word);
+        }
+
+        file
+    }
+
+    fn boot(name: &str, files: &[(&str, &[u8])]) -> Cpu {
+        let path = write_image(name, &build_image(files, 24));
+
+        let mut cpu = test_cpu();
+        cpu.memory.cdrom.insert_disc(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        cpu.fast_boot = true;
+        cpu.pc = SHELL_ENTRY;
+        cpu.run_instruction();
+        cpu
+    }
+
+    #[test]
+    fn watchpoints_in_delay_slots_report_the_slot() {
+        let mut cpu = test_cpu();
+
+        // beq $zero, $zero, +2 with sw $t0, 0($t1) in its delay slot.
+        cpu.memory.write_word(0x80010000, 0x1000_0002);
+        cpu.memory.write_word(0x80010004, 0xAD28_0000);
+        cpu.registers[8] = 0x1234;
+        cpu.registers[9] = 0x80001000;
+        cpu.memory.watchpoints.add(0x1000, 0x1003, super::super::watchpoints::WatchKind::Write);
+
+        cpu.pc = 0x80010000;
+        assert!(cpu.run_instruction() == CycleResult::Success);
+        assert!(cpu.run_instruction() == CycleResult::Watchpoint);
+
+        let hit = cpu.watch_hit.unwrap();
+        assert_eq!(hit.pc, 0x80010004);
+        assert_eq!(hit.value, 0x1234);
+    }
+
+    #[test]
+    fn breakpoints_in_delay_slots_fire() {
+        let mut cpu = test_cpu();
+
+        // beq $zero, $zero, +2 with sw $t0, 0($t1) in its delay slot.
+        cpu.memory.write_word(0x80010000, 0x1000_0002);
+        cpu.memory.write_word(0x80010004, 0xAD28_0000);
+        cpu.registers[8] = 0x1234;
+        cpu.registers[9] = 0x80001000;
+        cpu.breakpoints.add(0x80010004, None);
+
+        cpu.pc = 0x80010000;
+        assert!(cpu.run_instruction() == CycleResult::Success);
+        assert!(cpu.run_instruction() == CycleResult::Breakpoint);
+        assert_eq!(cpu.current_pc(), 0x80010004);
+        assert_eq!(cpu.memory.read_word(0x80001000), 0);
+
+        // Resuming runs the delay slot.
+        assert!(cpu.run_instruction() == CycleResult::Success);
+        assert_eq!(cpu.memory.read_word(0x80001000), 0x1234);
+    }
+
+    #[test]
+    fn fast_boot_loads_the_system_cnf_executable() {
+        // ori $t0, $zero, 0x1234
+        let main = executable(0x80010000, 0x8001_8000, 0x801F_FF00, &[0x3408_1234]);
+        let mut cpu = boot("fast_boot.iso", &[("SYSTEM.CNF;1", b"BOOT=cdrom:\\MAIN.EXE;1\n"), ("MAIN.EXE;1", &main)]);
+
+        assert!(!cpu.fast_boot);
+        assert_eq!(cpu.memory.read_word(0x80010000), 0x3408_1234);
+        assert_eq!(cpu.registers[8], 0x1234);
+        assert_eq!(cpu.registers[28], 0x8001_8000);
+        assert_eq!(cpu.registers[29], 0x801F_FF00);
+        assert_eq!(cpu.registers[30], 0x801F_FF00);
+        assert_eq!(cpu.pc, 0x80010004);
+    }
+
+    #[test]
+    fn fast_boot_falls_back_to_psx_exe() {
+        let main = executable(0x80020000, 0, 0, &[0x3408_0001]);
+        let cpu = boot("fast_boot_default.iso", &[("PSX.EXE;1", &main)]);
+
+        assert_eq!(cpu.registers[8], 1);
+        assert_eq!(cpu.registers[29], 0);
+        assert_eq!(cpu.pc, 0x80020004);
+    }
+
+    #[test]
+    fn failed_fast_boot_continues_to_the_shell() {
+        let cpu = boot("fast_boot_missing.iso", &[("SYSTEM.CNF;1", b"BOOT=cdrom:\\MISSING.EXE;1\n")]);
+
+        assert!(!cpu.fast_boot);
+        assert_eq!(cpu.pc, SHELL_ENTRY + 4);
+    }
+}
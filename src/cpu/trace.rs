@@ -0,0 +1,311 @@
+// Execution trace, one line per instruction the CPU runs:
+//
+//     bfc00000: 3c080013  lui $t0, 0x13 ; t0=00130000
+//
+// PC and opcode in lowercase hex, the disassembly, then every register the instruction changed.
+// That's the layout most emulators' trace logs share once their extra columns are cut, so
+// traces can be compared line by line against them.
+
+use std::io;
+use std::io::{BufWriter, Write};
+use std::fs::File;
+use std::path::Path;
+use std::collections::VecDeque;
+
+use super::super::instructions_decoder;
+
+// The general purpose registers followed by hi and lo.
+pub const REGISTER_COUNT: usize = 34;
+
+#[derive(Clone)]
+pub struct TraceSettings {
+    // Only instructions inside one of these ranges, both ends included, are logged. Empty logs everything.
+    pub ranges: Vec<(u32, u32)>,
+    // Logging begins when this address is reached.
+    pub start: Option<u32>,
+    // Logging ends for good after this address.
+    pub stop: Option<u32>,
+    // Keep only the last this many instructions, written out when the trace is closed.
+    pub last: Option<usize>,
+}
+
+impl TraceSettings {
+    pub fn new() -> TraceSettings {
+        TraceSettings {
+            ranges: Vec::new(),
+            start: None,
+            stop: None,
+            last: None,
+        }
+    }
+}
+
+pub struct Tracer {
+    output: BufWriter<File>,
+    settings: TraceSettings,
+
+    recording: bool,
+    stopped: bool,
+    ring: VecDeque<String>,
+
+    // The delay slot isn't where the PC register points, it's after the branch.
+    last_pc: u32,
+    registers: [u32; REGISTER_COUNT],
+}
+
+impl Tracer {
+    pub fn create(path: &Path, settings: TraceSettings) -> io::Result<Tracer> {
+        Ok(Tracer {
+            output: BufWriter::new(File::create(path)?),
+            recording: settings.start.is_none(),
+            settings,
+
+            stopped: false,
+            ring: VecDeque::new(),
+
+            last_pc: 0,
+            registers: [0; REGISTER_COUNT],
+        })
+    }
+
+    // Called before an instruction runs. Returns its address if it's going to be logged.
+    pub fn begin(&mut self, pc: u32, in_delay_slot: bool, registers: &[u32], hi: u32, lo: u32) -> Option<u32> {
+        let pc = if in_delay_slot { self.last_pc.wrapping_add(4) } else { pc };
+        self.last_pc = pc;
+
+        if self.stopped {
+            return None;
+        }
+        if Some(pc) == self.settings.start {
+            self.recording = true;
+        }
+        if !self.recording {
+            return None;
+        }
+        if Some(pc) == self.settings.stop {
+            self.stopped = true;
+        }
+        if !self.settings.ranges.is_empty() && !self.settings.ranges.iter().any(|(start, end)| pc >= *start && pc <= *end) {
+            return None;
+        }
+
+        self.registers = snapshot(registers, hi, lo);
+        Some(pc)
+    }
+
+    // Called after an instruction that begin() accepted has run.
+    pub fn end(&mut self, pc: u32, value: u32, registers: &[u32], hi: u32, lo: u32) {
+        let line = format_line(pc, value, &self.registers, &snapshot(registers, hi, lo));
+
+        match self.settings.last {
+            Some(last) => {
+                if self.ring.len() >= last {
+                    self.ring.pop_front();
+                }
+                self.ring.push_back(line);
+            },
+            None => {
+                if let Err(error) = writeln!(self.output, "{}", line) {
+                    println!("Stopping the trace after a write error: {}", error);
+                    self.stopped = true;
+                }
+            },
+        }
+    }
+
+    // Writes out what's still buffered, the last instructions included.
+    pub fn finish(&mut self) -> io::Result<()> {
+        for line in self.ring.drain(..) {
+            writeln!(self.output, "{}", line)?;
+        }
+        self.output.flush()
+    }
+}
+
+pub fn snapshot(registers: &[u32], hi: u32, lo: u32) -> [u32; REGISTER_COUNT] {
+    let mut snapshot = [0; REGISTER_COUNT];
+    snapshot[..32].copy_from_slice(&registers[..32]);
+    snapshot[32] = hi;
+    snapshot[33] = lo;
+    snapshot
+}
+
+pub fn register_name(index: usize) -> &'static str {
+    match index {
+        32 => "hi",
+        33 => "lo",
+        _ => &instructions_decoder::REGISTER_NAMES[index][1..],
+    }
+}
+
+// Also takes the names other emulators use: with a $, r0 to r31 and s8.
+pub fn register_index(name: &str) -> Option<usize> {
+    let name = name.trim_start_matches('$').to_ascii_lowercase();
+
+    match name.as_str() {
+        "s8" => return Some(30),
+        "hi" => return Some(32),
+        "lo" => return Some(33),
+        _ => {},
+    }
+
+    if let Some(index) = (0..32).position(|index| register_name(index) == name) {
+        return Some(index);
+    }
+
+    name.strip_prefix('r')
+        .and_then(|index| index.parse::<usize>().ok())
+        .filter(|index| *index < 32)
+}
+
+pub fn format_line(pc: u32, value: u32, before: &[u32; REGISTER_COUNT], after: &[u32; REGISTER_COUNT]) -> String {
+    let mut line = format!("{:08x}: {:08x}  {}", pc, value, instructions_decoder::disassemble(value, pc));
+
+    let changes: Vec<String> = (0..REGISTER_COUNT)
+        .filter(|index| before[*index] != after[*index])
+        .map(|index| format!("{}={:08x}", register_name(index), after[index]))
+        .collect();
+
+    if !changes.is_empty() {
+        line.push_str(" ; ");
+        line.push_str(&changes.join(" "));
+    }
+
+    line
+}
+
+// A line read back from a trace, ours or another emulator's.
+pub struct TraceLine {
+    pub pc: u32,
+    pub opcode: Option<u32>,
+    // Register values after the instruction ran, by register_index().
+    pub registers: Vec<(usize, u32)>,
+}
+
+// Takes the PC from the first word, or a pc= field, and the opcode from the word after a leading PC.
+// Any name=value field naming a register is read as its value. Lines without a PC give None.
+pub fn parse_line(line: &str) -> Option<TraceLine> {
+    let mut pc = None;
+    let mut opcode = None;
+    let mut registers = Vec::new();
+
+    for (position, word) in line.split(|c: char| c.is_whitespace() || c == ',' || c == ';').filter(|word| !word.is_empty()).enumerate() {
+        if let Some(equals) = word.find('=') {
+            let (name, value) = (&word[..equals], parse_hex(&word[equals + 1..]));
+
+            if let Some(value) = value {
+                if name.eq_ignore_ascii_case("pc") {
+                    pc = Some(value);
+                }
+                else if let Some(index) = register_index(name) {
+                    registers.push((index, value));
+                }
+            }
+        }
+        else if position == 0 {
+            pc = parse_word(word);
+        }
+        else if position == 1 && pc.is_some() {
+            opcode = parse_word(word);
+        }
+    }
+
+    pc.map(|pc| TraceLine { pc, opcode, registers })
+}
+
+fn parse_hex(word: &str) -> Option<u32> {
+    u32::from_str_radix(word.trim_start_matches("0x").trim_start_matches("0X"), 16).ok()
+}
+
+// The PC and opcode columns are always written out in full, which tells them apart from the
+// first word of a header or a mnemonic.
+fn parse_word(word: &str) -> Option<u32> {
+    let word = word.trim_end_matches(':');
+    if word.trim_start_matches("0x").trim_start_matches("0X").len() == 8 { parse_hex(word) } else { None }
+}
+
+// So the trace is complete however the CPU goes away, including a crash being unwound.
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        if let Err(error) = self.finish() {
+            println!("Couldn't finish the trace: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rusty_psx_{}_{}", std::process::id(), name))
+    }
+
+    // Runs `ori $t0, $zero, <n>` at every address through a tracer, in and out of delay slots as
+    // given, and returns the lines it wrote.
+    fn record(name: &str, settings: TraceSettings, steps: &[(u32, bool)]) -> Vec<String> {
+        let path = temporary_path(name);
+        let mut tracer = Tracer::create(&path, settings).unwrap();
+        let mut registers = vec![0; 32];
+
+        for (step, (pc, in_delay_slot)) in steps.iter().enumerate() {
+            if let Some(pc) = tracer.begin(*pc, *in_delay_slot, &registers, 0, 0) {
+                registers[8] = step as u32;
+                tracer.end(pc, 0x3408_0000 | step as u32, &registers, 0, 0);
+            }
+        }
+        drop(tracer);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        contents.lines().map(String::from).collect()
+    }
+
+    fn addresses(lines: &[String]) -> Vec<&str> {
+        lines.iter().map(|line| &line[..8]).collect()
+    }
+
+    #[test]
+    fn lines_list_the_changed_registers() {
+        let before = [0; REGISTER_COUNT];
+        let mut after = before;
+        after[8] = 0x00130000;
+        assert_eq!(format_line(0xBFC00000, 0x3C080013, &before, &after), "bfc00000: 3c080013  lui $t0, 0x13 ; t0=00130000");
+
+        after[32] = 1;
+        after[33] = 2;
+        assert!(format_line(0xBFC00000, 0x3C080013, &before, &after).ends_with(" ; t0=00130000 hi=00000001 lo=00000002"));
+
+        assert!(!format_line(0xBFC00000, 0, &before, &before).contains(';'));
+    }
+
+    #[test]
+    fn delay_slots_follow_the_branch() {
+        let lines = record("trace_delay_slot.txt", TraceSettings::new(), &[(0x80010000, false), (0x80020000, true), (0x80020000, false)]);
+        assert_eq!(addresses(&lines), ["80010000", "80010004", "80020000"]);
+    }
+
+    #[test]
+    fn ranges_start_and_stop() {
+        let steps: Vec<(u32, bool)> = (0..8).map(|index| (0x80010000 + index * 4, false)).collect();
+
+        let mut settings = TraceSettings::new();
+        settings.ranges = vec![(0x80010004, 0x80010008), (0x80010018, 0x80010018)];
+        assert_eq!(addresses(&record("trace_ranges.txt", settings, &steps)), ["80010004", "80010008", "80010018"]);
+
+        // The stop address is still logged.
+        let mut settings = TraceSettings::new();
+        settings.start = Some(0x80010008);
+        settings.stop = Some(0x80010010);
+        assert_eq!(addresses(&record("trace_start_stop.txt", settings, &steps)), ["80010008", "8001000c", "80010010"]);
+    }
+
+    #[test]
+    fn last_keeps_the_newest_lines() {
+        let steps: Vec<(u32, bool)> = (0..8).map(|index| (0x80010000 + index * 4, false)).collect();
+
+        let mut settings = TraceSettings::new();
+        settings.last = Some(3);
+        assert_eq!(addresses(&record("trace_last.txt", settings, &steps)), ["80010014", "80010018", "8001001c"]);
+    }
+}
@@ -0,0 +1,222 @@
+// Runs the CPU in lockstep with a reference trace, from this emulator or another one, and stops
+// at the first instruction where the PC, the opcode or a register doesn't match.
+
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::fs::File;
+use std::path::Path;
+use std::collections::VecDeque;
+
+use super::{Cpu, CycleResult};
+use super::trace;
+use super::trace::REGISTER_COUNT;
+
+// Lines shown before and after the divergence.
+pub const CONTEXT_LINES: usize = 8;
+
+// How far the CPU may run looking for the first instruction of the reference.
+const SYNC_LIMIT: u64 = 500_000_000;
+
+pub struct Divergence {
+    // Line of the reference file, counting from 1.
+    pub line_number: usize,
+    pub differences: Vec<String>,
+    // The instructions leading up to it, as the emulator ran them.
+    pub before: Vec<String>,
+    pub emulator: String,
+    pub reference: String,
+    pub after: Vec<String>,
+}
+
+pub struct Report {
+    // Instructions that matched.
+    pub matched: usize,
+    pub divergence: Option<Divergence>,
+}
+
+pub fn run_path(cpu: &mut Cpu, path: &Path) -> io::Result<Report> {
+    run(cpu, BufReader::new(File::open(path)?))
+}
+
+// Reads the reference a line at a time, so only the lines around a divergence are kept.
+pub fn run<R: BufRead>(cpu: &mut Cpu, reader: R) -> io::Result<Report> {
+    let mut lines = reader.lines()
+        .enumerate()
+        .filter_map(|(index, text)| match text {
+            Ok(text) => trace::parse_line(&text).map(|line| Ok((index + 1, text, line))),
+            Err(error) => Some(Err(error)),
+        });
+
+    let mut current = match lines.next().transpose()? {
+        Some(current) => current,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "No instructions in the reference trace")),
+    };
+    let first_pc = current.2.pc;
+
+    let mut last_pc = cpu.pc;
+    let mut skipped = 0;
+    while next_pc(cpu, last_pc) != first_pc {
+        if skipped == SYNC_LIMIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The CPU never reached {:08x}, where the reference starts", first_pc)));
+        }
+
+        last_pc = next_pc(cpu, last_pc);
+        if cpu.run_instruction() == CycleResult::Error {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The CPU stopped at {:08x} before reaching the start of the reference", last_pc)));
+        }
+        skipped += 1;
+    }
+
+    // The reference may only list the registers each instruction changes, so whatever it
+    // doesn't mention starts out as the emulator has it.
+    let mut expected = trace::snapshot(&cpu.registers, cpu.hi, cpu.lo);
+    let mut before = VecDeque::with_capacity(CONTEXT_LINES);
+    let mut matched = 0;
+
+    loop {
+        let (line_number, text, line) = current;
+
+        let pc = next_pc(cpu, last_pc);
+        last_pc = pc;
+
+        let registers = trace::snapshot(&cpu.registers, cpu.hi, cpu.lo);
+        let result = cpu.run_instruction();
+        let value = cpu.current_instruction.value();
+        let after = trace::snapshot(&cpu.registers, cpu.hi, cpu.lo);
+        let emulator = trace::format_line(pc, value, &registers, &after);
+
+        for (index, value) in line.registers.iter() {
+            expected[*index] = *value;
+        }
+
+        let differences = compare(pc, value, &after, &line, &expected, result);
+        if !differences.is_empty() {
+            let mut following = Vec::with_capacity(CONTEXT_LINES);
+            for next in lines.by_ref().take(CONTEXT_LINES) {
+                following.push(next?.1);
+            }
+
+            return Ok(Report {
+                matched,
+                divergence: Some(Divergence {
+                    line_number,
+                    differences,
+                    before: before.into_iter().collect(),
+                    emulator,
+                    reference: text,
+                    after: following,
+                }),
+            });
+        }
+
+        matched += 1;
+        if before.len() == CONTEXT_LINES {
+            before.pop_front();
+        }
+        before.push_back(emulator);
+
+        current = match lines.next().transpose()? {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    Ok(Report {
+        matched,
+        divergence: None,
+    })
+}
+
+// Address of the instruction the CPU runs next. The PC register is past the branch while it
+// runs a delay slot.
+fn next_pc(cpu: &Cpu, last_pc: u32) -> u32 {
+    if cpu.branch_delay { last_pc.wrapping_add(4) } else { cpu.pc }
+}
+
+fn compare(pc: u32, value: u32, registers: &[u32; REGISTER_COUNT], line: &trace::TraceLine, expected: &[u32; REGISTER_COUNT], result: CycleResult) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    if pc != line.pc {
+        differences.push(format!("pc expected {:08x}, got {:08x}", line.pc, pc));
+    }
+    if let Some(opcode) = line.opcode {
+        if opcode != value {
+            differences.push(format!("opcode expected {:08x}, got {:08x}", opcode, value));
+        }
+    }
+    for index in 0..REGISTER_COUNT {
+        if registers[index] != expected[index] {
+            differences.push(format!("{} expected {:08x}, got {:08x}", trace::register_name(index), expected[index], registers[index]));
+        }
+    }
+    if result == CycleResult::Error {
+        differences.push(String::from("the CPU stopped with an error"));
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::test_cpu;
+
+    // ori $t0..$t3, $zero, 1..4
+    fn straight_line_cpu() -> Cpu {
+        let mut cpu = test_cpu();
+        for (index, word) in [0x3408_0001, 0x3409_0002, 0x340A_0003, 0x340B_0004].iter().enumerate() {
+            cpu.memory.write_word(0x80010000 + index as u32 * 4, *word);
+        }
+        cpu.pc = 0x80010000;
+        cpu
+    }
+
+    #[test]
+    fn syncs_to_the_first_line_and_matches() {
+        let reference = "Trace of the test program\n\
+                         80010004: 34090002  ori $t1, $zero, 0x2 ; t1=00000002\n\
+                         pc=80010008 t2=00000003\n\
+                         8001000c 340b0004 ; $r11=0x4\n";
+
+        let mut cpu = straight_line_cpu();
+        let report = run(&mut cpu, reference.as_bytes()).unwrap();
+        assert_eq!(report.matched, 3);
+        assert!(report.divergence.is_none());
+        assert_eq!(cpu.registers[8], 1);
+    }
+
+    #[test]
+    fn reports_the_first_divergence() {
+        let reference = "80010000: 34080001 ; t0=00000001\n\
+                         80010004: 34090002 ; t1=00000002\n\
+                         80010008: 340a0003 ; t2=00000005\n\
+                         8001000c: 340b0004 ; t3=00000004\n";
+
+        let report = run(&mut straight_line_cpu(), reference.as_bytes()).unwrap();
+        assert_eq!(report.matched, 2);
+
+        let divergence = report.divergence.unwrap();
+        assert_eq!(divergence.line_number, 3);
+        assert_eq!(divergence.differences, ["t2 expected 00000005, got 00000003"]);
+        assert_eq!(divergence.before.len(), 2);
+        assert!(divergence.before[0].starts_with("80010000: 34080001"));
+        assert!(divergence.emulator.starts_with("80010008: 340a0003"));
+        assert_eq!(divergence.reference, "80010008: 340a0003 ; t2=00000005");
+        assert_eq!(divergence.after, ["8001000c: 340b0004 ; t3=00000004"]);
+    }
+
+    #[test]
+    fn reports_a_wrong_pc_and_opcode() {
+        let reference = "80010000: 34080001 ; t0=00000001\n80010008: 340a0003\n";
+
+        let divergence = run(&mut straight_line_cpu(), reference.as_bytes()).unwrap().divergence.unwrap();
+        assert_eq!(divergence.line_number, 2);
+        assert_eq!(divergence.differences, ["pc expected 80010008, got 80010004", "opcode expected 340a0003, got 34090002", "t1 expected 00000000, got 00000002"]);
+    }
+
+    #[test]
+    fn rejects_a_reference_without_instructions() {
+        assert!(run(&mut straight_line_cpu(), "".as_bytes()).is_err());
+        assert!(run(&mut straight_line_cpu(), "pc t0 t1\n".as_bytes()).is_err());
+    }
+}
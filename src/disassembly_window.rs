@@ -0,0 +1,202 @@
+use imgui::*;
+
+use super::cpu::Cpu;
+use super::instructions_decoder;
+use super::instructions_decoder::Instruction;
+
+// Instructions shown on either side of the centre of the view.
+const LINES_AROUND: u32 = 128;
+
+const CURRENT_COLOUR: [f32; 4] = [1.0, 1.0, 0.0, 1.0];
+const DELAY_SLOT_COLOUR: [f32; 4] = [0.6, 0.6, 1.0, 1.0];
+const BREAKPOINT_COLOUR: [f32; 4] = [1.0, 0.3, 0.3, 1.0];
+
+// Disassembly around the PC, or around any address the user jumps to.
+// Clicking a line toggles a breakpoint on it, right clicking opens a menu to run to it or follow it.
+pub struct DisassemblyWindow {
+    follow_pc: bool,
+    // Centre of the view while it isn't following the PC.
+    address: u32,
+    // Addresses the view was at before following a branch or going somewhere else.
+    history: Vec<u32>,
+    goto_address: ImString,
+    status: String,
+
+    last_pc: u32,
+    scroll_pending: bool,
+
+    // The line the menu was opened on, and where it leads if it's a jump or branch.
+    menu_address: u32,
+    menu_target: Option<u32>,
+}
+
+impl DisassemblyWindow {
+    pub fn new() -> DisassemblyWindow {
+        DisassemblyWindow {
+            follow_pc: true,
+            address: 0,
+            history: Vec::new(),
+            goto_address: ImString::with_capacity(8),
+            status: String::new(),
+
+            last_pc: 0,
+            scroll_pending: true,
+
+            menu_address: 0,
+            menu_target: None,
+        }
+    }
+
+    // Moves the view to `address`, from wherever it is now.
+    pub fn show(&mut self, address: u32) {
+        let centre = if self.follow_pc { self.last_pc & !3 } else { self.address };
+        self.go_to(address, centre);
+    }
+
+    fn go_to(&mut self, address: u32, centre: u32) {
+        self.history.push(centre);
+        self.address = address & !3;
+        self.follow_pc = false;
+        self.scroll_pending = true;
+    }
+
+    // Returns the address to run to when the user asks for it.
+    pub fn build(&mut self, ui: &Ui, cpu: &mut Cpu) -> Option<u32> {
+        if self.follow_pc && cpu.current_pc() != self.last_pc {
+            self.scroll_pending = true;
+        }
+        self.last_pc = cpu.current_pc();
+        let mut run_to = None;
+
+        Window::new(im_str!("Rusty PSX - Disassembly")).size([460.0, 500.0], Condition::FirstUseEver).build(ui, || {
+            let centre = if self.follow_pc { cpu.current_pc() & !3 } else { self.address };
+
+            if ui.checkbox(im_str!("Follow PC"), &mut self.follow_pc) {
+                self.scroll_pending = true;
+            }
+            ui.same_line(0.0);
+            if ui.button(im_str!("Back"), [60.0, 20.0]) {
+                if let Some(address) = self.history.pop() {
+                    self.address = address;
+                    self.follow_pc = false;
+                    self.scroll_pending = true;
+                }
+            }
+
+            if ui.input_text(im_str!("Go to address"), &mut self.goto_address).chars_hexadecimal(true).enter_returns_true(true).build() {
+                match u32::from_str_radix(self.goto_address.to_str(), 16) {
+                    Ok(address) => {
+                        self.go_to(address, centre);
+                        self.status.clear();
+                    },
+                    Err(_) => self.status = format!("\"{}\" isn't a hexadecimal address", self.goto_address.to_str()),
+                }
+            }
+
+            if !self.status.is_empty() {
+                ui.text_colored(BREAKPOINT_COLOUR, &self.status);
+            }
+            ui.separator();
+
+            let mut follow = None;
+            let mut toggle = None;
+
+            ChildWindow::new(im_str!("Disassembly Lines")).size([0.0, 0.0]).border(true).build(ui, || {
+                let start = centre.wrapping_sub(LINES_AROUND * 4);
+                let mut previous = cpu.memory.peek_word(start.wrapping_sub(4)).map(instructions_decoder::decode);
+
+                for line in 0..LINES_AROUND * 2 + 1 {
+                    let address = start.wrapping_add(line * 4);
+                    let instruction = cpu.memory.peek_word(address).map(|value| (value, instructions_decoder::decode(value)));
+
+                    let breakpoint = cpu.breakpoints.get(address).map(|breakpoint| breakpoint.enabled);
+                    let delay_slot = previous.map(|previous| previous.has_delay_slot()).unwrap_or(false);
+
+                    let text = match instruction {
+                        Some((value, decoded)) => format!("{} {:08X}  {:08X}  {}", marker(breakpoint), address, value, decoded.render(address)),
+                        None => format!("{} {:08X}  --------", marker(breakpoint), address),
+                    };
+
+                    let colour = if address == cpu.current_pc() {
+                        Some(CURRENT_COLOUR)
+                    }
+                    else if breakpoint == Some(true) {
+                        Some(BREAKPOINT_COLOUR)
+                    }
+                    else if delay_slot {
+                        Some(DELAY_SLOT_COLOUR)
+                    }
+                    else {
+                        None
+                    };
+
+                    let token = colour.map(|colour| ui.push_style_color(StyleColor::Text, colour));
+                    let clicked = Selectable::new(&ImString::new(text)).selected(address == cpu.current_pc()).build(ui);
+                    if let Some(token) = token {
+                        token.pop(ui);
+                    }
+
+                    if clicked {
+                        cpu.breakpoints.toggle(address);
+                    }
+
+                    if ui.is_item_clicked(MouseButton::Right) {
+                        self.menu_address = address;
+                        self.menu_target = instruction.and_then(|(_, decoded)| follow_target(cpu, &decoded, address));
+                        ui.open_popup(im_str!("Line Menu"));
+                    }
+
+                    if self.scroll_pending && address == centre {
+                        ui.set_scroll_here_y_with_ratio(0.5);
+                    }
+
+                    previous = instruction.map(|(_, decoded)| decoded);
+                }
+
+                self.scroll_pending = false;
+
+                ui.popup(im_str!("Line Menu"), || {
+                    ui.text(format!("{:08X}", self.menu_address));
+                    ui.separator();
+                    if MenuItem::new(im_str!("Run to here")).build(ui) {
+                        run_to = Some(self.menu_address);
+                    }
+                    if MenuItem::new(im_str!("Toggle breakpoint")).build(ui) {
+                        toggle = Some(self.menu_address);
+                    }
+                    if MenuItem::new(im_str!("Follow")).enabled(self.menu_target.is_some()).build(ui) {
+                        follow = self.menu_target;
+                    }
+                });
+            });
+
+            if let Some(address) = toggle {
+                cpu.breakpoints.toggle(address);
+            }
+
+            if let Some(target) = follow {
+                self.go_to(target, centre);
+            }
+        });
+
+        run_to
+    }
+}
+
+// B for a breakpoint, b for one that's switched off.
+fn marker(breakpoint: Option<bool>) -> &'static str {
+    match breakpoint {
+        Some(true) => "B",
+        Some(false) => "b",
+        None => " ",
+    }
+}
+
+// Where a jump or branch at `address` leads. Register jumps can only be followed where the
+// CPU is about to run them, since that's the only place the register holds the right value.
+fn follow_target(cpu: &Cpu, instruction: &Instruction, address: u32) -> Option<u32> {
+    match *instruction {
+        Instruction::Jr { rs } | Instruction::Jalr { rs, .. } if address == cpu.current_pc() => Some(cpu.registers[rs as usize]),
+        _ => instruction.target(address),
+    }
+}
@@ -0,0 +1,198 @@
+use std::io;
+
+use super::savestate::{StateReader, StateWriter};
+
+pub const CHANNEL_COUNT: usize = 7;
+
+pub const CHANNEL_MDEC_IN: usize = 0;
+pub const CHANNEL_MDEC_OUT: usize = 1;
+pub const CHANNEL_CDROM: usize = 3;
+pub const CHANNEL_SPU: usize = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    ToRam,
+    FromRam,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SyncMode {
+    Manual,
+    Request,
+    LinkedList,
+}
+
+#[derive(Clone, Copy)]
+pub struct Channel {
+    pub base_address: u32,
+    pub block_control: u32,
+    pub channel_control: u32,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            base_address: 0,
+            block_control: 0,
+            channel_control: 0,
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.channel_control & 0x01 != 0 { Direction::FromRam } else { Direction::ToRam }
+    }
+
+    pub fn step(&self) -> u32 {
+        if self.channel_control & 0x02 != 0 { (-4i32) as u32 } else { 4 }
+    }
+
+    pub fn sync_mode(&self) -> SyncMode {
+        match (self.channel_control >> 9) & 0x03 {
+            0 => SyncMode::Manual,
+            1 => SyncMode::Request,
+            _ => SyncMode::LinkedList,
+        }
+    }
+
+    // Manual mode channels also need the trigger bit to start.
+    pub fn active(&self) -> bool {
+        let enabled = self.channel_control & 0x01000000 != 0;
+
+        match self.sync_mode() {
+            SyncMode::Manual => enabled && self.channel_control & 0x10000000 != 0,
+            _ => enabled,
+        }
+    }
+
+    // Number of words to move for the block modes.
+    pub fn transfer_size(&self) -> u32 {
+        let block_size = self.block_control & 0xFFFF;
+        let block_count = self.block_control >> 16;
+
+        match self.sync_mode() {
+            SyncMode::Manual => if block_size == 0 { 0x10000 } else { block_size },
+            _ => block_size * block_count,
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.channel_control &= !0x11000000;
+    }
+}
+
+// Registers of the DMA controller at 0x1F801080-0x1F8010FF.
+// Transfers themselves are done by CpuMemory, which owns RAM and the devices on the other end.
+pub struct Dma {
+    pub channels: [Channel; CHANNEL_COUNT],
+    pub control: u32,
+    pub interrupt: u32,
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            channels: [Channel::new(); CHANNEL_COUNT],
+            control: 0x07654321,
+            interrupt: 0,
+        }
+    }
+
+    pub fn read_register(&self, offset: u32) -> u32 {
+        let channel = (offset >> 4) as usize;
+
+        match (channel, offset & 0x0F) {
+            (7, 0x0) => self.control,
+            (7, 0x4) => self.interrupt,
+            (7, _) => 0,
+            (_, 0x0) => self.channels[channel].base_address,
+            (_, 0x4) => self.channels[channel].block_control,
+            (_, 0x8) => self.channels[channel].channel_control,
+            _ => 0,
+        }
+    }
+
+    // Returns the channel to run if the write started a transfer.
+    pub fn write_register(&mut self, offset: u32, value: u32) -> Option<usize> {
+        let channel = (offset >> 4) as usize;
+
+        match (channel, offset & 0x0F) {
+            (7, 0x0) => self.control = value,
+            (7, 0x4) => {
+                // Flags are acknowledged by writing ones to them.
+                let flags = (self.interrupt & !value) & 0x7F000000;
+                self.interrupt = (value & 0x00FF803F) | flags;
+                self.update_master_flag();
+            },
+            (7, _) => {},
+            (_, 0x0) => self.channels[channel].base_address = value & 0x00FFFFFF,
+            (_, 0x4) => self.channels[channel].block_control = value,
+            (_, 0x8) => {
+                self.channels[channel].channel_control = value;
+
+                if self.channels[channel].active() && self.channel_enabled(channel) {
+                    return Some(channel);
+                }
+            },
+            _ => {},
+        }
+
+        None
+    }
+
+    pub fn channel_enabled(&self, channel: usize) -> bool {
+        self.control & (0x08 << (channel * 4)) != 0
+    }
+
+    // Marks a channel as done. Returns true if that should raise the DMA interrupt.
+    pub fn complete(&mut self, channel: usize) -> bool {
+        self.channels[channel].finish();
+
+        let raised_before = self.interrupt & 0x80000000 != 0;
+
+        if self.interrupt & (1 << (16 + channel)) != 0 {
+            self.interrupt |= 1 << (24 + channel);
+        }
+
+        self.update_master_flag();
+        !raised_before && self.interrupt & 0x80000000 != 0
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"DMA ");
+        state.write_u32(self.control);
+        state.write_u32(self.interrupt);
+
+        for channel in self.channels.iter() {
+            state.write_u32(channel.base_address);
+            state.write_u32(channel.block_control);
+            state.write_u32(channel.channel_control);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"DMA ")?;
+        self.control = state.read_u32()?;
+        self.interrupt = state.read_u32()?;
+
+        for channel in self.channels.iter_mut() {
+            channel.base_address = state.read_u32()?;
+            channel.block_control = state.read_u32()?;
+            channel.channel_control = state.read_u32()?;
+        }
+
+        Ok(())
+    }
+
+    fn update_master_flag(&mut self) {
+        let force = self.interrupt & 0x8000 != 0;
+        let master_enable = self.interrupt & 0x00800000 != 0;
+        let flags = (self.interrupt >> 24) & (self.interrupt >> 16) & 0x7F;
+
+        if force || (master_enable && flags != 0) {
+            self.interrupt |= 0x80000000;
+        }
+        else {
+            self.interrupt &= !0x80000000;
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::memory::CpuMemory;
+
+pub const HEADER_SIZE: usize = 0x800;
+
+// A PS-X EXE file, a 2KB header followed by the code/data to copy to RAM.
+pub struct Executable {
+    pub pc: u32,
+    pub gp: u32,
+    pub load_address: u32,
+    pub bss_address: u32,
+    pub bss_size: u32,
+    pub sp: u32,
+    pub data: Vec<u8>,
+}
+
+impl Executable {
+    pub fn parse(file: &[u8]) -> io::Result<Executable> {
+        if file.len() < HEADER_SIZE || &file[0..8] != b"PS-X EXE" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "File isn't a PS-X EXE"));
+        }
+
+        let text_size = LittleEndian::read_u32(&file[0x1C..0x20]) as usize;
+        let text_end = (HEADER_SIZE + text_size).min(file.len());
+
+        // The stack is set to base + offset, and left alone if the base is zero.
+        let sp_base = LittleEndian::read_u32(&file[0x30..0x34]);
+        let sp_offset = LittleEndian::read_u32(&file[0x34..0x38]);
+
+        Ok(Executable {
+            pc: LittleEndian::read_u32(&file[0x10..0x14]),
+            gp: LittleEndian::read_u32(&file[0x14..0x18]),
+            load_address: LittleEndian::read_u32(&file[0x18..0x1C]),
+            bss_address: LittleEndian::read_u32(&file[0x28..0x2C]),
+            bss_size: LittleEndian::read_u32(&file[0x2C..0x30]),
+            sp: if sp_base != 0 { sp_base.wrapping_add(sp_offset) } else { 0 },
+            data: file[HEADER_SIZE..text_end].to_vec(),
+        })
+    }
+
+    pub fn load(&self, memory: &mut CpuMemory) {
+        for (offset, value) in self.data.iter().enumerate() {
+            memory.write_byte(self.load_address.wrapping_add(offset as u32), *value);
+        }
+
+        for offset in 0..self.bss_size {
+            memory.write_byte(self.bss_address.wrapping_add(offset), 0);
+        }
+    }
+}
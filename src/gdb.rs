@@ -0,0 +1,711 @@
+// GDB remote serial protocol stub, so guest code can be debugged with gdb-multiarch.
+// Listens on localhost and drives the CPU itself: it only runs while GDB has asked it to continue
+// or step. Breakpoints and watchpoints use the emulator's own, nothing is patched into memory.
+//
+//     gdb-multiarch -ex "set architecture mips:3000" -ex "target remote localhost:3333"
+
+use std::io;
+use std::io::{Read, Write};
+use std::collections::HashSet;
+use std::net::{TcpListener, TcpStream};
+
+use super::cpu::{Cpu, CycleResult};
+use super::watchpoints::{Access, WatchKind};
+
+pub const DEFAULT_PORT: u16 = 3333;
+
+// Instructions run between checks for a Ctrl-C from GDB.
+const INTERRUPT_POLL_INTERVAL: u32 = 4096;
+// Largest packet we tell GDB we take, which replies are kept within too.
+const PACKET_SIZE: usize = 0x4000;
+
+// r0-r31, status, lo, hi, badvaddr, cause, pc, f0-f31, fcsr and fir, as GDB numbers them for MIPS,
+// followed by the rest of COP0.
+const REGISTER_COUNT: usize = 80;
+const FIRST_EXTRA_REGISTER: usize = 72;
+const EXTRA_COP0_REGISTERS: [(&str, usize); 8] = [
+    ("bpc", 3), ("bda", 5), ("jumpdest", 6), ("dcic", 7),
+    ("bdam", 9), ("bpcm", 11), ("epc", 14), ("prid", 15),
+];
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+enum Exit {
+    Detach,
+    Kill,
+}
+
+// Waits for GDB on the port and serves one connection after another, until GDB kills the target.
+pub fn serve(cpu: &mut Cpu, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB on localhost:{}", port);
+
+    loop {
+        let (stream, address) = listener.accept()?;
+        println!("GDB connected from {}", address);
+
+        let mut session = Session::new(stream)?;
+        match session.run(cpu) {
+            Ok(Exit::Detach) => println!("GDB detached"),
+            Ok(Exit::Kill) => {
+                println!("GDB killed the target");
+                return Ok(());
+            },
+            Err(error) => println!("Lost the GDB connection: {}", error),
+        }
+    }
+}
+
+enum Packet {
+    Command(String),
+    // Ctrl-C, sent outside of a packet.
+    Interrupt,
+}
+
+struct Session {
+    stream: TcpStream,
+    received: Vec<u8>,
+    // After QStartNoAckMode, packets aren't acknowledged with + anymore.
+    no_ack: bool,
+    // Breakpoints GDB added, removed again when it's done with them or goes away.
+    // Breakpoints the user already had are left alone, condition and all.
+    breakpoints: HashSet<u32>,
+}
+
+impl Session {
+    fn new(stream: TcpStream) -> io::Result<Session> {
+        stream.set_nodelay(true)?;
+
+        Ok(Session {
+            stream,
+            received: Vec::new(),
+            no_ack: false,
+            breakpoints: HashSet::new(),
+        })
+    }
+
+    fn run(&mut self, cpu: &mut Cpu) -> io::Result<Exit> {
+        let exit = self.serve_commands(cpu);
+
+        for address in self.breakpoints.drain() {
+            cpu.breakpoints.remove(address);
+        }
+
+        exit
+    }
+
+    fn serve_commands(&mut self, cpu: &mut Cpu) -> io::Result<Exit> {
+        loop {
+            let command = match self.read_packet()? {
+                Packet::Command(command) => command,
+                // Ctrl-C while stopped, just report where the CPU is.
+                Packet::Interrupt => {
+                    self.send(&stop_reply(SIGINT))?;
+                    continue;
+                },
+            };
+
+            match command.as_bytes().first() {
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(Exit::Detach);
+                },
+                Some(b'k') => return Ok(Exit::Kill),
+                Some(b'c') => {
+                    let reply = self.resume(cpu)?;
+                    self.send(&reply)?;
+                },
+                Some(b's') => {
+                    let result = step(cpu);
+                    let reply = stop_reason(cpu, result);
+                    self.send(&reply)?;
+                },
+                _ => {
+                    let reply = self.handle(cpu, &command);
+                    self.send(&reply)?;
+                },
+            }
+        }
+    }
+
+    // Everything but the commands that run the CPU or end the session.
+    fn handle(&mut self, cpu: &mut Cpu, command: &str) -> String {
+        if command == "QStartNoAckMode" {
+            self.no_ack = true;
+            return String::from("OK");
+        }
+
+        handle_command(cpu, &mut self.breakpoints, command).unwrap_or_else(|| String::from("E01"))
+    }
+
+    // Runs until something stops the CPU or GDB interrupts it.
+    fn resume(&mut self, cpu: &mut Cpu) -> io::Result<String> {
+        loop {
+            for _ in 0..INTERRUPT_POLL_INTERVAL {
+                let result = cpu.run_instruction();
+                if result != CycleResult::Success {
+                    return Ok(stop_reason(cpu, result));
+                }
+            }
+
+            // Nothing plays the audio.
+            cpu.memory.spu.output.clear();
+
+            if self.poll_interrupt()? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 256];
+
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "GDB closed the connection")),
+            Ok(length) => {
+                self.received.extend_from_slice(&buffer[..length]);
+                match self.received.iter().position(|byte| *byte == 0x03) {
+                    Some(position) => {
+                        self.received.remove(position);
+                        Ok(true)
+                    },
+                    None => Ok(false),
+                }
+            },
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn read_packet(&mut self) -> io::Result<Packet> {
+        loop {
+            // Drop acknowledgements and anything else before the start of a packet.
+            while let Some(&byte) = self.received.first() {
+                match byte {
+                    b'$' => break,
+                    0x03 => {
+                        self.received.remove(0);
+                        return Ok(Packet::Interrupt);
+                    },
+                    _ => { self.received.remove(0); },
+                }
+            }
+
+            if let Some(end) = self.received.iter().position(|byte| *byte == b'#') {
+                if self.received.len() >= end + 3 {
+                    let packet: Vec<u8> = self.received.drain(..end + 3).collect();
+                    let data = unescape(&packet[1..end]);
+                    let checksum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+
+                    if self.no_ack {
+                        return Ok(Packet::Command(String::from_utf8_lossy(&data).into_owned()));
+                    }
+
+                    if checksum == Some(packet[1..end].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))) {
+                        self.stream.write_all(b"+")?;
+                        return Ok(Packet::Command(String::from_utf8_lossy(&data).into_owned()));
+                    }
+
+                    self.stream.write_all(b"-")?;
+                    continue;
+                }
+            }
+
+            let mut buffer = [0; 4096];
+            let length = self.stream.read(&mut buffer)?;
+            if length == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "GDB closed the connection"));
+            }
+            self.received.extend_from_slice(&buffer[..length]);
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        for byte in data.bytes() {
+            // These would end or corrupt the packet.
+            if byte == b'$' || byte == b'#' || byte == b'}' || byte == b'*' {
+                packet.push(b'}');
+                packet.push(byte ^ 0x20);
+            }
+            else {
+                packet.push(byte);
+            }
+        }
+
+        let checksum = packet[1..].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        self.stream.write_all(&packet)
+    }
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte == b'}' {
+            if let Some(&escaped) = bytes.next() {
+                result.push(escaped ^ 0x20);
+            }
+        }
+        else {
+            result.push(byte);
+        }
+    }
+
+    result
+}
+
+// Runs one instruction. Stopping on a breakpoint doesn't run the instruction under it, so a
+// step that lands there goes on to run it.
+fn step(cpu: &mut Cpu) -> CycleResult {
+    match cpu.run_instruction() {
+        CycleResult::Breakpoint => cpu.run_instruction(),
+        result => result,
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn stop_reason(cpu: &Cpu, result: CycleResult) -> String {
+    match result {
+        CycleResult::Error => stop_reply(SIGILL),
+        CycleResult::Watchpoint => match &cpu.watch_hit {
+            Some(hit) => {
+                let kind = if hit.access == Access::Read { "rwatch" } else { "watch" };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.address)
+            },
+            None => stop_reply(SIGTRAP),
+        },
+        _ => stop_reply(SIGTRAP),
+    }
+}
+
+// Replies to a command, or None for an error. Unsupported commands get an empty reply.
+// `breakpoints` holds the addresses of the breakpoints GDB added itself.
+fn handle_command(cpu: &mut Cpu, breakpoints: &mut HashSet<u32>, command: &str) -> Option<String> {
+    if command.is_empty() {
+        return Some(String::new());
+    }
+    // Packets are decoded lossily, so the first character may take more than one byte.
+    let (kind, arguments) = command.split_at(command.chars().next().map_or(0, char::len_utf8));
+
+    match kind {
+        "?" => Some(stop_reply(SIGTRAP)),
+        "g" => Some((0..REGISTER_COUNT).map(|register| encode_u32(read_register(cpu, register))).collect()),
+        "G" => {
+            for register in 0..REGISTER_COUNT {
+                if let Some(chunk) = arguments.get(register * 8..register * 8 + 8) {
+                    write_register(cpu, register, decode_u32(chunk)?);
+                }
+            }
+            Some(String::from("OK"))
+        },
+        "p" => {
+            let register = usize::from_str_radix(arguments, 16).ok()?;
+            if register < REGISTER_COUNT { Some(encode_u32(read_register(cpu, register))) } else { None }
+        },
+        "P" => {
+            let (register, value) = split_pair(arguments, '=')?;
+            let register = usize::from_str_radix(register, 16).ok()?;
+            if register >= REGISTER_COUNT {
+                return None;
+            }
+            write_register(cpu, register, decode_u32(value)?);
+            Some(String::from("OK"))
+        },
+        "m" => {
+            let (address, length) = parse_range(arguments)?;
+            // Two hex digits a byte, and the reply has to fit in the packet size we advertise.
+            if length as usize * 2 > PACKET_SIZE {
+                return None;
+            }
+            let mut reply = String::with_capacity(length as usize * 2);
+            for offset in 0..length {
+                reply.push_str(&format!("{:02x}", cpu.memory.peek_byte(address.wrapping_add(offset))?));
+            }
+            Some(reply)
+        },
+        "M" => {
+            let (range, data) = split_pair(arguments, ':')?;
+            let (address, length) = parse_range(range)?;
+            if data.len() != length as usize * 2 {
+                return None;
+            }
+            for offset in 0..length {
+                let byte = u8::from_str_radix(data.get(offset as usize * 2..offset as usize * 2 + 2)?, 16).ok()?;
+                if !cpu.memory.poke_byte(address.wrapping_add(offset), byte) {
+                    return None;
+                }
+            }
+            Some(String::from("OK"))
+        },
+        "Z" | "z" => {
+            let mut fields = arguments.split(',');
+            let point_type = fields.next()?;
+            let address = u32::from_str_radix(fields.next()?, 16).ok()?;
+            let length = u32::from_str_radix(fields.next()?, 16).ok()?.max(1);
+
+            let insert = kind == "Z";
+            let watch_kind = match point_type {
+                "0" | "1" => {
+                    if insert {
+                        if cpu.breakpoints.get(address).is_none() {
+                            cpu.breakpoints.add(address, None);
+                            breakpoints.insert(address);
+                        }
+                    }
+                    else if breakpoints.remove(&address) {
+                        cpu.breakpoints.remove(address);
+                    }
+                    return Some(String::from("OK"));
+                },
+                "2" => WatchKind::Write,
+                "3" => WatchKind::Read,
+                "4" => WatchKind::Access,
+                _ => return Some(String::new()),
+            };
+
+            let watchpoints = &mut cpu.memory.watchpoints;
+            let end = address.wrapping_add(length - 1);
+            if insert {
+                watchpoints.add(address, end, watch_kind);
+            }
+            else {
+                let (start, end) = (address & 0x1FFFFFFF, end & 0x1FFFFFFF);
+                let index = watchpoints.list().iter().position(|watchpoint| watchpoint.start == start && watchpoint.end == end && watchpoint.kind == watch_kind);
+                if let Some(index) = index {
+                    watchpoints.remove(index);
+                }
+            }
+            Some(String::from("OK"))
+        },
+        "H" => Some(String::from("OK")),
+        "q" => Some(handle_query(arguments)),
+        // Binary writes, vCont and the rest: replying with nothing makes GDB fall back to the basics.
+        _ => Some(String::new()),
+    }
+}
+
+fn handle_query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+", PACKET_SIZE);
+    }
+
+    if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        let description = target_description();
+        return match parse_range(range) {
+            Some((offset, length)) => {
+                let start = (offset as usize).min(description.len());
+                let end = (start + length as usize).min(description.len());
+                let marker = if end == description.len() { 'l' } else { 'm' };
+                format!("{}{}", marker, &description[start..end])
+            },
+            None => String::from("E01"),
+        };
+    }
+
+    match query {
+        "Attached" => String::from("1"),
+        "C" => String::from("QC1"),
+        "fThreadInfo" => String::from("m1"),
+        "sThreadInfo" => String::from("l"),
+        _ => String::new(),
+    }
+}
+
+fn read_register(cpu: &Cpu, register: usize) -> u32 {
+    match register {
+        0..=31 => cpu.registers[register],
+        32 => cpu.cop0_registers[12],
+        33 => cpu.lo,
+        34 => cpu.hi,
+        35 => cpu.cop0_registers[8],
+        36 => cpu.cop0_registers[13],
+        37 => cpu.pc,
+        FIRST_EXTRA_REGISTER..=79 => cpu.cop0_registers[EXTRA_COP0_REGISTERS[register - FIRST_EXTRA_REGISTER].1],
+        // There's no FPU.
+        _ => 0,
+    }
+}
+
+fn write_register(cpu: &mut Cpu, register: usize, value: u32) {
+    match register {
+        // r0 stays 0.
+        1..=31 => cpu.registers[register] = value,
+        32 => cpu.cop0_registers[12] = value,
+        33 => cpu.lo = value,
+        34 => cpu.hi = value,
+        35 => cpu.cop0_registers[8] = value,
+        36 => cpu.cop0_registers[13] = value,
+        37 => {
+            cpu.pc = value;
+            cpu.branch_delay = false;
+        },
+        FIRST_EXTRA_REGISTER..=79 => cpu.cop0_registers[EXTRA_COP0_REGISTERS[register - FIRST_EXTRA_REGISTER].1] = value,
+        _ => {},
+    }
+}
+
+// Registers go over the wire in target byte order.
+fn encode_u32(value: u32) -> String {
+    format!("{:08x}", value.swap_bytes())
+}
+
+fn decode_u32(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok().map(|value| value.swap_bytes())
+}
+
+fn split_pair(text: &str, separator: char) -> Option<(&str, &str)> {
+    let position = text.find(separator)?;
+    Some((&text[..position], &text[position + 1..]))
+}
+
+// "address,length" in hex.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = split_pair(text, ',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?))
+}
+
+// 32 bit MIPS with the register numbering GDB uses without a description, and the rest of COP0 after it.
+fn target_description() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\"><architecture>mips:3000</architecture>");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.mips.cpu\">");
+    for register in 0..32 {
+        xml.push_str(&format!("<reg name=\"r{}\" bitsize=\"32\" regnum=\"{}\"/>", register, register));
+    }
+    xml.push_str("<reg name=\"lo\" bitsize=\"32\" regnum=\"33\"/><reg name=\"hi\" bitsize=\"32\" regnum=\"34\"/><reg name=\"pc\" bitsize=\"32\" regnum=\"37\"/></feature>");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.mips.cp0\"><reg name=\"status\" bitsize=\"32\" regnum=\"32\"/><reg name=\"badvaddr\" bitsize=\"32\" regnum=\"35\"/><reg name=\"cause\" bitsize=\"32\" regnum=\"36\"/>");
+    for (index, (name, _)) in EXTRA_COP0_REGISTERS.iter().enumerate() {
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" group=\"system\"/>", name, FIRST_EXTRA_REGISTER + index));
+    }
+    xml.push_str("</feature>");
+
+    // GDB won't take a MIPS description without an FPU.
+    xml.push_str("<feature name=\"org.gnu.gdb.mips.fpu\">");
+    for register in 0..32 {
+        xml.push_str(&format!("<reg name=\"f{}\" bitsize=\"32\" type=\"ieee_single\" regnum=\"{}\"/>", register, 38 + register));
+    }
+    xml.push_str("<reg name=\"fcsr\" bitsize=\"32\" group=\"float\" regnum=\"70\"/><reg name=\"fir\" bitsize=\"32\" group=\"float\" regnum=\"71\"/></feature>");
+
+    xml.push_str("</target>");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::net::SocketAddr;
+
+    use super::*;
+    use super::super::cpu::breakpoints::Condition;
+    use super::super::cpu::tests::test_cpu;
+
+    // The GDB end of the connection.
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Client {
+            Client { stream: TcpStream::connect(address).unwrap() }
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send_raw(&mut self, data: &[u8]) {
+            self.stream.write_all(data).unwrap();
+        }
+
+        // Sends a packet and expects it to be acknowledged.
+        fn send(&mut self, data: &str) {
+            self.send_raw(format!("${}#{:02x}", data, checksum(data.as_bytes())).as_bytes());
+            assert_eq!(self.read_byte(), b'+', "no acknowledgement for {}", data);
+        }
+
+        fn receive(&mut self) -> String {
+            assert_eq!(self.read_byte(), b'$');
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+
+            let sum = [self.read_byte(), self.read_byte()];
+            assert_eq!(u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap(), checksum(&data));
+            self.send_raw(b"+");
+
+            String::from_utf8(unescape(&data)).unwrap()
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.receive()
+        }
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+    }
+
+    // Runs a session on the CPU while `script` plays GDB on the other end.
+    fn run_session<F: FnOnce(Client) + Send + 'static>(cpu: &mut Cpu, script: F) -> Exit {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || script(Client::connect(address)));
+        let (stream, _) = listener.accept().unwrap();
+        let exit = Session::new(stream).unwrap().run(cpu);
+
+        client.join().unwrap();
+        exit.unwrap()
+    }
+
+    // A CPU spinning in RAM, with nothing to stop it but GDB.
+    fn spinning_cpu() -> Cpu {
+        let mut cpu = test_cpu();
+        for offset in (0..64).step_by(4) {
+            cpu.memory.ram[offset..offset + 4].copy_from_slice(&0x08000000u32.to_le_bytes());
+        }
+        cpu.pc = 0x80000000;
+        cpu
+    }
+
+    #[test]
+    fn packets_are_checked_and_acknowledged() {
+        let mut cpu = spinning_cpu();
+
+        let exit = run_session(&mut cpu, |mut client| {
+            client.send_raw(b"$?#00");
+            assert_eq!(client.read_byte(), b'-');
+
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(client.request("qAttached"), "1");
+            assert_eq!(client.request("vMustReplyEmpty"), "");
+
+            client.send_raw(b"+$D#44");
+            assert_eq!(client.read_byte(), b'+');
+            assert_eq!(client.receive(), "OK");
+        });
+
+        assert!(matches!(exit, Exit::Detach));
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut cpu = spinning_cpu();
+        cpu.registers[4] = 0x11223344;
+        cpu.hi = 0xAABBCCDD;
+
+        run_session(&mut cpu, |mut client| {
+            let registers = client.request("g");
+            assert_eq!(registers.len(), REGISTER_COUNT * 8);
+            assert_eq!(&registers[4 * 8..5 * 8], "44332211");
+            assert_eq!(&registers[34 * 8..35 * 8], "ddccbbaa");
+            assert_eq!(&registers[37 * 8..38 * 8], "00000080");
+
+            assert_eq!(client.request("p4"), "44332211");
+            assert_eq!(client.request("p25"), "00000080");
+            assert_eq!(client.request("P5=78563412"), "OK");
+            assert_eq!(client.request("p5"), "78563412");
+            assert_eq!(client.request("p50"), "E01");
+
+            assert_eq!(client.request("M80001000,4:deadbeef"), "OK");
+            assert_eq!(client.request("m80001000,4"), "deadbeef");
+            assert_eq!(client.request("M80001000,4:dead"), "E01");
+
+            client.send("k");
+        });
+
+        assert_eq!(cpu.registers[5], 0x12345678);
+        assert_eq!(&cpu.memory.ram[0x1000..0x1004], &[0xDE, 0xAD, 0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn odd_packets_are_answered() {
+        let mut cpu = test_cpu();
+        let mut breakpoints = HashSet::new();
+
+        // What a non-ASCII first byte turns into.
+        assert_eq!(handle_command(&mut cpu, &mut breakpoints, "\u{FFFD}1234").as_deref(), Some(""));
+        assert_eq!(handle_command(&mut cpu, &mut breakpoints, "\u{FFFD}").as_deref(), Some(""));
+
+        assert_eq!(handle_command(&mut cpu, &mut breakpoints, "m80000000,2000").map(|reply| reply.len()), Some(PACKET_SIZE));
+        assert!(handle_command(&mut cpu, &mut breakpoints, "m80000000,2001").is_none());
+        assert!(handle_command(&mut cpu, &mut breakpoints, "m80000000,ffffffff").is_none());
+    }
+
+    #[test]
+    fn breakpoints_leave_the_users_alone() {
+        let mut cpu = spinning_cpu();
+        cpu.breakpoints.add(0x80000010, Some(Condition::parse("a0 == 1").unwrap()));
+
+        run_session(&mut cpu, |mut client| {
+            assert_eq!(client.request("Z0,80000010,4"), "OK");
+            assert_eq!(client.request("Z0,80000020,4"), "OK");
+            assert_eq!(client.request("Z1,80000030,4"), "OK");
+            assert_eq!(client.request("z0,80000010,4"), "OK");
+            assert_eq!(client.request("z0,80000020,4"), "OK");
+
+            // The one left behind goes when GDB does.
+            client.send("k");
+        });
+
+        let breakpoint = cpu.breakpoints.get(0x80000010).unwrap();
+        assert_eq!(breakpoint.condition.as_ref().map(|condition| condition.text.as_str()), Some("a0 == 1"));
+        assert!(cpu.breakpoints.get(0x80000020).is_none());
+        assert!(cpu.breakpoints.get(0x80000030).is_none());
+    }
+
+    #[test]
+    fn breakpoints_stop_continue() {
+        let mut cpu = spinning_cpu();
+
+        run_session(&mut cpu, |mut client| {
+            assert_eq!(client.request("Z0,80000000,4"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p25"), "00000080");
+            assert_eq!(client.request("z0,80000000,4"), "OK");
+            client.send("k");
+        });
+
+        assert!(cpu.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn step_and_interrupt() {
+        let mut cpu = spinning_cpu();
+
+        run_session(&mut cpu, |mut client| {
+            assert_eq!(client.request("s"), "S05");
+            assert_ne!(client.request("p25"), "00000080");
+
+            client.send("c");
+            client.send_raw(&[0x03]);
+            assert_eq!(client.receive(), "S02");
+
+            // Still answering afterwards.
+            assert_eq!(client.request("?"), "S05");
+            client.send("k");
+        });
+    }
+}
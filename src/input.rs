@@ -0,0 +1,180 @@
+use std::io;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Button {
+    Select = 0,
+    L3 = 1,
+    R3 = 2,
+    Start = 3,
+    Up = 4,
+    Right = 5,
+    Down = 6,
+    Left = 7,
+    L2 = 8,
+    R2 = 9,
+    L1 = 10,
+    R1 = 11,
+    Triangle = 12,
+    Circle = 13,
+    Cross = 14,
+    Square = 15,
+}
+
+pub const BUTTONS: [Button; 16] = [
+    Button::Select, Button::L3, Button::R3, Button::Start, Button::Up, Button::Right, Button::Down, Button::Left,
+    Button::L2, Button::R2, Button::L1, Button::R1, Button::Triangle, Button::Circle, Button::Cross, Button::Square,
+];
+
+impl Button {
+    pub fn name(self) -> &'static str {
+        match self {
+            Button::Select => "Select",
+            Button::L3 => "L3",
+            Button::R3 => "R3",
+            Button::Start => "Start",
+            Button::Up => "Up",
+            Button::Right => "Right",
+            Button::Down => "Down",
+            Button::Left => "Left",
+            Button::L2 => "L2",
+            Button::R2 => "R2",
+            Button::L1 => "L1",
+            Button::R1 => "R1",
+            Button::Triangle => "Triangle",
+            Button::Circle => "Circle",
+            Button::Cross => "Cross",
+            Button::Square => "Square",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button> {
+        BUTTONS.iter().cloned().find(|button| button.name().eq_ignore_ascii_case(name))
+    }
+}
+
+// State of one emulated pad, independent of where the input comes from.
+#[derive(Clone, Copy)]
+pub struct PadState {
+    // Pressed buttons, one bit per `Button`. The pad inverts these on the wire.
+    pub buttons: u16,
+    // Stick positions, 0x80 is centered.
+    pub left_stick: (u8, u8),
+    pub right_stick: (u8, u8),
+    // The DualShock's ANALOG button, which toggles between digital and analog modes.
+    pub analog_button: bool,
+}
+
+impl PadState {
+    pub fn new() -> PadState {
+        PadState {
+            buttons: 0,
+            left_stick: (0x80, 0x80),
+            right_stick: (0x80, 0x80),
+            analog_button: false,
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.buttons |= 1 << button as u16;
+        }
+        else {
+            self.buttons &= !(1 << button as u16);
+        }
+    }
+}
+
+// Input for every pad that can be connected, indexed by [port][multitap slot].
+// Without a multitap only slot 0 of each port is used.
+#[derive(Clone, Copy)]
+pub struct InputState {
+    pub pads: [[PadState; 4]; 2],
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        InputState {
+            pads: [[PadState::new(); 4]; 2],
+        }
+    }
+}
+
+struct ScriptEvent {
+    frame: u32,
+    port: usize,
+    slot: usize,
+    button: Button,
+    pressed: bool,
+}
+
+// Scripted input for headless runs. Each line is `<frame> <port> <button> <press|release>`,
+// with the port optionally followed by a multitap slot as `<port>.<slot>`.
+pub struct InputScript {
+    events: Vec<ScriptEvent>,
+    next_event: usize,
+}
+
+impl InputScript {
+    pub fn load(path: &Path) -> io::Result<InputScript> {
+        let contents = fs::read_to_string(path)?;
+        let mut events = Vec::new();
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid input script line {}: {}", line_number + 1, line));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            if fields.len() != 4 {
+                return Err(invalid());
+            }
+
+            let frame = fields[0].parse().map_err(|_| invalid())?;
+            let mut port_fields = fields[1].splitn(2, '.');
+            let port: usize = port_fields.next().unwrap_or("").parse().map_err(|_| invalid())?;
+            let slot: usize = port_fields.next().unwrap_or("0").parse().map_err(|_| invalid())?;
+            let button = Button::from_name(fields[2]).ok_or_else(invalid)?;
+            let pressed = match fields[3] {
+                "press" => true,
+                "release" => false,
+                _ => return Err(invalid()),
+            };
+
+            if !(1..=2).contains(&port) || slot > 3 {
+                return Err(invalid());
+            }
+
+            events.push(ScriptEvent {
+                frame,
+                port: port - 1,
+                slot,
+                button,
+                pressed,
+            });
+        }
+
+        events.sort_by_key(|event| event.frame);
+
+        Ok(InputScript {
+            events,
+            next_event: 0,
+        })
+    }
+
+    // Applies every event scheduled up to and including `frame`.
+    pub fn apply(&mut self, frame: u32, state: &mut InputState) {
+        while let Some(event) = self.events.get(self.next_event) {
+            if event.frame > frame {
+                break;
+            }
+
+            state.pads[event.port][event.slot].set_button(event.button, event.pressed);
+            self.next_event += 1;
+        }
+    }
+}
@@ -0,0 +1,644 @@
+use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{self, Axis, GameController};
+
+use imgui::*;
+
+use super::input::{self, Button, InputState, PadState};
+
+// How far an axis has to move to press a button bound to it, or to be picked when rebinding.
+const AXIS_THRESHOLD: i16 = 16384;
+// Rumble is refreshed every frame, this only bounds how long it lingers if we stop.
+const RUMBLE_DURATION_MS: u32 = 200;
+
+// Something a key or controller button can be bound to.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Target {
+    Button(Button),
+    AnalogToggle,
+    // Stick (0 left, 1 right), axis (0 X, 1 Y) and whether it pushes towards the maximum.
+    Stick(usize, usize, bool),
+}
+
+fn targets() -> Vec<Target> {
+    let mut targets: Vec<Target> = input::BUTTONS.iter().map(|button| Target::Button(*button)).collect();
+    targets.push(Target::AnalogToggle);
+
+    for stick in 0..2 {
+        targets.push(Target::Stick(stick, 1, false));
+        targets.push(Target::Stick(stick, 1, true));
+        targets.push(Target::Stick(stick, 0, false));
+        targets.push(Target::Stick(stick, 0, true));
+    }
+
+    targets
+}
+
+impl Target {
+    pub fn name(self) -> String {
+        match self {
+            Target::Button(button) => button.name().to_string(),
+            Target::AnalogToggle => String::from("Analog"),
+            Target::Stick(stick, axis, positive) => {
+                let stick = if stick == 0 { "Left" } else { "Right" };
+                let direction = match (axis, positive) {
+                    (0, false) => "Left",
+                    (0, true) => "Right",
+                    (_, false) => "Up",
+                    (_, true) => "Down",
+                };
+                format!("{}Stick{}", stick, direction)
+            },
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Target> {
+        targets().into_iter().find(|target| target.name().eq_ignore_ascii_case(name))
+    }
+
+    fn apply(self, pad: &mut PadState, pressed: bool) {
+        match self {
+            Target::Button(button) => pad.set_button(button, pressed),
+            Target::AnalogToggle => pad.analog_button = pressed,
+            Target::Stick(stick, axis, positive) => {
+                let value = match (pressed, positive) {
+                    (false, _) => 0x80,
+                    (true, false) => 0x00,
+                    (true, true) => 0xFF,
+                };
+                set_axis(pad, stick, axis, value);
+            },
+        }
+    }
+}
+
+// Whether two targets are driven by the same thing, both directions of a stick axis counting as one.
+fn same_target(first: Target, second: Target) -> bool {
+    match (first, second) {
+        (Target::Stick(stick, axis, _), Target::Stick(other_stick, other_axis, _)) => stick == other_stick && axis == other_axis,
+        _ => first == second,
+    }
+}
+
+fn set_axis(pad: &mut PadState, stick: usize, axis: usize, value: u8) {
+    let position = if stick == 0 { &mut pad.left_stick } else { &mut pad.right_stick };
+
+    if axis == 0 {
+        position.0 = value;
+    }
+    else {
+        position.1 = value;
+    }
+}
+
+// Which inputs drive one emulated pad.
+#[derive(Clone, Copy)]
+pub struct Assignment {
+    pub keyboard: bool,
+    // Index into the connected controllers, in the order they were plugged in.
+    pub controller: Option<usize>,
+}
+
+pub struct InputConfig {
+    // Indexed by [port][multitap slot], like `InputState`.
+    pub assignments: [[Assignment; 4]; 2],
+    pub keys: Vec<(Target, Keycode)>,
+    pub buttons: Vec<(Target, controller::Button)>,
+    // A controller axis bound to a stick moves it in both directions, the target being where the
+    // axis's positive end pushes it. Bound to anything else, it presses it past `AXIS_THRESHOLD`.
+    pub axes: Vec<(Target, Axis)>,
+}
+
+impl InputConfig {
+    pub fn new() -> InputConfig {
+        let unassigned = Assignment { keyboard: false, controller: None };
+        let mut assignments = [[unassigned; 4]; 2];
+        assignments[0][0] = Assignment { keyboard: true, controller: Some(0) };
+        assignments[1][0] = Assignment { keyboard: false, controller: Some(1) };
+
+        let keys = vec![
+            (Target::Button(Button::Up), Keycode::Up),
+            (Target::Button(Button::Down), Keycode::Down),
+            (Target::Button(Button::Left), Keycode::Left),
+            (Target::Button(Button::Right), Keycode::Right),
+            (Target::Button(Button::Cross), Keycode::X),
+            (Target::Button(Button::Circle), Keycode::C),
+            (Target::Button(Button::Square), Keycode::Z),
+            (Target::Button(Button::Triangle), Keycode::S),
+            (Target::Button(Button::L1), Keycode::Q),
+            (Target::Button(Button::R1), Keycode::E),
+            (Target::Button(Button::L2), Keycode::Num1),
+            (Target::Button(Button::R2), Keycode::Num3),
+            (Target::Button(Button::L3), Keycode::F),
+            (Target::Button(Button::R3), Keycode::G),
+            (Target::Button(Button::Start), Keycode::Return),
+            (Target::Button(Button::Select), Keycode::Backspace),
+            (Target::AnalogToggle, Keycode::A),
+            (Target::Stick(0, 1, false), Keycode::I),
+            (Target::Stick(0, 1, true), Keycode::K),
+            (Target::Stick(0, 0, false), Keycode::J),
+            (Target::Stick(0, 0, true), Keycode::L),
+        ];
+
+        let buttons = vec![
+            (Target::Button(Button::Up), controller::Button::DPadUp),
+            (Target::Button(Button::Down), controller::Button::DPadDown),
+            (Target::Button(Button::Left), controller::Button::DPadLeft),
+            (Target::Button(Button::Right), controller::Button::DPadRight),
+            (Target::Button(Button::Cross), controller::Button::A),
+            (Target::Button(Button::Circle), controller::Button::B),
+            (Target::Button(Button::Square), controller::Button::X),
+            (Target::Button(Button::Triangle), controller::Button::Y),
+            (Target::Button(Button::L1), controller::Button::LeftShoulder),
+            (Target::Button(Button::R1), controller::Button::RightShoulder),
+            (Target::Button(Button::L3), controller::Button::LeftStick),
+            (Target::Button(Button::R3), controller::Button::RightStick),
+            (Target::Button(Button::Start), controller::Button::Start),
+            (Target::Button(Button::Select), controller::Button::Back),
+            (Target::AnalogToggle, controller::Button::Guide),
+        ];
+
+        let axes = vec![
+            (Target::Stick(0, 0, true), Axis::LeftX),
+            (Target::Stick(0, 1, true), Axis::LeftY),
+            (Target::Stick(1, 0, true), Axis::RightX),
+            (Target::Stick(1, 1, true), Axis::RightY),
+            (Target::Button(Button::L2), Axis::TriggerLeft),
+            (Target::Button(Button::R2), Axis::TriggerRight),
+        ];
+
+        InputConfig {
+            assignments,
+            keys,
+            buttons,
+            axes,
+        }
+    }
+
+    // Reads a config file of `name = value` lines, on top of the defaults:
+    //   port1 = keyboard controller0    (or port1.2 for a multitap slot)
+    //   key.Cross = X                   (SDL key names)
+    //   button.Cross = a                (SDL game controller button names)
+    //   axis.LeftStickDown = lefty      (SDL game controller axis names)
+    pub fn load(path: &Path) -> io::Result<InputConfig> {
+        let contents = fs::read_to_string(path)?;
+        let mut config = InputConfig::new();
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid input config line {}: {}", line_number + 1, line));
+            let mut fields = line.splitn(2, '=');
+            let name = fields.next().unwrap_or("").trim();
+            let value = fields.next().ok_or_else(invalid)?.trim();
+
+            if let Some(port_name) = name.strip_prefix("port") {
+                let mut port_fields = port_name.splitn(2, '.');
+                let port: usize = port_fields.next().unwrap_or("").parse().map_err(|_| invalid())?;
+                let slot: usize = port_fields.next().unwrap_or("0").parse().map_err(|_| invalid())?;
+
+                if !(1..=2).contains(&port) || slot > 3 {
+                    return Err(invalid());
+                }
+
+                let mut assignment = Assignment { keyboard: false, controller: None };
+                for source in value.split_whitespace() {
+                    if source == "keyboard" {
+                        assignment.keyboard = true;
+                    }
+                    else if let Some(index) = source.strip_prefix("controller") {
+                        assignment.controller = Some(index.parse().map_err(|_| invalid())?);
+                    }
+                    else if source != "none" {
+                        return Err(invalid());
+                    }
+                }
+
+                config.assignments[port - 1][slot] = assignment;
+            }
+            else if let Some(target_name) = name.strip_prefix("key.") {
+                let target = Target::from_name(target_name).ok_or_else(invalid)?;
+                let key = Keycode::from_name(value).ok_or_else(invalid)?;
+                config.bind_key(target, key);
+            }
+            else if let Some(target_name) = name.strip_prefix("button.") {
+                let target = Target::from_name(target_name).ok_or_else(invalid)?;
+                let button = controller::Button::from_string(value).ok_or_else(invalid)?;
+                config.bind_button(target, button);
+            }
+            else if let Some(target_name) = name.strip_prefix("axis.") {
+                let target = Target::from_name(target_name).ok_or_else(invalid)?;
+                let axis = Axis::from_string(value).ok_or_else(invalid)?;
+                config.bind_axis(target, axis);
+            }
+            else {
+                return Err(invalid());
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut contents = String::new();
+
+        for (port, slots) in self.assignments.iter().enumerate() {
+            for (slot, assignment) in slots.iter().enumerate() {
+                let mut sources = Vec::new();
+                if assignment.keyboard {
+                    sources.push(String::from("keyboard"));
+                }
+                if let Some(controller) = assignment.controller {
+                    sources.push(format!("controller{}", controller));
+                }
+                if sources.is_empty() {
+                    sources.push(String::from("none"));
+                }
+
+                let name = if slot == 0 { format!("port{}", port + 1) } else { format!("port{}.{}", port + 1, slot) };
+                contents.push_str(&format!("{} = {}\n", name, sources.join(" ")));
+            }
+        }
+
+        for (target, key) in self.keys.iter() {
+            contents.push_str(&format!("key.{} = {}\n", target.name(), key.name()));
+        }
+        for (target, button) in self.buttons.iter() {
+            contents.push_str(&format!("button.{} = {}\n", target.name(), button.string()));
+        }
+        for (target, axis) in self.axes.iter() {
+            contents.push_str(&format!("axis.{} = {}\n", target.name(), axis.string()));
+        }
+
+        fs::write(path, contents)
+    }
+
+    // Each target has at most one key and one controller button or axis.
+    pub fn bind_key(&mut self, target: Target, key: Keycode) {
+        self.keys.retain(|(other, bound)| *other != target && *bound != key);
+        self.keys.push((target, key));
+    }
+
+    pub fn bind_button(&mut self, target: Target, button: controller::Button) {
+        self.buttons.retain(|(other, bound)| *other != target && *bound != button);
+        self.axes.retain(|(other, _)| !same_target(*other, target));
+        self.buttons.push((target, button));
+    }
+
+    pub fn bind_axis(&mut self, target: Target, axis: Axis) {
+        self.axes.retain(|(other, bound)| !same_target(*other, target) && *bound != axis);
+        self.buttons.retain(|(other, _)| !same_target(*other, target));
+        self.axes.push((target, axis));
+    }
+
+    // The controller button or axis bound to a target, by its SDL name.
+    fn controller_input(&self, target: Target) -> String {
+        if let Some((_, button)) = self.buttons.iter().find(|(bound, _)| *bound == target) {
+            return button.string();
+        }
+
+        // Stick directions show which end of the axis drives them.
+        match (target, self.axes.iter().find(|(bound, _)| same_target(*bound, target))) {
+            (Target::Stick(_, _, direction), Some((Target::Stick(_, _, positive), axis))) => {
+                format!("{}{}", if direction == *positive { "+" } else { "-" }, axis.string())
+            },
+            (_, Some((_, axis))) => axis.string(),
+            (_, None) => String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Rebinding {
+    Key(Target),
+    Button(Target),
+}
+
+struct Controller {
+    device: GameController,
+    state: PadState,
+    rumble: (u8, u8),
+}
+
+// Turns SDL keyboard and game controller events into the state of the emulated pads.
+pub struct InputMapper {
+    pub config: InputConfig,
+    config_path: PathBuf,
+    subsystem: Option<GameControllerSubsystem>,
+
+    keyboard: PadState,
+    // Connected controllers. Unplugged ones leave a hole so the others keep their index.
+    controllers: Vec<Option<Controller>>,
+
+    rebinding: Option<Rebinding>,
+    status: String,
+}
+
+impl InputMapper {
+    pub fn new(config_path: &Path, subsystem: Option<GameControllerSubsystem>) -> InputMapper {
+        let config = if config_path.exists() {
+            match InputConfig::load(config_path) {
+                Ok(config) => config,
+                Err(error) => {
+                    println!("Couldn't load {}, using the default input mapping: {}", config_path.display(), error);
+                    InputConfig::new()
+                },
+            }
+        }
+        else {
+            InputConfig::new()
+        };
+
+        InputMapper {
+            config,
+            config_path: config_path.to_path_buf(),
+            subsystem,
+
+            keyboard: PadState::new(),
+            controllers: Vec::new(),
+
+            rebinding: None,
+            status: String::new(),
+        }
+    }
+
+    fn controller_index(&self, instance_id: i32) -> Option<usize> {
+        self.controllers.iter().position(|controller| {
+            controller.as_ref().map(|controller| controller.device.instance_id() == instance_id).unwrap_or(false)
+        })
+    }
+
+    fn add_controller(&mut self, joystick_index: u32) {
+        let device = match self.subsystem.as_ref().map(|subsystem| subsystem.open(joystick_index)) {
+            Some(Ok(device)) => device,
+            Some(Err(error)) => {
+                println!("Couldn't open game controller {}: {}", joystick_index, error);
+                return;
+            },
+            None => return,
+        };
+
+        // SDL can report a controller twice when it's there at startup.
+        if self.controller_index(device.instance_id()).is_some() {
+            return;
+        }
+
+        println!("Connected game controller: {}", device.name());
+
+        let controller = Controller {
+            device,
+            state: PadState::new(),
+            rumble: (0, 0),
+        };
+
+        match self.controllers.iter().position(|slot| slot.is_none()) {
+            Some(index) => self.controllers[index] = Some(controller),
+            None => self.controllers.push(Some(controller)),
+        }
+    }
+
+    fn controller_button(&mut self, instance_id: i32, button: controller::Button, pressed: bool) {
+        if let Some(index) = self.controller_index(instance_id) {
+            let controller = self.controllers[index].as_mut().unwrap();
+            for (target, bound) in self.config.buttons.iter() {
+                if *bound == button {
+                    target.apply(&mut controller.state, pressed);
+                }
+            }
+        }
+    }
+
+    // Handles an SDL event. Keyboard events should only be passed when the UI isn't using them.
+    pub fn handle_event(&mut self, event: &Event) {
+        match event {
+            Event::KeyDown { keycode: Some(key), repeat: false, .. } => {
+                if let Some(Rebinding::Key(target)) = self.rebinding {
+                    if *key != Keycode::Escape {
+                        self.config.bind_key(target, *key);
+                    }
+                    self.rebinding = None;
+                    return;
+                }
+
+                for (target, bound) in self.config.keys.iter() {
+                    if bound == key {
+                        target.apply(&mut self.keyboard, true);
+                    }
+                }
+            },
+            Event::KeyUp { keycode: Some(key), .. } => {
+                for (target, bound) in self.config.keys.iter() {
+                    if bound == key {
+                        target.apply(&mut self.keyboard, false);
+                    }
+                }
+            },
+            Event::ControllerDeviceAdded { which, .. } => self.add_controller(*which),
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(index) = self.controller_index(*which) {
+                    println!("Disconnected game controller {}", index);
+                    self.controllers[index] = None;
+                }
+            },
+            Event::ControllerButtonDown { which, button, .. } => {
+                if let Some(Rebinding::Button(target)) = self.rebinding {
+                    self.config.bind_button(target, *button);
+                    self.rebinding = None;
+                    return;
+                }
+
+                self.controller_button(*which, *button, true);
+            },
+            Event::ControllerButtonUp { which, button, .. } => self.controller_button(*which, *button, false),
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                if let Some(Rebinding::Button(target)) = self.rebinding {
+                    if value.saturating_abs() > AXIS_THRESHOLD {
+                        // For a stick, the direction the axis was pushed in becomes that direction.
+                        let target = match target {
+                            Target::Stick(stick, stick_axis, direction) => Target::Stick(stick, stick_axis, direction == (*value > 0)),
+                            _ => target,
+                        };
+                        self.config.bind_axis(target, *axis);
+                        self.rebinding = None;
+                    }
+                    return;
+                }
+
+                if let Some(index) = self.controller_index(*which) {
+                    let state = &mut self.controllers[index].as_mut().unwrap().state;
+                    let position = ((*value as i32 + 0x8000) >> 8) as u8;
+
+                    for (target, bound) in self.config.axes.iter() {
+                        if bound != axis {
+                            continue;
+                        }
+
+                        match *target {
+                            Target::Stick(stick, stick_axis, positive) => set_axis(state, stick, stick_axis, if positive { position } else { !position }),
+                            _ => target.apply(state, *value > AXIS_THRESHOLD),
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // Combines every source assigned to each pad. Sticks take whichever source moved off center.
+    pub fn state(&self) -> InputState {
+        let mut state = InputState::new();
+
+        for (pads, assignments) in state.pads.iter_mut().zip(self.config.assignments.iter()) {
+            for (pad, assignment) in pads.iter_mut().zip(assignments.iter()) {
+                let mut sources = Vec::new();
+
+                if assignment.keyboard {
+                    sources.push(&self.keyboard);
+                }
+                if let Some(Some(controller)) = assignment.controller.and_then(|index| self.controllers.get(index)) {
+                    sources.push(&controller.state);
+                }
+
+                for source in sources {
+                    pad.buttons |= source.buttons;
+                    pad.analog_button |= source.analog_button;
+
+                    if source.left_stick != (0x80, 0x80) {
+                        pad.left_stick = source.left_stick;
+                    }
+                    if source.right_stick != (0x80, 0x80) {
+                        pad.right_stick = source.right_stick;
+                    }
+                }
+            }
+        }
+
+        state
+    }
+
+    // Forwards the emulated motors to the controllers driving each pad, indexed by [port][slot].
+    pub fn set_rumble(&mut self, rumble: [[(u8, u8); 4]; 2]) {
+        let controllers = &mut self.controllers;
+
+        for (motors, assignments) in rumble.iter().zip(self.config.assignments.iter()) {
+            for (motors, assignment) in motors.iter().zip(assignments.iter()) {
+                let controller = match assignment.controller.and_then(|index| controllers.get_mut(index)) {
+                    Some(Some(controller)) => controller,
+                    _ => continue,
+                };
+
+                if *motors == (0, 0) && controller.rumble == (0, 0) {
+                    continue;
+                }
+
+                // The large motor is the low frequency one.
+                let (small, large) = *motors;
+                if controller.device.set_rumble(large as u16 * 257, small as u16 * 257, RUMBLE_DURATION_MS).is_err() {
+                    // Not every controller can rumble, don't keep trying.
+                    controller.rumble = (0, 0);
+                    continue;
+                }
+
+                controller.rumble = *motors;
+            }
+        }
+    }
+
+    pub fn build_window(&mut self, ui: &Ui) {
+        Window::new(im_str!("Rusty PSX - Input")).size([380.0, 500.0], Condition::FirstUseEver).build(ui, || {
+            ui.text("Controllers");
+            ui.separator();
+
+            if self.controllers.iter().all(|controller| controller.is_none()) {
+                ui.text("No game controllers connected.");
+            }
+            for (index, controller) in self.controllers.iter().enumerate() {
+                if let Some(controller) = controller {
+                    ui.text(format!("controller{}: {}", index, controller.device.name()));
+                }
+            }
+
+            ui.spacing();
+            ui.text("Ports");
+            ui.separator();
+
+            for (port, slots) in self.config.assignments.iter_mut().enumerate() {
+                let assignment = &mut slots[0];
+                ui.checkbox(&ImString::new(format!("Keyboard on port {}", port + 1)), &mut assignment.keyboard);
+
+                // Same numbering as the list above and the config file, -1 for none.
+                let mut controller = assignment.controller.map(|index| index as i32).unwrap_or(-1);
+                if ui.input_int(&ImString::new(format!("Controller on port {}", port + 1)), &mut controller).build() {
+                    assignment.controller = if controller >= 0 { Some(controller as usize) } else { None };
+                }
+            }
+
+            ui.spacing();
+            ui.text("Bindings (click, then press a key, button or axis, Escape cancels a key)");
+            ui.separator();
+            ui.columns(3, im_str!("Binding Columns"), false);
+
+            for target in targets() {
+                let key = self.config.keys.iter().find(|(bound, _)| *bound == target).map(|(_, key)| key.name()).unwrap_or_default();
+                let button = self.config.controller_input(target);
+
+                let key_label = if self.rebinding == Some(Rebinding::Key(target)) { String::from("...") } else { key };
+                let button_label = if self.rebinding == Some(Rebinding::Button(target)) { String::from("...") } else { button };
+
+                ui.text(target.name());
+                ui.next_column();
+                if ui.button(&ImString::new(format!("{}##key-{}", key_label, target.name())), [100.0, 20.0]) {
+                    self.rebinding = Some(Rebinding::Key(target));
+                }
+                ui.next_column();
+                if ui.button(&ImString::new(format!("{}##button-{}", button_label, target.name())), [100.0, 20.0]) {
+                    self.rebinding = Some(Rebinding::Button(target));
+                }
+                ui.next_column();
+            }
+
+            ui.columns(1, im_str!("Binding Columns End"), false);
+            ui.spacing();
+
+            if ui.button(im_str!("Save mapping"), [120.0, 20.0]) {
+                self.status = match self.config.save(&self.config_path) {
+                    Ok(()) => format!("Saved to {}", self.config_path.display()),
+                    Err(error) => format!("Couldn't save the mapping: {}", error),
+                };
+            }
+            if !self.status.is_empty() {
+                ui.text(&self.status);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axes_replace_other_controller_bindings() {
+        let mut config = InputConfig::new();
+
+        // L2 moves from the trigger to a button, and the trigger to R1.
+        config.bind_button(Target::Button(Button::L2), controller::Button::LeftShoulder);
+        assert!(!config.axes.iter().any(|(target, _)| *target == Target::Button(Button::L2)));
+        config.bind_axis(Target::Button(Button::R1), Axis::TriggerLeft);
+        assert!(!config.buttons.iter().any(|(target, _)| *target == Target::Button(Button::R1)));
+
+        // Binding either direction of a stick axis replaces the whole axis.
+        config.bind_axis(Target::Stick(0, 1, false), Axis::RightY);
+        let bound: Vec<(Target, Axis)> = config.axes.iter().cloned().filter(|(target, _)| same_target(*target, Target::Stick(0, 1, true))).collect();
+        assert!(bound == [(Target::Stick(0, 1, false), Axis::RightY)]);
+        assert!(!config.axes.iter().any(|(target, _)| *target == Target::Stick(1, 1, true)));
+    }
+}
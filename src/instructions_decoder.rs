@@ -1,230 +1,500 @@
-use super::cpu::Instruction;
-
-
-pub fn get_instruction_info(instruction: &Instruction) -> String {
-
-    let mut result = String::from("");
-    if instruction.op() != 0 {
-        match instruction.op() {
-            0x01 => {
-                match instruction.rt() {
-                    0x00 => result = String::from(format!("bltz, r{:02X}, {:08X}", instruction.rs(), instruction.target())),
-                    0x01 => result = String::from(format!("bgtz, r{:02X}, {:08X}", instruction.rs(), instruction.target())),
-                    0x10 => result = String::from(format!("bltzal, r{:02X}, {:08X}", instruction.rs(), instruction.target())),
-                    0x11 => result = String::from(format!("bgezal, r{:02X}, {:08X}", instruction.rs(), instruction.target())),
-                    _ => unreachable!(),
-                }
-            },
-            0x02 => {
-                result = String::from(format!("j {:08X}", instruction.target()));
-            },
-            0x03 => {
-                result = String::from(format!("jal {:08X}", instruction.target()));
-            },
-            0x04 => {
-                result = String::from(format!("beq, r{:02X}, r{:02X}, {:08X}", instruction.rs(), instruction.rt(), instruction.target()));
-            },
-            0x05 => {
-                result = String::from(format!("bne, r{:02X}, r{:02X}, {:08X}", instruction.rs(), instruction.rt(), instruction.target()));
-            },
-            0x06 => {
-                result = String::from(format!("blez, r{:02X}, {:08X}", instruction.rs(), instruction.target()));
-            },
-            0x07 => {
-                result = String::from(format!("bgtz, r{:02X}, {:08X}", instruction.rs(), instruction.target()));
-            },
-            0x08 => {
-                result = String::from(format!("addi, r{:02X}, r{:02X}, {:08X}", instruction.rt(), instruction.rs(), instruction.immediate()));
-            },
-            0x09 => {
-                result = String::from(format!("addiu, r{:02X}, r{:02X}, {:08X}", instruction.rt(), instruction.rs(), instruction.immediate()));
-            },
-            0x0A => {
-                result = String::from(format!("slti, r{:02X}, r{:02X}, {:08X}", instruction.rt(), instruction.rs(), instruction.immediate()));
-            },
-            0x0B => {
-                result = String::from(format!("sltiu, r{:02X}, r{:02X}, {:08X}", instruction.rt(), instruction.rs(), instruction.immediate()));
-            },
-            0x0C => {
-                result = String::from(format!("andi, r{:02X}, r{:02X}, {:08X}", instruction.rt(), instruction.rs(), instruction.immediate()));
-            },
-            0x0D => {
-                result = String::from(format!("ori, r{:02X}, r{:02X}, {:08X}", instruction.rt(), instruction.rs(), instruction.immediate()));
-            },
-            0x0E => {
-                result = String::from(format!("xori, r{:02X}, r{:02X}, {:08X}", instruction.rt(), instruction.rs(), instruction.immediate()));
-            },
-            0x0F => {
-                result = String::from(format!("lui, r{:02X}, {:08X}", instruction.rt(), instruction.immediate()));
-            },
-            0x10 => {
-                // COP0
-            },
-            0x11 => {
+// Disassembler for the R3000A, its COP0 and the GTE.
+// Instructions are decoded into operands first, so the debugger can follow branches without
+// parsing text back, and rendered with the usual assembler syntax afterwards.
 
-            },
-            0x12 => {
+pub const REGISTER_NAMES: [&str; 32] = [
+    "$zero", "$at", "$v0", "$v1", "$a0", "$a1", "$a2", "$a3",
+    "$t0", "$t1", "$t2", "$t3", "$t4", "$t5", "$t6", "$t7",
+    "$s0", "$s1", "$s2", "$s3", "$s4", "$s5", "$s6", "$s7",
+    "$t8", "$t9", "$k0", "$k1", "$gp", "$sp", "$fp", "$ra",
+];
 
-            },
-            0x13 => {
+// Register operands are indices into REGISTER_NAMES, or the coprocessor register number.
+// Branch offsets are in instructions, relative to the delay slot.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Instruction {
+    Sll { rd: u32, rt: u32, shift: u32 },
+    Srl { rd: u32, rt: u32, shift: u32 },
+    Sra { rd: u32, rt: u32, shift: u32 },
+    Sllv { rd: u32, rt: u32, rs: u32 },
+    Srlv { rd: u32, rt: u32, rs: u32 },
+    Srav { rd: u32, rt: u32, rs: u32 },
+    Jr { rs: u32 },
+    Jalr { rd: u32, rs: u32 },
+    Syscall { code: u32 },
+    Break { code: u32 },
+    Mfhi { rd: u32 },
+    Mthi { rs: u32 },
+    Mflo { rd: u32 },
+    Mtlo { rs: u32 },
+    Mult { rs: u32, rt: u32 },
+    Multu { rs: u32, rt: u32 },
+    Div { rs: u32, rt: u32 },
+    Divu { rs: u32, rt: u32 },
+    Add { rd: u32, rs: u32, rt: u32 },
+    Addu { rd: u32, rs: u32, rt: u32 },
+    Sub { rd: u32, rs: u32, rt: u32 },
+    Subu { rd: u32, rs: u32, rt: u32 },
+    And { rd: u32, rs: u32, rt: u32 },
+    Or { rd: u32, rs: u32, rt: u32 },
+    Xor { rd: u32, rs: u32, rt: u32 },
+    Nor { rd: u32, rs: u32, rt: u32 },
+    Slt { rd: u32, rs: u32, rt: u32 },
+    Sltu { rd: u32, rs: u32, rt: u32 },
 
-            },
-            0x20 => {
-                result = String::from(format!("lb, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x21 => {
-                result = String::from(format!("lh, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x22 => {
-                result = String::from(format!("lwl, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x23 => {
-                result = String::from(format!("lw, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x24 => {
-                result = String::from(format!("lbu, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x25 => {
-                result = String::from(format!("lhu, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x26 => {
-                result = String::from(format!("lwr, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x28 => {
-                result = String::from(format!("sb, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x29 => {
-                result = String::from(format!("sh, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x2A => {
-                result = String::from(format!("swl, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x2B => {
-                result = String::from(format!("sw, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x2E => {
-                result = String::from(format!("swr, r{:02X}, {:08X}(r{:02X})", instruction.rt(), instruction.immediate(), instruction.rs()));
-            },
-            0x30 => {
+    Bltz { rs: u32, offset: i16 },
+    Bgez { rs: u32, offset: i16 },
+    Bltzal { rs: u32, offset: i16 },
+    Bgezal { rs: u32, offset: i16 },
+    J { target: u32 },
+    Jal { target: u32 },
+    Beq { rs: u32, rt: u32, offset: i16 },
+    Bne { rs: u32, rt: u32, offset: i16 },
+    Blez { rs: u32, offset: i16 },
+    Bgtz { rs: u32, offset: i16 },
 
-            },
-            0x31 => {
+    Addi { rt: u32, rs: u32, immediate: i16 },
+    Addiu { rt: u32, rs: u32, immediate: i16 },
+    Slti { rt: u32, rs: u32, immediate: i16 },
+    Sltiu { rt: u32, rs: u32, immediate: i16 },
+    Andi { rt: u32, rs: u32, immediate: u16 },
+    Ori { rt: u32, rs: u32, immediate: u16 },
+    Xori { rt: u32, rs: u32, immediate: u16 },
+    Lui { rt: u32, immediate: u16 },
 
-            },
-            0x32 => {
+    Mfc0 { rt: u32, rd: u32 },
+    Cfc0 { rt: u32, rd: u32 },
+    Mtc0 { rt: u32, rd: u32 },
+    Ctc0 { rt: u32, rd: u32 },
+    Bc0f { offset: i16 },
+    Bc0t { offset: i16 },
+    Rfe,
 
-            },
-            0x33 => {
+    Mfc2 { rt: u32, rd: u32 },
+    Cfc2 { rt: u32, rd: u32 },
+    Mtc2 { rt: u32, rd: u32 },
+    Ctc2 { rt: u32, rd: u32 },
+    Bc2f { offset: i16 },
+    Bc2t { offset: i16 },
+    // The low 25 bits of a GTE command.
+    Gte { command: u32 },
 
-            },
-            0x38 => {
+    Lb { rt: u32, rs: u32, offset: i16 },
+    Lh { rt: u32, rs: u32, offset: i16 },
+    Lwl { rt: u32, rs: u32, offset: i16 },
+    Lw { rt: u32, rs: u32, offset: i16 },
+    Lbu { rt: u32, rs: u32, offset: i16 },
+    Lhu { rt: u32, rs: u32, offset: i16 },
+    Lwr { rt: u32, rs: u32, offset: i16 },
+    Sb { rt: u32, rs: u32, offset: i16 },
+    Sh { rt: u32, rs: u32, offset: i16 },
+    Swl { rt: u32, rs: u32, offset: i16 },
+    Sw { rt: u32, rs: u32, offset: i16 },
+    Swr { rt: u32, rs: u32, offset: i16 },
+    Lwc2 { rt: u32, rs: u32, offset: i16 },
+    Swc2 { rt: u32, rs: u32, offset: i16 },
 
-            },
-            0x39 => {
+    Illegal(u32),
+}
 
-            },
-            0x3A => {
+pub fn decode(value: u32) -> Instruction {
+    use self::Instruction::*;
 
-            },
-            0x3B => {
+    let op = value >> 26;
+    let rs = (value >> 21) & 0x1F;
+    let rt = (value >> 16) & 0x1F;
+    let rd = (value >> 11) & 0x1F;
+    let shift = (value >> 6) & 0x1F;
+    let immediate = value as u16;
+    let offset = value as i16;
+    let target = value & 0x03FFFFFF;
 
-            },
-            _ => {
-                result = String::from("Illegal instruction");
-            }
+    match op {
+        0x00 => match value & 0x3F {
+            0x00 => Sll { rd, rt, shift },
+            0x02 => Srl { rd, rt, shift },
+            0x03 => Sra { rd, rt, shift },
+            0x04 => Sllv { rd, rt, rs },
+            0x06 => Srlv { rd, rt, rs },
+            0x07 => Srav { rd, rt, rs },
+            0x08 => Jr { rs },
+            0x09 => Jalr { rd, rs },
+            0x0C => Syscall { code: (value >> 6) & 0xFFFFF },
+            0x0D => Break { code: (value >> 6) & 0xFFFFF },
+            0x10 => Mfhi { rd },
+            0x11 => Mthi { rs },
+            0x12 => Mflo { rd },
+            0x13 => Mtlo { rs },
+            0x18 => Mult { rs, rt },
+            0x19 => Multu { rs, rt },
+            0x1A => Div { rs, rt },
+            0x1B => Divu { rs, rt },
+            0x20 => Add { rd, rs, rt },
+            0x21 => Addu { rd, rs, rt },
+            0x22 => Sub { rd, rs, rt },
+            0x23 => Subu { rd, rs, rt },
+            0x24 => And { rd, rs, rt },
+            0x25 => Or { rd, rs, rt },
+            0x26 => Xor { rd, rs, rt },
+            0x27 => Nor { rd, rs, rt },
+            0x2A => Slt { rd, rs, rt },
+            0x2B => Sltu { rd, rs, rt },
+            _ => Illegal(value),
+        },
+        0x01 => match rt {
+            0x00 => Bltz { rs, offset },
+            0x01 => Bgez { rs, offset },
+            0x10 => Bltzal { rs, offset },
+            0x11 => Bgezal { rs, offset },
+            _ => Illegal(value),
+        },
+        0x02 => J { target },
+        0x03 => Jal { target },
+        0x04 => Beq { rs, rt, offset },
+        0x05 => Bne { rs, rt, offset },
+        0x06 => Blez { rs, offset },
+        0x07 => Bgtz { rs, offset },
+
+        0x08 => Addi { rt, rs, immediate: offset },
+        0x09 => Addiu { rt, rs, immediate: offset },
+        0x0A => Slti { rt, rs, immediate: offset },
+        0x0B => Sltiu { rt, rs, immediate: offset },
+        0x0C => Andi { rt, rs, immediate },
+        0x0D => Ori { rt, rs, immediate },
+        0x0E => Xori { rt, rs, immediate },
+        0x0F => Lui { rt, immediate },
+
+        0x10 => match rs {
+            0x00 => Mfc0 { rt, rd },
+            0x02 => Cfc0 { rt, rd },
+            0x04 => Mtc0 { rt, rd },
+            0x06 => Ctc0 { rt, rd },
+            0x08 if rt == 0 => Bc0f { offset },
+            0x08 if rt == 1 => Bc0t { offset },
+            0x10..=0x1F if value & 0x3F == 0x10 => Rfe,
+            _ => Illegal(value),
+        },
+        0x12 => match rs {
+            0x00 => Mfc2 { rt, rd },
+            0x02 => Cfc2 { rt, rd },
+            0x04 => Mtc2 { rt, rd },
+            0x06 => Ctc2 { rt, rd },
+            0x08 if rt == 0 => Bc2f { offset },
+            0x08 if rt == 1 => Bc2t { offset },
+            0x10..=0x1F => Gte { command: value & 0x1FFFFFF },
+            _ => Illegal(value),
+        },
+
+        0x20 => Lb { rt, rs, offset },
+        0x21 => Lh { rt, rs, offset },
+        0x22 => Lwl { rt, rs, offset },
+        0x23 => Lw { rt, rs, offset },
+        0x24 => Lbu { rt, rs, offset },
+        0x25 => Lhu { rt, rs, offset },
+        0x26 => Lwr { rt, rs, offset },
+        0x28 => Sb { rt, rs, offset },
+        0x29 => Sh { rt, rs, offset },
+        0x2A => Swl { rt, rs, offset },
+        0x2B => Sw { rt, rs, offset },
+        0x2E => Swr { rt, rs, offset },
+        0x32 => Lwc2 { rt, rs, offset },
+        0x3A => Swc2 { rt, rs, offset },
+
+        _ => Illegal(value),
+    }
+}
+
+// Decodes and renders in one go, for places that only want the text.
+pub fn disassemble(value: u32, pc: u32) -> String {
+    decode(value).render(pc)
+}
+
+impl Instruction {
+    // Address a branch or jump at `pc` goes to when taken. None for register jumps and
+    // everything that isn't a jump.
+    pub fn target(&self, pc: u32) -> Option<u32> {
+        use self::Instruction::*;
+
+        match *self {
+            J { target } | Jal { target } => Some((pc.wrapping_add(4) & 0xF0000000) | (target << 2)),
+            Bltz { offset, .. } | Bgez { offset, .. } | Bltzal { offset, .. } | Bgezal { offset, .. } |
+            Beq { offset, .. } | Bne { offset, .. } | Blez { offset, .. } | Bgtz { offset, .. } |
+            Bc0f { offset } | Bc0t { offset } | Bc2f { offset } | Bc2t { offset } => {
+                Some(pc.wrapping_add(4).wrapping_add((offset as i32 * 4) as u32))
+            },
+            _ => None,
+        }
+    }
+
+    // Jumps and branches, which run the next instruction before they take effect.
+    pub fn has_delay_slot(&self) -> bool {
+        use self::Instruction::*;
+
+        match *self {
+            Jr { .. } | Jalr { .. } | J { .. } | Jal { .. } => true,
+            _ => self.target(0).is_some(),
         }
     }
+
+    // Conventional assembler syntax, with `pc` used to resolve branch targets.
+    pub fn render(&self, pc: u32) -> String {
+        use self::Instruction::*;
+
+        let target = || format!("0x{:08x}", self.target(pc).unwrap_or(0));
+
+        match *self {
+            Sll { rd: 0, rt: 0, shift: 0 } => String::from("nop"),
+            Sll { rd, rt, shift } => format!("sll {}, {}, {}", register(rd), register(rt), shift),
+            Srl { rd, rt, shift } => format!("srl {}, {}, {}", register(rd), register(rt), shift),
+            Sra { rd, rt, shift } => format!("sra {}, {}, {}", register(rd), register(rt), shift),
+            Sllv { rd, rt, rs } => format!("sllv {}, {}, {}", register(rd), register(rt), register(rs)),
+            Srlv { rd, rt, rs } => format!("srlv {}, {}, {}", register(rd), register(rt), register(rs)),
+            Srav { rd, rt, rs } => format!("srav {}, {}, {}", register(rd), register(rt), register(rs)),
+            Jr { rs } => format!("jr {}", register(rs)),
+            Jalr { rd: 31, rs } => format!("jalr {}", register(rs)),
+            Jalr { rd, rs } => format!("jalr {}, {}", register(rd), register(rs)),
+            Syscall { code: 0 } => String::from("syscall"),
+            Syscall { code } => format!("syscall 0x{:x}", code),
+            Break { code: 0 } => String::from("break"),
+            Break { code } => format!("break 0x{:x}", code),
+            Mfhi { rd } => format!("mfhi {}", register(rd)),
+            Mthi { rs } => format!("mthi {}", register(rs)),
+            Mflo { rd } => format!("mflo {}", register(rd)),
+            Mtlo { rs } => format!("mtlo {}", register(rs)),
+            Mult { rs, rt } => format!("mult {}, {}", register(rs), register(rt)),
+            Multu { rs, rt } => format!("multu {}, {}", register(rs), register(rt)),
+            Div { rs, rt } => format!("div {}, {}", register(rs), register(rt)),
+            Divu { rs, rt } => format!("divu {}, {}", register(rs), register(rt)),
+            Add { rd, rs, rt } => format!("add {}, {}, {}", register(rd), register(rs), register(rt)),
+            Addu { rd, rs, rt } => format!("addu {}, {}, {}", register(rd), register(rs), register(rt)),
+            Sub { rd, rs, rt } => format!("sub {}, {}, {}", register(rd), register(rs), register(rt)),
+            Subu { rd, rs, rt } => format!("subu {}, {}, {}", register(rd), register(rs), register(rt)),
+            And { rd, rs, rt } => format!("and {}, {}, {}", register(rd), register(rs), register(rt)),
+            Or { rd, rs, rt } => format!("or {}, {}, {}", register(rd), register(rs), register(rt)),
+            Xor { rd, rs, rt } => format!("xor {}, {}, {}", register(rd), register(rs), register(rt)),
+            Nor { rd, rs, rt } => format!("nor {}, {}, {}", register(rd), register(rs), register(rt)),
+            Slt { rd, rs, rt } => format!("slt {}, {}, {}", register(rd), register(rs), register(rt)),
+            Sltu { rd, rs, rt } => format!("sltu {}, {}, {}", register(rd), register(rs), register(rt)),
+
+            Bltz { rs, .. } => format!("bltz {}, {}", register(rs), target()),
+            Bgez { rs, .. } => format!("bgez {}, {}", register(rs), target()),
+            Bltzal { rs, .. } => format!("bltzal {}, {}", register(rs), target()),
+            Bgezal { rs, .. } => format!("bgezal {}, {}", register(rs), target()),
+            J { .. } => format!("j {}", target()),
+            Jal { .. } => format!("jal {}", target()),
+            Beq { rs: 0, rt: 0, .. } => format!("b {}", target()),
+            Beq { rs, rt, .. } => format!("beq {}, {}, {}", register(rs), register(rt), target()),
+            Bne { rs, rt, .. } => format!("bne {}, {}, {}", register(rs), register(rt), target()),
+            Blez { rs, .. } => format!("blez {}, {}", register(rs), target()),
+            Bgtz { rs, .. } => format!("bgtz {}, {}", register(rs), target()),
+
+            Addi { rt, rs, immediate } => format!("addi {}, {}, {}", register(rt), register(rs), signed_hex(immediate)),
+            Addiu { rt, rs, immediate } => format!("addiu {}, {}, {}", register(rt), register(rs), signed_hex(immediate)),
+            Slti { rt, rs, immediate } => format!("slti {}, {}, {}", register(rt), register(rs), signed_hex(immediate)),
+            Sltiu { rt, rs, immediate } => format!("sltiu {}, {}, {}", register(rt), register(rs), signed_hex(immediate)),
+            Andi { rt, rs, immediate } => format!("andi {}, {}, 0x{:x}", register(rt), register(rs), immediate),
+            Ori { rt, rs, immediate } => format!("ori {}, {}, 0x{:x}", register(rt), register(rs), immediate),
+            Xori { rt, rs, immediate } => format!("xori {}, {}, 0x{:x}", register(rt), register(rs), immediate),
+            Lui { rt, immediate } => format!("lui {}, 0x{:x}", register(rt), immediate),
+
+            Mfc0 { rt, rd } => format!("mfc0 {}, ${}", register(rt), rd),
+            Cfc0 { rt, rd } => format!("cfc0 {}, ${}", register(rt), rd),
+            Mtc0 { rt, rd } => format!("mtc0 {}, ${}", register(rt), rd),
+            Ctc0 { rt, rd } => format!("ctc0 {}, ${}", register(rt), rd),
+            Bc0f { .. } => format!("bc0f {}", target()),
+            Bc0t { .. } => format!("bc0t {}", target()),
+            Rfe => String::from("rfe"),
+
+            Mfc2 { rt, rd } => format!("mfc2 {}, ${}", register(rt), rd),
+            Cfc2 { rt, rd } => format!("cfc2 {}, ${}", register(rt), rd),
+            Mtc2 { rt, rd } => format!("mtc2 {}, ${}", register(rt), rd),
+            Ctc2 { rt, rd } => format!("ctc2 {}, ${}", register(rt), rd),
+            Bc2f { .. } => format!("bc2f {}", target()),
+            Bc2t { .. } => format!("bc2t {}", target()),
+            Gte { command } => render_gte(command),
+
+            Lb { rt, rs, offset } => format!("lb {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Lh { rt, rs, offset } => format!("lh {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Lwl { rt, rs, offset } => format!("lwl {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Lw { rt, rs, offset } => format!("lw {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Lbu { rt, rs, offset } => format!("lbu {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Lhu { rt, rs, offset } => format!("lhu {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Lwr { rt, rs, offset } => format!("lwr {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Sb { rt, rs, offset } => format!("sb {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Sh { rt, rs, offset } => format!("sh {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Swl { rt, rs, offset } => format!("swl {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Sw { rt, rs, offset } => format!("sw {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Swr { rt, rs, offset } => format!("swr {}, {}({})", register(rt), signed_hex(offset), register(rs)),
+            Lwc2 { rt, rs, offset } => format!("lwc2 ${}, {}({})", rt, signed_hex(offset), register(rs)),
+            Swc2 { rt, rs, offset } => format!("swc2 ${}, {}({})", rt, signed_hex(offset), register(rs)),
+
+            Illegal(value) => format!(".word 0x{:08x}", value),
+        }
+    }
+}
+
+fn register(index: u32) -> &'static str {
+    REGISTER_NAMES[index as usize & 0x1F]
+}
+
+fn signed_hex(value: i16) -> String {
+    if value < 0 {
+        format!("-0x{:x}", -(value as i32))
+    }
     else {
-        match instruction.op() {
-            0x00 => {
-                result = String::from(format!("sll r{:02X}, r{:02X}, {:08X}", instruction.rd(), instruction.rt(), instruction.immediate()));
-            },
-            0x02 => {
-                result = String::from(format!("srl r{:02X}, r{:02X}, {:08X}", instruction.rd(), instruction.rt(), instruction.immediate()));
-            },
-            0x03 => {
-                result = String::from(format!("sra r{:02X}, r{:02X}, {:08X}", instruction.rd(), instruction.rt(), instruction.immediate()));
-            },
-            0x04 => {
-                result = String::from(format!("sllv r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rt(), instruction.rs()));
-            },
-            0x06 => {
-                result = String::from(format!("srlv r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rt(), instruction.rs()));
-            },
-            0x07 => {
-                result = String::from(format!("srav r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rt(), instruction.rs()));
-            },
-            0x08 => {
-                result = String::from(format!("jr r{:02X}", instruction.rs()));
-            },
-            0x09 => {
-                result = String::from(format!("jalr r{:02X}, r{:02X}", instruction.rs(), instruction.rd()));
-            },
-            0x0C => {
-                result = String::from("syscall");
-            },
-            0x0D => {
-                result = String::from("break");
-            },
-            0x10 => {
-                result = String::from(format!("mfhi r{:02X}", instruction.rd()));
-            },
-            0x11 => {
-                result = String::from(format!("mthi r{:02X}", instruction.rs()));
-            },
-            0x12 => {
-                result = String::from(format!("mflo r{:02X}", instruction.rd()));
-            },
-            0x13 => {
-                result = String::from(format!("mtlo r{:02X}", instruction.rs()));
-            },
-            0x18 => {
-                result = String::from(format!("mult r{:02X}, r{:02X}", instruction.rs(), instruction.rt()));
-            },
-            0x19 => {
-                result = String::from(format!("multu r{:02X}, r{:02X}", instruction.rs(), instruction.rt()));
-            },
-            0x1A => {
-                result = String::from(format!("div r{:02X}, r{:02X}", instruction.rs(), instruction.rt()));
-            },
-            0x1B => {
-                result = String::from(format!("divu r{:02X}, r{:02X}", instruction.rs(), instruction.rt()));
-            },
-            0x20 => {
-                result = String::from(format!("add r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x21 => {
-                result = String::from(format!("addu r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x22 => {
-                result = String::from(format!("sub r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x23 => {
-                result = String::from(format!("subu r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x24 => {
-                result = String::from(format!("and r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x25 => {
-                result = String::from(format!("or r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x26 => {
-                result = String::from(format!("xor r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x27 => {
-                result = String::from(format!("nor r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x2A => {
-                result = String::from(format!("slt r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            0x2B => {
-                result = String::from(format!("sltu r{:02X}, r{:02X}, r{:02X}", instruction.rd(), instruction.rs(), instruction.rt()));
-            },
-            _ => {
-                result = String::from("Illegal instruction");
-            }
+        format!("0x{:x}", value)
+    }
+}
+
+// GTE commands by name, with the sf/lm bits and the MVMVA operands spelled out.
+fn render_gte(command: u32) -> String {
+    let name = match command & 0x3F {
+        0x01 => "rtps",
+        0x06 => "nclip",
+        0x0C => "op",
+        0x10 => "dpcs",
+        0x11 => "intpl",
+        0x12 => "mvmva",
+        0x13 => "ncds",
+        0x14 => "cdp",
+        0x16 => "ncdt",
+        0x1B => "nccs",
+        0x1C => "cc",
+        0x1E => "ncs",
+        0x20 => "nct",
+        0x28 => "sqr",
+        0x29 => "dcpl",
+        0x2A => "dpct",
+        0x2D => "avsz3",
+        0x2E => "avsz4",
+        0x30 => "rtpt",
+        0x3D => "gpf",
+        0x3E => "gpl",
+        0x3F => "ncct",
+        _ => return format!("cop2 0x{:07x}", command),
+    };
+
+    let mut operands = Vec::new();
+    if command & (1 << 19) != 0 {
+        operands.push(String::from("sf"));
+    }
+    if command & (1 << 10) != 0 {
+        operands.push(String::from("lm"));
+    }
+    if command & 0x3F == 0x12 {
+        const MATRICES: [&str; 4] = ["rt", "llm", "lcm", "bad"];
+        const VECTORS: [&str; 4] = ["v0", "v1", "v2", "ir"];
+        const TRANSLATIONS: [&str; 4] = ["tr", "bk", "fc", "none"];
+
+        operands.push(String::from(MATRICES[(command >> 17) as usize & 3]));
+        operands.push(String::from(VECTORS[(command >> 15) as usize & 3]));
+        operands.push(String::from(TRANSLATIONS[(command >> 13) as usize & 3]));
+    }
+
+    if operands.is_empty() {
+        String::from(name)
+    }
+    else {
+        format!("{} {}", name, operands.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (encoding, pc, text). The pc only matters for branches and jumps.
+    const CASES: [(u32, u32, &str); 61] = [
+        // R-type, picked by the function field.
+        (0x00000000, 0, "nop"),
+        (0x00094100, 0, "sll $t0, $t1, 4"),
+        (0x00021403, 0, "sra $v0, $v0, 16"),
+        (0x00021402, 0, "srl $v0, $v0, 16"),
+        (0x00831004, 0, "sllv $v0, $v1, $a0"),
+        (0x00831007, 0, "srav $v0, $v1, $a0"),
+        (0x03E00008, 0, "jr $ra"),
+        (0x0320F809, 0, "jalr $t9"),
+        (0x03201009, 0, "jalr $v0, $t9"),
+        (0x0000000C, 0, "syscall"),
+        (0x000001CD, 0, "break 0x7"),
+        (0x00004010, 0, "mfhi $t0"),
+        (0x00004012, 0, "mflo $t0"),
+        (0x01000013, 0, "mtlo $t0"),
+        (0x00850018, 0, "mult $a0, $a1"),
+        (0x0085001B, 0, "divu $a0, $a1"),
+        (0x00851021, 0, "addu $v0, $a0, $a1"),
+        (0x00851023, 0, "subu $v0, $a0, $a1"),
+        (0x00851025, 0, "or $v0, $a0, $a1"),
+        (0x00004027, 0, "nor $t0, $zero, $zero"),
+        (0x0085402A, 0, "slt $t0, $a0, $a1"),
+        (0x0085402B, 0, "sltu $t0, $a0, $a1"),
+        (0x00000001, 0, ".word 0x00000001"),
+
+        // Arithmetic immediates are sign-extended, logical ones aren't.
+        (0x27BDFFE8, 0, "addiu $sp, $sp, -0x18"),
+        (0x24047FFF, 0, "addiu $a0, $zero, 0x7fff"),
+        (0x21088000, 0, "addi $t0, $t0, -0x8000"),
+        (0x2882FFFF, 0, "slti $v0, $a0, -0x1"),
+        (0x3042FFFF, 0, "andi $v0, $v0, 0xffff"),
+        (0x34218000, 0, "ori $at, $at, 0x8000"),
+        (0x3C088000, 0, "lui $t0, 0x8000"),
+        (0x8FBF0010, 0, "lw $ra, 0x10($sp)"),
+        (0xAFBF0014, 0, "sw $ra, 0x14($sp)"),
+        (0x80A4FFFF, 0, "lb $a0, -0x1($a1)"),
+        (0x94A48000, 0, "lhu $a0, -0x8000($a1)"),
+
+        // Branch targets are relative to the delay slot, jumps keep the top bits of it.
+        (0x11090004, 0x80010000, "beq $t0, $t1, 0x80010014"),
+        (0x1480FFFD, 0x80010010, "bne $a0, $zero, 0x80010008"),
+        (0x10000001, 0, "b 0x00000008"),
+        (0x0440FFFF, 0xBFC00100, "bltz $v0, 0xbfc00100"),
+        (0x06110002, 0x00001000, "bgezal $s0, 0x0000100c"),
+        (0x1C60FFFE, 0x80020000, "bgtz $v1, 0x8001fffc"),
+        (0x08040000, 0x80001000, "j 0x80100000"),
+        (0x0FF00040, 0xBFC00000, "jal 0xbfc00100"),
+        (0x0BFFFFFF, 0x8FFFFFFC, "j 0x9ffffffc"),
+
+        // COP0.
+        (0x40086000, 0, "mfc0 $t0, $12"),
+        (0x408C6000, 0, "mtc0 $t4, $12"),
+        (0x42000010, 0, "rfe"),
+        (0x42000001, 0, ".word 0x42000001"),
+
+        // COP2 moves and loads.
+        (0x48083800, 0, "mfc2 $t0, $7"),
+        (0x48C9F000, 0, "ctc2 $t1, $30"),
+        (0x48885800, 0, "mtc2 $t0, $11"),
+        (0xC8800000, 0, "lwc2 $0, 0x0($a0)"),
+        (0xCBA9FFFC, 0, "lwc2 $9, -0x4($sp)"),
+        (0xE9180010, 0, "swc2 $24, 0x10($t0)"),
+
+        // GTE commands.
+        (0x4A180001, 0, "rtps sf"),
+        (0x4B400006, 0, "nclip"),
+        (0x4AF8043F, 0, "ncct sf, lm"),
+        (0x4A486012, 0, "mvmva sf, rt, v0, none"),
+        (0x4A03A412, 0, "mvmva lm, llm, ir, bk"),
+        (0x4A070012, 0, "mvmva bad, v2, tr"),
+        (0x4A000005, 0, "cop2 0x0000005"),
+
+        (0xFC000000, 0, ".word 0xfc000000"),
+    ];
+
+    #[test]
+    fn disassembly_table() {
+        for (value, pc, text) in CASES.iter() {
+            assert_eq!(disassemble(*value, *pc), *text, "0x{:08x} at 0x{:08x}", value, pc);
         }
     }
 
-    result
-}
\ No newline at end of file
+    #[test]
+    fn branches_expose_their_targets() {
+        assert_eq!(decode(0x11090004).target(0x80010000), Some(0x80010014));
+        assert_eq!(decode(0x0FF00040).target(0xBFC00000), Some(0xBFC00100));
+        assert_eq!(decode(0x03E00008).target(0), None);
+
+        assert!(decode(0x03E00008).has_delay_slot());
+        assert!(decode(0x11090004).has_delay_slot());
+        assert!(!decode(0x27BDFFE8).has_delay_slot());
+    }
+}
@@ -0,0 +1,61 @@
+use std::io;
+
+use super::savestate::{StateReader, StateWriter};
+
+#[derive(Clone, Copy)]
+pub enum Interrupt {
+    Cdrom = 2,
+    Dma = 3,
+    Controller = 7,
+    Spu = 9,
+}
+
+// I_STAT and I_MASK at 0x1F801070/74.
+// The CPU doesn't take exceptions yet, so requests are only latched for software that polls them.
+pub struct InterruptController {
+    pub status: u16,
+    pub mask: u16,
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController {
+            status: 0,
+            mask: 0,
+        }
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.status |= 1 << interrupt as u16;
+    }
+
+    pub fn read_register(&self, offset: u32) -> u32 {
+        match offset {
+            0 => self.status as u32,
+            4 => self.mask as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn write_register(&mut self, offset: u32, value: u32) {
+        match offset {
+            // Writing zeroes acknowledges, ones leave the bit alone.
+            0 => self.status &= value as u16,
+            4 => self.mask = value as u16 & 0x07FF,
+            _ => {},
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"IRQ ");
+        state.write_u16(self.status);
+        state.write_u16(self.mask);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"IRQ ")?;
+        self.status = state.read_u16()?;
+        self.mask = state.read_u16()?;
+        Ok(())
+    }
+}
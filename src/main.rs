@@ -1,45 +1,634 @@
 mod cpu;
+mod dma;
+mod exe;
+mod spu;
+mod mdec;
+mod png;
+mod str_video;
+mod sio;
+mod input;
+mod input_mapping;
+mod wav;
+mod audio;
+mod cdrom;
 mod memory;
+mod savestate;
+mod rewind;
+mod interrupts;
+mod watchpoints;
 mod instructions_decoder;
+mod gdb;
+mod disassembly_window;
+mod call_stack_window;
+mod registers_window;
+mod memory_window;
+mod breakpoints_window;
+mod memcard_window;
+mod savestate_window;
 
 use sdl2;
 use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
 
 use imgui::*;
 use imgui_sdl2;
 use imgui_opengl_renderer;
 
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use cpu::call_stack::StepTarget;
+
+const INSTRUCTIONS_PER_FRAME: u32 = cpu::CLOCK_RATE / cpu::CYCLES_PER_INSTRUCTION / 60;
+const FRAME_DURATION: Duration = Duration::from_micros(1_000_000 / 60);
+
+const REWIND_KEY: Keycode = Keycode::F2;
+
+struct Options {
+    disc_path: Option<PathBuf>,
+    fast_boot: bool,
+    dump_cd_audio: Option<(u32, u32, PathBuf)>,
+    dump_str: Option<(String, PathBuf)>,
+
+    headless: bool,
+    run_seconds: Option<u32>,
+    record_wav: Option<PathBuf>,
+    audio_enabled: bool,
+
+    gte_conformance: Option<PathBuf>,
+
+    controllers: [Option<sio::ControllerKind>; 2],
+    input_script: Option<PathBuf>,
+    memory_cards: [Option<PathBuf>; 2],
+    convert_memory_card: Option<(PathBuf, PathBuf)>,
+
+    load_state: Option<PathBuf>,
+    save_state: Option<PathBuf>,
+
+    rewind_interval: u32,
+    rewind_budget: usize,
+
+    gdb_port: Option<u16>,
+
+    trace: Option<PathBuf>,
+    trace_settings: cpu::trace::TraceSettings,
+    trace_diff: Option<PathBuf>,
+}
+
+impl Options {
+    fn from_args() -> Options {
+        let mut options = Options {
+            disc_path: None,
+            fast_boot: false,
+            dump_cd_audio: None,
+            dump_str: None,
+
+            headless: false,
+            run_seconds: None,
+            record_wav: None,
+            audio_enabled: true,
+
+            gte_conformance: None,
+
+            controllers: [Some(sio::ControllerKind::DualShock), None],
+            input_script: None,
+            memory_cards: [None, None],
+            convert_memory_card: None,
+
+            load_state: None,
+            save_state: None,
+
+            rewind_interval: rewind::DEFAULT_INTERVAL,
+            rewind_budget: rewind::DEFAULT_BUDGET,
+
+            gdb_port: None,
+
+            trace: None,
+            trace_settings: cpu::trace::TraceSettings::new(),
+            trace_diff: None,
+        };
+
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--disc" => options.disc_path = args.next().map(PathBuf::from),
+                "--fast-boot" => options.fast_boot = true,
+                "--dump-cd-audio" => {
+                    let start = args.next().and_then(|value| value.parse().ok());
+                    let count = args.next().and_then(|value| value.parse().ok());
+                    let output = args.next().map(PathBuf::from);
+
+                    match (start, count, output) {
+                        (Some(start), Some(count), Some(output)) => options.dump_cd_audio = Some((start, count, output)),
+                        _ => println!("Usage: --dump-cd-audio <start lba> <sector count> <output file>"),
+                    }
+                },
+                "--dump-str" => {
+                    let file = args.next();
+                    let output = args.next().map(PathBuf::from);
+
+                    match (file, output) {
+                        (Some(file), Some(output)) => options.dump_str = Some((file, output)),
+                        _ => println!("Usage: --dump-str <file on disc> <output directory>"),
+                    }
+                },
+                "--headless" => options.headless = true,
+                "--run-seconds" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(seconds) => options.run_seconds = Some(seconds),
+                    None => {
+                        // Carrying on would run a headless session forever.
+                        println!("Usage: --run-seconds <whole seconds>");
+                        std::process::exit(1);
+                    },
+                },
+                "--record-wav" => options.record_wav = args.next().map(PathBuf::from),
+                "--no-audio" => options.audio_enabled = false,
+                "--gte-conformance" => options.gte_conformance = args.next().map(PathBuf::from),
+                "--pad" => {
+                    let port = args.next().and_then(|value| value.parse::<usize>().ok());
+                    let kind = args.next();
+
+                    match (port, kind) {
+                        (Some(port), Some(kind)) if port == 1 || port == 2 => {
+                            if kind == "none" {
+                                options.controllers[port - 1] = None;
+                            }
+                            else if let Some(kind) = sio::ControllerKind::from_name(&kind) {
+                                options.controllers[port - 1] = Some(kind);
+                            }
+                            else {
+                                println!("Unknown controller type {}", kind);
+                            }
+                        },
+                        _ => println!("Usage: --pad <1|2> <digital|dualshock|multitap|none>"),
+                    }
+                },
+                "--input-script" => options.input_script = args.next().map(PathBuf::from),
+                "--memory-card" => {
+                    let port = args.next().and_then(|value| value.parse::<usize>().ok());
+                    let path = args.next().map(PathBuf::from);
+
+                    match (port, path) {
+                        (Some(port), Some(path)) if port == 1 || port == 2 => options.memory_cards[port - 1] = Some(path),
+                        _ => println!("Usage: --memory-card <1|2> <card image>"),
+                    }
+                },
+                "--convert-memory-card" => {
+                    let input = args.next().map(PathBuf::from);
+                    let output = args.next().map(PathBuf::from);
+
+                    match (input, output) {
+                        (Some(input), Some(output)) => options.convert_memory_card = Some((input, output)),
+                        _ => println!("Usage: --convert-memory-card <input image> <output image>"),
+                    }
+                },
+                "--load-state" => options.load_state = args.next().map(PathBuf::from),
+                "--save-state" => options.save_state = args.next().map(PathBuf::from),
+                "--rewind-interval" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(frames) => options.rewind_interval = frames,
+                    None => println!("Usage: --rewind-interval <frames between snapshots>"),
+                },
+                "--rewind-budget" => match args.next().and_then(|value| value.parse::<usize>().ok()) {
+                    Some(megabytes) => options.rewind_budget = megabytes * 1024 * 1024,
+                    None => println!("Usage: --rewind-budget <megabytes, 0 to disable>"),
+                },
+                "--gdb" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(port) => options.gdb_port = Some(port),
+                    None => println!("Usage: --gdb <port, usually {}>", gdb::DEFAULT_PORT),
+                },
+                "--trace" => options.trace = args.next().map(PathBuf::from),
+                "--trace-range" => {
+                    let start = args.next().and_then(|value| parse_address(&value));
+                    let end = args.next().and_then(|value| parse_address(&value));
+
+                    match (start, end) {
+                        (Some(start), Some(end)) => options.trace_settings.ranges.push((start, end)),
+                        _ => println!("Usage: --trace-range <first address> <last address>"),
+                    }
+                },
+                "--trace-start" => match args.next().and_then(|value| parse_address(&value)) {
+                    Some(address) => options.trace_settings.start = Some(address),
+                    None => println!("Usage: --trace-start <address>"),
+                },
+                "--trace-stop" => match args.next().and_then(|value| parse_address(&value)) {
+                    Some(address) => options.trace_settings.stop = Some(address),
+                    None => println!("Usage: --trace-stop <address>"),
+                },
+                "--trace-last" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(count) => options.trace_settings.last = Some(count),
+                    None => println!("Usage: --trace-last <instruction count>"),
+                },
+                "--trace-diff" => options.trace_diff = args.next().map(PathBuf::from),
+                _ => println!("Unknown argument {}", arg),
+            }
+        }
+
+        options
+    }
+}
+
+// Hexadecimal, with or without 0x.
+fn parse_address(value: &str) -> Option<u32> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).ok()
+}
+
+fn create_cpu(options: &Options) -> cpu::Cpu {
+    let mut new_cpu = cpu::Cpu::new();
+
+    for (port, kind) in new_cpu.memory.sio0.ports.iter_mut().zip(options.controllers.iter()) {
+        port.controller = kind.map(|kind| kind.create());
+    }
+
+    for (port, path) in new_cpu.memory.sio0.ports.iter_mut().zip(options.memory_cards.iter()) {
+        if let Some(path) = path {
+            match sio::memcard::MemoryCard::open(path) {
+                Ok(card) => port.memory_card = Some(card),
+                Err(error) => println!("Couldn't load memory card {}: {}", path.display(), error),
+            }
+        }
+    }
+
+    if let Some(path) = &options.disc_path {
+        match new_cpu.memory.cdrom.insert_disc(path) {
+            Ok(()) => new_cpu.fast_boot = options.fast_boot,
+            Err(error) => println!("Couldn't load disc image {}: {}", path.display(), error),
+        }
+    }
+
+    if let Some(path) = &options.trace {
+        match cpu::trace::Tracer::create(path, options.trace_settings.clone()) {
+            Ok(tracer) => new_cpu.tracer = Some(tracer),
+            Err(error) => println!("Couldn't create trace {}: {}", path.display(), error),
+        }
+    }
+
+    new_cpu
+}
+
+fn load_state(current_cpu: &mut cpu::Cpu, path: &Path) -> bool {
+    match savestate::load(path, current_cpu) {
+        Ok(()) => {
+            println!("Loaded state {}", path.display());
+            true
+        },
+        Err(error) => {
+            println!("Couldn't load state {}: {}", path.display(), error);
+            false
+        },
+    }
+}
+
+fn flush_memory_cards(current_cpu: &mut cpu::Cpu) {
+    if let Err(error) = current_cpu.memory.sio0.flush_memory_cards() {
+        println!("Couldn't save a memory card: {}", error);
+    }
+}
+
+fn convert_memory_card(input: &Path, output: &Path) {
+    match sio::memcard::import(input).and_then(|data| sio::memcard::export(&data, output)) {
+        Ok(()) => println!("Converted {} to {}", input.display(), output.display()),
+        Err(error) => println!("Couldn't convert the memory card: {}", error),
+    }
+}
+
+fn dump_cd_audio(options: &Options, start: u32, count: u32, output: &Path) {
+    let mut cdrom = cdrom::Cdrom::new();
+
+    let result = match &options.disc_path {
+        Some(path) => cdrom.insert_disc(path).and_then(|_| cdrom.dump_audio(start, count, output)),
+        None => {
+            println!("--dump-cd-audio needs a disc, pass one with --disc");
+            return;
+        },
+    };
+
+    match result {
+        Ok(()) => println!("Wrote decoded CD audio to {}", output.display()),
+        Err(error) => println!("Couldn't dump CD audio: {}", error),
+    }
+}
+
+fn dump_str(options: &Options, file: &str, output: &Path) {
+    let result = match &options.disc_path {
+        Some(path) => str_video::dump_str(path, file, output),
+        None => {
+            println!("--dump-str needs a disc, pass one with --disc");
+            return;
+        },
+    };
+
+    match result {
+        Ok(frames) => println!("Wrote {} frames to {}", frames, output.display()),
+        Err(error) => println!("Couldn't dump {}: {}", file, error),
+    }
+}
+
+fn start_recording(path: &Path) -> Option<wav::WavWriter> {
+    match wav::WavWriter::create(path, audio::SPU_SAMPLE_RATE) {
+        Ok(writer) => Some(writer),
+        Err(error) => {
+            println!("Couldn't create {}: {}", path.display(), error);
+            None
+        },
+    }
+}
+
+fn stop_recording(writer: Option<wav::WavWriter>) {
+    if let Some(writer) = writer {
+        if let Err(error) = writer.finish() {
+            println!("Couldn't finish the WAV recording: {}", error);
+        }
+    }
+}
+
+fn record_samples(writer: &mut Option<wav::WavWriter>, samples: &[(i16, i16)]) {
+    if let Some(recording) = writer {
+        if let Err(error) = recording.write_samples(samples) {
+            println!("Stopping the WAV recording after a write error: {}", error);
+            *writer = None;
+        }
+    }
+}
+
+// Sleeps until a 60th of a second after the last frame. Falls back to running right away after a
+// stall, rather than catching up with a burst of frames.
+fn wait_for_frame(next_frame: &mut Instant) {
+    let now = Instant::now();
+
+    if *next_frame > now {
+        std::thread::sleep(*next_frame - now);
+        *next_frame += FRAME_DURATION;
+    }
+    else {
+        *next_frame = now + FRAME_DURATION;
+    }
+}
+
+// Runs the emulator without a window or audio device, until the CPU stops or the time limit is reached.
+fn run_headless(options: &Options) {
+    let mut current_cpu = create_cpu(options);
+    if let Some(path) = &options.load_state {
+        if !load_state(&mut current_cpu, path) {
+            return;
+        }
+    }
+
+    let mut recording = options.record_wav.as_ref().and_then(|path| start_recording(path));
+
+    let mut input = input::InputState::new();
+    let mut input_script = match &options.input_script {
+        Some(path) => match input::InputScript::load(path) {
+            Ok(script) => Some(script),
+            Err(error) => {
+                println!("Couldn't load the input script {}: {}", path.display(), error);
+                return;
+            },
+        },
+        None => None,
+    };
+
+    let instruction_limit = options.run_seconds.map(|seconds| seconds as u64 * (cpu::CLOCK_RATE / cpu::CYCLES_PER_INSTRUCTION) as u64);
+    let mut instructions = 0;
+
+    loop {
+        if let Some(limit) = instruction_limit {
+            if instructions >= limit {
+                break;
+            }
+        }
+
+        // Input changes once per frame, the same as with a window.
+        if instructions % INSTRUCTIONS_PER_FRAME as u64 == 0 {
+            if let Some(script) = input_script.as_mut() {
+                script.apply((instructions / INSTRUCTIONS_PER_FRAME as u64) as u32, &mut input);
+            }
+            current_cpu.memory.sio0.update_input(&input);
+        }
+
+        let result = current_cpu.run_instruction();
+        instructions += 1;
+
+        if current_cpu.memory.spu.output.len() >= audio::SPU_SAMPLE_RATE as usize / 60 {
+            let samples: Vec<(i16, i16)> = current_cpu.memory.spu.output.drain(..).collect();
+            record_samples(&mut recording, &samples);
+        }
+
+        match result {
+            cpu::CycleResult::Error => {
+                println!("CPU stopped with an error at PC {:08X}", current_cpu.pc);
+                break;
+            },
+            cpu::CycleResult::Breakpoint => {
+                println!("CPU hit a breakpoint at PC {:08X}", current_cpu.current_pc());
+                break;
+            },
+            cpu::CycleResult::Watchpoint => {
+                if let Some(hit) = &current_cpu.watch_hit {
+                    println!("CPU hit a watchpoint: {}", hit.describe());
+                }
+                break;
+            },
+            _ => {},
+        }
+    }
+
+    let samples: Vec<(i16, i16)> = current_cpu.memory.spu.output.drain(..).collect();
+    record_samples(&mut recording, &samples);
+    stop_recording(recording);
+    flush_memory_cards(&mut current_cpu);
+
+    // There's no frame to take a thumbnail from without a window.
+    if let Some(path) = &options.save_state {
+        let thumbnail = vec![0; savestate::THUMBNAIL_WIDTH * savestate::THUMBNAIL_HEIGHT * 3];
+        match savestate::save(path, &mut current_cpu, &thumbnail) {
+            Ok(()) => println!("Saved state to {}", path.display()),
+            Err(error) => println!("Couldn't save state {}: {}", path.display(), error),
+        }
+    }
+}
+
+// Runs the emulator under the control of a GDB connection instead of a window.
+fn run_gdb(options: &Options, port: u16) {
+    let mut current_cpu = create_cpu(options);
+    if let Some(path) = &options.load_state {
+        if !load_state(&mut current_cpu, path) {
+            return;
+        }
+    }
+
+    if let Err(error) = gdb::serve(&mut current_cpu, port) {
+        println!("GDB server stopped: {}", error);
+    }
+
+    flush_memory_cards(&mut current_cpu);
+}
+
+// Runs the CPU against a reference trace and shows where they first part ways.
+fn run_trace_diff(options: &Options, path: &Path) -> bool {
+    let mut current_cpu = create_cpu(options);
+    if let Some(state) = &options.load_state {
+        if !load_state(&mut current_cpu, state) {
+            return false;
+        }
+    }
+
+    let report = cpu::trace_diff::run_path(&mut current_cpu, path);
+    flush_memory_cards(&mut current_cpu);
+
+    match report {
+        Ok(report) => match report.divergence {
+            Some(divergence) => {
+                println!("Diverged at line {} of {} after {} matching instructions:", divergence.line_number, path.display(), report.matched);
+                for difference in divergence.differences.iter() {
+                    println!("    {}", difference);
+                }
+
+                println!();
+                for line in divergence.before.iter() {
+                    println!("            {}", line);
+                }
+                println!("emulator  > {}", divergence.emulator);
+                println!("reference > {}", divergence.reference);
+                for line in divergence.after.iter() {
+                    println!("            {}", line);
+                }
+                false
+            },
+            None => {
+                println!("No divergence in {} instructions", report.matched);
+                true
+            },
+        },
+        Err(error) => {
+            println!("Couldn't compare against {}: {}", path.display(), error);
+            false
+        },
+    }
+}
+
+// Runs GTE register vectors and reports every register that doesn't match.
+fn run_gte_conformance(path: &Path) -> bool {
+    match cpu::gte_vectors::run_path(path) {
+        Ok((count, mismatches)) => {
+            for mismatch in mismatches.iter() {
+                println!("{}: {} expected {:08X}, got {:08X}", mismatch.vector, cpu::gte_vectors::register_name(mismatch.register), mismatch.expected, mismatch.actual);
+            }
+
+            println!("Ran {} GTE vectors, {} mismatched registers", count, mismatches.len());
+            mismatches.is_empty()
+        },
+        Err(error) => {
+            println!("Couldn't load GTE vectors from {}: {}", path.display(), error);
+            false
+        },
+    }
+}
 
 fn main() {
+    let mut options = Options::from_args();
+
+    if let Some((start, count, output)) = &options.dump_cd_audio {
+        dump_cd_audio(&options, *start, *count, output);
+        return;
+    }
+
+    if let Some((file, output)) = &options.dump_str {
+        dump_str(&options, file, output);
+        return;
+    }
+
+    if let Some((input, output)) = &options.convert_memory_card {
+        convert_memory_card(input, output);
+        return;
+    }
+
+    if let Some(path) = &options.gte_conformance {
+        let passed = run_gte_conformance(path);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    if let Some(path) = &options.trace_diff {
+        let matched = run_trace_diff(&options, path);
+        std::process::exit(if matched { 0 } else { 1 });
+    }
+
+    if let Some(port) = options.gdb_port {
+        run_gdb(&options, port);
+        return;
+    }
+
+    if options.headless {
+        run_headless(&options);
+        return;
+    }
+
     let sdl_context = sdl2::init().unwrap();
     let sdl_video = sdl_context.video().unwrap();
     let mut sdl_events = sdl_context.event_pump().unwrap();
     let main_window = sdl_video.window("Rusty PSX - Main Window", 1280, 720).position_centered().opengl().resizable().build().unwrap();
     let _gl_context = main_window.gl_create_context().expect("Failed to create OpenGL context");
     gl::load_with(|s| sdl_video.gl_get_proc_address(s) as _);
-    sdl_video.gl_set_swap_interval(0).unwrap();
 
     // Init IMGUI
     let mut imgui_context = imgui::Context::create();
     let mut sdl2_imgui = imgui_sdl2::ImguiSdl2::new(&mut imgui_context, &main_window);
     let imgui_renderer = imgui_opengl_renderer::Renderer::new(&mut imgui_context, |s| sdl_video.gl_get_proc_address(s) as _);
 
-    let mut current_cpu = cpu::Cpu::new();
+    let mut audio_output = if options.audio_enabled {
+        match sdl_context.audio().and_then(|audio| audio::AudioOutput::new(&audio)) {
+            Ok(output) => Some(output),
+            Err(error) => {
+                println!("Couldn't open an audio device, continuing without sound: {}", error);
+                None
+            },
+        }
+    }
+    else {
+        None
+    };
+    let mut recording = options.record_wav.as_ref().and_then(|path| start_recording(path));
+
+    // The audio device paces emulation when there is one. Without it frames are tied to the
+    // display's refresh, or to a timer when vsync isn't available.
+    let vsync = audio_output.is_none() && sdl_video.gl_set_swap_interval(1).is_ok();
+    if !vsync {
+        sdl_video.gl_set_swap_interval(0).unwrap();
+    }
+    let mut next_frame = Instant::now();
+
+    let mut current_cpu = create_cpu(&options);
+    if let Some(path) = &options.load_state {
+        load_state(&mut current_cpu, path);
+    }
+    let mut input_mapper = input_mapping::InputMapper::new(Path::new("input.cfg"), sdl_context.game_controller().ok());
+    let mut show_input = false;
+    let mut frames_since_flush = 0;
     let mut show_debugger = false;
-    let mut cpu_stepping = false;
+    let mut disassembly_window = disassembly_window::DisassemblyWindow::new();
+    let mut breakpoints_window = breakpoints_window::BreakpointsWindow::new();
+    let mut registers_window = registers_window::RegistersWindow::new();
+    let mut memory_window = memory_window::MemoryWindow::new();
+    let mut show_memory_cards = false;
+    let mut memory_card_window = memcard_window::MemoryCardWindow::new();
+    let mut show_save_states = false;
+    let mut save_state_window = savestate_window::SaveStateWindow::new();
+    let mut state_request = None;
+    let mut rewind_buffer = rewind::RewindBuffer::new(options.rewind_interval, options.rewind_budget);
+    let mut rewind_requested = false;
+    // Where the debugger is running the CPU to, None while it runs freely.
+    let mut step_target = None;
     let mut last_cycle = cpu::CycleResult::None;
-    let mut cpu_breakpoint = ImString::with_capacity(8);
-    let mut range_start_str = ImString::with_capacity(8);
-    let mut range_end_str = ImString::with_capacity(8);
-    let mut range_start = 0;
-    let mut range_end = 0;
 
     'render_loop: loop {
         for event in sdl_events.poll_iter() {
             sdl2_imgui.handle_event(&mut imgui_context, &event);
             match event {
                 Event::Quit {..} => break 'render_loop,
-                _ => {}
+                Event::KeyDown {..} | Event::KeyUp {..} if sdl2_imgui.ignore_event(&event) => {},
+                Event::KeyDown { keycode: Some(REWIND_KEY), .. } => rewind_requested = true,
+                _ => input_mapper.handle_event(&event),
             }
         }
 
@@ -58,23 +647,63 @@ fn main() {
                 imgui_frame.text_colored([1.0, 0.0, 0.0, 1.0], "Couldn't find the BIOS file.");
             }
 
+            // The path stays set when the image couldn't be opened, only the CD-ROM knows it's in.
+            match &options.disc_path {
+                Some(path) if current_cpu.memory.cdrom.disc.is_some() => imgui_frame.text(format!("Disc: {}", path.display())),
+                Some(path) => imgui_frame.text_colored([1.0, 0.0, 0.0, 1.0], format!("Couldn't load disc: {}", path.display())),
+                None => imgui_frame.text("No disc inserted."),
+            }
+
+            imgui_frame.checkbox(im_str!("Fast boot"), &mut options.fast_boot);
+
             imgui_frame.spacing();
 
             if imgui_frame.button(im_str!("Start emulation"), [120.0, 20.0]) {
                 current_cpu.cpu_paused = false;
-                cpu_stepping = false;
+                step_target = None;
             }
             if imgui_frame.button(im_str!("Pause"), [120.0, 20.0]) {
                 current_cpu.cpu_paused = true;
+                step_target = None;
             }
             if imgui_frame.button(im_str!("Restart"), [120.0, 20.0]) {
-                current_cpu = cpu::Cpu::new();
-                cpu_stepping = false;
+                flush_memory_cards(&mut current_cpu);
+                // Finished before the new CPU starts the trace file over.
+                current_cpu.tracer = None;
+                current_cpu = create_cpu(&options);
+                rewind_buffer.clear();
+                step_target = None;
                 last_cycle = cpu::CycleResult::None;
             }
             imgui_frame.checkbox(im_str!("Show debugger"), &mut show_debugger);
+            imgui_frame.checkbox(im_str!("Show memory cards"), &mut show_memory_cards);
+            imgui_frame.checkbox(im_str!("Show save states"), &mut show_save_states);
+            imgui_frame.checkbox(im_str!("Show input settings"), &mut show_input);
+
+            imgui_frame.spacing();
+
+            if recording.is_none() {
+                if imgui_frame.button(im_str!("Record to WAV"), [120.0, 20.0]) {
+                    recording = start_recording(Path::new("recording.wav"));
+                }
+            }
+            else if imgui_frame.button(im_str!("Stop recording"), [120.0, 20.0]) {
+                stop_recording(recording.take());
+            }
         });
 
+        if show_input {
+            input_mapper.build_window(&imgui_frame);
+        }
+
+        if show_memory_cards {
+            memory_card_window.build(&imgui_frame, &mut current_cpu.memory.sio0);
+        }
+
+        if show_save_states {
+            state_request = save_state_window.build(&imgui_frame, options.disc_path.as_deref());
+        }
+
         if show_debugger {
             Window::new(im_str!("Rusty PSX - Debugger")).size([400.0, 400.0], Condition::FirstUseEver).build(&imgui_frame, || {
                 imgui_frame.text("Debugger Controls");
@@ -88,6 +717,13 @@ fn main() {
                         imgui_frame.text_colored([1.0, 1.0, 0.0, 1.0], "CPU found a breakpoint and stopped.");
                         current_cpu.cpu_paused = true;
                     },
+                    cpu::CycleResult::Watchpoint => {
+                        imgui_frame.text_colored([1.0, 1.0, 0.0, 1.0], "CPU hit a watchpoint and stopped.");
+                        if let Some(hit) = &current_cpu.watch_hit {
+                            imgui_frame.text_colored([1.0, 1.0, 0.0, 1.0], hit.describe());
+                        }
+                        current_cpu.cpu_paused = true;
+                    },
                     cpu::CycleResult::Error => {
                         imgui_frame.text_colored([1.0, 0.0, 0.0, 1.0], "CPU found an error and stopped.");
                         current_cpu.cpu_paused = true;
@@ -99,109 +735,61 @@ fn main() {
 
                 if imgui_frame.button(im_str!("Run"), [120.0, 20.0]) {
                     current_cpu.cpu_paused = false;
-                    cpu_stepping = false;
+                    step_target = None;
                 }
                 if imgui_frame.button(im_str!("Pause"), [120.0, 20.0]) {
                     current_cpu.cpu_paused = true;
                     last_cycle = cpu::CycleResult::None;
+                    step_target = None;
                 }
                 if imgui_frame.button(im_str!("CPU Step"), [120.0, 20.0]) {
                     current_cpu.cpu_paused = false;
-                    cpu_stepping = true;
+                    step_target = Some(StepTarget::Instruction);
                 }
-                if imgui_frame.input_text(im_str!("CPU Breakpoint"), &mut cpu_breakpoint).chars_hexadecimal(true).enter_returns_true(true).build() {
-                    let value = u32::from_str_radix(cpu_breakpoint.to_str(), 16).unwrap();
-                    current_cpu.debugger_breakpoints.push(value);
-                    cpu_breakpoint = ImString::with_capacity(8);
+                imgui_frame.same_line(0.0);
+                if imgui_frame.button(im_str!("Step Over"), [120.0, 20.0]) {
+                    current_cpu.cpu_paused = false;
+                    step_target = Some(StepTarget::step_over(&current_cpu));
+                }
+                if imgui_frame.button(im_str!("Step Out"), [120.0, 20.0]) {
+                    current_cpu.cpu_paused = false;
+                    step_target = Some(StepTarget::step_out(&current_cpu));
                 }
+                if imgui_frame.button(im_str!("Rewind"), [120.0, 20.0]) {
+                    rewind_requested = true;
+                }
+                imgui_frame.text(format!("Rewind: {} snapshots, {:.1} of {} MB", rewind_buffer.len(), rewind_buffer.used_bytes() as f32 / (1024.0 * 1024.0), rewind_buffer.budget / (1024 * 1024)));
                 
-                let mut instruction = String::from("Instruction: ");
-                instruction.push_str(instructions_decoder::get_instruction_info(&current_cpu.current_instruction).as_str());
-                imgui_frame.text(instruction);
-                imgui_frame.spacing();
-                imgui_frame.separator();
-                imgui_frame.text("R3000A Main Registers");
-                imgui_frame.separator();
-                imgui_frame.spacing();
-                imgui_frame.columns(4, im_str!("Register Columns"), false);
-                imgui_frame.text(format!("r0 {:08X}", current_cpu.registers[0])); imgui_frame.text(format!("r1 {:08X}", current_cpu.registers[1]));
-                imgui_frame.text(format!("r2 {:08X}", current_cpu.registers[2])); imgui_frame.text(format!("r3 {:08X}", current_cpu.registers[3]));
-                imgui_frame.text(format!("r4 {:08X}", current_cpu.registers[4])); imgui_frame.text(format!("r5 {:08X}", current_cpu.registers[5]));
-                imgui_frame.text(format!("r6 {:08X}", current_cpu.registers[6])); imgui_frame.text(format!("r7 {:08X}", current_cpu.registers[7]));
-                imgui_frame.next_column();
-                imgui_frame.text(format!("r8 {:08X}", current_cpu.registers[8])); imgui_frame.text(format!("r9 {:08X}", current_cpu.registers[9]));
-                imgui_frame.text(format!("r10 {:08X}", current_cpu.registers[10])); imgui_frame.text(format!("r11 {:08X}", current_cpu.registers[11]));
-                imgui_frame.text(format!("r12 {:08X}", current_cpu.registers[12])); imgui_frame.text(format!("r13 {:08X}", current_cpu.registers[13]));
-                imgui_frame.text(format!("r14 {:08X}", current_cpu.registers[14])); imgui_frame.text(format!("r15 {:08X}", current_cpu.registers[15]));
-                imgui_frame.next_column();
-                imgui_frame.text(format!("r16 {:08X}", current_cpu.registers[16])); imgui_frame.text(format!("r17 {:08X}", current_cpu.registers[17]));
-                imgui_frame.text(format!("r18 {:08X}", current_cpu.registers[18])); imgui_frame.text(format!("r19 {:08X}", current_cpu.registers[19]));
-                imgui_frame.text(format!("r20 {:08X}", current_cpu.registers[20])); imgui_frame.text(format!("r21 {:08X}", current_cpu.registers[21]));
-                imgui_frame.text(format!("r22 {:08X}", current_cpu.registers[22])); imgui_frame.text(format!("r23 {:08X}", current_cpu.registers[23]));
-                imgui_frame.next_column();
-                imgui_frame.text(format!("r24 {:08X}", current_cpu.registers[24])); imgui_frame.text(format!("r25 {:08X}", current_cpu.registers[25]));
-                imgui_frame.text(format!("r26 {:08X}", current_cpu.registers[26])); imgui_frame.text(format!("r27 {:08X}", current_cpu.registers[27]));
-                imgui_frame.text(format!("r28 {:08X}", current_cpu.registers[28])); imgui_frame.text(format!("r29 {:08X}", current_cpu.registers[29]));
-                imgui_frame.text(format!("r30 {:08X}", current_cpu.registers[30])); imgui_frame.text(format!("r31 {:08X}", current_cpu.registers[31]));
-                imgui_frame.next_column();
-                imgui_frame.spacing();
-                imgui_frame.text(format!("PC {:08X}", current_cpu.pc));
-                imgui_frame.next_column();
-                imgui_frame.spacing();
-                imgui_frame.text(format!("hi {:08X}", current_cpu.hi));
-                imgui_frame.next_column();
-                imgui_frame.spacing();
-                imgui_frame.text(format!("lo {:08X}", current_cpu.lo));
-                imgui_frame.spacing();
-                imgui_frame.next_column(); imgui_frame.next_column();
-            });
-
-            Window::new(im_str!("Rusty PSX - Memory Viewer")).size([470.0, 300.0], Condition::FirstUseEver).build(&imgui_frame, || {
-
-                imgui_frame.input_text(im_str!("Memory Range Start"), &mut range_start_str).chars_hexadecimal(true).build();
-                if imgui_frame.input_text(im_str!("Memory Range End"), &mut range_end_str).chars_hexadecimal(true).enter_returns_true(true).build() {
-                    range_start = u32::from_str_radix(range_start_str.to_str(), 16).unwrap();
-                    range_end = u32::from_str_radix(range_end_str.to_str(), 16).unwrap();
+                // The delay slot was fetched along with the branch, anything else is peeked where the PC
+                // points, so drawing this doesn't trip watchpoints or touch I/O registers.
+                let next_instruction = if current_cpu.branch_delay {
+                    Some(current_cpu.next_instruction.value())
                 }
-
-                imgui_frame.spacing();
-                imgui_frame.text_colored([1.0, 0.0, 0.0, 1.0], "High ranges can cause low performance on the debugger.");
-                imgui_frame.spacing();
-                imgui_frame.separator();
-
-                if range_end > range_start {
-                    imgui_frame.columns(17, im_str!("Mem Viewer Columns"), false);
-                    imgui_frame.set_column_width(0, 70.0);
-                    imgui_frame.set_column_width(1, 23.0);
-                    imgui_frame.set_column_width(2, 23.0);
-                    imgui_frame.set_column_width(3, 23.0);
-                    imgui_frame.set_column_width(4, 23.0);
-                    imgui_frame.set_column_width(5, 23.0);
-                    imgui_frame.set_column_width(6, 23.0);
-                    imgui_frame.set_column_width(7, 23.0);
-                    imgui_frame.set_column_width(8, 23.0);
-                    imgui_frame.set_column_width(9, 23.0);
-                    imgui_frame.set_column_width(10, 23.0);
-                    imgui_frame.set_column_width(11, 23.0);
-                    imgui_frame.set_column_width(12, 23.0);
-                    imgui_frame.set_column_width(13, 23.0);
-                    imgui_frame.set_column_width(14, 23.0);
-                    imgui_frame.set_column_width(15, 23.0);
-                    imgui_frame.set_column_width(16, 23.0);
-
-                    let mut address = range_start;
-
-                    while address < range_end {
-                        imgui_frame.text(format!("{:08X}", address));
-                        for offset in 0..16 {
-                            imgui_frame.next_column();
-                            imgui_frame.text(format!("{:02X}", current_cpu.memory.read_byte(address + offset)));
-                        }
-                        address += 16;
-                        imgui_frame.next_column();
-                    }
+                else if current_cpu.pc & 3 != 0 {
+                    None
                 }
+                else {
+                    current_cpu.memory.peek_word(current_cpu.pc)
+                };
+                let disassembly = match next_instruction {
+                    Some(instruction) => instructions_decoder::disassemble(instruction, current_cpu.current_pc()),
+                    None if current_cpu.pc & 3 != 0 => String::from("unaligned"),
+                    None => String::from("--------"),
+                };
+                imgui_frame.text(format!("Instruction: {:08X} {}", current_cpu.current_pc(), disassembly));
             });
+
+            if let Some(address) = disassembly_window.build(&imgui_frame, &mut current_cpu) {
+                current_cpu.cpu_paused = false;
+                step_target = Some(StepTarget::Address(address));
+            }
+            registers_window.build(&imgui_frame, &mut current_cpu);
+            if let Some(address) = call_stack_window::build(&imgui_frame, &current_cpu) {
+                disassembly_window.show(address);
+            }
+            breakpoints_window.build(&imgui_frame, &mut current_cpu.breakpoints, &mut current_cpu.memory.watchpoints);
+
+            memory_window.build(&imgui_frame, &mut current_cpu);
         }
 
         unsafe {
@@ -210,13 +798,94 @@ fn main() {
         }
       
         imgui_renderer.render(imgui_frame);
+
+        match state_request.take() {
+            Some(savestate_window::Request::Save(slot)) => {
+                let path = savestate::slot_path(options.disc_path.as_deref(), slot);
+                let (width, height) = main_window.drawable_size();
+                let thumbnail = savestate_window::capture_thumbnail(width, height);
+
+                save_state_window.set_status(match savestate::save(&path, &mut current_cpu, &thumbnail) {
+                    Ok(()) => format!("Saved slot {}", slot),
+                    Err(error) => format!("Couldn't save slot {}: {}", slot, error),
+                });
+                save_state_window.refresh();
+            },
+            Some(savestate_window::Request::Load(slot)) => {
+                let path = savestate::slot_path(options.disc_path.as_deref(), slot);
+
+                save_state_window.set_status(match savestate::load(&path, &mut current_cpu) {
+                    Ok(()) => {
+                        last_cycle = cpu::CycleResult::None;
+                        format!("Loaded slot {}", slot)
+                    },
+                    Err(error) => format!("Couldn't load slot {}: {}", slot, error),
+                });
+            },
+            None => {},
+        }
+
         main_window.gl_swap_window();
 
+        current_cpu.memory.sio0.update_input(&input_mapper.state());
+
+        if rewind_requested {
+            rewind_requested = false;
+
+            match rewind_buffer.rewind(&mut current_cpu) {
+                Ok(true) => last_cycle = cpu::CycleResult::None,
+                Ok(false) => println!("Nothing left to rewind"),
+                Err(error) => println!("Couldn't rewind: {}", error),
+            }
+        }
+
+        match audio_output.as_ref() {
+            Some(output) => output.wait_for_room(),
+            None if !vsync => wait_for_frame(&mut next_frame),
+            None => {},
+        }
+
         if !current_cpu.cpu_paused {
-            last_cycle = current_cpu.run_instruction();
-            if cpu_stepping {
+            // Run a frame worth of instructions, unless the CPU gets where it's stepping to or stops on its own.
+            let mut reached = false;
+            for _ in 0..INSTRUCTIONS_PER_FRAME {
+                last_cycle = current_cpu.run_instruction();
+                if last_cycle != cpu::CycleResult::Success {
+                    step_target = None;
+                    break;
+                }
+                if let Some(target) = step_target {
+                    if target.reached(&current_cpu) {
+                        reached = true;
+                        break;
+                    }
+                }
+            }
+            if reached {
                 current_cpu.cpu_paused = true;
+                step_target = None;
+            }
+            else {
+                rewind_buffer.frame_finished(&current_cpu);
             }
         }
+
+        input_mapper.set_rumble([current_cpu.memory.sio0.rumble(0), current_cpu.memory.sio0.rumble(1)]);
+
+        let samples: Vec<(i16, i16)> = current_cpu.memory.spu.output.drain(..).collect();
+        if let Some(output) = audio_output.as_mut() {
+            output.queue(&samples);
+        }
+        record_samples(&mut recording, &samples);
+
+        // Save memory cards about once a second, rather than on every sector a game writes.
+        frames_since_flush += 1;
+        if frames_since_flush == 60 {
+            frames_since_flush = 0;
+            flush_memory_cards(&mut current_cpu);
+        }
     }
+
+    stop_recording(recording);
+    flush_memory_cards(&mut current_cpu);
 }
\ No newline at end of file
@@ -0,0 +1,503 @@
+use std::io;
+use std::collections::VecDeque;
+
+use super::savestate::{StateReader, StateWriter};
+
+// Order the run-length decoded coefficients come in, mapped to their position in the 8x8 block.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10,
+    17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+const END_OF_BLOCK: u16 = 0xFE00;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Depth {
+    Bits4 = 0,
+    Bits8 = 1,
+    Bits24 = 2,
+    Bits15 = 3,
+}
+
+impl Depth {
+    fn from_bits(bits: u32) -> Depth {
+        match bits & 3 {
+            0 => Depth::Bits4,
+            1 => Depth::Bits8,
+            2 => Depth::Bits24,
+            _ => Depth::Bits15,
+        }
+    }
+}
+
+// The motion decoder, MDEC0 (commands and data) and MDEC1 (control and status) at 0x1F801820/24.
+// Macroblocks are decoded as soon as all of a command's data has arrived, with integer maths
+// only, so the output is the same on every run and every host.
+pub struct Mdec {
+    luma_quant: [u8; 64],
+    chroma_quant: [u8; 64],
+    scale: [i16; 64],
+
+    command: u32,
+    // Parameter words still expected by the current command.
+    remaining: u32,
+    parameters: Vec<u32>,
+    output: VecDeque<u32>,
+    current_block: u32,
+
+    dma_in_enabled: bool,
+    dma_out_enabled: bool,
+}
+
+impl Mdec {
+    pub fn new() -> Mdec {
+        Mdec {
+            luma_quant: [0; 64],
+            chroma_quant: [0; 64],
+            scale: [0; 64],
+
+            command: 0,
+            remaining: 0,
+            parameters: Vec::new(),
+            output: VecDeque::new(),
+            current_block: 4,
+
+            dma_in_enabled: false,
+            dma_out_enabled: false,
+        }
+    }
+
+    fn depth(&self) -> Depth {
+        Depth::from_bits(self.command >> 27)
+    }
+
+    fn signed(&self) -> bool {
+        self.command & (1 << 26) != 0
+    }
+
+    fn set_bit15(&self) -> bool {
+        self.command & (1 << 25) != 0
+    }
+
+    pub fn status(&self) -> u32 {
+        let mut status = 0;
+
+        if self.output.is_empty() {
+            status |= 1 << 31;
+        }
+        if self.remaining > 0 {
+            status |= 1 << 29;
+
+            if self.dma_in_enabled {
+                status |= 1 << 28;
+            }
+        }
+        if self.dma_out_enabled && !self.output.is_empty() {
+            status |= 1 << 27;
+        }
+
+        status |= ((self.command >> 25) & 0x0F) << 23;
+        status |= (self.current_block & 7) << 16;
+        status | (self.remaining.wrapping_sub(1) & 0xFFFF)
+    }
+
+    pub fn output_len(&self) -> usize {
+        self.output.len()
+    }
+
+    pub fn read_data(&mut self) -> u32 {
+        self.output.pop_front().unwrap_or(0)
+    }
+
+    pub fn write_control(&mut self, value: u32) {
+        if value & (1 << 31) != 0 {
+            self.command = 0;
+            self.remaining = 0;
+            self.parameters.clear();
+            self.output.clear();
+            self.current_block = 4;
+        }
+
+        self.dma_in_enabled = value & (1 << 30) != 0;
+        self.dma_out_enabled = value & (1 << 29) != 0;
+    }
+
+    pub fn write_command(&mut self, value: u32) {
+        if self.remaining > 0 {
+            self.parameters.push(value);
+            self.remaining -= 1;
+
+            if self.remaining == 0 {
+                self.run_command();
+            }
+            return;
+        }
+
+        self.command = value;
+        self.parameters.clear();
+
+        self.remaining = match value >> 29 {
+            1 => value & 0xFFFF,
+            2 => if value & 1 != 0 { 32 } else { 16 },
+            3 => 32,
+            _ => 0,
+        };
+
+        if self.remaining == 0 {
+            self.run_command();
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"MDEC");
+        state.write_bytes(&self.luma_quant);
+        state.write_bytes(&self.chroma_quant);
+        state.write_i16s(&self.scale);
+
+        state.write_u32(self.command);
+        state.write_u32(self.remaining);
+        state.write_u32s(&self.parameters);
+        state.write_u32s(&self.output.iter().cloned().collect::<Vec<u32>>());
+        state.write_u32(self.current_block);
+
+        state.write_bool(self.dma_in_enabled);
+        state.write_bool(self.dma_out_enabled);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"MDEC")?;
+        state.read_bytes_into(&mut self.luma_quant)?;
+        state.read_bytes_into(&mut self.chroma_quant)?;
+        state.read_i16s_into(&mut self.scale)?;
+
+        self.command = state.read_u32()?;
+        self.remaining = state.read_u32()?;
+        self.parameters = state.read_u32s()?;
+        self.output = state.read_u32s()?.into_iter().collect();
+        self.current_block = state.read_u32()?;
+
+        self.dma_in_enabled = state.read_bool()?;
+        self.dma_out_enabled = state.read_bool()?;
+        Ok(())
+    }
+
+    fn run_command(&mut self) {
+        match self.command >> 29 {
+            1 => self.decode_macroblocks(),
+            2 => {
+                let bytes: Vec<u8> = self.parameters.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect();
+                self.luma_quant.copy_from_slice(&bytes[..64]);
+                if bytes.len() == 128 {
+                    self.chroma_quant.copy_from_slice(&bytes[64..]);
+                }
+            },
+            3 => {
+                for (index, word) in self.parameters.iter().enumerate() {
+                    self.scale[index * 2] = *word as i16;
+                    self.scale[index * 2 + 1] = (*word >> 16) as i16;
+                }
+            },
+            _ => {},
+        }
+
+        self.parameters.clear();
+    }
+
+    fn decode_macroblocks(&mut self) {
+        let data: Vec<u16> = self.parameters.iter().flat_map(|word| vec![*word as u16, (*word >> 16) as u16]).collect();
+        let mut position = 0;
+
+        loop {
+            match self.depth() {
+                Depth::Bits4 | Depth::Bits8 => {
+                    let mut luma = [0; 64];
+                    self.current_block = 0;
+
+                    if !self.decode_block(&data, &mut position, &mut luma, false) {
+                        break;
+                    }
+                    self.output_mono(&luma);
+                },
+                Depth::Bits15 | Depth::Bits24 => {
+                    let mut blocks = [[0; 64]; 6];
+
+                    // Cr and Cb come first, then the four luma blocks.
+                    for (index, block) in blocks.iter_mut().enumerate() {
+                        self.current_block = match index {
+                            0 => 4,
+                            1 => 5,
+                            _ => index as u32 - 2,
+                        };
+
+                        if !self.decode_block(&data, &mut position, block, index < 2) {
+                            self.current_block = 4;
+                            return;
+                        }
+                    }
+
+                    self.output_colour(&blocks);
+                },
+            }
+        }
+
+        self.current_block = 4;
+    }
+
+    // Decodes one run-length coded block and runs the IDCT on it. Returns false once the data runs out.
+    fn decode_block(&self, data: &[u16], position: &mut usize, block: &mut [i32; 64], chroma: bool) -> bool {
+        let quant = if chroma { &self.chroma_quant } else { &self.luma_quant };
+
+        // Padding between macroblocks.
+        while data.get(*position) == Some(&END_OF_BLOCK) {
+            *position += 1;
+        }
+
+        let mut value = match data.get(*position) {
+            Some(value) => *value,
+            None => return false,
+        };
+        *position += 1;
+
+        let mut coefficients = [0; 64];
+        let scale = (value >> 10) as i32 & 0x3F;
+        let mut k = 0;
+        let mut level = signed_10bit(value) * quant[0] as i32;
+
+        loop {
+            if scale == 0 {
+                level = signed_10bit(value) * 2;
+            }
+
+            let level_clamped = level.clamp(-0x400, 0x3FF);
+            if scale > 0 {
+                coefficients[ZIGZAG[k]] = level_clamped;
+            }
+            else {
+                coefficients[k] = level_clamped;
+            }
+
+            value = match data.get(*position) {
+                Some(value) => *value,
+                None => return false,
+            };
+            *position += 1;
+
+            k += (value >> 10) as usize + 1;
+            if k > 63 {
+                break;
+            }
+
+            level = (signed_10bit(value) * quant[k] as i32 * scale + 4) / 8;
+        }
+
+        *block = self.idct(&coefficients);
+        true
+    }
+
+    fn idct(&self, coefficients: &[i32; 64]) -> [i32; 64] {
+        let mut source = *coefficients;
+        let mut destination = [0; 64];
+
+        for _ in 0..2 {
+            for x in 0..8 {
+                for y in 0..8 {
+                    let mut sum = 0;
+                    for z in 0..8 {
+                        sum += source[y + z * 8] * (self.scale[x + z * 8] as i32 / 8);
+                    }
+                    destination[x + y * 8] = (sum + 0xFFF) >> 13;
+                }
+            }
+
+            std::mem::swap(&mut source, &mut destination);
+        }
+
+        source
+    }
+
+    fn output_mono(&mut self, luma: &[i32; 64]) {
+        let mut pixels: Vec<u8> = luma.iter().map(|value| {
+            let value = clamp_signed(*value) as u8;
+            if self.signed() { value } else { value ^ 0x80 }
+        }).collect();
+
+        if self.depth() == Depth::Bits4 {
+            pixels = pixels.chunks(2).map(|pair| (pair[0] >> 4) | (pair[1] & 0xF0)).collect();
+        }
+
+        self.push_bytes(&pixels);
+    }
+
+    fn output_colour(&mut self, blocks: &[[i32; 64]; 6]) {
+        let (chroma_red, chroma_blue) = (&blocks[0], &blocks[1]);
+        let mut pixels = Vec::with_capacity(16 * 16 * 3);
+
+        for y in 0..16 {
+            for x in 0..16 {
+                let luma = &blocks[2 + (y / 8) * 2 + x / 8];
+                let luma = luma[(x % 8) + (y % 8) * 8];
+                let red = chroma_red[x / 2 + (y / 2) * 8];
+                let blue = chroma_blue[x / 2 + (y / 2) * 8];
+
+                // 1.402 Cr, -0.3437 Cb - 0.7143 Cr and 1.772 Cb, in 8.8 fixed point.
+                let r = clamp_signed(luma + ((359 * red) >> 8));
+                let g = clamp_signed(luma + ((-88 * blue - 183 * red) >> 8));
+                let b = clamp_signed(luma + ((454 * blue) >> 8));
+
+                let flip = if self.signed() { 0 } else { 0x80 };
+                pixels.push(r as u8 ^ flip);
+                pixels.push(g as u8 ^ flip);
+                pixels.push(b as u8 ^ flip);
+            }
+        }
+
+        if self.depth() == Depth::Bits24 {
+            self.push_bytes(&pixels);
+        }
+        else {
+            let bit15 = if self.set_bit15() { 0x8000 } else { 0 };
+            let colours: Vec<u8> = pixels.chunks(3).flat_map(|rgb| {
+                let colour = (rgb[0] as u16 >> 3) | (rgb[1] as u16 >> 3) << 5 | (rgb[2] as u16 >> 3) << 10 | bit15;
+                vec![colour as u8, (colour >> 8) as u8]
+            }).collect();
+            self.push_bytes(&colours);
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for word in bytes.chunks(4) {
+            let mut value = 0;
+            for (index, byte) in word.iter().enumerate() {
+                value |= (*byte as u32) << (index * 8);
+            }
+            self.output.push_back(value);
+        }
+    }
+}
+
+fn signed_10bit(value: u16) -> i32 {
+    ((value as i32) << 22) >> 22
+}
+
+fn clamp_signed(value: i32) -> i32 {
+    value.clamp(-128, 127)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A DC coefficient of 0x100 with a quantizer scale of 1, then the end of the block.
+    const DC_BLOCK: u32 = 0xFE00_0500;
+    const EMPTY_BLOCK: u32 = 0xFE00_0400;
+
+    // All quantizer values at 1 and a scale table whose first row is the DC basis, 0x5A82, with
+    // the rest left at zero. A DC-only block then comes out flat: 0x100 * 0x5A82 / 8 >> 13 gives 91
+    // after the first pass and 32 after the second.
+    fn mdec() -> Mdec {
+        let mut mdec = Mdec::new();
+
+        mdec.write_command(0x4000_0001);
+        for _ in 0..32 {
+            mdec.write_command(0x0101_0101);
+        }
+
+        mdec.write_command(0x6000_0000);
+        for index in 0..32 {
+            mdec.write_command(if index < 4 { 0x5A82_5A82 } else { 0 });
+        }
+
+        mdec
+    }
+
+    fn decode(mdec: &mut Mdec, command: u32, data: &[u32]) -> Vec<u8> {
+        mdec.write_command(command | data.len() as u32);
+        for word in data.iter() {
+            mdec.write_command(*word);
+        }
+
+        (0..mdec.output_len()).flat_map(|_| mdec.read_data().to_le_bytes().to_vec()).collect()
+    }
+
+    // Cr at 32, Cb at 0 and the luma at 32: R = 32 + 359 * 32 >> 8, G = 32 + (-183 * 32 >> 8), B = 32.
+    const MACROBLOCK: [u32; 6] = [DC_BLOCK, EMPTY_BLOCK, DC_BLOCK, DC_BLOCK, DC_BLOCK, DC_BLOCK];
+    const RGB: [u8; 3] = [76, 9, 32];
+
+    #[test]
+    fn dc_only_macroblock_to_24bit() {
+        let mut mdec = mdec();
+
+        let unsigned = decode(&mut mdec, 0x3000_0000, &MACROBLOCK);
+        assert_eq!(unsigned.len(), 16 * 16 * 3);
+        assert!(unsigned.chunks(3).all(|rgb| rgb == [76 ^ 0x80, 9 ^ 0x80, 32 ^ 0x80]));
+
+        let signed = decode(&mut mdec, 0x3400_0000, &MACROBLOCK);
+        assert!(signed.chunks(3).all(|rgb| rgb == RGB));
+    }
+
+    #[test]
+    fn macroblock_to_15bit() {
+        let mut mdec = mdec();
+
+        // 0xCC, 0x89 and 0xA0 with the low three bits dropped.
+        let colour: u16 = 25 | 17 << 5 | 20 << 10;
+
+        let pixels = decode(&mut mdec, 0x3800_0000, &MACROBLOCK);
+        assert_eq!(pixels.len(), 16 * 16 * 2);
+        assert!(pixels.chunks(2).all(|pixel| pixel == colour.to_le_bytes()));
+
+        let pixels = decode(&mut mdec, 0x3A00_0000, &MACROBLOCK);
+        assert!(pixels.chunks(2).all(|pixel| pixel == (colour | 0x8000).to_le_bytes()));
+    }
+
+    #[test]
+    fn monochrome_blocks_to_8bit_and_4bit() {
+        let mut mdec = mdec();
+
+        let pixels = decode(&mut mdec, 0x2800_0000, &[DC_BLOCK]);
+        assert_eq!(pixels, [32 ^ 0x80; 64]);
+
+        let pixels = decode(&mut mdec, 0x2C00_0000, &[DC_BLOCK]);
+        assert_eq!(pixels, [32; 64]);
+
+        // Two pixels per byte, the first in the low nibble.
+        let pixels = decode(&mut mdec, 0x2000_0000, &[DC_BLOCK]);
+        assert_eq!(pixels, [0xAA; 32]);
+    }
+
+    #[test]
+    fn status_bits() {
+        let mut mdec = mdec();
+
+        // Output empty, current block 4 and no parameters left.
+        assert_eq!(mdec.status(), 0x8004_FFFF);
+
+        mdec.write_control(0x6000_0000);
+        mdec.write_command(0x3A00_0000 | MACROBLOCK.len() as u32);
+
+        // Busy, wanting data in, with bit 15, signed and the depth from the command in bits 23-26.
+        assert_eq!(mdec.status(), 0x8000_0000 | 0x3000_0000 | 0x0680_0000 | 0x0004_0000 | 5);
+
+        for word in MACROBLOCK.iter().take(3) {
+            mdec.write_command(*word);
+        }
+        assert_eq!(mdec.status() & 0xFFFF, 2);
+
+        for word in MACROBLOCK.iter().skip(3) {
+            mdec.write_command(*word);
+        }
+
+        // Data out request now there's output.
+        assert_eq!(mdec.status(), 0x0800_0000 | 0x0680_0000 | 0x0004_FFFF);
+
+        mdec.write_control(0x8000_0000);
+        assert_eq!(mdec.status(), 0x8004_FFFF);
+        assert_eq!(mdec.output_len(), 0);
+    }
+}
@@ -0,0 +1,203 @@
+use imgui::*;
+
+use super::sio::Sio0;
+use super::sio::directory;
+use super::sio::memcard::MemoryCard;
+
+const ICON_SIZE: i32 = 16;
+// Icons change frame every 16 video frames, like the BIOS browser.
+const ICON_FRAME_LENGTH: u32 = 16;
+const MAX_ICON_FRAMES: usize = 3;
+
+// The saves on a card, kept until the card changes.
+struct CardView {
+    revision: u32,
+    saves: Vec<directory::Save>,
+}
+
+// Memory card manager, working on the cards inserted in the controller ports.
+pub struct MemoryCardWindow {
+    active_port: usize,
+    selected: Option<usize>,
+    export_path: ImString,
+    status: String,
+
+    views: [Option<CardView>; 2],
+    // A texture for every icon frame of every block of both cards, created on first use.
+    textures: Vec<u32>,
+    frame: u32,
+}
+
+impl MemoryCardWindow {
+    pub fn new() -> MemoryCardWindow {
+        MemoryCardWindow {
+            active_port: 0,
+            selected: None,
+            export_path: ImString::with_capacity(256),
+            status: String::new(),
+
+            views: [None, None],
+            textures: Vec::new(),
+            frame: 0,
+        }
+    }
+
+    fn icon_texture(&self, port: usize, block: usize, frame: usize) -> TextureId {
+        TextureId::from(self.textures[(port * directory::BLOCK_COUNT + block) * MAX_ICON_FRAMES + frame] as usize)
+    }
+
+    fn upload_icon(&mut self, port: usize, block: usize, frame: usize, pixels: &[u32]) {
+        if self.textures.is_empty() {
+            self.textures = vec![0; 2 * directory::BLOCK_COUNT * MAX_ICON_FRAMES];
+            unsafe {
+                gl::GenTextures(self.textures.len() as i32, self.textures.as_mut_ptr());
+            }
+        }
+
+        let texture = self.textures[(port * directory::BLOCK_COUNT + block) * MAX_ICON_FRAMES + frame];
+
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA as i32, ICON_SIZE, ICON_SIZE, 0, gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_ptr() as _);
+        }
+    }
+
+    // Lists the saves on the card in `port` again and uploads their icons, if it changed since last time.
+    fn refresh(&mut self, port: usize, card: Option<&MemoryCard>) {
+        let card = match card {
+            Some(card) => card,
+            None => {
+                self.views[port] = None;
+                return;
+            },
+        };
+
+        if self.views[port].as_ref().map(|view| view.revision) == Some(card.revision()) {
+            return;
+        }
+
+        let saves = directory::list_saves(&card.data);
+        for save in saves.iter() {
+            for (frame, pixels) in save.icon.iter().take(MAX_ICON_FRAMES).enumerate() {
+                self.upload_icon(port, save.first_block(), frame, pixels);
+            }
+        }
+
+        self.views[port] = Some(CardView {
+            revision: card.revision(),
+            saves,
+        });
+    }
+
+    pub fn build(&mut self, ui: &Ui, sio0: &mut Sio0) {
+        self.frame = self.frame.wrapping_add(1);
+
+        for port in 0..2 {
+            self.refresh(port, sio0.ports[port].memory_card.as_ref());
+        }
+
+        Window::new(im_str!("Rusty PSX - Memory Cards")).size([420.0, 500.0], Condition::FirstUseEver).build(ui, || {
+            for port in 0..2 {
+                let (card, view) = match (sio0.ports[port].memory_card.as_ref(), self.views[port].as_ref()) {
+                    (Some(card), Some(view)) => (card, view),
+                    _ => {
+                        ui.text(format!("Port {}: no memory card", port + 1));
+                        ui.separator();
+                        continue;
+                    },
+                };
+
+                ui.text(format!("Port {}: {} free blocks", port + 1, directory::free_blocks(&card.data)));
+                ui.separator();
+
+                for (index, save) in view.saves.iter().enumerate() {
+                    if !save.icon.is_empty() {
+                        let icon_frame = (self.frame / ICON_FRAME_LENGTH) as usize % save.icon.len().min(MAX_ICON_FRAMES);
+                        Image::new(self.icon_texture(port, save.first_block(), icon_frame), [32.0, 32.0]).build(ui);
+                        ui.same_line(0.0);
+                    }
+
+                    let label = ImString::new(format!("{}{}\n{} ({} blocks)##{}-{}", if save.deleted { "[Deleted] " } else { "" }, save.title, save.file_name, save.blocks.len(), port, index));
+                    let selected = self.active_port == port && self.selected == Some(index);
+
+                    if Selectable::new(&label).selected(selected).build(ui) {
+                        self.active_port = port;
+                        self.selected = Some(index);
+                    }
+                }
+
+                ui.spacing();
+            }
+
+            ui.separator();
+
+            let other_port = 1 - self.active_port;
+            if ui.button(&ImString::new(format!("Copy to port {}", other_port + 1)), [120.0, 20.0]) {
+                self.run(sio0, |card, other, save| {
+                    match other {
+                        Some(destination) => {
+                            directory::copy(&card.data, save, &mut destination.data)?;
+                            destination.modified();
+                            Ok(format!("Copied {}", save.file_name))
+                        },
+                        None => Ok(String::from("There's no card to copy to")),
+                    }
+                });
+            }
+            ui.same_line(0.0);
+            if ui.button(im_str!("Delete"), [80.0, 20.0]) {
+                self.run(sio0, |card, _, save| {
+                    directory::delete(&mut card.data, save);
+                    card.modified();
+                    Ok(format!("Deleted {}", save.file_name))
+                });
+            }
+            ui.same_line(0.0);
+            if ui.button(im_str!("Undelete"), [80.0, 20.0]) {
+                self.run(sio0, |card, _, save| {
+                    directory::undelete(&mut card.data, save)?;
+                    card.modified();
+                    Ok(format!("Restored {}", save.file_name))
+                });
+            }
+
+            ui.input_text(im_str!("Export path"), &mut self.export_path).build();
+            if ui.button(im_str!("Export save"), [120.0, 20.0]) {
+                let path = std::path::PathBuf::from(self.export_path.to_str());
+                self.run(sio0, |card, _, save| directory::export(&card.data, save, &path).map(|_| format!("Exported {} to {}", save.file_name, path.display())));
+            }
+
+            if !self.status.is_empty() {
+                ui.text_wrapped(&ImString::new(self.status.clone()));
+            }
+        });
+    }
+
+    // Runs an action on the selected save, passing the card it's on and the card in the other port.
+    fn run<F>(&mut self, sio0: &mut Sio0, action: F) where F: FnOnce(&mut MemoryCard, Option<&mut MemoryCard>, &directory::Save) -> std::io::Result<String> {
+        let (first, second) = sio0.ports.split_at_mut(1);
+        let (card, other) = if self.active_port == 0 { (&mut first[0], &mut second[0]) } else { (&mut second[0], &mut first[0]) };
+
+        let card = match card.memory_card.as_mut() {
+            Some(card) => card,
+            None => return,
+        };
+
+        let save = match self.selected.and_then(|index| directory::list_saves(&card.data).into_iter().nth(index)) {
+            Some(save) => save,
+            None => {
+                self.status = String::from("Select a save first");
+                return;
+            },
+        };
+
+        self.status = match action(card, other.memory_card.as_mut(), &save) {
+            Ok(message) => message,
+            Err(error) => error.to_string(),
+        };
+
+        self.selected = None;
+    }
+}
@@ -1,8 +1,26 @@
+use std::io;
+
 use byteorder::{ByteOrder, LittleEndian};
 
+use super::dma;
+use super::dma::{Direction, Dma};
+use super::spu::Spu;
+use super::cdrom::Cdrom;
+use super::sio::Sio0;
+use super::mdec::Mdec;
+use super::interrupts::{Interrupt, InterruptController};
+use super::watchpoints::{Access, Watchpoints};
+use super::savestate::{StateReader, StateWriter};
+
 pub const RAM: MemoryRegion = MemoryRegion(0x00000000, 2048 * 1024);
 pub const EXPANSION_1: MemoryRegion = MemoryRegion(0x1F000000, 8192 * 1024);
 pub const SCRATCH: MemoryRegion = MemoryRegion(0x1F800000, 1024);
+pub const SIO0_REGISTERS: MemoryRegion = MemoryRegion(0x1F801040, 0x10);
+pub const INTERRUPT_REGISTERS: MemoryRegion = MemoryRegion(0x1F801070, 8);
+pub const DMA_REGISTERS: MemoryRegion = MemoryRegion(0x1F801080, 0x80);
+pub const CDROM_REGISTERS: MemoryRegion = MemoryRegion(0x1F801800, 4);
+pub const MDEC_REGISTERS: MemoryRegion = MemoryRegion(0x1F801820, 8);
+pub const SPU_REGISTERS: MemoryRegion = MemoryRegion(0x1F801C00, 0x400);
 pub const IO_PORTS: MemoryRegion = MemoryRegion(0x1F801000, 8192);
 pub const EXPANSION_2: MemoryRegion = MemoryRegion(0x1F802000, 8192);
 pub const EXPANSION_3: MemoryRegion = MemoryRegion(0x1FA00000, 2048 * 1024);
@@ -35,6 +53,15 @@ pub struct CpuMemory {
     pub expansion_3: Vec<u8>,
     pub bios: Vec<u8>,
     pub cache_control: Vec<u8>,
+
+    pub interrupts: InterruptController,
+    pub dma: Dma,
+    pub cdrom: Cdrom,
+    pub spu: Spu,
+    pub sio0: Sio0,
+    pub mdec: Mdec,
+
+    pub watchpoints: Watchpoints,
 }
 
 impl CpuMemory {
@@ -49,87 +76,225 @@ impl CpuMemory {
             expansion_3: vec![0; 2048*1024],
             bios: bios_data,
             cache_control: vec![0; 512],
+
+            interrupts: InterruptController::new(),
+            dma: Dma::new(),
+            cdrom: Cdrom::new(),
+            spu: Spu::new(),
+            sio0: Sio0::new(),
+            mdec: Mdec::new(),
+
+            watchpoints: Watchpoints::new(),
+        }
+    }
+
+    // The BIOS isn't saved, states are tied to it through a hash instead.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"MEM ");
+        state.write_bytes(&self.ram);
+        state.write_bytes(&self.expansion_1);
+        state.write_bytes(&self.scratchpad);
+        state.write_bytes(&self.io_ports);
+        state.write_bytes(&self.expansion_2);
+        state.write_bytes(&self.expansion_3);
+        state.write_bytes(&self.cache_control);
+
+        self.interrupts.save_state(state);
+        self.dma.save_state(state);
+        self.cdrom.save_state(state);
+        self.spu.save_state(state);
+        self.sio0.save_state(state);
+        self.mdec.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"MEM ")?;
+        state.read_bytes_into(&mut self.ram)?;
+        state.read_bytes_into(&mut self.expansion_1)?;
+        state.read_bytes_into(&mut self.scratchpad)?;
+        state.read_bytes_into(&mut self.io_ports)?;
+        state.read_bytes_into(&mut self.expansion_2)?;
+        state.read_bytes_into(&mut self.expansion_3)?;
+        state.read_bytes_into(&mut self.cache_control)?;
+
+        self.interrupts.load_state(state)?;
+        self.dma.load_state(state)?;
+        self.cdrom.load_state(state)?;
+        self.spu.load_state(state)?;
+        self.sio0.load_state(state)?;
+        self.mdec.load_state(state)
+    }
+
+    // Advances the devices that run on their own clock.
+    pub fn tick(&mut self, cycles: u32) {
+        self.cdrom.tick(cycles);
+
+        if self.cdrom.irq_pending {
+            self.cdrom.irq_pending = false;
+            self.interrupts.request(Interrupt::Cdrom);
+        }
+
+        let cdrom = &mut self.cdrom;
+        self.spu.tick(cycles, || cdrom.audio.next_sample());
+
+        if self.spu.irq_pending {
+            self.spu.irq_pending = false;
+            self.interrupts.request(Interrupt::Spu);
+        }
+
+        self.sio0.tick(cycles);
+
+        if self.sio0.irq_pending {
+            self.sio0.irq_pending = false;
+            self.interrupts.request(Interrupt::Controller);
         }
     }
 
-    pub fn read_byte(&self, address: u32) -> u8 {
-        self.read(address) as u8
+    pub fn read_byte(&mut self, address: u32) -> u8 {
+        let value = self.read(address) as u8;
+        if self.watchpoints.armed() {
+            self.watchpoints.check(Access::Read, address, 1, value as u32, None);
+        }
+        value
     }
 
-    pub fn read_halfword(&self, address: u32) -> u16 {
+    pub fn read_halfword(&mut self, address: u32) -> u16 {
         if address % 2 != 0 {
             panic!("Unaligned memory read at address {:08X}", address);
         }
-        self.read(address) as u16
+        let value = self.read(address) as u16;
+        if self.watchpoints.armed() {
+            self.watchpoints.check(Access::Read, address, 2, value as u32, None);
+        }
+        value
     }
 
-    pub fn read_word(&self, address: u32) -> u32 {
+    pub fn read_word(&mut self, address: u32) -> u32 {
         if address % 4 != 0 {
             panic!("Unaligned memory read at address {:08X}", address);
         }
+        let value = self.read(address);
+        if self.watchpoints.armed() {
+            self.watchpoints.check(Access::Read, address, 4, value, None);
+        }
+        value
+    }
+
+    // Instruction fetches, which don't count as reads for watchpoints.
+    pub fn fetch_word(&mut self, address: u32) -> u32 {
+        if address & 3 != 0 {
+            panic!("Unaligned instruction fetch at address {:08X}", address);
+        }
         self.read(address)
     }
 
-    fn read(&self, address: u32) -> u32 {
+    // Reads a word of plain memory for the debugger, without the side effects of reading
+    // an I/O register. None for anything that isn't RAM, scratchpad, expansion 1 or the BIOS.
+    pub fn peek_word(&self, address: u32) -> Option<u32> {
+        let address = address & 0x1FFFFFFC;
+
+        let (memory, offset) = if let Some(offset) = RAM.contains(address) {
+            (&self.ram, offset)
+        }
+        else if let Some(offset) = EXPANSION_1.contains(address) {
+            (&self.expansion_1, offset)
+        }
+        else if let Some(offset) = SCRATCH.contains(address) {
+            (&self.scratchpad, offset)
+        }
+        else if let Some(offset) = BIOS.contains(address) {
+            (&self.bios, offset)
+        }
+        else {
+            return None;
+        };
+
+        Some(LittleEndian::read_u32(&memory[offset as usize..offset as usize + 4]))
+    }
+
+    pub fn peek_byte(&self, address: u32) -> Option<u8> {
+        self.peek_word(address).map(|word| (word >> ((address & 3) * 8)) as u8)
+    }
+
+    // Writes a byte of RAM, scratchpad or expansion 1 for the debugger, past the devices and
+    // watchpoints. Returns false for anything else, the BIOS included.
+    pub fn poke_byte(&mut self, address: u32, value: u8) -> bool {
+        let address = address & 0x1FFFFFFF;
+
+        let byte = if let Some(offset) = RAM.contains(address) {
+            &mut self.ram[offset as usize]
+        }
+        else if let Some(offset) = EXPANSION_1.contains(address) {
+            &mut self.expansion_1[offset as usize]
+        }
+        else if let Some(offset) = SCRATCH.contains(address) {
+            &mut self.scratchpad[offset as usize]
+        }
+        else {
+            return false;
+        };
+
+        *byte = value;
+        true
+    }
+
+    fn read(&mut self, address: u32) -> u32 {
 
         let address = address & 0x1FFFFFFF;
-        let mut bytes = vec![0; 4];
 
         if let Some(offset) = RAM.contains(address) {
-            bytes[0] = self.ram[offset as usize];
-            bytes[1] = self.ram[offset as usize + 1];
-            bytes[2] = self.ram[offset as usize + 2];
-            bytes[3] = self.ram[offset as usize + 3];
-            LittleEndian::read_u32(&bytes)
+            read_buffer(&self.ram, offset)
         }
         else if let Some(offset) = EXPANSION_1.contains(address) {
-            bytes[0] = self.expansion_1[offset as usize];
-            bytes[1] = self.expansion_1[offset as usize + 1];
-            bytes[2] = self.expansion_1[offset as usize + 2];
-            bytes[3] = self.expansion_1[offset as usize + 3];
-            LittleEndian::read_u32(&bytes)
+            read_buffer(&self.expansion_1, offset)
         }
         else if let Some(offset) = SCRATCH.contains(address) {
-            bytes[0] = self.scratchpad[offset as usize];
-            bytes[1] = self.scratchpad[offset as usize + 1];
-            bytes[2] = self.scratchpad[offset as usize + 2];
-            bytes[3] = self.scratchpad[offset as usize + 3];
-            LittleEndian::read_u32(&bytes)
+            read_buffer(&self.scratchpad, offset)
+        }
+        else if let Some(offset) = SIO0_REGISTERS.contains(address) {
+            // Reading JOY_DATA pops the receive FIFO.
+            if offset == 0 {
+                self.sio0.read_data() as u32
+            }
+            else {
+                self.sio0.read_register(offset & !3) >> ((offset & 3) * 8)
+            }
+        }
+        else if let Some(offset) = INTERRUPT_REGISTERS.contains(address) {
+            self.interrupts.read_register(offset & !3) >> ((offset & 3) * 8)
+        }
+        else if let Some(offset) = DMA_REGISTERS.contains(address) {
+            self.dma.read_register(offset & !3) >> ((offset & 3) * 8)
+        }
+        else if let Some(offset) = CDROM_REGISTERS.contains(address) {
+            self.cdrom.read_register(offset) as u32
+        }
+        else if let Some(offset) = MDEC_REGISTERS.contains(address) {
+            if offset & !3 == 0 {
+                self.mdec.read_data() >> ((offset & 3) * 8)
+            }
+            else {
+                self.mdec.status() >> ((offset & 3) * 8)
+            }
+        }
+        else if let Some(offset) = SPU_REGISTERS.contains(address) {
+            let value = self.spu.read_register(offset) as u32 | (self.spu.read_register(offset + 2) as u32) << 16;
+            value >> ((offset & 1) * 8)
         }
         else if let Some(offset) = IO_PORTS.contains(address) {
-            bytes[0] = self.io_ports[offset as usize];
-            bytes[1] = self.io_ports[offset as usize + 1];
-            bytes[2] = self.io_ports[offset as usize + 2];
-            bytes[3] = self.io_ports[offset as usize + 3];
-            LittleEndian::read_u32(&bytes)
+            read_buffer(&self.io_ports, offset)
         }
         else if let Some(offset) = EXPANSION_2.contains(address) {
-            bytes[0] = self.expansion_2[offset as usize];
-            bytes[1] = self.expansion_2[offset as usize + 1];
-            bytes[2] = self.expansion_2[offset as usize + 2];
-            bytes[3] = self.expansion_2[offset as usize + 3];
-            LittleEndian::read_u32(&bytes)
+            read_buffer(&self.expansion_2, offset)
         }
         else if let Some(offset) = EXPANSION_3.contains(address) {
-            bytes[0] = self.expansion_3[offset as usize];
-            bytes[1] = self.expansion_3[offset as usize + 1];
-            bytes[2] = self.expansion_3[offset as usize + 2];
-            bytes[3] = self.expansion_3[offset as usize + 3];
-            LittleEndian::read_u32(&bytes)
+            read_buffer(&self.expansion_3, offset)
         }
         else if let Some(offset) = BIOS.contains(address) {
-            bytes[0] = self.bios[offset as usize];
-            bytes[1] = self.bios[offset as usize + 1];
-            bytes[2] = self.bios[offset as usize + 2];
-            bytes[3] = self.bios[offset as usize + 3];
-            LittleEndian::read_u32(&bytes)
+            read_buffer(&self.bios, offset)
         }
         else if let Some(offset) = CACHE_CONTROL.contains(address) {
-            bytes[0] = self.cache_control[offset as usize];
-            bytes[1] = self.cache_control[offset as usize + 1];
-            bytes[2] = self.cache_control[offset as usize + 2];
-            bytes[3] = self.cache_control[offset as usize + 3];
-            LittleEndian::read_u32(&bytes)
+            read_buffer(&self.cache_control, offset)
         }
         else {
             println!("Read to unknown location {:08X}", address);
@@ -138,6 +303,12 @@ impl CpuMemory {
     }
 
     pub fn write_byte(&mut self, address: u32, value: u8) {
+        if self.watchpoints.armed() {
+            self.watchpoints.check(Access::Write, address, 1, value as u32, None);
+        }
+        if self.write_device(address, value as u32, 1) {
+            return;
+        }
         self.write(address, value);
     }
 
@@ -145,6 +316,12 @@ impl CpuMemory {
         if address % 2 != 0 {
             panic!("Unaligned memory write at address {:08X}", address);
         }
+        if self.watchpoints.armed() {
+            self.watchpoints.check(Access::Write, address, 2, value as u32, None);
+        }
+        if self.write_device(address, value as u32, 2) {
+            return;
+        }
         self.write(address, value as u8);
         self.write(address + 1, (value >> 8) as u8);
     }
@@ -153,12 +330,158 @@ impl CpuMemory {
         if address % 4 != 0 {
             panic!("Unaligned memory write at address {:08X}", address);
         }
+        if self.watchpoints.armed() {
+            self.watchpoints.check(Access::Write, address, 4, value, None);
+        }
+        if self.write_device(address, value, 4) {
+            return;
+        }
         self.write(address, value as u8);
         self.write(address + 1, (value >> 8) as u8);
         self.write(address + 2, (value >> 16) as u8);
         self.write(address + 3, (value >> 24) as u8);
     }
 
+    // Writes to registers of emulated devices, which care about the access size.
+    // Returns false if the address doesn't belong to a device.
+    fn write_device(&mut self, address: u32, value: u32, size: u32) -> bool {
+        let address = address & 0x1FFFFFFF;
+
+        if let Some(offset) = SIO0_REGISTERS.contains(address) {
+            match offset {
+                0x0 => self.sio0.write_data(value as u8),
+                0x8 => {
+                    self.sio0.write_mode(value as u16);
+                    if size == 4 {
+                        self.sio0.write_control((value >> 16) as u16);
+                    }
+                },
+                0xA => self.sio0.write_control(value as u16),
+                0xC if size == 4 => self.sio0.write_baud((value >> 16) as u16),
+                0xE => self.sio0.write_baud(value as u16),
+                _ => {},
+            }
+        }
+        else if let Some(offset) = INTERRUPT_REGISTERS.contains(address) {
+            let register = offset & !3;
+            let value = merge_write(self.interrupts.read_register(register), value, offset & 3, size);
+            self.interrupts.write_register(register, value);
+        }
+        else if let Some(offset) = DMA_REGISTERS.contains(address) {
+            let register = offset & !3;
+            let value = merge_write(self.dma.read_register(register), value, offset & 3, size);
+
+            if let Some(channel) = self.dma.write_register(register, value) {
+                self.run_dma(channel);
+            }
+        }
+        else if let Some(offset) = CDROM_REGISTERS.contains(address) {
+            self.cdrom.write_register(offset, value as u8);
+        }
+        else if let Some(offset) = MDEC_REGISTERS.contains(address) {
+            if offset & !3 == 0 {
+                self.mdec.write_command(value);
+                self.run_waiting_mdec_out();
+            }
+            else {
+                self.mdec.write_control(value);
+            }
+        }
+        else if let Some(offset) = SPU_REGISTERS.contains(address) {
+            let register = offset & !1;
+
+            if size == 4 {
+                self.spu.write_register(register, value as u16);
+                self.spu.write_register(register + 2, (value >> 16) as u16);
+            }
+            else {
+                let value = merge_write(self.spu.read_register(register) as u32, value, offset & 1, size);
+                self.spu.write_register(register, value as u16);
+            }
+        }
+        else {
+            return false;
+        }
+
+        true
+    }
+
+    fn run_dma(&mut self, channel: usize) {
+        let state = self.dma.channels[channel];
+
+        // Decoding is instant, but games usually start the output channel before feeding the input.
+        // The output transfer waits until the MDEC has produced enough data for it.
+        if channel == dma::CHANNEL_MDEC_OUT && self.mdec.output_len() < state.transfer_size() as usize {
+            return;
+        }
+
+        let step = state.step();
+        let mut address = state.base_address & 0x1FFFFC;
+
+        for _ in 0..state.transfer_size() {
+            match (channel, state.direction()) {
+                (dma::CHANNEL_MDEC_IN, Direction::FromRam) => {
+                    let value = LittleEndian::read_u32(&self.ram[address as usize..address as usize + 4]);
+                    self.mdec.write_command(value);
+                },
+                (dma::CHANNEL_MDEC_OUT, Direction::ToRam) => {
+                    let value = self.mdec.read_data();
+                    LittleEndian::write_u32(&mut self.ram[address as usize..address as usize + 4], value);
+                },
+                (dma::CHANNEL_CDROM, Direction::ToRam) => {
+                    let value = self.cdrom.read_data_word();
+                    LittleEndian::write_u32(&mut self.ram[address as usize..address as usize + 4], value);
+                },
+                (dma::CHANNEL_SPU, Direction::FromRam) => {
+                    let value = LittleEndian::read_u32(&self.ram[address as usize..address as usize + 4]);
+                    self.spu.dma_write(value);
+                },
+                (dma::CHANNEL_SPU, Direction::ToRam) => {
+                    let value = self.spu.dma_read();
+                    LittleEndian::write_u32(&mut self.ram[address as usize..address as usize + 4], value);
+                },
+                _ => {
+                    println!("Unhandled DMA transfer on channel {}", channel);
+                    break;
+                },
+            }
+
+            if self.watchpoints.armed() {
+                self.check_dma_watchpoint(channel, state.direction(), address);
+            }
+
+            address = address.wrapping_add(step) & 0x1FFFFC;
+        }
+
+        if self.dma.complete(channel) {
+            self.interrupts.request(Interrupt::Dma);
+        }
+
+        if channel == dma::CHANNEL_MDEC_IN {
+            self.run_waiting_mdec_out();
+        }
+    }
+
+    // Checked once the word has moved, so transfers to RAM report the value written, and transfers
+    // from RAM the value read.
+    fn check_dma_watchpoint(&mut self, channel: usize, direction: Direction, address: u32) {
+        let value = LittleEndian::read_u32(&self.ram[address as usize..address as usize + 4]);
+        let access = match direction {
+            Direction::FromRam => Access::Read,
+            Direction::ToRam => Access::Write,
+        };
+
+        self.watchpoints.check(access, address, 4, value, Some(channel));
+    }
+
+    fn run_waiting_mdec_out(&mut self) {
+        let channel = self.dma.channels[dma::CHANNEL_MDEC_OUT];
+
+        if channel.active() && self.dma.channel_enabled(dma::CHANNEL_MDEC_OUT) {
+            self.run_dma(dma::CHANNEL_MDEC_OUT);
+        }
+    }
+
     fn write(&mut self, address: u32, value: u8) {
 
         let address = address & 0x1FFFFFFF;
@@ -191,4 +514,86 @@ impl CpuMemory {
             println!("Tried to write {:X} to unhandled address {:X}", value, address);
         }
     }
-}
\ No newline at end of file
+}
+
+// The word at `offset`, for reads of any size. A read that starts in the last three bytes
+// of a region gets zeroes for the bytes past its end.
+fn read_buffer(buffer: &[u8], offset: u32) -> u32 {
+    let offset = offset as usize;
+
+    match buffer.get(offset..offset + 4) {
+        Some(word) => LittleEndian::read_u32(word),
+        None => (0..4).fold(0, |value, byte| value | (buffer.get(offset + byte).cloned().unwrap_or(0) as u32) << (byte * 8)),
+    }
+}
+
+// Merges a narrow write into the current value of a wider register.
+fn merge_write(current: u32, value: u32, byte_offset: u32, size: u32) -> u32 {
+    if size == 4 {
+        return value;
+    }
+
+    let shift = byte_offset * 8;
+    let mask = if size == 2 { 0xFFFF } else { 0xFF } << shift;
+
+    (current & !mask) | ((value << shift) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::watchpoints::WatchKind;
+
+    // A single word moved by the SPU channel in manual mode.
+    fn spu_transfer(memory: &mut CpuMemory, direction: Direction) {
+        let channel = &mut memory.dma.channels[dma::CHANNEL_SPU];
+        channel.base_address = 0x100;
+        channel.block_control = 1;
+        channel.channel_control = 0x11000000 | if direction == Direction::FromRam { 1 } else { 0 };
+
+        memory.run_dma(dma::CHANNEL_SPU);
+    }
+
+    #[test]
+    fn dma_watchpoints_report_the_value_written() {
+        let mut memory = CpuMemory::new(vec![0; 512 * 1024]);
+        LittleEndian::write_u32(&mut memory.ram[0x100..0x104], 0xDEADBEEF);
+        memory.watchpoints.add(0x100, 0x103, WatchKind::Write);
+
+        // Sound RAM starts out cleared, so the SPU writes zero over the old word.
+        spu_transfer(&mut memory, Direction::ToRam);
+
+        let hit = memory.watchpoints.take_hit(0).unwrap();
+        assert!(hit.access == Access::Write);
+        assert_eq!(hit.value, 0);
+        assert_eq!(hit.dma_channel, Some(dma::CHANNEL_SPU));
+        assert_eq!(LittleEndian::read_u32(&memory.ram[0x100..0x104]), 0);
+    }
+
+    #[test]
+    fn reads_at_the_end_of_a_region() {
+        let mut memory = CpuMemory::new(vec![0; 512 * 1024]);
+        memory.io_ports[8188..].copy_from_slice(&[1, 2, 3, 4]);
+        memory.ram[0x1FFFFF] = 0x55;
+
+        assert_eq!(memory.read_byte(0x1F802FFD), 2);
+        assert_eq!(memory.read_byte(0x1F802FFE), 3);
+        assert_eq!(memory.read_byte(0x1F802FFF), 4);
+        assert_eq!(memory.read_halfword(0x1F802FFE), 0x0403);
+        assert_eq!(memory.read_word(0x1F802FFC), 0x04030201);
+        assert_eq!(memory.read_byte(0x801FFFFF), 0x55);
+    }
+
+    #[test]
+    fn dma_watchpoints_report_the_value_read() {
+        let mut memory = CpuMemory::new(vec![0; 512 * 1024]);
+        LittleEndian::write_u32(&mut memory.ram[0x100..0x104], 0x12345678);
+        memory.watchpoints.add(0x100, 0x103, WatchKind::Read);
+
+        spu_transfer(&mut memory, Direction::FromRam);
+
+        let hit = memory.watchpoints.take_hit(0).unwrap();
+        assert!(hit.access == Access::Read);
+        assert_eq!(hit.value, 0x12345678);
+    }
+}
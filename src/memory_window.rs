@@ -0,0 +1,462 @@
+use imgui::*;
+
+use super::cpu::Cpu;
+use super::memory;
+use super::memory::CpuMemory;
+
+const BYTES_PER_ROW: u32 = 16;
+// Rows moved per notch of the mouse wheel.
+const WHEEL_ROWS: f32 = 3.0;
+
+const HIGHLIGHT_COLOUR: [f32; 4] = [1.0, 1.0, 0.0, 1.0];
+const ERROR_COLOUR: [f32; 4] = [1.0, 0.3, 0.3, 1.0];
+
+struct Region {
+    name: &'static str,
+    // Where the jump button takes the view, in the segment games use.
+    start: u32,
+    length: u32,
+    // I/O registers have side effects when read, so searches leave them alone.
+    searchable: bool,
+}
+
+const REGIONS: [Region; 5] = [
+    Region { name: "RAM", start: 0x80000000, length: memory::RAM.1, searchable: true },
+    Region { name: "Scratchpad", start: 0x1F800000, length: memory::SCRATCH.1, searchable: true },
+    Region { name: "BIOS", start: 0xBFC00000, length: memory::BIOS.1, searchable: true },
+    Region { name: "I/O", start: 0x1F801000, length: memory::IO_PORTS.1, searchable: false },
+    Region { name: "Expansion 1", start: 0x1F000000, length: memory::EXPANSION_1.1, searchable: true },
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Byte,
+    Halfword,
+    Word,
+}
+
+impl Unit {
+    fn size(self) -> u32 {
+        match self {
+            Unit::Byte => 1,
+            Unit::Halfword => 2,
+            Unit::Word => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SearchKind {
+    // Hex bytes, or text in quotes.
+    Bytes,
+    // A number the size of the current unit.
+    Value,
+}
+
+// Hex editor over the whole address space. The view is a window of rows starting at `top`,
+// scrolled by hand rather than by imgui, so only the visible rows are ever read.
+pub struct MemoryWindow {
+    top: u32,
+    visible_rows: u32,
+    unit: Unit,
+    // Read the I/O registers too, with whatever side effects that has.
+    live_io: bool,
+
+    goto_address: ImString,
+    status: String,
+
+    editing: Option<u32>,
+    edit_buffer: ImString,
+    focus_edit: bool,
+
+    search: ImString,
+    search_kind: SearchKind,
+    // Address and length of the last match.
+    found: Option<(u32, u32)>,
+}
+
+impl MemoryWindow {
+    pub fn new() -> MemoryWindow {
+        MemoryWindow {
+            top: REGIONS[0].start,
+            visible_rows: 16,
+            unit: Unit::Byte,
+            live_io: false,
+
+            goto_address: ImString::with_capacity(8),
+            status: String::new(),
+
+            editing: None,
+            edit_buffer: ImString::with_capacity(8),
+            focus_edit: false,
+
+            search: ImString::with_capacity(64),
+            search_kind: SearchKind::Bytes,
+            found: None,
+        }
+    }
+
+    fn go_to(&mut self, address: u32) {
+        self.top = address & !(BYTES_PER_ROW - 1);
+        self.editing = None;
+    }
+
+    pub fn build(&mut self, ui: &Ui, cpu: &mut Cpu) {
+        Window::new(im_str!("Rusty PSX - Memory Editor")).size([640.0, 420.0], Condition::FirstUseEver).build(ui, || {
+            for (index, region) in REGIONS.iter().enumerate() {
+                if index != 0 {
+                    ui.same_line(0.0);
+                }
+                if ui.button(&ImString::new(region.name), [0.0, 0.0]) {
+                    self.go_to(region.start);
+                }
+            }
+
+            if ui.input_text(im_str!("Go to address"), &mut self.goto_address).chars_hexadecimal(true).enter_returns_true(true).build() {
+                match u32::from_str_radix(self.goto_address.to_str(), 16) {
+                    Ok(address) => {
+                        self.go_to(address);
+                        self.status.clear();
+                    },
+                    Err(_) => self.status = format!("\"{}\" isn't a hexadecimal address", self.goto_address.to_str()),
+                }
+            }
+
+            ui.radio_button(im_str!("Bytes"), &mut self.unit, Unit::Byte);
+            ui.same_line(0.0);
+            ui.radio_button(im_str!("Halfwords"), &mut self.unit, Unit::Halfword);
+            ui.same_line(0.0);
+            ui.radio_button(im_str!("Words"), &mut self.unit, Unit::Word);
+            ui.same_line(0.0);
+            ui.checkbox(im_str!("Read I/O registers"), &mut self.live_io);
+
+            let mut find = ui.input_text(im_str!("Find"), &mut self.search).enter_returns_true(true).build();
+            ui.radio_button(im_str!("Byte pattern"), &mut self.search_kind, SearchKind::Bytes);
+            ui.same_line(0.0);
+            ui.radio_button(im_str!("Value"), &mut self.search_kind, SearchKind::Value);
+            ui.same_line(0.0);
+            find |= ui.button(im_str!("Find next"), [100.0, 20.0]);
+            ui.same_line(0.0);
+            if ui.button(im_str!("Page up"), [80.0, 20.0]) {
+                self.top = self.top.wrapping_sub(self.visible_rows * BYTES_PER_ROW);
+            }
+            ui.same_line(0.0);
+            if ui.button(im_str!("Page down"), [80.0, 20.0]) {
+                self.top = self.top.wrapping_add(self.visible_rows * BYTES_PER_ROW);
+            }
+
+            if find {
+                self.find_next(&cpu.memory);
+            }
+
+            if !self.status.is_empty() {
+                ui.text_colored(ERROR_COLOUR, &self.status);
+            }
+            ui.separator();
+
+            ChildWindow::new(im_str!("Memory Rows")).size([0.0, 0.0]).scroll_bar(false).scrollable(false).build(ui, || {
+                if ui.is_window_hovered() {
+                    let rows = (-ui.io().mouse_wheel * WHEEL_ROWS) as i32;
+                    self.top = self.top.wrapping_add((rows * BYTES_PER_ROW as i32) as u32);
+                }
+
+                self.visible_rows = ((ui.content_region_avail()[1] / ui.text_line_height_with_spacing()) as u32).max(1);
+                for row in 0..self.visible_rows {
+                    self.build_row(ui, cpu, self.top.wrapping_add(row * BYTES_PER_ROW));
+                }
+            });
+        });
+    }
+
+    fn build_row(&mut self, ui: &Ui, cpu: &mut Cpu, address: u32) {
+        let size = self.unit.size();
+        let digits = size as usize * 2;
+        let cell_width = ui.calc_text_size(im_str!("F"), false, -1.0)[0] * digits as f32;
+
+        ui.text(format!("{:08X} ", address));
+        let mut bytes = Vec::with_capacity(BYTES_PER_ROW as usize);
+
+        for offset in (0..BYTES_PER_ROW).step_by(size as usize) {
+            let cell = address.wrapping_add(offset);
+            let value = self.read(cpu, cell, size);
+
+            for byte in 0..size {
+                bytes.push(value.map(|value| (value >> (byte * 8)) as u8));
+            }
+
+            ui.same_line(0.0);
+            let id = ui.push_id(cell as i32);
+
+            if self.editing == Some(cell) {
+                if self.focus_edit {
+                    ui.set_keyboard_focus_here(FocusedWidget::Next);
+                    self.focus_edit = false;
+                }
+
+                let width = ui.push_item_width(cell_width + 8.0);
+                let entered = ui.input_text(im_str!(""), &mut self.edit_buffer).chars_hexadecimal(true).enter_returns_true(true).build();
+                width.pop(ui);
+
+                if entered {
+                    self.finish_edit(cpu, cell, size);
+                }
+                else if ui.is_item_deactivated() {
+                    self.editing = None;
+                }
+            }
+            else {
+                let text = match value {
+                    Some(value) => format!("{:0width$X}", value, width = digits),
+                    None => "-".repeat(digits),
+                };
+                let found = self.found.map(|(start, length)| cell.wrapping_sub(start) < length).unwrap_or(false);
+
+                let colour = if found { Some(ui.push_style_color(StyleColor::Text, HIGHLIGHT_COLOUR)) } else { None };
+                let clicked = Selectable::new(&ImString::new(text)).size([cell_width, 0.0]).build(ui);
+                if let Some(colour) = colour {
+                    colour.pop(ui);
+                }
+
+                // Only what could be read can be edited, which keeps the I/O registers alone until asked for.
+                if clicked && value.is_some() {
+                    self.edit_buffer.clear();
+                    self.edit_buffer.push_str(&format!("{:0width$X}", value.unwrap_or(0), width = digits));
+                    self.editing = Some(cell);
+                    self.focus_edit = true;
+                }
+            }
+
+            id.pop(ui);
+        }
+
+        let text: String = bytes.iter().map(|byte| match byte {
+            Some(byte) if *byte >= 0x20 && *byte < 0x7F => *byte as char,
+            Some(_) => '.',
+            None => ' ',
+        }).collect();
+
+        ui.same_line(0.0);
+        ui.text(format!(" {}", text));
+    }
+
+    // Writes the edited value and moves on to the next cell, like typing over a hex dump.
+    fn finish_edit(&mut self, cpu: &mut Cpu, address: u32, size: u32) {
+        let value = match u32::from_str_radix(self.edit_buffer.to_str(), 16) {
+            Ok(value) if size == 4 || value >> (size * 8) == 0 => value,
+            _ => {
+                self.status = format!("\"{}\" doesn't fit in {} bytes", self.edit_buffer.to_str(), size);
+                self.editing = None;
+                return;
+            },
+        };
+
+        if let Err(error) = write(cpu, address, size, value) {
+            self.status = error;
+            self.editing = None;
+            return;
+        }
+        self.status.clear();
+
+        let next = address.wrapping_add(size);
+        match self.read(cpu, next, size) {
+            Some(value) => {
+                self.edit_buffer.clear();
+                self.edit_buffer.push_str(&format!("{:0width$X}", value, width = size as usize * 2));
+                self.editing = Some(next);
+                self.focus_edit = true;
+
+                if next.wrapping_sub(self.top) >= self.visible_rows * BYTES_PER_ROW {
+                    self.top = self.top.wrapping_add(BYTES_PER_ROW);
+                }
+            },
+            None => self.editing = None,
+        }
+    }
+
+    fn read(&self, cpu: &mut Cpu, address: u32, size: u32) -> Option<u32> {
+        if is_io(address) {
+            // Register reads panic when they aren't aligned, and can't go past the I/O ports.
+            if !self.live_io || !io_access_allowed(address, size) {
+                return None;
+            }
+
+            return Some(match size {
+                1 => cpu.memory.read_byte(address) as u32,
+                2 => cpu.memory.read_halfword(address) as u32,
+                _ => cpu.memory.read_word(address),
+            });
+        }
+
+        (0..size).try_fold(0, |value, byte| {
+            cpu.memory.peek_byte(address.wrapping_add(byte)).map(|read| value | (read as u32) << (byte * 8))
+        })
+    }
+
+    fn find_next(&mut self, memory: &CpuMemory) {
+        let pattern = match self.parse_search() {
+            Ok(pattern) => pattern,
+            Err(error) => {
+                self.status = error;
+                return;
+            },
+        };
+
+        let from = match self.found {
+            Some((address, _)) => address.wrapping_add(1),
+            None => self.top,
+        };
+
+        match find(memory, &pattern, from) {
+            Some(address) => {
+                self.found = Some((address, pattern.len() as u32));
+                self.go_to(address);
+                self.status.clear();
+            },
+            None => {
+                self.found = None;
+                self.status = String::from("Not found");
+            },
+        }
+    }
+
+    fn parse_search(&self) -> Result<Vec<u8>, String> {
+        let text = self.search.to_str().trim();
+
+        let pattern = match self.search_kind {
+            SearchKind::Bytes if text.starts_with('"') => text.trim_matches('"').as_bytes().to_vec(),
+            SearchKind::Bytes => {
+                let digits: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+                if digits.len() & 1 != 0 {
+                    return Err(String::from("A byte pattern needs two hex digits per byte"));
+                }
+
+                let mut bytes = Vec::new();
+                for index in (0..digits.len()).step_by(2) {
+                    match digits.get(index..index + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()) {
+                        Some(byte) => bytes.push(byte),
+                        None => return Err(format!("\"{}\" isn't a hex byte pattern", text)),
+                    }
+                }
+                bytes
+            },
+            SearchKind::Value => {
+                let size = self.unit.size();
+                match u32::from_str_radix(text.trim_start_matches("0x"), 16) {
+                    Ok(value) if size == 4 || value >> (size * 8) == 0 => value.to_le_bytes()[..size as usize].to_vec(),
+                    _ => return Err(format!("\"{}\" isn't a {} byte hex value", text, size)),
+                }
+            },
+        };
+
+        if pattern.is_empty() {
+            return Err(String::from("Nothing to search for"));
+        }
+        Ok(pattern)
+    }
+}
+
+fn is_io(address: u32) -> bool {
+    memory::IO_PORTS.contains(address & 0x1FFFFFFF).is_some()
+}
+
+fn io_access_allowed(address: u32, size: u32) -> bool {
+    address & (size - 1) == 0 && is_io(address.wrapping_add(size - 1))
+}
+
+// Writes past the devices for plain memory, and through them for the I/O registers.
+fn write(cpu: &mut Cpu, address: u32, size: u32, value: u32) -> Result<(), String> {
+    if is_io(address) {
+        if !io_access_allowed(address, size) {
+            return Err(format!("{:08X} isn't aligned to {} bytes", address, size));
+        }
+
+        match size {
+            1 => cpu.memory.write_byte(address, value as u8),
+            2 => cpu.memory.write_halfword(address, value as u16),
+            _ => cpu.memory.write_word(address, value),
+        }
+        return Ok(());
+    }
+
+    for byte in 0..size {
+        let target = address.wrapping_add(byte);
+        if !cpu.memory.poke_byte(target, (value >> (byte * 8)) as u8) {
+            return Err(format!("{:08X} can't be written", target));
+        }
+    }
+    Ok(())
+}
+
+// First match at or after `from`, going through the searchable regions in turn and wrapping
+// around to the start of the one `from` is in.
+fn find(memory: &CpuMemory, pattern: &[u8], from: u32) -> Option<u32> {
+    let regions: Vec<&Region> = REGIONS.iter().filter(|region| region.searchable).collect();
+    let first = regions.iter().position(|region| from.wrapping_sub(region.start) < region.length);
+
+    for step in 0..=regions.len() {
+        let region = regions[(first.unwrap_or(0) + step) % regions.len()];
+        let skip = match first {
+            Some(_) if step == 0 => from - region.start,
+            _ => 0,
+        };
+
+        let last = match region.length.checked_sub(pattern.len() as u32) {
+            Some(last) => last,
+            None => continue,
+        };
+
+        for offset in skip..=last {
+            let address = region.start + offset;
+            let matches = pattern.iter()
+                .zip(address..)
+                .all(|(byte, address)| memory.peek_byte(address).unwrap_or(0) == *byte);
+
+            if matches {
+                return Some(address);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cpu::tests::test_cpu;
+
+    #[test]
+    fn live_io_reads_stay_in_bounds() {
+        let mut cpu = test_cpu();
+        cpu.memory.io_ports[8191] = 0x7E;
+
+        let mut window = MemoryWindow::new();
+        assert_eq!(window.read(&mut cpu, 0x1F802FFF, 1), None);
+
+        window.live_io = true;
+        for address in 0x1F802FFC..=0x1F802FFF {
+            assert!(window.read(&mut cpu, address, 1).is_some());
+        }
+        assert_eq!(window.read(&mut cpu, 0x1F802FFF, 1), Some(0x7E));
+        assert_eq!(window.read(&mut cpu, 0x1F802FFE, 2), Some(0x7E00));
+        assert_eq!(window.read(&mut cpu, 0x1F802FFD, 2), None);
+        assert_eq!(window.read(&mut cpu, 0x1F802FFE, 4), None);
+
+        assert!(write(&mut cpu, 0x1F802FFD, 2, 0).is_err());
+    }
+
+    #[test]
+    fn find_searches_forward_and_wraps() {
+        let mut cpu = test_cpu();
+        for (offset, byte) in [0xDE, 0xAD, 0xBE, 0xEF].iter().enumerate() {
+            cpu.memory.poke_byte(0x80001001 + offset as u32, *byte);
+            cpu.memory.poke_byte(0x80100003 + offset as u32, *byte);
+        }
+
+        let pattern = [0xDE, 0xAD, 0xBE, 0xEF];
+        assert_eq!(find(&cpu.memory, &pattern, 0x80000000), Some(0x80001001));
+        assert_eq!(find(&cpu.memory, &pattern, 0x80001002), Some(0x80100003));
+        assert_eq!(find(&cpu.memory, &pattern, 0x80100004), Some(0x80001001));
+        assert_eq!(find(&cpu.memory, &[0xDE, 0xAD, 0xBE, 0xEE], 0x80000000), None);
+    }
+}
@@ -0,0 +1,123 @@
+use std::io;
+use std::fs;
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// Largest payload of a stored deflate block.
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+// Writes 8-bit RGB pixels as a PNG. The image data isn't compressed, only wrapped in stored
+// deflate blocks, which keeps this small and the output byte-for-byte reproducible.
+pub fn write_rgb(path: &Path, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    if width == 0 || height == 0 || pixels.len() as u64 != width as u64 * height as u64 * 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} bytes don't make a {}x{} RGB image", pixels.len(), width, height)));
+    }
+
+    let mut header = [0; 13];
+    BigEndian::write_u32(&mut header[0..4], width);
+    BigEndian::write_u32(&mut header[4..8], height);
+    header[8] = 8;
+    header[9] = 2;
+
+    // Every scanline starts with its filter type, 0 for none.
+    let stride = width as usize * 3;
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for line in pixels.chunks(stride).take(height as usize) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+
+    let mut contents = SIGNATURE.to_vec();
+    write_chunk(&mut contents, b"IHDR", &header);
+    write_chunk(&mut contents, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut contents, b"IEND", &[]);
+
+    fs::write(path, contents)
+}
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut length = [0; 4];
+    BigEndian::write_u32(&mut length, data.len() as u32);
+    output.extend_from_slice(&length);
+
+    let start = output.len();
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+
+    let mut crc = [0; 4];
+    BigEndian::write_u32(&mut crc, crc32(&output[start..]));
+    output.extend_from_slice(&crc);
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x01];
+    let block_count = data.len().div_ceil(STORED_BLOCK_SIZE);
+
+    for (index, block) in data.chunks(STORED_BLOCK_SIZE).enumerate() {
+        let last = index + 1 == block_count;
+        let length = block.len() as u16;
+
+        output.push(if last { 1 } else { 0 });
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+
+    if data.is_empty() {
+        output.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    let mut checksum = [0; 4];
+    BigEndian::write_u32(&mut checksum, adler32(data));
+    output.extend_from_slice(&checksum);
+    output
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_must_match_the_pixels() {
+        let path = std::env::temp_dir().join(format!("rusty_psx_{}_size.png", std::process::id()));
+
+        assert!(write_rgb(&path, 0, 1, &[]).is_err());
+        assert!(write_rgb(&path, 2, 0, &[]).is_err());
+        assert!(write_rgb(&path, 2, 2, &[0; 9]).is_err());
+        assert!(!path.exists());
+
+        write_rgb(&path, 2, 2, &[0; 12]).unwrap();
+        let contents = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(contents.starts_with(&SIGNATURE));
+        assert_eq!(BigEndian::read_u32(&contents[16..20]), 2);
+        assert_eq!(BigEndian::read_u32(&contents[20..24]), 2);
+    }
+}
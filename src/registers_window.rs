@@ -0,0 +1,204 @@
+use imgui::*;
+
+use super::cpu::Cpu;
+use super::instructions_decoder::REGISTER_NAMES;
+
+// The general purpose registers, then PC, hi and lo, then the COP0 registers below.
+const PC: usize = 32;
+const HI: usize = 33;
+const LO: usize = 34;
+const FIRST_COP0: usize = 35;
+const REGISTER_COUNT: usize = FIRST_COP0 + COP0_REGISTERS.len();
+
+const COP0_REGISTERS: [(&str, usize); 4] = [
+    ("SR", 12),
+    ("CAUSE", 13),
+    ("EPC", 14),
+    ("BadVaddr", 8),
+];
+
+const CHANGED_COLOUR: [f32; 4] = [1.0, 1.0, 0.0, 1.0];
+
+const EXCEPTION_NAMES: [&str; 13] = ["Int", "Mod", "TLBL", "TLBS", "AdEL", "AdES", "IBE", "DBE", "Syscall", "Bp", "RI", "CpU", "Ov"];
+
+// Every register the CPU has, editable in place. Registers that changed since the CPU last
+// stopped are highlighted.
+pub struct RegistersWindow {
+    // Values as last shown, and as they were before the last change.
+    current: [u32; REGISTER_COUNT],
+    previous: [u32; REGISTER_COUNT],
+
+    buffers: Vec<ImString>,
+    // The register whose text is being edited, which mustn't be refreshed under the user.
+    editing: Option<usize>,
+}
+
+impl RegistersWindow {
+    pub fn new() -> RegistersWindow {
+        RegistersWindow {
+            current: [0; REGISTER_COUNT],
+            previous: [0; REGISTER_COUNT],
+
+            buffers: (0..REGISTER_COUNT).map(|_| ImString::with_capacity(8)).collect(),
+            editing: None,
+        }
+    }
+
+    pub fn build(&mut self, ui: &Ui, cpu: &mut Cpu) {
+        let values = read_registers(cpu);
+        if values != self.current {
+            self.previous = self.current;
+            self.current = values;
+        }
+
+        Window::new(im_str!("Rusty PSX - Registers")).size([560.0, 420.0], Condition::FirstUseEver).build(ui, || {
+            ui.text("R3000A Main Registers");
+            ui.separator();
+
+            ui.columns(4, im_str!("Register Columns"), false);
+            for column in 0..4 {
+                for (index, name) in REGISTER_NAMES.iter().enumerate().skip(column * 8).take(8) {
+                    if index == 0 {
+                        // Always 0, nothing to edit.
+                        ui.text(format!("{:<4} {:08X}", &name[1..], 0));
+                    }
+                    else if let Some(value) = self.register_input(ui, index, &name[1..]) {
+                        cpu.registers[index] = value;
+                    }
+                }
+                ui.next_column();
+            }
+            ui.columns(1, im_str!("Register Columns"), false);
+
+            ui.separator();
+            if let Some(value) = self.register_input(ui, PC, "pc") {
+                // Running from somewhere else abandons any pending delay slot. Instructions are
+                // word aligned, so the low bits are dropped rather than faulting on the next fetch.
+                cpu.pc = value & !3;
+                cpu.branch_delay = false;
+            }
+            ui.same_line(0.0);
+            if let Some(value) = self.register_input(ui, HI, "hi") {
+                cpu.hi = value;
+            }
+            ui.same_line(0.0);
+            if let Some(value) = self.register_input(ui, LO, "lo") {
+                cpu.lo = value;
+            }
+
+            ui.spacing();
+            ui.text("COP0 Registers");
+            ui.separator();
+
+            for (offset, (name, register)) in COP0_REGISTERS.iter().enumerate() {
+                if let Some(value) = self.register_input(ui, FIRST_COP0 + offset, name) {
+                    cpu.cop0_registers[*register] = value;
+                }
+
+                let fields = match *register {
+                    12 => status_fields(cpu.cop0_registers[12]),
+                    13 => cause_fields(cpu.cop0_registers[13]),
+                    _ => String::new(),
+                };
+                if !fields.is_empty() {
+                    ui.same_line(0.0);
+                    ui.text_disabled(fields);
+                }
+            }
+        });
+
+        // Edits aren't changes made by the CPU.
+        self.current = read_registers(cpu);
+    }
+
+    // A hex input for one register. Returns the new value once the user presses enter.
+    fn register_input(&mut self, ui: &Ui, index: usize, name: &str) -> Option<u32> {
+        let value = self.current[index];
+        if self.editing != Some(index) {
+            self.buffers[index].clear();
+            self.buffers[index].push_str(&format!("{:08X}", value));
+        }
+
+        let colour = if value != self.previous[index] { Some(ui.push_style_color(StyleColor::Text, CHANGED_COLOUR)) } else { None };
+        ui.text(format!("{:<4}", name));
+        ui.same_line(0.0);
+
+        let id = ui.push_id(index as i32);
+        let width = ui.push_item_width(72.0);
+        let entered = ui.input_text(im_str!(""), &mut self.buffers[index]).chars_hexadecimal(true).enter_returns_true(true).build();
+        let active = ui.is_item_active();
+        width.pop(ui);
+        id.pop(ui);
+
+        if let Some(colour) = colour {
+            colour.pop(ui);
+        }
+
+        if active {
+            self.editing = Some(index);
+        }
+        else if self.editing == Some(index) {
+            self.editing = None;
+        }
+
+        if entered {
+            self.editing = None;
+            u32::from_str_radix(self.buffers[index].to_str(), 16).ok()
+        }
+        else {
+            None
+        }
+    }
+}
+
+fn read_registers(cpu: &Cpu) -> [u32; REGISTER_COUNT] {
+    let mut values = [0; REGISTER_COUNT];
+    values[..32].copy_from_slice(&cpu.registers[..32]);
+    values[PC] = cpu.pc;
+    values[HI] = cpu.hi;
+    values[LO] = cpu.lo;
+    for (offset, (_, register)) in COP0_REGISTERS.iter().enumerate() {
+        values[FIRST_COP0 + offset] = cpu.cop0_registers[*register];
+    }
+    values
+}
+
+// The flags that are set, and the interrupt mask.
+fn status_fields(value: u32) -> String {
+    const FLAGS: [(u32, &str); 14] = [
+        (0, "IEc"), (1, "KUc"), (2, "IEp"), (3, "KUp"), (4, "IEo"), (5, "KUo"),
+        (16, "IsC"), (17, "SwC"), (18, "PZ"), (19, "CM"), (20, "PE"), (21, "TS"), (22, "BEV"), (25, "RE"),
+    ];
+
+    let mut fields: Vec<String> = FLAGS.iter()
+        .filter(|(bit, _)| value & (1 << bit) != 0)
+        .map(|(_, name)| String::from(*name))
+        .collect();
+
+    fields.push(format!("Im={:02X}", (value >> 8) & 0xFF));
+    for coprocessor in 0..4 {
+        if value & (1 << (28 + coprocessor)) != 0 {
+            fields.push(format!("CU{}", coprocessor));
+        }
+    }
+
+    fields.join(" ")
+}
+
+fn cause_fields(value: u32) -> String {
+    let code = ((value >> 2) & 0x1F) as usize;
+    let exception = match EXCEPTION_NAMES.get(code) {
+        Some(name) => String::from(*name),
+        None => format!("Exc{}", code),
+    };
+
+    let mut fields = vec![exception, format!("IP={:02X}", (value >> 8) & 0xFF)];
+    if value & (3 << 28) != 0 {
+        fields.push(format!("CE={}", (value >> 28) & 3));
+    }
+    if value & (1 << 31) != 0 {
+        fields.push(String::from("BD"));
+    }
+
+    fields.join(" ")
+}
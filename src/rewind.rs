@@ -0,0 +1,285 @@
+use std::io;
+use std::collections::VecDeque;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::cpu::Cpu;
+use super::savestate::{StateReader, StateWriter};
+
+pub const DEFAULT_INTERVAL: u32 = 30;
+pub const DEFAULT_BUDGET: usize = 64 * 1024 * 1024;
+
+// Changes closer together than this are kept in one run, since starting a new run costs eight bytes.
+const MIN_UNCHANGED_RUN: usize = 8;
+
+// Keeps the last few seconds of emulation so they can be stepped back through.
+// Only the newest state is kept whole. Every older one is stored as the XOR against the state
+// after it, run-length encoded, so a snapshot costs about as much as what changed since.
+pub struct RewindBuffer {
+    // Frames between snapshots.
+    pub interval: u32,
+    // Bytes the snapshots may take up, the newest full state included. 0 disables rewinding.
+    pub budget: usize,
+
+    latest: Option<Vec<u8>>,
+    // Oldest first. Applying the last delta to `latest` gives the state before it.
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+    frames: u32,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: u32, budget: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            budget,
+
+            latest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+            frames: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+        self.frames = 0;
+    }
+
+    // Number of snapshots kept, the newest one included.
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.delta_bytes + self.latest.as_ref().map(|state| state.len()).unwrap_or(0)
+    }
+
+    // Called after every emulated frame, takes a snapshot every `interval` frames.
+    pub fn frame_finished(&mut self, cpu: &Cpu) {
+        if self.budget == 0 {
+            return;
+        }
+
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let mut state = StateWriter::new();
+        cpu.save_state(&mut state);
+        let state = state.into_bytes();
+
+        if let Some(previous) = self.latest.take() {
+            // Plugging in a different controller changes the size of the state, and the old
+            // snapshots can't be diffed against the new ones anymore.
+            if previous.len() == state.len() {
+                let delta = encode_delta(&previous, &state);
+                self.delta_bytes += delta.len();
+                self.deltas.push_back(delta);
+            }
+            else {
+                self.clear();
+            }
+        }
+
+        self.latest = Some(state);
+
+        while self.used_bytes() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Goes back one snapshot. Returns false when there's nothing left to rewind to.
+    pub fn rewind(&mut self, cpu: &mut Cpu) -> io::Result<bool> {
+        let mut state = match self.latest.take() {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+
+        // If the machine hasn't moved on since the newest snapshot, go to the one before it instead.
+        if self.frames == 0 {
+            let delta = match self.deltas.pop_back() {
+                Some(delta) => delta,
+                None => {
+                    self.latest = Some(state);
+                    return Ok(false);
+                },
+            };
+
+            self.delta_bytes -= delta.len();
+            if let Err(error) = apply_delta(&mut state, &delta) {
+                self.clear();
+                return Err(error);
+            }
+        }
+
+        let mut backup = StateWriter::new();
+        cpu.save_state(&mut backup);
+        let backup = backup.into_bytes();
+
+        // Whatever didn't load can't be rewound through either. The machine goes back to where it
+        // was rather than being left half restored.
+        if let Err(error) = cpu.load_state(&mut StateReader::new(&state)) {
+            self.clear();
+            cpu.load_state(&mut StateReader::new(&backup))?;
+            return Err(error);
+        }
+
+        self.latest = Some(state);
+        self.frames = 0;
+        Ok(true)
+    }
+}
+
+// Runs of (unchanged length, changed length, XOR of the changed bytes).
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut position = 0;
+
+    while position < new.len() {
+        let start = position;
+        while position < new.len() && old[position] == new[position] {
+            position += 1;
+        }
+        let unchanged = position - start;
+
+        if position == new.len() {
+            break;
+        }
+
+        let changed_start = position;
+        let mut same = 0;
+        while position < new.len() && same < MIN_UNCHANGED_RUN {
+            if old[position] == new[position] { same += 1; } else { same = 0; }
+            position += 1;
+        }
+        position -= same;
+
+        let mut header = [0; 8];
+        LittleEndian::write_u32(&mut header[0..4], unchanged as u32);
+        LittleEndian::write_u32(&mut header[4..8], (position - changed_start) as u32);
+        delta.extend_from_slice(&header);
+        delta.extend(old[changed_start..position].iter().zip(new[changed_start..position].iter()).map(|(old, new)| old ^ new));
+    }
+
+    delta
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) -> io::Result<()> {
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "Rewind snapshot doesn't match the state it was taken from");
+    let mut position = 0;
+    let mut offset = 0;
+
+    while offset < delta.len() {
+        if offset + 8 > delta.len() {
+            return Err(corrupt());
+        }
+
+        position += LittleEndian::read_u32(&delta[offset..offset + 4]) as usize;
+        let length = LittleEndian::read_u32(&delta[offset + 4..offset + 8]) as usize;
+        offset += 8;
+
+        if position + length > state.len() || offset + length > delta.len() {
+            return Err(corrupt());
+        }
+
+        for (value, difference) in state[position..position + length].iter_mut().zip(delta[offset..offset + length].iter()) {
+            *value ^= difference;
+        }
+
+        position += length;
+        offset += length;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cpu::tests::test_cpu;
+
+    #[test]
+    fn delta_round_trip() {
+        let old: Vec<u8> = (0..4096).map(|value| (value % 251) as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 1;
+        new[100] = 0;
+        new[105] = 0;
+        new[2000..2100].iter_mut().for_each(|value| *value = 0xEE);
+        new[4095] ^= 0x80;
+
+        let delta = encode_delta(&old, &new);
+        let mut state = new.clone();
+        apply_delta(&mut state, &delta).unwrap();
+        assert_eq!(state, old);
+
+        // Nothing changed, nothing to store.
+        assert!(encode_delta(&old, &old).is_empty());
+
+        // Deltas don't fit states of another size.
+        let mut short = new[..1000].to_vec();
+        assert!(apply_delta(&mut short, &delta).is_err());
+        assert!(apply_delta(&mut state, &delta[..delta.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rewinding_steps_back_a_snapshot() {
+        let mut cpu = test_cpu();
+        let mut buffer = RewindBuffer::new(1, DEFAULT_BUDGET);
+
+        for value in 1..=3 {
+            cpu.registers[2] = value;
+            buffer.frame_finished(&cpu);
+        }
+        assert_eq!(buffer.len(), 3);
+
+        assert!(buffer.rewind(&mut cpu).unwrap());
+        assert_eq!(cpu.registers[2], 2);
+        assert!(buffer.rewind(&mut cpu).unwrap());
+        assert_eq!(cpu.registers[2], 1);
+        assert!(!buffer.rewind(&mut cpu).unwrap());
+    }
+
+    #[test]
+    fn failed_loads_leave_the_machine_as_it_was() {
+        let mut cpu = test_cpu();
+        let mut buffer = RewindBuffer::new(1, DEFAULT_BUDGET);
+
+        cpu.registers[2] = 1;
+        buffer.frame_finished(&cpu);
+        cpu.registers[2] = 5;
+        cpu.pc = 0x80010000;
+
+        // Cut off after the registers, so loading gets partway through.
+        let state = buffer.latest.take().unwrap();
+        buffer.latest = Some(state[..state.len() / 2].to_vec());
+        buffer.frames = 1;
+
+        assert!(buffer.rewind(&mut cpu).is_err());
+        assert_eq!(cpu.registers[2], 5);
+        assert_eq!(cpu.pc, 0x80010000);
+    }
+
+    #[test]
+    fn failed_loads_clear_the_buffer() {
+        let mut cpu = test_cpu();
+        let mut buffer = RewindBuffer::new(1, DEFAULT_BUDGET);
+        buffer.frame_finished(&cpu);
+        buffer.frame_finished(&cpu);
+
+        buffer.latest = Some(vec![0; 16]);
+        buffer.frames = 1;
+
+        assert!(buffer.rewind(&mut cpu).is_err());
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(buffer.used_bytes(), 0);
+    }
+}
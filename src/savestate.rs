@@ -0,0 +1,578 @@
+use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::cpu::Cpu;
+use super::cdrom::disc::Disc;
+
+const MAGIC: &[u8; 8] = b"RPSXSTAT";
+// Bump whenever the layout of any device's state changes. Older states are rejected, not migrated.
+pub const VERSION: u32 = 3;
+
+pub const SLOT_COUNT: usize = 10;
+const STATE_DIRECTORY: &str = "states";
+
+// Well above the largest machine state, which is about 13MB, mostly the expansion regions.
+// A header claiming more is corrupted, and isn't trusted with an allocation that size.
+const MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+pub const THUMBNAIL_WIDTH: usize = 160;
+pub const THUMBNAIL_HEIGHT: usize = 90;
+
+// Sectors hashed to tell discs apart: the volume descriptors, which hold the volume name and dates.
+const DISC_HASH_START: u32 = 16;
+const DISC_HASH_SECTORS: u32 = 16;
+
+// Everything in front of the compressed machine state, readable without loading the rest.
+pub struct Header {
+    pub bios_hash: u64,
+    // Zero when no disc was inserted.
+    pub disc_hash: u64,
+    // Seconds since the Unix epoch.
+    pub timestamp: u64,
+    // THUMBNAIL_WIDTH x THUMBNAIL_HEIGHT 8-bit RGB pixels.
+    pub thumbnail: Vec<u8>,
+
+    body_size: u32,
+    body_hash: u64,
+}
+
+// Builds the uncompressed machine state. Every device appends its fields in a fixed order,
+// and reads them back in the same order through StateReader.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    // Marks the start of a device's state, so a mismatch is caught where it happens.
+    pub fn write_tag(&mut self, tag: &[u8; 4]) {
+        self.data.extend_from_slice(tag);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i16(&mut self, value: i16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Slices are stored with their length, which is checked on load for fixed size arrays.
+    pub fn write_bytes(&mut self, values: &[u8]) {
+        self.write_u32(values.len() as u32);
+        self.data.extend_from_slice(values);
+    }
+
+    pub fn write_u16s(&mut self, values: &[u16]) {
+        self.write_u32(values.len() as u32);
+        for value in values {
+            self.write_u16(*value);
+        }
+    }
+
+    pub fn write_i16s(&mut self, values: &[i16]) {
+        self.write_u32(values.len() as u32);
+        for value in values {
+            self.write_i16(*value);
+        }
+    }
+
+    pub fn write_u32s(&mut self, values: &[u32]) {
+        self.write_u32(values.len() as u32);
+        for value in values {
+            self.write_u32(*value);
+        }
+    }
+
+    pub fn write_i32s(&mut self, values: &[i32]) {
+        self.write_u32(values.len() as u32);
+        for value in values {
+            self.write_i32(*value);
+        }
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data,
+            position: 0,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.position < length {
+            return Err(invalid("The save state ends early"));
+        }
+
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_tag(&mut self, tag: &[u8; 4]) -> io::Result<()> {
+        if self.take(4)? != tag {
+            return Err(invalid(&format!("The save state is missing the {} section", String::from_utf8_lossy(tag).trim())));
+        }
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        Ok(LittleEndian::read_u16(self.take(2)?))
+    }
+
+    pub fn read_i16(&mut self) -> io::Result<i16> {
+        Ok(LittleEndian::read_i16(self.take(2)?))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    pub fn read_i32(&mut self) -> io::Result<i32> {
+        Ok(LittleEndian::read_i32(self.take(4)?))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    fn read_length(&mut self, expected: Option<usize>) -> io::Result<usize> {
+        let length = self.read_u32()? as usize;
+
+        match expected {
+            Some(expected) if expected != length => Err(invalid(&format!("Array of {} entries in the save state, expected {}", length, expected))),
+            _ => Ok(length),
+        }
+    }
+
+    pub fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.read_length(None)?;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    pub fn read_bytes_into(&mut self, values: &mut [u8]) -> io::Result<()> {
+        self.read_length(Some(values.len()))?;
+        values.copy_from_slice(self.take(values.len())?);
+        Ok(())
+    }
+
+    pub fn read_u16s(&mut self) -> io::Result<Vec<u16>> {
+        let length = self.read_length(None)?;
+        (0..length).map(|_| self.read_u16()).collect()
+    }
+
+    pub fn read_u16s_into(&mut self, values: &mut [u16]) -> io::Result<()> {
+        self.read_length(Some(values.len()))?;
+        for value in values.iter_mut() {
+            *value = self.read_u16()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_i16s_into(&mut self, values: &mut [i16]) -> io::Result<()> {
+        self.read_length(Some(values.len()))?;
+        for value in values.iter_mut() {
+            *value = self.read_i16()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_u32s(&mut self) -> io::Result<Vec<u32>> {
+        let length = self.read_length(None)?;
+        (0..length).map(|_| self.read_u32()).collect()
+    }
+
+    pub fn read_u32s_into(&mut self, values: &mut [u32]) -> io::Result<()> {
+        self.read_length(Some(values.len()))?;
+        for value in values.iter_mut() {
+            *value = self.read_u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_i32s_into(&mut self, values: &mut [i32]) -> io::Result<()> {
+        self.read_length(Some(values.len()))?;
+        for value in values.iter_mut() {
+            *value = self.read_i32()?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// 64-bit FNV-1a, enough to tell BIOS versions and discs apart and to catch corrupted files.
+pub fn hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF29CE484222325;
+
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001B3);
+    }
+
+    hash
+}
+
+pub fn disc_hash(disc: &mut Disc) -> u64 {
+    let mut data = Vec::new();
+
+    for lba in DISC_HASH_START..DISC_HASH_START + DISC_HASH_SECTORS {
+        match disc.read_data_sector(lba) {
+            Ok(sector) => data.extend_from_slice(&sector),
+            Err(_) => break,
+        }
+    }
+
+    hash(&data)
+}
+
+// PackBits run-length encoding. A control byte n below 128 copies the next n + 1 bytes,
+// 129 and above repeats the next byte 257 - n times. Most of RAM and the expansion regions are
+// long runs of zeroes, which this shrinks well without needing a real compressor.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() / 4);
+    let mut position = 0;
+
+    while position < data.len() {
+        let value = data[position];
+        let mut run = 1;
+        while run < 128 && position + run < data.len() && data[position + run] == value {
+            run += 1;
+        }
+
+        if run >= 3 {
+            output.push((257 - run) as u8);
+            output.push(value);
+            position += run;
+            continue;
+        }
+
+        // Gather literals until the next run of three or more.
+        let start = position;
+        while position < data.len() && position - start < 128 {
+            if position + 2 < data.len() && data[position] == data[position + 1] && data[position] == data[position + 2] {
+                break;
+            }
+            position += 1;
+        }
+
+        output.push((position - start - 1) as u8);
+        output.extend_from_slice(&data[start..position]);
+    }
+
+    output
+}
+
+pub fn decompress(data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    if size > MAX_BODY_SIZE {
+        return Err(invalid("The save state is too large"));
+    }
+
+    let mut output = Vec::with_capacity(size);
+    let mut position = 0;
+
+    while position < data.len() {
+        let control = data[position] as usize;
+        position += 1;
+
+        if control < 128 {
+            let end = position + control + 1;
+            if end > data.len() {
+                return Err(invalid("The compressed data ends early"));
+            }
+            output.extend_from_slice(&data[position..end]);
+            position = end;
+        }
+        else if control > 128 {
+            let value = *data.get(position).ok_or_else(|| invalid("The compressed data ends early"))?;
+            output.resize(output.len() + 257 - control, value);
+            position += 1;
+        }
+
+        if output.len() > size {
+            return Err(invalid("The compressed data doesn't match its size"));
+        }
+    }
+
+    if output.len() != size {
+        return Err(invalid("The compressed data doesn't match its size"));
+    }
+
+    Ok(output)
+}
+
+// Where a slot is kept, per disc so every game gets its own set of slots.
+pub fn slot_path(disc_path: Option<&Path>, slot: usize) -> PathBuf {
+    let name = disc_path.and_then(|path| path.file_stem()).map(|stem| stem.to_string_lossy().to_string()).unwrap_or_else(|| String::from("bios"));
+    Path::new(STATE_DIRECTORY).join(format!("{}.{}.state", name, slot))
+}
+
+fn current_hashes(cpu: &mut Cpu) -> (u64, u64) {
+    let bios_hash = hash(&cpu.memory.bios);
+    let disc_hash = cpu.memory.cdrom.disc.as_mut().map(disc_hash).unwrap_or(0);
+    (bios_hash, disc_hash)
+}
+
+pub fn save(path: &Path, cpu: &mut Cpu, thumbnail: &[u8]) -> io::Result<()> {
+    let (bios_hash, disc_hash) = current_hashes(cpu);
+
+    let mut state = StateWriter::new();
+    cpu.save_state(&mut state);
+    let body = state.into_bytes();
+    let compressed = compress(&body);
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+
+    let mut header = StateWriter::new();
+    header.write_u32(VERSION);
+    header.write_u64(bios_hash);
+    header.write_u64(disc_hash);
+    header.write_u64(timestamp);
+    header.write_bytes(thumbnail);
+    header.write_u32(body.len() as u32);
+    header.write_u64(hash(&body));
+
+    let mut contents = MAGIC.to_vec();
+    contents.extend_from_slice(&header.into_bytes());
+    contents.extend_from_slice(&compressed);
+
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    // Written next to the slot first, so a failed save doesn't destroy the previous one.
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+fn parse_header(contents: &[u8]) -> io::Result<(Header, usize)> {
+    if contents.len() < MAGIC.len() || &contents[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Not a save state"));
+    }
+
+    let mut reader = StateReader::new(&contents[MAGIC.len()..]);
+
+    let version = reader.read_u32()?;
+    if version != VERSION {
+        return Err(invalid(&format!("The save state is version {}, this build only loads version {}", version, VERSION)));
+    }
+
+    let header = Header {
+        bios_hash: reader.read_u64()?,
+        disc_hash: reader.read_u64()?,
+        timestamp: reader.read_u64()?,
+        thumbnail: reader.read_bytes()?,
+
+        body_size: reader.read_u32()?,
+        body_hash: reader.read_u64()?,
+    };
+
+    Ok((header, MAGIC.len() + reader.position))
+}
+
+pub fn read_header(path: &Path) -> io::Result<Header> {
+    let contents = fs::read(path)?;
+    parse_header(&contents).map(|(header, _)| header)
+}
+
+// Restores a state saved by `save`. The version, hashes and checksum are all checked before the
+// CPU is touched. Devices load one after another, so if one of them still rejects its part, the
+// machine is put back from a snapshot taken beforehand. Either way a rejected state leaves the
+// running machine as it was.
+pub fn load(path: &Path, cpu: &mut Cpu) -> io::Result<()> {
+    let contents = fs::read(path)?;
+    let (header, body_start) = parse_header(&contents)?;
+
+    let (bios_hash, disc_hash) = current_hashes(cpu);
+    if header.bios_hash != bios_hash {
+        return Err(invalid("The save state was made with a different BIOS"));
+    }
+    if header.disc_hash != disc_hash {
+        return Err(invalid(if header.disc_hash == 0 { "The save state was made without a disc" } else { "The save state was made with a different disc" }));
+    }
+
+    let body = decompress(&contents[body_start..], header.body_size as usize)?;
+    if hash(&body) != header.body_hash {
+        return Err(invalid("The save state is corrupted"));
+    }
+
+    let mut backup = StateWriter::new();
+    cpu.save_state(&mut backup);
+    let backup = backup.into_bytes();
+
+    let mut state = StateReader::new(&body);
+    let result = cpu.load_state(&mut state).and_then(|_| {
+        if state.finished() { Ok(()) } else { Err(invalid("The save state has unexpected data at the end")) }
+    });
+
+    if result.is_err() {
+        cpu.load_state(&mut StateReader::new(&backup))?;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cpu::tests::test_cpu;
+
+    fn temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rusty_psx_{}_{}", std::process::id(), name))
+    }
+
+    fn state_of(cpu: &Cpu) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        cpu.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // A state file around the given body, with the header `save` would write for `cpu`.
+    fn write_state(path: &Path, cpu: &mut Cpu, body: &[u8]) {
+        let (bios_hash, disc_hash) = current_hashes(cpu);
+
+        let mut header = StateWriter::new();
+        header.write_u32(VERSION);
+        header.write_u64(bios_hash);
+        header.write_u64(disc_hash);
+        header.write_u64(0);
+        header.write_bytes(&[]);
+        header.write_u32(body.len() as u32);
+        header.write_u64(hash(body));
+
+        let mut contents = MAGIC.to_vec();
+        contents.extend_from_slice(&header.into_bytes());
+        contents.extend_from_slice(&compress(body));
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let mut mixed = vec![0; 1000];
+        mixed.extend((0..1000).map(|value| (value * 7 % 251) as u8));
+        mixed.extend_from_slice(&[1, 1, 2, 2, 2, 3, 3, 3, 3, 4]);
+        mixed.extend(vec![0xFF; 129]);
+
+        let inputs: Vec<Vec<u8>> = vec![Vec::new(), vec![5], vec![5, 5], vec![5, 5, 5], vec![0; 128], vec![0; 129], mixed];
+        for input in inputs.iter() {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed, input.len()).unwrap(), *input);
+        }
+
+        assert!(compress(&vec![0; 1024 * 1024]).len() < 20 * 1024);
+    }
+
+    #[test]
+    fn decompress_checks_the_size() {
+        let compressed = compress(&[9; 300]);
+
+        assert!(decompress(&compressed, 299).is_err());
+        assert!(decompress(&compressed, 301).is_err());
+        assert!(decompress(&compressed, MAX_BODY_SIZE + 1).is_err());
+        assert!(decompress(&[0x81], 128).is_err());
+        assert!(decompress(&[3, 1, 2], 4).is_err());
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut saved = test_cpu();
+        saved.pc = 0x80010000;
+        saved.registers[4] = 0x12345678;
+        saved.memory.ram[0x100] = 0xAB;
+        assert!(state_of(&saved).len() < MAX_BODY_SIZE);
+
+        let path = temporary_path("round_trip.state");
+        save(&path, &mut saved, &vec![0; THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 3]).unwrap();
+
+        let mut loaded = test_cpu();
+        let result = load(&path, &mut loaded);
+        fs::remove_file(&path).unwrap();
+
+        result.unwrap();
+        assert_eq!(loaded.pc, 0x80010000);
+        assert_eq!(loaded.registers[4], 0x12345678);
+        assert_eq!(loaded.memory.ram[0x100], 0xAB);
+        assert!(state_of(&loaded) == state_of(&saved));
+    }
+
+    #[test]
+    fn rejected_states_leave_the_machine_alone() {
+        let mut other = test_cpu();
+        other.registers[1] = 7;
+        other.memory.ram[0] = 7;
+        let body = state_of(&other);
+
+        let mut cpu = test_cpu();
+        cpu.registers[1] = 5;
+        let before = state_of(&cpu);
+
+        // The CPU and RAM load fine, then the state ends early.
+        let path = temporary_path("truncated.state");
+        write_state(&path, &mut cpu, &body[..body.len() - 4]);
+        assert!(load(&path, &mut cpu).is_err());
+        assert_eq!(cpu.registers[1], 5);
+        assert!(state_of(&cpu) == before);
+
+        let mut extra = body.clone();
+        extra.push(0);
+        write_state(&path, &mut cpu, &extra);
+        assert!(load(&path, &mut cpu).is_err());
+        assert!(state_of(&cpu) == before);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use imgui::*;
+
+use super::savestate;
+
+pub enum Request {
+    Save(usize),
+    Load(usize),
+}
+
+// Lists the save state slots for the current disc. Saving and loading are left to the caller,
+// which owns the CPU and has to grab the thumbnail from the finished frame.
+pub struct SaveStateWindow {
+    selected: usize,
+    status: String,
+
+    // Headers of every slot, reread after a save or when the disc changes.
+    headers: Vec<Option<savestate::Header>>,
+    headers_loaded: bool,
+    // Thumbnail texture of the selected slot, 0 until first needed.
+    texture: u32,
+    texture_slot: Option<usize>,
+}
+
+impl SaveStateWindow {
+    pub fn new() -> SaveStateWindow {
+        SaveStateWindow {
+            selected: 0,
+            status: String::new(),
+
+            headers: Vec::new(),
+            headers_loaded: false,
+            texture: 0,
+            texture_slot: None,
+        }
+    }
+
+    pub fn set_status(&mut self, status: String) {
+        self.status = status;
+    }
+
+    // Forgets the cached slot headers, after a save or when the disc changes.
+    pub fn refresh(&mut self) {
+        self.headers_loaded = false;
+        self.texture_slot = None;
+    }
+
+    fn load_headers(&mut self, disc_path: Option<&Path>) {
+        self.headers = (0..savestate::SLOT_COUNT).map(|slot| savestate::read_header(&savestate::slot_path(disc_path, slot)).ok()).collect();
+        self.headers_loaded = true;
+    }
+
+    fn upload_thumbnail(&mut self, pixels: &[u8]) -> TextureId {
+        unsafe {
+            if self.texture == 0 {
+                gl::GenTextures(1, &mut self.texture);
+            }
+
+            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGB as i32, savestate::THUMBNAIL_WIDTH as i32, savestate::THUMBNAIL_HEIGHT as i32, 0, gl::RGB, gl::UNSIGNED_BYTE, pixels.as_ptr() as _);
+        }
+
+        TextureId::from(self.texture as usize)
+    }
+
+    pub fn build(&mut self, ui: &Ui, disc_path: Option<&Path>) -> Option<Request> {
+        if !self.headers_loaded {
+            self.load_headers(disc_path);
+        }
+
+        let mut request = None;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+
+        Window::new(im_str!("Rusty PSX - Save States")).size([360.0, 420.0], Condition::FirstUseEver).build(ui, || {
+            for slot in 0..savestate::SLOT_COUNT {
+                let description = match &self.headers[slot] {
+                    Some(header) => format!("Saved {}", describe_age(now.saturating_sub(header.timestamp))),
+                    None => String::from("Empty"),
+                };
+
+                let label = ImString::new(format!("Slot {}: {}", slot, description));
+                if Selectable::new(&label).selected(self.selected == slot).build(ui) {
+                    self.selected = slot;
+                }
+            }
+
+            ui.separator();
+
+            let thumbnail = self.headers[self.selected].as_ref()
+                .map(|header| header.thumbnail.clone())
+                .filter(|thumbnail| thumbnail.len() == savestate::THUMBNAIL_WIDTH * savestate::THUMBNAIL_HEIGHT * 3);
+
+            if let Some(thumbnail) = thumbnail {
+                if self.texture_slot != Some(self.selected) {
+                    self.upload_thumbnail(&thumbnail);
+                    self.texture_slot = Some(self.selected);
+                }
+                Image::new(TextureId::from(self.texture as usize), [savestate::THUMBNAIL_WIDTH as f32 * 2.0, savestate::THUMBNAIL_HEIGHT as f32 * 2.0]).build(ui);
+            }
+
+            if ui.button(im_str!("Save state"), [120.0, 20.0]) {
+                request = Some(Request::Save(self.selected));
+            }
+            ui.same_line(0.0);
+            if ui.button(im_str!("Load state"), [120.0, 20.0]) {
+                request = Some(Request::Load(self.selected));
+            }
+
+            if !self.status.is_empty() {
+                ui.text_wrapped(&ImString::new(self.status.clone()));
+            }
+        });
+
+        request
+    }
+}
+
+fn describe_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => String::from("just now"),
+        60..=3599 => format!("{} minutes ago", seconds / 60),
+        3600..=86399 => format!("{} hours ago", seconds / 3600),
+        _ => format!("{} days ago", seconds / 86400),
+    }
+}
+
+// Grabs the finished frame from the back buffer, scaled down to thumbnail size.
+// Has to be called after rendering and before the buffers are swapped.
+pub fn capture_thumbnail(width: u32, height: u32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    if width == 0 || height == 0 {
+        return vec![0; savestate::THUMBNAIL_WIDTH * savestate::THUMBNAIL_HEIGHT * 3];
+    }
+
+    let mut frame = vec![0u8; width * height * 3];
+
+    unsafe {
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        gl::ReadPixels(0, 0, width as i32, height as i32, gl::RGB, gl::UNSIGNED_BYTE, frame.as_mut_ptr() as _);
+    }
+
+    let mut thumbnail = Vec::with_capacity(savestate::THUMBNAIL_WIDTH * savestate::THUMBNAIL_HEIGHT * 3);
+
+    for y in 0..savestate::THUMBNAIL_HEIGHT {
+        // OpenGL rows start at the bottom.
+        let source_y = height - 1 - (y * height / savestate::THUMBNAIL_HEIGHT).min(height - 1);
+
+        for x in 0..savestate::THUMBNAIL_WIDTH {
+            let source_x = x * width / savestate::THUMBNAIL_WIDTH;
+            let offset = (source_y * width + source_x) * 3;
+            thumbnail.extend_from_slice(&frame[offset..offset + 3]);
+        }
+    }
+
+    thumbnail
+}
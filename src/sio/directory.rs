@@ -0,0 +1,421 @@
+use std::io;
+use std::fs;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::shift_jis;
+use super::memcard::{FRAME_SIZE, update_checksum};
+
+pub const BLOCK_SIZE: usize = 8192;
+pub const BLOCK_COUNT: usize = 15;
+
+const FILE_NAME_LENGTH: usize = 20;
+const TITLE_LENGTH: usize = 64;
+
+// PS3 saves of PS1 games. The PS3 checks a signature at 0x1C that needs keys we don't have,
+// so exported files are left unsigned, which other tools and emulators accept.
+const PSV_SIGNATURE: &[u8] = b"\0VSP";
+const PSV_HEADER_SIZE: usize = 0x84;
+
+// States in the first word of a directory frame.
+const STATE_FREE: u8 = 0xA0;
+const STATE_FIRST: u8 = 0x51;
+const STATE_MIDDLE: u8 = 0x52;
+const STATE_LAST: u8 = 0x53;
+const STATE_DELETED_FIRST: u8 = 0xA1;
+const STATE_DELETED_LAST: u8 = 0xA3;
+
+// A save as found in the card directory, possibly deleted but still recoverable.
+pub struct Save {
+    // Blocks in chain order, 0 being the first block after the directory.
+    pub blocks: Vec<usize>,
+    pub file_name: String,
+    pub deleted: bool,
+    pub title: String,
+    // Icon frames as 16x16 RGBA pixels.
+    pub icon: Vec<Vec<u32>>,
+}
+
+impl Save {
+    pub fn first_block(&self) -> usize {
+        self.blocks[0]
+    }
+}
+
+fn directory_frame(block: usize) -> usize {
+    (block + 1) * FRAME_SIZE
+}
+
+fn block_start(block: usize) -> usize {
+    (block + 1) * BLOCK_SIZE
+}
+
+fn next_block(data: &[u8], block: usize) -> Option<usize> {
+    let frame = directory_frame(block);
+    let next = data[frame + 8] as usize | (data[frame + 9] as usize) << 8;
+
+    if next < BLOCK_COUNT { Some(next) } else { None }
+}
+
+// Lists every save whose first block is in use or deleted.
+pub fn list_saves(data: &[u8]) -> Vec<Save> {
+    let mut saves = Vec::new();
+
+    for first in 0..BLOCK_COUNT {
+        let frame = directory_frame(first);
+        let deleted = match data[frame] {
+            STATE_FIRST => false,
+            STATE_DELETED_FIRST => true,
+            _ => continue,
+        };
+
+        let mut blocks = vec![first];
+        let mut block = first;
+
+        // Bounded in case a corrupted directory links blocks in a loop.
+        while let Some(next) = next_block(data, block) {
+            if blocks.contains(&next) || blocks.len() == BLOCK_COUNT {
+                break;
+            }
+            blocks.push(next);
+            block = next;
+        }
+
+        let header = block_start(first);
+        let name_bytes = &data[frame + 0x0A..frame + 0x0A + FILE_NAME_LENGTH];
+        let name_length = name_bytes.iter().position(|byte| *byte == 0).unwrap_or(FILE_NAME_LENGTH);
+
+        saves.push(Save {
+            blocks,
+            file_name: String::from_utf8_lossy(&name_bytes[..name_length]).into_owned(),
+            deleted,
+            title: decode_title(&data[header + 4..header + 4 + TITLE_LENGTH]),
+            icon: decode_icon(&data[header..header + 4 * FRAME_SIZE]),
+        });
+    }
+
+    saves
+}
+
+pub fn free_blocks(data: &[u8]) -> usize {
+    (0..BLOCK_COUNT).filter(|block| is_free(data, *block)).count()
+}
+
+// Deleted blocks count as free, they're only recoverable until something overwrites them.
+fn is_free(data: &[u8], block: usize) -> bool {
+    let state = data[directory_frame(block)];
+    state == STATE_FREE || (STATE_DELETED_FIRST..=STATE_DELETED_LAST).contains(&state)
+}
+
+fn set_state(data: &mut [u8], block: usize, state: u8) {
+    let frame = directory_frame(block);
+    data[frame] = state;
+    update_checksum(&mut data[frame..frame + FRAME_SIZE]);
+}
+
+fn chain_state(save: &Save, position: usize, deleted: bool) -> u8 {
+    let state = if position == 0 {
+        STATE_FIRST
+    }
+    else if position + 1 == save.blocks.len() {
+        STATE_LAST
+    }
+    else {
+        STATE_MIDDLE
+    };
+
+    if deleted { state + 0x50 } else { state }
+}
+
+pub fn delete(data: &mut [u8], save: &Save) {
+    for (position, block) in save.blocks.iter().enumerate() {
+        set_state(data, *block, chain_state(save, position, true));
+    }
+}
+
+// Restores a deleted save, as long as none of its blocks were reused since.
+pub fn undelete(data: &mut [u8], save: &Save) -> io::Result<()> {
+    let intact = save.blocks.iter().enumerate().all(|(position, block)| {
+        data[directory_frame(*block)] == chain_state(save, position, true)
+    });
+
+    if !intact {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "some of the save's blocks have been reused"));
+    }
+
+    for (position, block) in save.blocks.iter().enumerate() {
+        set_state(data, *block, chain_state(save, position, false));
+    }
+
+    Ok(())
+}
+
+// Copies a save from one card to another, into whichever blocks are free there.
+pub fn copy(source: &[u8], save: &Save, destination: &mut [u8]) -> io::Result<()> {
+    let exists = list_saves(destination).iter().any(|other| !other.deleted && other.file_name == save.file_name);
+    if exists {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is already on the card", save.file_name)));
+    }
+
+    let free: Vec<usize> = (0..BLOCK_COUNT).filter(|block| is_free(destination, *block)).take(save.blocks.len()).collect();
+    if free.len() < save.blocks.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the save needs {} free blocks", save.blocks.len())));
+    }
+
+    for (position, (from, to)) in save.blocks.iter().zip(free.iter()).enumerate() {
+        let source_frame = directory_frame(*from);
+        let frame = directory_frame(*to);
+
+        destination[frame..frame + FRAME_SIZE].copy_from_slice(&source[source_frame..source_frame + FRAME_SIZE]);
+        destination[frame] = chain_state(save, position, false);
+
+        let next = free.get(position + 1).map(|block| *block as u16).unwrap_or(0xFFFF);
+        destination[frame + 8] = next as u8;
+        destination[frame + 9] = (next >> 8) as u8;
+        update_checksum(&mut destination[frame..frame + FRAME_SIZE]);
+
+        destination[block_start(*to)..block_start(*to) + BLOCK_SIZE].copy_from_slice(&source[block_start(*from)..block_start(*from) + BLOCK_SIZE]);
+    }
+
+    Ok(())
+}
+
+fn save_data(data: &[u8], save: &Save) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(save.blocks.len() * BLOCK_SIZE);
+
+    for block in save.blocks.iter() {
+        contents.extend_from_slice(&data[block_start(*block)..block_start(*block) + BLOCK_SIZE]);
+    }
+
+    contents
+}
+
+// Exports a single save as .mcs (directory frame and blocks) or .psv, based on the extension.
+pub fn export(data: &[u8], save: &Save, path: &Path) -> io::Result<()> {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_ascii_lowercase();
+    let save_bytes = save_data(data, save);
+    let mut contents = Vec::new();
+
+    match extension.as_str() {
+        "mcs" => {
+            let frame = directory_frame(save.first_block());
+            let mut header = data[frame..frame + FRAME_SIZE].to_vec();
+            header[0] = STATE_FIRST;
+            header[8] = 0xFF;
+            header[9] = 0xFF;
+            update_checksum(&mut header);
+
+            contents.extend_from_slice(&header);
+        },
+        "psv" => {
+            let mut header = vec![0; PSV_HEADER_SIZE];
+            header[..PSV_SIGNATURE.len()].copy_from_slice(PSV_SIGNATURE);
+            LittleEndian::write_u32(&mut header[0x38..], 0x14);
+            LittleEndian::write_u32(&mut header[0x3C..], 1);
+            // Size of the save in bytes, not blocks.
+            LittleEndian::write_u32(&mut header[0x40..], (save.blocks.len() * BLOCK_SIZE) as u32);
+            LittleEndian::write_u32(&mut header[0x44..], PSV_HEADER_SIZE as u32);
+            LittleEndian::write_u32(&mut header[0x48..], 0x200);
+            LittleEndian::write_u32(&mut header[0x5C..], BLOCK_SIZE as u32);
+            LittleEndian::write_u32(&mut header[0x60..], 0x9003);
+
+            let name_length = save.file_name.len().min(FILE_NAME_LENGTH);
+            header[0x64..0x64 + name_length].copy_from_slice(&save.file_name.as_bytes()[..name_length]);
+
+            contents.extend_from_slice(&header);
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "saves can only be exported as .mcs or .psv")),
+    }
+
+    contents.extend_from_slice(&save_bytes);
+    fs::write(path, contents)
+}
+
+// Decodes the icon frames from a save's header block. Colour 0 of the CLUT is transparent.
+fn decode_icon(header: &[u8]) -> Vec<Vec<u32>> {
+    if &header[0..2] != b"SC" {
+        return Vec::new();
+    }
+
+    let frame_count = match header[2] {
+        0x11 => 1,
+        0x12 => 2,
+        0x13 => 3,
+        _ => 1,
+    };
+
+    let palette: Vec<u32> = (0..16).map(|index| {
+        let colour = header[0x60 + index * 2] as u32 | (header[0x61 + index * 2] as u32) << 8;
+        if colour == 0 {
+            return 0;
+        }
+
+        let expand = |value: u32| (value << 3) | (value >> 2);
+        let red = expand(colour & 0x1F);
+        let green = expand((colour >> 5) & 0x1F);
+        let blue = expand((colour >> 10) & 0x1F);
+
+        // Stored as RGBA bytes in memory.
+        red | green << 8 | blue << 16 | 0xFF << 24
+    }).collect();
+
+    (0..frame_count).map(|frame| {
+        let pixels = &header[FRAME_SIZE * (frame + 1)..FRAME_SIZE * (frame + 2)];
+        pixels.iter().flat_map(|byte| vec![palette[(byte & 0xF) as usize], palette[(byte >> 4) as usize]]).collect()
+    }).collect()
+}
+
+// Decodes a save title from Shift-JIS. Full-width ASCII, which most titles are written in, comes
+// out as plain ASCII. Codes outside JIS X 0208 show up as '?'.
+pub fn decode_title(bytes: &[u8]) -> String {
+    let mut title = String::new();
+    let mut index = 0;
+
+    while index < bytes.len() && bytes[index] != 0 {
+        let lead = bytes[index];
+
+        if lead < 0x80 {
+            title.push(lead as char);
+            index += 1;
+            continue;
+        }
+
+        // Half-width katakana.
+        if (0xA1..=0xDF).contains(&lead) {
+            title.push(std::char::from_u32(0xFF61 + (lead - 0xA1) as u32).unwrap_or('?'));
+            index += 1;
+            continue;
+        }
+
+        let trail = match bytes.get(index + 1) {
+            Some(&trail) => trail,
+            None => break,
+        };
+        index += 2;
+
+        let character = match double_byte(lead, trail) {
+            0x3000 => ' ',
+            code @ 0xFF01..=0xFF5E => (code - 0xFF01 + 0x21) as u8 as char,
+            code => std::char::from_u32(code as u32).filter(|_| code != 0).unwrap_or('?'),
+        };
+
+        title.push(character);
+    }
+
+    title.trim_end().to_string()
+}
+
+// Looks up a double byte code, zero if it isn't one.
+fn double_byte(lead: u8, trail: u8) -> u16 {
+    let row = match lead {
+        0x81..=0x9F => lead - 0x81,
+        0xE0..=0xEF => lead - 0xE0 + 0x1F,
+        _ => return 0,
+    } as usize;
+
+    let column = match trail {
+        0x40..=0x7E => trail - 0x40,
+        0x80..=0xFC => trail - 0x41,
+        _ => return 0,
+    } as usize;
+
+    shift_jis::TABLE[row * 188 + column]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memcard::format;
+
+    // Writes a save called BESLES-00000GAME over the given blocks, with a title in the header block.
+    fn card_with_save(blocks: &[usize], title: &[u8]) -> Vec<u8> {
+        let mut data = format();
+
+        for (position, block) in blocks.iter().enumerate() {
+            let frame = directory_frame(*block);
+            data[frame] = if position == 0 { STATE_FIRST } else if position + 1 == blocks.len() { STATE_LAST } else { STATE_MIDDLE };
+
+            let next = blocks.get(position + 1).map(|block| *block as u16).unwrap_or(0xFFFF);
+            data[frame + 8] = next as u8;
+            data[frame + 9] = (next >> 8) as u8;
+            data[frame + 0x0A..frame + 0x0A + 16].copy_from_slice(b"BESLES-00000GAME");
+            update_checksum(&mut data[frame..frame + FRAME_SIZE]);
+
+            let start = block_start(*block);
+            for byte in data[start..start + BLOCK_SIZE].iter_mut() {
+                *byte = position as u8 + 1;
+            }
+        }
+
+        let header = block_start(blocks[0]);
+        data[header..header + 4].copy_from_slice(&[b'S', b'C', 0x11, blocks.len() as u8]);
+        data[header + 4..header + 4 + TITLE_LENGTH].iter_mut().for_each(|byte| *byte = 0);
+        data[header + 4..header + 4 + title.len()].copy_from_slice(title);
+
+        data
+    }
+
+    fn temporary_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rusty_psx_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn psv_header_matches_memcardrex() {
+        let data = card_with_save(&[2, 5, 3], b"GAME");
+        let save = &list_saves(&data)[0];
+        assert_eq!(save.blocks, vec![2, 5, 3]);
+
+        let path = temporary_path("save.psv");
+        export(&data, save, &path).unwrap();
+        let exported = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let word = |offset: usize| LittleEndian::read_u32(&exported[offset..]);
+        assert_eq!(&exported[..4], PSV_SIGNATURE);
+        assert_eq!(word(0x38), 0x14);
+        assert_eq!(word(0x3C), 1);
+        assert_eq!(word(0x40), 3 * 0x2000);
+        assert_eq!(word(0x44), 0x84);
+        assert_eq!(word(0x48), 0x200);
+        assert_eq!(word(0x5C), 0x2000);
+        assert_eq!(word(0x60), 0x9003);
+        assert_eq!(&exported[0x64..0x74], b"BESLES-00000GAME");
+        assert_eq!(&exported[0x74..0x78], &[0; 4]);
+
+        assert_eq!(exported.len(), PSV_HEADER_SIZE + 3 * BLOCK_SIZE);
+        assert_eq!(exported[PSV_HEADER_SIZE + 4], b'G');
+        assert_eq!(exported[PSV_HEADER_SIZE + BLOCK_SIZE], 2);
+        assert_eq!(exported[PSV_HEADER_SIZE + 2 * BLOCK_SIZE], 3);
+    }
+
+    #[test]
+    fn mcs_export_is_a_directory_frame_and_the_blocks() {
+        let data = card_with_save(&[0, 1], b"GAME");
+        let save = &list_saves(&data)[0];
+
+        let path = temporary_path("save.mcs");
+        export(&data, save, &path).unwrap();
+        let exported = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(exported.len(), FRAME_SIZE + 2 * BLOCK_SIZE);
+        assert_eq!(exported[0], STATE_FIRST);
+        assert_eq!(&exported[8..10], &[0xFF, 0xFF]);
+        assert_eq!(exported[..FRAME_SIZE - 1].iter().fold(0, |checksum, byte| checksum ^ byte), exported[FRAME_SIZE - 1]);
+    }
+
+    #[test]
+    fn titles_decode_shift_jis() {
+        // "ＡＢ１ あア ｱ" followed by padding.
+        let title = [0x82, 0x60, 0x82, 0x61, 0x82, 0x50, 0x81, 0x40, 0x82, 0xA0, 0x83, 0x41, 0x20, 0xB1, 0x20, 0x20];
+        assert_eq!(decode_title(&title), "AB1 あア ｱ");
+
+        // Kanji from the first and second levels, and the last row.
+        assert_eq!(decode_title(&[0x88, 0x9F, 0x98, 0x9F, 0xEA, 0xA4, b'X']), "亜弌熙X");
+        assert_eq!(decode_title(&[0x81, 0x69, 0x81, 0x79, 0x81, 0x7A, 0x81, 0x6A]), "(【】)");
+
+        // Unassigned codes and a lead byte without a trail.
+        assert_eq!(decode_title(&[0x85, 0x40, b'X', 0x88]), "?X");
+    }
+}
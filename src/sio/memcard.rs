@@ -0,0 +1,497 @@
+use std::io;
+use std::io::Write;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use super::Device;
+use super::super::savestate::{StateReader, StateWriter};
+
+pub const CARD_SIZE: usize = 128 * 1024;
+pub const FRAME_SIZE: usize = 128;
+const FRAME_COUNT: u16 = (CARD_SIZE / FRAME_SIZE) as u16;
+
+// DexDrive images have a 3904 byte header with the signature and one comment per save.
+const GME_HEADER_SIZE: usize = 0xF40;
+const GME_SIGNATURE: &[u8] = b"123-456-STD";
+// PSP/PS3 virtual memory cards have a 128 byte header, starting with "\0PMV".
+const VMP_HEADER_SIZE: usize = 0x80;
+const VMP_SIGNATURE: &[u8] = b"\0PMV";
+
+// FLAG bit 3, set until the first successful write after power on.
+const FLAG_NOT_WRITTEN: u8 = 0x08;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ImageFormat {
+    // The 128KB of card data and nothing else (.mcr/.mcd/.bin).
+    Raw,
+    // DexDrive (.gme).
+    DexDrive,
+    // PSP/PS3 virtual memory card (.vmp).
+    Psp,
+}
+
+impl ImageFormat {
+    fn from_extension(path: &Path) -> ImageFormat {
+        match extension(path).as_str() {
+            "gme" => ImageFormat::DexDrive,
+            "vmp" => ImageFormat::Psp,
+            _ => ImageFormat::Raw,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Read,
+    Write,
+    Id,
+}
+
+// A 128KB memory card on the SIO0 bus, backed by an image on disk.
+pub struct MemoryCard {
+    pub data: Vec<u8>,
+    path: Option<PathBuf>,
+    // What the image on disk was, which it's saved back as.
+    format: ImageFormat,
+    dirty: bool,
+    // Bumped on every change to `data`, so views of the card know when to refresh.
+    revision: u32,
+    flag: u8,
+
+    index: usize,
+    command: Option<Command>,
+    sector: u16,
+    checksum: u8,
+    // Byte received during the previous step, which writes echo back.
+    previous: u8,
+    buffer: Vec<u8>,
+}
+
+impl MemoryCard {
+    pub fn new(data: Vec<u8>, path: Option<PathBuf>) -> MemoryCard {
+        let format = path.as_ref().map_or(ImageFormat::Raw, |path| ImageFormat::from_extension(path));
+
+        MemoryCard {
+            data,
+            path,
+            format,
+            dirty: false,
+            revision: 0,
+            flag: FLAG_NOT_WRITTEN,
+
+            index: 0,
+            command: None,
+            sector: 0,
+            checksum: 0,
+            previous: 0,
+            buffer: Vec::with_capacity(FRAME_SIZE),
+        }
+    }
+
+    // Opens the card image at `path`, or starts a freshly formatted one there if it doesn't exist yet.
+    // Changes are saved back to `path` in the format the image was in, whatever its extension says.
+    pub fn open(path: &Path) -> io::Result<MemoryCard> {
+        if !path.exists() {
+            return Ok(MemoryCard::new(format(), Some(path.to_path_buf())));
+        }
+
+        let (data, format) = import_image(path)?;
+
+        let mut card = MemoryCard::new(data, Some(path.to_path_buf()));
+        card.format = format;
+        Ok(card)
+    }
+
+    // Marks the card as changed from outside the emulated bus, so it gets saved.
+    pub fn modified(&mut self) {
+        self.dirty = true;
+        self.revision = self.revision.wrapping_add(1);
+    }
+
+    pub fn revision(&self) -> u32 {
+        self.revision
+    }
+
+    fn start_command(&mut self, value: u8) -> bool {
+        self.command = match value {
+            0x52 => Some(Command::Read),
+            0x57 => Some(Command::Write),
+            0x53 => Some(Command::Id),
+            _ => None,
+        };
+
+        self.command.is_some()
+    }
+
+    fn read_step(&mut self, step: usize, value: u8) -> (u8, bool) {
+        match step {
+            0 => (0x5A, true),
+            1 => (0x5D, true),
+            2 => {
+                self.sector = (value as u16) << 8;
+                (0x00, true)
+            },
+            3 => {
+                self.sector |= value as u16;
+
+                // Out of range sectors answer FFFF and end the transfer there.
+                if self.sector >= FRAME_COUNT {
+                    self.sector = 0xFFFF;
+                }
+                (self.previous, true)
+            },
+            4 if self.sector == 0xFFFF => (0xFF, false),
+            4 => (0x5C, true),
+            5 => (0x5D, true),
+            6 => {
+                self.checksum = (self.sector >> 8) as u8 ^ self.sector as u8;
+                ((self.sector >> 8) as u8, true)
+            },
+            7 => (self.sector as u8, true),
+            8..=135 => {
+                let value = self.data[self.sector as usize * FRAME_SIZE + step - 8];
+                self.checksum ^= value;
+                (value, true)
+            },
+            136 => (self.checksum, true),
+            137 => (0x47, false),
+            _ => (0xFF, false),
+        }
+    }
+
+    fn write_step(&mut self, step: usize, value: u8) -> (u8, bool) {
+        match step {
+            0 => (0x5A, true),
+            1 => (0x5D, true),
+            2 => {
+                self.sector = (value as u16) << 8;
+                self.checksum = value;
+                self.buffer.clear();
+                (0x00, true)
+            },
+            3 => {
+                self.sector |= value as u16;
+                self.checksum ^= value;
+                (self.previous, true)
+            },
+            4..=131 => {
+                self.buffer.push(value);
+                self.checksum ^= value;
+                (self.previous, true)
+            },
+            132 => {
+                self.checksum ^= value;
+                (self.previous, true)
+            },
+            133 => (0x5C, true),
+            134 => (0x5D, true),
+            135 => {
+                // The checksum covers the sector number and data, so a good one XORs out to zero.
+                let end = if self.sector >= FRAME_COUNT {
+                    0xFF
+                }
+                else if self.checksum != 0 {
+                    0x4E
+                }
+                else {
+                    let start = self.sector as usize * FRAME_SIZE;
+                    self.data[start..start + FRAME_SIZE].copy_from_slice(&self.buffer);
+                    self.flag &= !FLAG_NOT_WRITTEN;
+                    self.modified();
+                    0x47
+                };
+                (end, false)
+            },
+            _ => (0xFF, false),
+        }
+    }
+
+    fn id_step(&mut self, step: usize) -> (u8, bool) {
+        const REPLY: [u8; 8] = [0x5A, 0x5D, 0x5C, 0x5D, 0x04, 0x00, 0x00, 0x80];
+
+        match REPLY.get(step) {
+            Some(&value) => (value, step + 1 < REPLY.len()),
+            None => (0xFF, false),
+        }
+    }
+}
+
+impl Device for MemoryCard {
+    fn transfer(&mut self, value: u8) -> (u8, bool) {
+        let index = self.index;
+        self.index += 1;
+
+        let reply = match index {
+            0 => (0xFF, true),
+            1 => {
+                let known = self.start_command(value);
+                (self.flag, known)
+            },
+            _ => {
+                let step = index - 2;
+                match self.command {
+                    Some(Command::Read) => self.read_step(step, value),
+                    Some(Command::Write) => self.write_step(step, value),
+                    Some(Command::Id) => self.id_step(step),
+                    None => (0xFF, false),
+                }
+            },
+        };
+
+        self.previous = value;
+        reply
+    }
+
+    fn deselect(&mut self) {
+        self.index = 0;
+        self.command = None;
+    }
+
+    // Writes the card back to disk if it changed. The image goes to a temporary file first, synced
+    // to the disk and then renamed over the old one, so a crash at any point leaves either the
+    // previous save or the new one.
+    fn flush(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        let mut file = File::create(&temporary)?;
+        file.write_all(&encode(&self.data, self.format))?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&temporary, path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Only the state of the protocol is saved. The card's contents stay on disk, so loading an
+    // older state doesn't roll back saves made since.
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"CARD");
+        state.write_u8(self.flag);
+
+        state.write_u32(self.index as u32);
+        state.write_u8(match self.command {
+            None => 0,
+            Some(Command::Read) => 1,
+            Some(Command::Write) => 2,
+            Some(Command::Id) => 3,
+        });
+        state.write_u16(self.sector);
+        state.write_u8(self.checksum);
+        state.write_u8(self.previous);
+        state.write_bytes(&self.buffer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"CARD")?;
+        self.flag = state.read_u8()?;
+
+        self.index = state.read_u32()? as usize;
+        self.command = match state.read_u8()? {
+            1 => Some(Command::Read),
+            2 => Some(Command::Write),
+            3 => Some(Command::Id),
+            _ => None,
+        };
+        self.sector = state.read_u16()?;
+        self.checksum = state.read_u8()?;
+        self.previous = state.read_u8()?;
+        self.buffer = state.read_bytes()?;
+        Ok(())
+    }
+}
+
+fn extension(path: &Path) -> String {
+    path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_ascii_lowercase()
+}
+
+// Reads a card image in any of the supported formats, returning the raw 128KB of card data.
+pub fn import(path: &Path) -> io::Result<Vec<u8>> {
+    import_image(path).map(|(data, _)| data)
+}
+
+// The card data of an image, and the format it was in going by its contents.
+fn import_image(path: &Path) -> io::Result<(Vec<u8>, ImageFormat)> {
+    let contents = fs::read(path)?;
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message));
+
+    let (data, format) = if contents.starts_with(GME_SIGNATURE) {
+        (contents.get(GME_HEADER_SIZE..GME_HEADER_SIZE + CARD_SIZE).ok_or_else(|| invalid("truncated DexDrive image"))?, ImageFormat::DexDrive)
+    }
+    else if contents.starts_with(VMP_SIGNATURE) {
+        (contents.get(VMP_HEADER_SIZE..VMP_HEADER_SIZE + CARD_SIZE).ok_or_else(|| invalid("truncated PSP memory card image"))?, ImageFormat::Psp)
+    }
+    else if contents.len() == CARD_SIZE {
+        (&contents[..], ImageFormat::Raw)
+    }
+    else {
+        return Err(invalid("not a memory card image"));
+    };
+
+    if &data[0..2] != b"MC" {
+        return Err(invalid("the card isn't formatted"));
+    }
+
+    Ok((data.to_vec(), format))
+}
+
+// Writes card data to `path`, in the format its extension asks for.
+pub fn export(data: &[u8], path: &Path) -> io::Result<()> {
+    fs::write(path, encode(data, ImageFormat::from_extension(path)))
+}
+
+fn encode(data: &[u8], format: ImageFormat) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(CARD_SIZE + GME_HEADER_SIZE);
+
+    match format {
+        ImageFormat::DexDrive => {
+            let mut header = vec![0; GME_HEADER_SIZE];
+            header[..GME_SIGNATURE.len()].copy_from_slice(GME_SIGNATURE);
+            header[0x12] = 0x01;
+            header[0x14] = 0x01;
+            header[0x15] = b'M';
+
+            // Copies of each directory frame's state byte and next block pointer. Comments stay empty.
+            for frame in 0..16 {
+                header[0x16 + frame] = data[frame * FRAME_SIZE];
+                header[0x26 + frame] = data[frame * FRAME_SIZE + 8];
+            }
+
+            contents.extend_from_slice(&header);
+        },
+        ImageFormat::Psp => {
+            // The PSP also checks a signature at 0x0C, derived with keys we don't have.
+            // Other emulators ignore it, the PSP will reject the card.
+            let mut header = vec![0; VMP_HEADER_SIZE];
+            header[..VMP_SIGNATURE.len()].copy_from_slice(VMP_SIGNATURE);
+            header[4] = VMP_HEADER_SIZE as u8;
+
+            contents.extend_from_slice(&header);
+        },
+        ImageFormat::Raw => {},
+    }
+
+    contents.extend_from_slice(data);
+    contents
+}
+
+// Returns the data of a blank, formatted card.
+pub fn format() -> Vec<u8> {
+    let mut data = vec![0; CARD_SIZE];
+
+    data[0] = b'M';
+    data[1] = b'C';
+
+    for frame in 1..16 {
+        let start = frame * FRAME_SIZE;
+        data[start] = 0xA0;
+        data[start + 8] = 0xFF;
+        data[start + 9] = 0xFF;
+    }
+
+    // The broken sector list, with no replacements in use.
+    for frame in 16..36 {
+        let start = frame * FRAME_SIZE;
+        data[start..start + 4].copy_from_slice(&[0xFF; 4]);
+        data[start + 8] = 0xFF;
+        data[start + 9] = 0xFF;
+    }
+
+    for frame in 0..36 {
+        update_checksum(&mut data[frame * FRAME_SIZE..(frame + 1) * FRAME_SIZE]);
+    }
+
+    // The last frame of the header block is used to test writes, and starts as a copy of frame 0.
+    let (header, rest) = data.split_at_mut(FRAME_SIZE);
+    rest[62 * FRAME_SIZE..63 * FRAME_SIZE].copy_from_slice(header);
+
+    data
+}
+
+// Header frames end with an XOR of their other 127 bytes.
+pub fn update_checksum(frame: &mut [u8]) {
+    frame[FRAME_SIZE - 1] = frame[..FRAME_SIZE - 1].iter().fold(0, |checksum, value| checksum ^ value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rusty_psx_{}_{}", std::process::id(), name))
+    }
+
+    fn save_changed(path: &Path) -> Vec<u8> {
+        let mut card = MemoryCard::open(path).unwrap();
+        card.data[64 * FRAME_SIZE] = 0x51;
+        card.modified();
+        card.flush().unwrap();
+        card.data.clone()
+    }
+
+    #[test]
+    fn images_keep_their_format() {
+        for name in ["card.gme", "card.vmp", "card.mcr"].iter() {
+            let path = temporary_path(name);
+            export(&format(), &path).unwrap();
+            let original = fs::read(&path).unwrap();
+
+            let data = save_changed(&path);
+            let saved = fs::read(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            assert_eq!(saved.len(), original.len(), "{}", name);
+            assert_eq!(saved[..saved.len() - CARD_SIZE], original[..original.len() - CARD_SIZE], "{}", name);
+            assert_eq!(saved[saved.len() - CARD_SIZE..], data[..], "{}", name);
+            assert!(!temporary_path(&format!("{}.tmp", name)).exists());
+        }
+    }
+
+    // The contents decide, a DexDrive image with the wrong extension stays a DexDrive image.
+    #[test]
+    fn format_comes_from_the_contents() {
+        let path = temporary_path("dexdrive.mcr");
+        fs::write(&path, encode(&format(), ImageFormat::DexDrive)).unwrap();
+
+        let data = save_changed(&path);
+        let saved = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(saved.starts_with(GME_SIGNATURE));
+        assert_eq!(saved[GME_HEADER_SIZE..], data[..]);
+    }
+
+    #[test]
+    fn new_cards_use_the_extension() {
+        let path = temporary_path("new.vmp");
+        let _ = fs::remove_file(&path);
+
+        save_changed(&path);
+        let saved = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(saved.starts_with(VMP_SIGNATURE));
+        assert_eq!(saved.len(), VMP_HEADER_SIZE + CARD_SIZE);
+    }
+
+    #[test]
+    fn unformatted_and_odd_images_are_rejected() {
+        let path = temporary_path("odd.mcr");
+
+        fs::write(&path, vec![0; CARD_SIZE]).unwrap();
+        assert!(import(&path).is_err());
+
+        fs::write(&path, vec![0; 1000]).unwrap();
+        assert!(import(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,566 @@
+pub mod pad;
+pub mod multitap;
+pub mod memcard;
+pub mod directory;
+mod shift_jis;
+
+use std::io;
+use std::collections::VecDeque;
+
+use super::input::{InputState, PadState};
+use super::savestate::{StateReader, StateWriter};
+
+// Cycles between the end of a byte transfer and the device pulling /ACK low.
+const ACK_DELAY: u32 = 338;
+// How long a device holds /ACK low.
+const ACK_LENGTH: u32 = 100;
+
+const RX_FIFO_SIZE: usize = 8;
+
+// Something that can sit on a controller port, talking one byte at a time.
+// The port routes a transfer to a device based on the address byte that starts it:
+// 0x01 for controllers and multitaps, 0x81 for memory cards.
+pub trait Device {
+    // Exchanges one byte, the address byte included. Returns the reply and whether
+    // the device acknowledges it, which it only does if it expects more bytes.
+    fn transfer(&mut self, value: u8) -> (u8, bool);
+
+    // /JOYn went high, ending the current transfer.
+    fn deselect(&mut self);
+
+    // Input for the pads on this port, indexed by multitap slot.
+    fn update_input(&mut self, _pads: &[PadState; 4]) {}
+
+    // Rumble motor speeds as (small, large), for each multitap slot.
+    fn rumble(&self) -> [(u8, u8); 4] {
+        [(0, 0); 4]
+    }
+
+    // Saves anything the device keeps on disk.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Protocol and mode state for save states. Input isn't included, it's fed in every frame.
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Devices are stored as a blob each, so a state made with a different controller plugged in
+// still loads, with that device keeping its current state.
+pub fn save_device<D: Device + ?Sized>(device: Option<&D>, state: &mut StateWriter) {
+    let mut blob = StateWriter::new();
+    if let Some(device) = device {
+        device.save_state(&mut blob);
+    }
+    state.write_bytes(&blob.into_bytes());
+}
+
+pub fn load_device<D: Device + ?Sized>(device: Option<&mut D>, state: &mut StateReader) -> io::Result<()> {
+    let blob = state.read_bytes()?;
+
+    if let Some(device) = device {
+        if blob.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(error) = device.load_state(&mut StateReader::new(&blob)) {
+            println!("Couldn't restore a device on the controller port: {}", error);
+            device.deselect();
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ControllerKind {
+    Digital,
+    DualShock,
+    // A multitap with a DualShock in every slot.
+    Multitap,
+}
+
+impl ControllerKind {
+    pub fn from_name(name: &str) -> Option<ControllerKind> {
+        match name {
+            "digital" => Some(ControllerKind::Digital),
+            "dualshock" => Some(ControllerKind::DualShock),
+            "multitap" => Some(ControllerKind::Multitap),
+            _ => None,
+        }
+    }
+
+    pub fn create(self) -> Box<dyn Device> {
+        match self {
+            ControllerKind::Digital => Box::new(pad::DigitalPad::new()),
+            ControllerKind::DualShock => Box::new(pad::DualShock::new()),
+            ControllerKind::Multitap => {
+                let mut multitap = multitap::Multitap::new();
+                for slot in multitap.slots.iter_mut() {
+                    *slot = Some(Box::new(pad::DualShock::new()));
+                }
+                Box::new(multitap)
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    // Waiting for an address byte.
+    None,
+    Controller,
+    MemoryCard,
+    // Nobody answered the address byte, or the device stopped acknowledging.
+    Finished,
+}
+
+pub struct Port {
+    pub controller: Option<Box<dyn Device>>,
+    pub memory_card: Option<memcard::MemoryCard>,
+    target: Target,
+}
+
+impl Port {
+    pub fn new() -> Port {
+        Port {
+            controller: None,
+            memory_card: None,
+            target: Target::None,
+        }
+    }
+
+    fn transfer(&mut self, value: u8) -> (u8, bool) {
+        if self.target == Target::None {
+            self.target = match value {
+                0x01 if self.controller.is_some() => Target::Controller,
+                0x81 if self.memory_card.is_some() => Target::MemoryCard,
+                _ => Target::Finished,
+            };
+        }
+
+        let device: Option<&mut dyn Device> = match self.target {
+            Target::Controller => self.controller.as_mut().map(|controller| controller.as_mut()),
+            Target::MemoryCard => self.memory_card.as_mut().map(|memory_card| memory_card as &mut dyn Device),
+            _ => None,
+        };
+
+        let (reply, ack) = match device {
+            Some(device) => device.transfer(value),
+            None => (0xFF, false),
+        };
+
+        if !ack {
+            self.target = Target::Finished;
+        }
+
+        (reply, ack)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(match self.target {
+            Target::None => 0,
+            Target::Controller => 1,
+            Target::MemoryCard => 2,
+            Target::Finished => 3,
+        });
+
+        save_device(self.controller.as_deref(), state);
+        save_device(self.memory_card.as_ref(), state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.target = match state.read_u8()? {
+            1 => Target::Controller,
+            2 => Target::MemoryCard,
+            3 => Target::Finished,
+            _ => Target::None,
+        };
+
+        load_device(self.controller.as_deref_mut(), state)?;
+        load_device(self.memory_card.as_mut(), state)?;
+
+        // The device loaded above may not be the one the target pointed at.
+        if self.target == Target::Controller && self.controller.is_none() || self.target == Target::MemoryCard && self.memory_card.is_none() {
+            self.target = Target::Finished;
+        }
+
+        Ok(())
+    }
+
+    fn deselect(&mut self) {
+        if let Some(controller) = self.controller.as_mut() {
+            controller.deselect();
+        }
+        if let Some(memory_card) = self.memory_card.as_mut() {
+            memory_card.deselect();
+        }
+
+        self.target = Target::None;
+    }
+}
+
+// The controller and memory card serial port, JOY_DATA/STAT/MODE/CTRL/BAUD at 0x1F801040.
+pub struct Sio0 {
+    pub ports: [Port; 2],
+
+    mode: u16,
+    control: u16,
+    baud: u16,
+
+    rx_fifo: VecDeque<u8>,
+    // Byte being shifted out, and cycles left until it's done.
+    transfer: Option<u8>,
+    transfer_cycles: u32,
+    // Cycles until /ACK goes low and back high. Zero when idle.
+    ack_delay: u32,
+    ack_cycles: u32,
+
+    interrupt: bool,
+    pub irq_pending: bool,
+}
+
+impl Sio0 {
+    pub fn new() -> Sio0 {
+        Sio0 {
+            ports: [Port::new(), Port::new()],
+
+            mode: 0,
+            control: 0,
+            baud: 0,
+
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_SIZE),
+            transfer: None,
+            transfer_cycles: 0,
+            ack_delay: 0,
+            ack_cycles: 0,
+
+            interrupt: false,
+            irq_pending: false,
+        }
+    }
+
+    pub fn update_input(&mut self, input: &InputState) {
+        for (port, pads) in self.ports.iter_mut().zip(input.pads.iter()) {
+            if let Some(controller) = port.controller.as_mut() {
+                controller.update_input(pads);
+            }
+        }
+    }
+
+    pub fn rumble(&self, port: usize) -> [(u8, u8); 4] {
+        match self.ports[port].controller.as_ref() {
+            Some(controller) => controller.rumble(),
+            None => [(0, 0); 4],
+        }
+    }
+
+    pub fn flush_memory_cards(&mut self) -> io::Result<()> {
+        for port in self.ports.iter_mut() {
+            if let Some(memory_card) = port.memory_card.as_mut() {
+                memory_card.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"SIO0");
+        for port in self.ports.iter() {
+            port.save_state(state);
+        }
+
+        state.write_u16(self.mode);
+        state.write_u16(self.control);
+        state.write_u16(self.baud);
+
+        state.write_bytes(&self.rx_fifo.iter().cloned().collect::<Vec<u8>>());
+        state.write_bool(self.transfer.is_some());
+        state.write_u8(self.transfer.unwrap_or(0));
+        state.write_u32(self.transfer_cycles);
+        state.write_u32(self.ack_delay);
+        state.write_u32(self.ack_cycles);
+
+        state.write_bool(self.interrupt);
+        state.write_bool(self.irq_pending);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"SIO0")?;
+        for port in self.ports.iter_mut() {
+            port.load_state(state)?;
+        }
+
+        self.mode = state.read_u16()?;
+        self.control = state.read_u16()?;
+        self.baud = state.read_u16()?;
+
+        self.rx_fifo = state.read_bytes()?.into_iter().collect();
+        let transferring = state.read_bool()?;
+        let value = state.read_u8()?;
+        self.transfer = if transferring { Some(value) } else { None };
+        self.transfer_cycles = state.read_u32()?;
+        self.ack_delay = state.read_u32()?;
+        self.ack_cycles = state.read_u32()?;
+
+        self.interrupt = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        Ok(())
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if self.ack_cycles > 0 {
+            self.ack_cycles = self.ack_cycles.saturating_sub(cycles);
+        }
+
+        if self.ack_delay > 0 {
+            if self.ack_delay > cycles {
+                self.ack_delay -= cycles;
+            }
+            else {
+                self.ack_delay = 0;
+                self.ack_cycles = ACK_LENGTH;
+
+                if self.control & 0x1000 != 0 && !self.interrupt {
+                    self.interrupt = true;
+                    self.irq_pending = true;
+                }
+            }
+        }
+
+        if self.transfer.is_some() {
+            if self.transfer_cycles > cycles {
+                self.transfer_cycles -= cycles;
+            }
+            else {
+                let value = self.transfer.take().unwrap();
+                self.finish_transfer(value);
+            }
+        }
+    }
+
+    fn transfer_length(&self) -> u32 {
+        let factor = match self.mode & 3 {
+            2 => 16,
+            3 => 64,
+            _ => 1,
+        };
+
+        (self.baud as u32 * factor * 8).max(1)
+    }
+
+    fn selected(&self) -> bool {
+        self.control & 0x0002 != 0
+    }
+
+    fn selected_port(&self) -> usize {
+        ((self.control >> 13) & 1) as usize
+    }
+
+    fn finish_transfer(&mut self, value: u8) {
+        let (reply, ack) = if self.selected() {
+            let port = self.selected_port();
+            self.ports[port].transfer(value)
+        }
+        else {
+            (0xFF, false)
+        };
+
+        if self.rx_fifo.len() == RX_FIFO_SIZE {
+            self.rx_fifo.pop_front();
+        }
+        self.rx_fifo.push_back(reply);
+
+        if ack {
+            self.ack_delay = ACK_DELAY;
+        }
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = 0;
+
+        if self.transfer.is_none() {
+            status |= 0x0001 | 0x0004;
+        }
+        if !self.rx_fifo.is_empty() {
+            status |= 0x0002;
+        }
+        if self.ack_cycles > 0 {
+            status |= 0x0080;
+        }
+        if self.interrupt {
+            status |= 0x0200;
+        }
+
+        status
+    }
+
+    // Side effect free read, for everything except popping JOY_DATA.
+    pub fn read_register(&self, offset: u32) -> u32 {
+        match offset {
+            0x0 => {
+                let mut value = 0;
+                for (index, byte) in self.rx_fifo.iter().take(4).enumerate() {
+                    value |= (*byte as u32) << (index * 8);
+                }
+                if self.rx_fifo.is_empty() { 0xFF } else { value }
+            },
+            0x4 => self.status(),
+            0x8 => self.mode as u32 | (self.control as u32) << 16,
+            0xC => (self.baud as u32) << 16,
+            _ => 0,
+        }
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        self.rx_fifo.pop_front().unwrap_or(0xFF)
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        if self.transfer.is_some() {
+            println!("SIO0: byte {:02X} written while a transfer is in progress", value);
+        }
+
+        self.transfer = Some(value);
+        self.transfer_cycles = self.transfer_length();
+    }
+
+    pub fn write_mode(&mut self, value: u16) {
+        self.mode = value;
+    }
+
+    pub fn write_baud(&mut self, value: u16) {
+        self.baud = value;
+    }
+
+    pub fn write_control(&mut self, value: u16) {
+        let was_selected = self.selected();
+        let previous_port = self.selected_port();
+
+        if value & 0x0040 != 0 {
+            self.mode = 0;
+            self.baud = 0;
+            self.rx_fifo.clear();
+            self.transfer = None;
+            self.ack_delay = 0;
+            self.ack_cycles = 0;
+            self.interrupt = false;
+            self.control = 0;
+        }
+        else {
+            // Bits 4 and 6 are write-only strobes.
+            self.control = value & !0x0050;
+        }
+
+        if value & 0x0010 != 0 {
+            self.interrupt = false;
+        }
+
+        if was_selected && (!self.selected() || self.selected_port() != previous_port) {
+            self.ports[previous_port].deselect();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::memory::CpuMemory;
+
+    // TX enable, /JOY select and the /ACK interrupt, on port 1.
+    const SELECT: u16 = 0x1003;
+
+    // A port with `controller` plugged into port 1, at the BIOS's baud of 0x88 with a 1x factor.
+    pub fn sio_with(controller: Box<dyn Device>) -> Sio0 {
+        let mut sio = Sio0::new();
+        sio.ports[0].controller = Some(controller);
+        sio.write_mode(0x000D);
+        sio.write_baud(0x88);
+        sio
+    }
+
+    // Feeds `input` to the pads and sends `bytes` as one transfer, waiting for every byte and its
+    // /ACK the way the BIOS does, then deselects. Stops after the first byte that isn't acknowledged.
+    pub fn exchange(sio: &mut Sio0, input: &InputState, bytes: &[u8]) -> Vec<u8> {
+        sio.update_input(input);
+        sio.write_control(SELECT);
+
+        let mut replies = Vec::new();
+        for byte in bytes.iter() {
+            sio.write_data(*byte);
+            sio.tick(sio.transfer_length());
+            replies.push(sio.read_data());
+
+            sio.tick(ACK_DELAY);
+            if !sio.interrupt {
+                break;
+            }
+
+            sio.tick(ACK_LENGTH);
+            sio.write_control(SELECT | 0x0010);
+        }
+
+        sio.write_control(0);
+        replies
+    }
+
+    #[test]
+    fn transfer_and_ack_timing() {
+        let mut sio = sio_with(Box::new(pad::DigitalPad::new()));
+        sio.write_control(SELECT);
+        sio.write_data(0x01);
+
+        // 0x88 * 8 cycles per byte.
+        sio.tick(0x88 * 8 - 1);
+        assert_eq!(sio.status() & 0x0007, 0x0000);
+        sio.tick(1);
+        assert_eq!(sio.status() & 0x0007, 0x0007);
+        assert_eq!(sio.read_data(), 0xFF);
+
+        sio.tick(ACK_DELAY - 1);
+        assert_eq!(sio.status() & 0x0280, 0x0000);
+        assert!(!sio.irq_pending);
+        sio.tick(1);
+        assert_eq!(sio.status() & 0x0280, 0x0280);
+        assert!(sio.irq_pending);
+
+        sio.tick(ACK_LENGTH);
+        assert_eq!(sio.status() & 0x0280, 0x0200);
+
+        sio.write_control(SELECT | 0x0010);
+        assert_eq!(sio.status() & 0x0200, 0x0000);
+    }
+
+    #[test]
+    fn nothing_answers_an_empty_port() {
+        let mut sio = Sio0::new();
+        sio.write_mode(0x000D);
+        sio.write_baud(0x88);
+
+        assert_eq!(exchange(&mut sio, &InputState::new(), &[0x01, 0x42]), [0xFF]);
+        assert!(!sio.irq_pending);
+    }
+
+    #[test]
+    fn acks_raise_irq7() {
+        let mut memory = CpuMemory::new(vec![0; 512 * 1024]);
+        memory.sio0.ports[0].controller = Some(Box::new(pad::DigitalPad::new()));
+
+        memory.write_halfword(0x1F801048, 0x000D);
+        memory.write_halfword(0x1F80104E, 0x88);
+        memory.write_halfword(0x1F80104A, SELECT);
+        memory.write_byte(0x1F801040, 0x01);
+
+        for _ in 0..(0x88 * 8 + ACK_DELAY) / 2 {
+            assert_eq!(memory.interrupts.status & 0x80, 0);
+            memory.tick(2);
+        }
+        assert_eq!(memory.interrupts.status & 0x80, 0x80);
+        assert_eq!(memory.read_byte(0x1F801040), 0xFF);
+    }
+}
@@ -0,0 +1,213 @@
+use std::io;
+
+use super::{Device, save_device, load_device};
+use super::super::input::PadState;
+use super::super::savestate::{StateReader, StateWriter};
+
+// Bytes each slot takes up in a multitap poll.
+const SLOT_SIZE: usize = 8;
+
+// SCPH-1070 multitap. By default it passes transfers through to slot A.
+// Sending 0x01 in the TAP byte of a 0x42 poll makes the next 0x42 return all four slots at once.
+// Rumble commands aren't forwarded to the slots in that mode.
+pub struct Multitap {
+    pub slots: [Option<Box<dyn Device>>; 4],
+    multi_mode: bool,
+
+    index: usize,
+    command: u8,
+    // Whether this transfer reads all slots, rather than passing through to slot A.
+    reading_all: bool,
+    reply: Vec<u8>,
+}
+
+impl Multitap {
+    pub fn new() -> Multitap {
+        Multitap {
+            slots: [None, None, None, None],
+            multi_mode: false,
+
+            index: 0,
+            command: 0,
+            reading_all: false,
+            reply: Vec::new(),
+        }
+    }
+
+    // Runs a complete 0x42 poll on every slot, padding each reply to eight bytes.
+    fn poll_slots(&mut self) -> Vec<u8> {
+        let mut reply = Vec::with_capacity(SLOT_SIZE * 4);
+
+        for slot in self.slots.iter_mut() {
+            let mut slot_reply = Vec::with_capacity(SLOT_SIZE);
+
+            if let Some(device) = slot.as_mut() {
+                let (_, ack) = device.transfer(0x01);
+
+                if ack {
+                    let (id, mut ack) = device.transfer(0x42);
+                    slot_reply.push(id);
+
+                    while ack && slot_reply.len() < SLOT_SIZE {
+                        let (value, next_ack) = device.transfer(0x00);
+                        slot_reply.push(value);
+                        ack = next_ack;
+                    }
+                }
+
+                device.deselect();
+            }
+
+            slot_reply.resize(SLOT_SIZE, 0xFF);
+            reply.extend_from_slice(&slot_reply);
+        }
+
+        reply
+    }
+}
+
+impl Device for Multitap {
+    fn transfer(&mut self, value: u8) -> (u8, bool) {
+        let index = self.index;
+        self.index += 1;
+
+        if index == 0 {
+            return (0xFF, self.slots.iter().any(|slot| slot.is_some()));
+        }
+
+        if index == 1 {
+            self.command = value;
+            self.reading_all = self.multi_mode && value == 0x42;
+
+            if self.reading_all {
+                self.reply = self.poll_slots();
+                return (0x80, true);
+            }
+        }
+
+        if index == 2 && self.command == 0x42 {
+            self.multi_mode = value == 0x01;
+        }
+
+        if self.reading_all {
+            if index == 2 {
+                return (0x5A, true);
+            }
+
+            let position = index - 3;
+            let reply = self.reply.get(position).cloned().unwrap_or(0xFF);
+            return (reply, position + 1 < self.reply.len());
+        }
+
+        match self.slots[0].as_mut() {
+            Some(device) => {
+                if index == 1 {
+                    device.transfer(0x01);
+                }
+                device.transfer(value)
+            },
+            None => (0xFF, false),
+        }
+    }
+
+    fn deselect(&mut self) {
+        for slot in self.slots.iter_mut().flatten() {
+            slot.deselect();
+        }
+
+        self.index = 0;
+        self.reading_all = false;
+    }
+
+    fn update_input(&mut self, pads: &[PadState; 4]) {
+        for (slot, pad) in self.slots.iter_mut().zip(pads.iter()) {
+            if let Some(device) = slot.as_mut() {
+                // Each slot sees its own pad as the first one.
+                device.update_input(&[*pad; 4]);
+            }
+        }
+    }
+
+    fn rumble(&self) -> [(u8, u8); 4] {
+        let mut rumble = [(0, 0); 4];
+
+        for (motors, slot) in rumble.iter_mut().zip(self.slots.iter()) {
+            if let Some(device) = slot.as_ref() {
+                *motors = device.rumble()[0];
+            }
+        }
+
+        rumble
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"TAP ");
+        for slot in self.slots.iter() {
+            save_device(slot.as_deref(), state);
+        }
+
+        state.write_bool(self.multi_mode);
+        state.write_u32(self.index as u32);
+        state.write_u8(self.command);
+        state.write_bool(self.reading_all);
+        state.write_bytes(&self.reply);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"TAP ")?;
+        for slot in self.slots.iter_mut() {
+            load_device(slot.as_deref_mut(), state)?;
+        }
+
+        self.multi_mode = state.read_bool()?;
+        self.index = state.read_u32()? as usize;
+        self.command = state.read_u8()?;
+        self.reading_all = state.read_bool()?;
+        self.reply = state.read_bytes()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ControllerKind;
+    use super::super::tests::{exchange, sio_with};
+    use super::super::super::input::{Button, InputState};
+
+    #[test]
+    fn passes_through_to_slot_a() {
+        let mut sio = sio_with(ControllerKind::Multitap.create());
+        let mut input = InputState::new();
+        input.pads[0][0].set_button(Button::Cross, true);
+        input.pads[0][1].set_button(Button::Circle, true);
+
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x42, 0x00, 0x00, 0x00]), [0xFF, 0x41, 0x5A, 0xFF, 0xBF]);
+    }
+
+    #[test]
+    fn reads_all_slots_after_the_tap_byte() {
+        let mut sio = sio_with(ControllerKind::Multitap.create());
+        let mut input = InputState::new();
+        input.pads[0][0].set_button(Button::Cross, true);
+        input.pads[0][2].set_button(Button::Circle, true);
+
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x42, 0x01, 0x00, 0x00]), [0xFF, 0x41, 0x5A, 0xFF, 0xBF]);
+
+        let mut poll = vec![0x01, 0x42, 0x01];
+        poll.resize(3 + 32, 0x00);
+        let replies = exchange(&mut sio, &input, &poll);
+
+        assert_eq!(replies.len(), 3 + 32);
+        assert_eq!(replies[..3], [0xFF, 0x80, 0x5A]);
+        assert_eq!(replies[3..11], [0x41, 0x5A, 0xFF, 0xBF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(replies[11..19], [0x41, 0x5A, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(replies[19..27], [0x41, 0x5A, 0xFF, 0xDF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(replies[27..35], [0x41, 0x5A, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // A zero TAP byte goes back to passing through.
+        let mut poll = vec![0x01, 0x42, 0x00];
+        poll.resize(3 + 32, 0x00);
+        exchange(&mut sio, &input, &poll);
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x42, 0x00, 0x00, 0x00]), [0xFF, 0x41, 0x5A, 0xFF, 0xBF]);
+    }
+}
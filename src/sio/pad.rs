@@ -0,0 +1,347 @@
+use std::io;
+
+use super::Device;
+use super::super::input::PadState;
+use super::super::savestate::{StateReader, StateWriter};
+
+// SCPH-1080 digital pad. Answers every command with its button state.
+pub struct DigitalPad {
+    state: PadState,
+    index: usize,
+    buttons: u16,
+}
+
+impl DigitalPad {
+    pub fn new() -> DigitalPad {
+        DigitalPad {
+            state: PadState::new(),
+            index: 0,
+            buttons: 0xFFFF,
+        }
+    }
+}
+
+impl Device for DigitalPad {
+    fn transfer(&mut self, _value: u8) -> (u8, bool) {
+        let reply = match self.index {
+            0 => (0xFF, true),
+            1 => {
+                // Buttons are latched when the command byte arrives, and active low on the wire.
+                self.buttons = !self.state.buttons;
+                (0x41, true)
+            },
+            2 => (0x5A, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, false),
+            _ => (0xFF, false),
+        };
+
+        self.index += 1;
+        reply
+    }
+
+    fn deselect(&mut self) {
+        self.index = 0;
+    }
+
+    fn update_input(&mut self, pads: &[PadState; 4]) {
+        self.state = pads[0];
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"PAD ");
+        state.write_u32(self.index as u32);
+        state.write_u16(self.buttons);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"PAD ")?;
+        self.index = state.read_u32()? as usize;
+        self.buttons = state.read_u16()?;
+        Ok(())
+    }
+}
+
+// SCPH-1200 DualShock, with the analog mode, config mode and rumble motors.
+pub struct DualShock {
+    state: PadState,
+    analog: bool,
+    // The game asked to keep the ANALOG button from switching modes.
+    mode_locked: bool,
+    config_mode: bool,
+    analog_button_held: bool,
+
+    // Which bytes of a poll drive the motors: 0x00 small, 0x01 large, 0xFF nothing.
+    rumble_mapping: [u8; 6],
+    small_motor: u8,
+    large_motor: u8,
+
+    index: usize,
+    command: u8,
+    reply: Vec<u8>,
+}
+
+impl DualShock {
+    pub fn new() -> DualShock {
+        DualShock {
+            state: PadState::new(),
+            analog: false,
+            mode_locked: false,
+            config_mode: false,
+            analog_button_held: false,
+
+            rumble_mapping: [0xFF; 6],
+            small_motor: 0,
+            large_motor: 0,
+
+            index: 0,
+            command: 0,
+            reply: Vec::new(),
+        }
+    }
+
+    fn id(&self) -> u8 {
+        if self.config_mode {
+            0xF3
+        }
+        else if self.analog {
+            0x73
+        }
+        else {
+            0x41
+        }
+    }
+
+    fn poll_reply(&self) -> Vec<u8> {
+        let buttons = !self.state.buttons;
+        let mut reply = vec![buttons as u8, (buttons >> 8) as u8];
+
+        if self.analog {
+            reply.push(self.state.right_stick.0);
+            reply.push(self.state.right_stick.1);
+            reply.push(self.state.left_stick.0);
+            reply.push(self.state.left_stick.1);
+        }
+
+        reply
+    }
+
+    // Builds the payload that follows the 0x5A byte. None if the command isn't understood.
+    fn start_command(&mut self, command: u8) -> Option<Vec<u8>> {
+        if !self.config_mode {
+            return match command {
+                0x42 | 0x43 => Some(self.poll_reply()),
+                _ => None,
+            };
+        }
+
+        let reply = match command {
+            0x42 => self.poll_reply(),
+            0x45 => vec![0x01, 0x02, self.analog as u8, 0x02, 0x01, 0x00],
+            // Variable parts of 0x46 and 0x4C depend on the first parameter, see `parameter`.
+            0x47 => vec![0x00, 0x00, 0x02, 0x00, 0x01, 0x00],
+            0x4D => self.rumble_mapping.to_vec(),
+            0x40 | 0x41 | 0x43 | 0x44 | 0x46 | 0x4C | 0x4F => vec![0; 6],
+            _ => return None,
+        };
+
+        Some(reply)
+    }
+
+    // Handles a parameter byte sent alongside payload byte `position`.
+    fn parameter(&mut self, position: usize, value: u8) {
+        match (self.command, position) {
+            (0x42, _) => {
+                if let Some(&motor) = self.rumble_mapping.get(position) {
+                    match motor {
+                        0x00 => self.small_motor = if value & 0x01 != 0 { 0xFF } else { 0 },
+                        0x01 => self.large_motor = value,
+                        _ => {},
+                    }
+                }
+            },
+            (0x43, 0) => self.config_mode = value == 0x01,
+            (0x44, 0) if self.config_mode => {
+                self.analog = value == 0x01;
+            },
+            (0x44, 1) if self.config_mode => {
+                self.mode_locked = value == 0x03;
+            },
+            (0x46, 0) if self.config_mode => {
+                let tail: [u8; 4] = if value == 0x01 { [0x01, 0x01, 0x01, 0x14] } else { [0x01, 0x02, 0x00, 0x0A] };
+                self.reply[2..].copy_from_slice(&tail);
+            },
+            (0x4C, 0) if self.config_mode => {
+                self.reply[3] = if value == 0x01 { 0x07 } else { 0x04 };
+            },
+            (0x4D, _) if self.config_mode && position < self.rumble_mapping.len() => {
+                self.rumble_mapping[position] = value;
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Device for DualShock {
+    fn transfer(&mut self, value: u8) -> (u8, bool) {
+        let index = self.index;
+        self.index += 1;
+
+        match index {
+            0 => (0xFF, true),
+            1 => {
+                // The ID in the reply reflects the mode before this command runs.
+                let id = self.id();
+
+                match self.start_command(value) {
+                    Some(reply) => {
+                        self.command = value;
+                        self.reply = reply;
+                        (id, true)
+                    },
+                    None => (0xFF, false),
+                }
+            },
+            2 => (0x5A, true),
+            _ => {
+                let position = index - 3;
+                let reply = self.reply.get(position).cloned().unwrap_or(0xFF);
+                self.parameter(position, value);
+                (reply, position + 1 < self.reply.len())
+            },
+        }
+    }
+
+    fn deselect(&mut self) {
+        self.index = 0;
+    }
+
+    fn update_input(&mut self, pads: &[PadState; 4]) {
+        self.state = pads[0];
+
+        if self.state.analog_button && !self.analog_button_held && !self.mode_locked {
+            self.analog = !self.analog;
+        }
+        self.analog_button_held = self.state.analog_button;
+    }
+
+    fn rumble(&self) -> [(u8, u8); 4] {
+        [(self.small_motor, self.large_motor), (0, 0), (0, 0), (0, 0)]
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"DS  ");
+        state.write_bool(self.analog);
+        state.write_bool(self.mode_locked);
+        state.write_bool(self.config_mode);
+        state.write_bool(self.analog_button_held);
+
+        state.write_bytes(&self.rumble_mapping);
+        state.write_u8(self.small_motor);
+        state.write_u8(self.large_motor);
+
+        state.write_u32(self.index as u32);
+        state.write_u8(self.command);
+        state.write_bytes(&self.reply);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"DS  ")?;
+        self.analog = state.read_bool()?;
+        self.mode_locked = state.read_bool()?;
+        self.config_mode = state.read_bool()?;
+        self.analog_button_held = state.read_bool()?;
+
+        state.read_bytes_into(&mut self.rumble_mapping)?;
+        self.small_motor = state.read_u8()?;
+        self.large_motor = state.read_u8()?;
+
+        self.index = state.read_u32()? as usize;
+        self.command = state.read_u8()?;
+        self.reply = state.read_bytes()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::{exchange, sio_with};
+    use super::super::super::input::{Button, InputState};
+
+    const POLL: [u8; 5] = [0x01, 0x42, 0x00, 0x00, 0x00];
+    const ANALOG_POLL: [u8; 9] = [0x01, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn digital_pad_reports_buttons_active_low() {
+        let mut sio = sio_with(Box::new(DigitalPad::new()));
+        let mut input = InputState::new();
+        input.pads[0][0].set_button(Button::Start, true);
+        input.pads[0][0].set_button(Button::Cross, true);
+
+        // The last byte isn't acknowledged, so a sixth one never goes out.
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x42, 0x00, 0x00, 0x00, 0x00]), [0xFF, 0x41, 0x5A, 0xF7, 0xBF]);
+
+        input.pads[0][0].set_button(Button::Start, false);
+        assert_eq!(exchange(&mut sio, &input, &POLL), [0xFF, 0x41, 0x5A, 0xFF, 0xBF]);
+    }
+
+    #[test]
+    fn dualshock_analog_button_toggles_modes() {
+        let mut sio = sio_with(Box::new(DualShock::new()));
+        let mut input = InputState::new();
+        input.pads[0][0].left_stick = (0x10, 0x20);
+        input.pads[0][0].right_stick = (0x30, 0x40);
+
+        assert_eq!(exchange(&mut sio, &input, &ANALOG_POLL), [0xFF, 0x41, 0x5A, 0xFF, 0xFF]);
+
+        // Holding the button only switches once.
+        input.pads[0][0].analog_button = true;
+        for _ in 0..2 {
+            assert_eq!(exchange(&mut sio, &input, &ANALOG_POLL), [0xFF, 0x73, 0x5A, 0xFF, 0xFF, 0x30, 0x40, 0x10, 0x20]);
+        }
+
+        input.pads[0][0].analog_button = false;
+        exchange(&mut sio, &input, &POLL);
+        input.pads[0][0].analog_button = true;
+        assert_eq!(exchange(&mut sio, &input, &ANALOG_POLL), [0xFF, 0x41, 0x5A, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn dualshock_config_mode_and_rumble() {
+        let mut sio = sio_with(Box::new(DualShock::new()));
+        let mut input = InputState::new();
+
+        // Enter config mode from digital mode.
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x43, 0x00, 0x01, 0x00]), [0xFF, 0x41, 0x5A, 0xFF, 0xFF]);
+
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            [0xFF, 0xF3, 0x5A, 0x01, 0x02, 0x00, 0x02, 0x01, 0x00]);
+
+        // Analog mode, locked.
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x44, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00]),
+            [0xFF, 0xF3, 0x5A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        // Small motor on the first poll byte, large on the second.
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x4D, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFF]),
+            [0xFF, 0xF3, 0x5A, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // Leaving config mode still answers with the config ID.
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            [0xFF, 0xF3, 0x5A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        assert_eq!(exchange(&mut sio, &input, &[0x01, 0x42, 0x00, 0x01, 0xC0, 0x00, 0x00, 0x00, 0x00]),
+            [0xFF, 0x73, 0x5A, 0xFF, 0xFF, 0x80, 0x80, 0x80, 0x80]);
+        assert_eq!(sio.rumble(0)[0], (0xFF, 0xC0));
+
+        // The lock keeps the ANALOG button from switching back.
+        input.pads[0][0].analog_button = true;
+        assert_eq!(exchange(&mut sio, &input, &ANALOG_POLL)[1], 0x73);
+    }
+
+    #[test]
+    fn dualshock_ignores_config_commands_outside_config_mode() {
+        let mut sio = sio_with(Box::new(DualShock::new()));
+        assert_eq!(exchange(&mut sio, &InputState::new(), &[0x01, 0x45, 0x00]), [0xFF, 0xFF]);
+    }
+}
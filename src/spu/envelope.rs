@@ -0,0 +1,210 @@
+use std::io;
+
+use super::super::savestate::{StateReader, StateWriter};
+
+// Shared envelope stepping used by ADSR and volume sweeps.
+// Levels are 15 bit, the step is applied every `cycles` samples.
+fn envelope_step(level: i32, shift: u8, step: u8, exponential: bool, decreasing: bool) -> (i32, u32) {
+    let shift = shift as i32;
+    let step = if decreasing { -8 + step as i32 } else { 7 - step as i32 };
+
+    let mut cycles = 1 << (shift - 11).max(0);
+    let mut delta = step << (11 - shift).max(0);
+
+    if exponential && !decreasing && level > 0x6000 {
+        cycles *= 4;
+    }
+
+    if exponential && decreasing {
+        delta = (delta * level) >> 15;
+    }
+
+    (delta, cycles as u32)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AdsrPhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+pub struct Adsr {
+    pub register_low: u16,
+    pub register_high: u16,
+
+    pub phase: AdsrPhase,
+    pub level: i16,
+    counter: u32,
+}
+
+impl Adsr {
+    pub fn new() -> Adsr {
+        Adsr {
+            register_low: 0,
+            register_high: 0,
+
+            phase: AdsrPhase::Off,
+            level: 0,
+            counter: 0,
+        }
+    }
+
+    pub fn key_on(&mut self) {
+        self.phase = AdsrPhase::Attack;
+        self.level = 0;
+        self.counter = 0;
+    }
+
+    pub fn key_off(&mut self) {
+        self.phase = AdsrPhase::Release;
+        self.counter = 0;
+    }
+
+    pub fn stop(&mut self) {
+        self.phase = AdsrPhase::Off;
+        self.level = 0;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.register_low);
+        state.write_u16(self.register_high);
+
+        state.write_u8(match self.phase {
+            AdsrPhase::Attack => 0,
+            AdsrPhase::Decay => 1,
+            AdsrPhase::Sustain => 2,
+            AdsrPhase::Release => 3,
+            AdsrPhase::Off => 4,
+        });
+        state.write_i16(self.level);
+        state.write_u32(self.counter);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.register_low = state.read_u16()?;
+        self.register_high = state.read_u16()?;
+
+        self.phase = match state.read_u8()? {
+            0 => AdsrPhase::Attack,
+            1 => AdsrPhase::Decay,
+            2 => AdsrPhase::Sustain,
+            3 => AdsrPhase::Release,
+            _ => AdsrPhase::Off,
+        };
+        self.level = state.read_i16()?;
+        self.counter = state.read_u32()?;
+        Ok(())
+    }
+
+    fn sustain_level(&self) -> i32 {
+        (((self.register_low & 0x0F) as i32) + 1) * 0x800
+    }
+
+    // Returns (shift, step, exponential, decreasing) for the current phase.
+    fn phase_parameters(&self) -> (u8, u8, bool, bool) {
+        let low = self.register_low;
+        let high = self.register_high;
+
+        match self.phase {
+            AdsrPhase::Attack => (((low >> 10) & 0x1F) as u8, ((low >> 8) & 0x03) as u8, low & 0x8000 != 0, false),
+            AdsrPhase::Decay => (((low >> 4) & 0x0F) as u8, 0, true, true),
+            AdsrPhase::Sustain => (((high >> 8) & 0x1F) as u8, ((high >> 6) & 0x03) as u8, high & 0x8000 != 0, high & 0x4000 != 0),
+            AdsrPhase::Release => ((high & 0x1F) as u8, 0, high & 0x20 != 0, true),
+            AdsrPhase::Off => (0, 0, false, false),
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.phase == AdsrPhase::Off {
+            return;
+        }
+
+        let (shift, step, exponential, decreasing) = self.phase_parameters();
+        let (delta, cycles) = envelope_step(self.level as i32, shift, step, exponential, decreasing);
+
+        self.counter += 1;
+        if self.counter < cycles {
+            return;
+        }
+        self.counter = 0;
+
+        let level = (self.level as i32 + delta).clamp(0, 0x7FFF);
+        self.level = level as i16;
+
+        match self.phase {
+            AdsrPhase::Attack if level >= 0x7FFF => self.phase = AdsrPhase::Decay,
+            AdsrPhase::Decay if level <= self.sustain_level() => self.phase = AdsrPhase::Sustain,
+            AdsrPhase::Release if level == 0 => self.phase = AdsrPhase::Off,
+            _ => {},
+        }
+    }
+}
+
+// A volume register, either a fixed level or a sweep when bit 15 is set.
+pub struct Sweep {
+    pub register: u16,
+    pub level: i16,
+    counter: u32,
+}
+
+impl Sweep {
+    pub fn new() -> Sweep {
+        Sweep {
+            register: 0,
+            level: 0,
+            counter: 0,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.register);
+        state.write_i16(self.level);
+        state.write_u32(self.counter);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.register = state.read_u16()?;
+        self.level = state.read_i16()?;
+        self.counter = state.read_u32()?;
+        Ok(())
+    }
+
+    pub fn set(&mut self, value: u16) {
+        self.register = value;
+        self.counter = 0;
+
+        if value & 0x8000 == 0 {
+            self.level = (value << 1) as i16;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        let value = self.register;
+
+        if value & 0x8000 == 0 {
+            return;
+        }
+
+        let exponential = value & 0x4000 != 0;
+        let decreasing = value & 0x2000 != 0;
+        let negative_phase = value & 0x1000 != 0;
+        let shift = ((value >> 2) & 0x1F) as u8;
+        let step = (value & 0x03) as u8;
+
+        // Sweeps run on the magnitude, negative phase just inverts the output.
+        let magnitude = (self.level as i32).abs();
+        let (delta, cycles) = envelope_step(magnitude, shift, step, exponential, decreasing);
+
+        self.counter += 1;
+        if self.counter < cycles {
+            return;
+        }
+        self.counter = 0;
+
+        let magnitude = (magnitude + delta).clamp(0, 0x7FFF);
+        self.level = if negative_phase { -magnitude as i16 } else { magnitude as i16 };
+    }
+}
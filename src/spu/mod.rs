@@ -0,0 +1,675 @@
+pub mod voice;
+pub mod reverb;
+pub mod envelope;
+
+use std::io;
+use std::collections::VecDeque;
+
+use super::savestate::{StateReader, StateWriter};
+
+use envelope::Sweep;
+use reverb::Reverb;
+use voice::Voice;
+
+pub const SOUND_RAM_SIZE: usize = 512 * 1024;
+pub const VOICE_COUNT: usize = 24;
+
+// The SPU outputs a sample every 768 CPU cycles, 33.8688MHz / 768 = 44.1kHz.
+pub const CYCLES_PER_SAMPLE: u32 = 768;
+
+// Never let the output grow past a second of audio if nobody is pulling samples.
+const MAX_QUEUED_SAMPLES: usize = 44100;
+
+const TRANSFER_FIFO_SIZE: usize = 32;
+
+// SPUCNT bits.
+const CONTROL_ENABLE: u16 = 0x8000;
+const CONTROL_UNMUTE: u16 = 0x4000;
+const CONTROL_IRQ_ENABLE: u16 = 0x0040;
+const CONTROL_REVERB_ENABLE: u16 = 0x0080;
+const CONTROL_CD_REVERB: u16 = 0x0004;
+const CONTROL_CD_ENABLE: u16 = 0x0001;
+
+const STATUS_IRQ: u16 = 0x0040;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TransferMode {
+    Stop,
+    ManualWrite,
+    DmaWrite,
+    DmaRead,
+}
+
+pub struct Spu {
+    pub ram: Vec<u8>,
+    pub voices: Vec<Voice>,
+    pub reverb: Reverb,
+
+    pub main_volume_left: Sweep,
+    pub main_volume_right: Sweep,
+    pub cd_volume_left: i16,
+    pub cd_volume_right: i16,
+    pub external_volume_left: i16,
+    pub external_volume_right: i16,
+
+    pub control: u16,
+    pub status: u16,
+
+    pub pitch_modulation: u32,
+    pub noise_mode: u32,
+    pub reverb_mode: u32,
+    pub endx: u32,
+
+    pub irq_address: u16,
+    pub irq_pending: bool,
+
+    pub transfer_address: u16,
+    pub transfer_control: u16,
+    current_transfer_address: u32,
+    transfer_fifo: VecDeque<u16>,
+
+    noise_level: u16,
+    noise_timer: i32,
+
+    // Offset inside the four 1KB capture buffers at the start of sound RAM.
+    capture_offset: u32,
+
+    // Last value written to every register, read back for the ones without state of their own.
+    registers: Vec<u16>,
+
+    cycles: u32,
+
+    // 44.1kHz stereo samples waiting for the audio backend.
+    pub output: VecDeque<(i16, i16)>,
+}
+
+impl Spu {
+    pub fn new() -> Spu {
+        let mut voices = Vec::with_capacity(VOICE_COUNT);
+        for _ in 0..VOICE_COUNT {
+            voices.push(Voice::new());
+        }
+
+        Spu {
+            ram: vec![0; SOUND_RAM_SIZE],
+            voices,
+            reverb: Reverb::new(),
+
+            main_volume_left: Sweep::new(),
+            main_volume_right: Sweep::new(),
+            cd_volume_left: 0,
+            cd_volume_right: 0,
+            external_volume_left: 0,
+            external_volume_right: 0,
+
+            control: 0,
+            status: 0,
+
+            pitch_modulation: 0,
+            noise_mode: 0,
+            reverb_mode: 0,
+            endx: 0,
+
+            irq_address: 0,
+            irq_pending: false,
+
+            transfer_address: 0,
+            transfer_control: 0,
+            current_transfer_address: 0,
+            transfer_fifo: VecDeque::with_capacity(TRANSFER_FIFO_SIZE),
+
+            noise_level: 1,
+            noise_timer: 0,
+
+            capture_offset: 0,
+
+            registers: vec![0; 0x200],
+
+            cycles: 0,
+
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn transfer_mode(&self) -> TransferMode {
+        match (self.control >> 4) & 0x03 {
+            0 => TransferMode::Stop,
+            1 => TransferMode::ManualWrite,
+            2 => TransferMode::DmaWrite,
+            _ => TransferMode::DmaRead,
+        }
+    }
+
+    pub fn read_register(&self, offset: u32) -> u16 {
+        let offset = offset & 0x3FE;
+
+        match offset {
+            0x000..=0x17F => {
+                let voice = &self.voices[(offset >> 4) as usize];
+
+                match offset & 0x0F {
+                    0x0 => voice.volume_left.register,
+                    0x2 => voice.volume_right.register,
+                    0x4 => voice.pitch,
+                    0x6 => voice.start_address,
+                    0x8 => voice.adsr.register_low,
+                    0xA => voice.adsr.register_high,
+                    0xC => voice.adsr.level as u16,
+                    _ => voice.repeat_address,
+                }
+            },
+
+            reverb::OUTPUT_VOLUME_LEFT | reverb::OUTPUT_VOLUME_RIGHT | reverb::WORK_AREA_START | reverb::CONFIG_START..=reverb::CONFIG_END => {
+                self.reverb.read_register(offset)
+            },
+
+            0x180 => self.main_volume_left.register,
+            0x182 => self.main_volume_right.register,
+
+            0x190 => self.pitch_modulation as u16,
+            0x192 => (self.pitch_modulation >> 16) as u16,
+            0x194 => self.noise_mode as u16,
+            0x196 => (self.noise_mode >> 16) as u16,
+            0x198 => self.reverb_mode as u16,
+            0x19A => (self.reverb_mode >> 16) as u16,
+            0x19C => self.endx as u16,
+            0x19E => (self.endx >> 16) as u16,
+
+            0x1A4 => self.irq_address,
+            0x1A6 => self.transfer_address,
+            0x1AA => self.control,
+            0x1AC => self.transfer_control,
+            0x1AE => self.read_status(),
+            0x1B0 => self.cd_volume_left as u16,
+            0x1B2 => self.cd_volume_right as u16,
+            0x1B4 => self.external_volume_left as u16,
+            0x1B6 => self.external_volume_right as u16,
+            0x1B8 => self.main_volume_left.level as u16,
+            0x1BA => self.main_volume_right.level as u16,
+
+            0x200..=0x25F => {
+                let voice = &self.voices[((offset - 0x200) >> 2) as usize];
+
+                if offset & 0x02 == 0 {
+                    voice.volume_left.level as u16
+                }
+                else {
+                    voice.volume_right.level as u16
+                }
+            },
+
+            _ => self.registers[(offset >> 1) as usize],
+        }
+    }
+
+    fn read_status(&self) -> u16 {
+        let mut status = (self.control & 0x3F) | (self.status & STATUS_IRQ);
+
+        match self.transfer_mode() {
+            TransferMode::DmaWrite | TransferMode::DmaRead => status |= 0x0080,
+            _ => {},
+        }
+
+        // Which half of the capture buffers is being written.
+        if self.capture_offset >= 0x200 {
+            status |= 0x0800;
+        }
+
+        status
+    }
+
+    pub fn write_register(&mut self, offset: u32, value: u16) {
+        let offset = offset & 0x3FE;
+        self.registers[(offset >> 1) as usize] = value;
+
+        match offset {
+            0x000..=0x17F => {
+                let index = (offset >> 4) as usize;
+                let voice = &mut self.voices[index];
+
+                match offset & 0x0F {
+                    0x0 => voice.volume_left.set(value),
+                    0x2 => voice.volume_right.set(value),
+                    0x4 => voice.pitch = value,
+                    0x6 => voice.start_address = value,
+                    0x8 => voice.adsr.register_low = value,
+                    0xA => voice.adsr.register_high = value,
+                    0xC => voice.adsr.level = value as i16,
+                    _ => voice.repeat_address = value,
+                }
+            },
+
+            reverb::OUTPUT_VOLUME_LEFT | reverb::OUTPUT_VOLUME_RIGHT | reverb::WORK_AREA_START | reverb::CONFIG_START..=reverb::CONFIG_END => {
+                self.reverb.write_register(offset, value)
+            },
+
+            0x180 => self.main_volume_left.set(value),
+            0x182 => self.main_volume_right.set(value),
+
+            0x188 => self.key_on(value as u32),
+            0x18A => self.key_on((value as u32) << 16),
+            0x18C => self.key_off(value as u32),
+            0x18E => self.key_off((value as u32) << 16),
+            0x190 => self.pitch_modulation = (self.pitch_modulation & 0xFFFF0000) | (value as u32 & 0xFFFE),
+            0x192 => self.pitch_modulation = (self.pitch_modulation & 0x0000FFFF) | ((value as u32 & 0xFF) << 16),
+            0x194 => self.noise_mode = (self.noise_mode & 0xFFFF0000) | value as u32,
+            0x196 => self.noise_mode = (self.noise_mode & 0x0000FFFF) | ((value as u32 & 0xFF) << 16),
+            0x198 => self.reverb_mode = (self.reverb_mode & 0xFFFF0000) | value as u32,
+            0x19A => self.reverb_mode = (self.reverb_mode & 0x0000FFFF) | ((value as u32 & 0xFF) << 16),
+
+            // ENDX is read-only.
+            0x19C | 0x19E => {},
+
+            0x1A4 => self.irq_address = value,
+            0x1A6 => {
+                self.transfer_address = value;
+                self.current_transfer_address = (value as u32) * 8;
+            },
+            0x1A8 if self.transfer_fifo.len() < TRANSFER_FIFO_SIZE => self.transfer_fifo.push_back(value),
+            0x1AA => {
+                self.control = value;
+
+                if value & CONTROL_IRQ_ENABLE == 0 {
+                    self.status &= !STATUS_IRQ;
+                }
+
+                if self.transfer_mode() == TransferMode::ManualWrite {
+                    self.flush_transfer_fifo();
+                }
+            },
+            0x1AC => self.transfer_control = value,
+            0x1AE => {},
+            0x1B0 => self.cd_volume_left = value as i16,
+            0x1B2 => self.cd_volume_right = value as i16,
+            0x1B4 => self.external_volume_left = value as i16,
+            0x1B6 => self.external_volume_right = value as i16,
+
+            _ => {},
+        }
+    }
+
+    // The output queue isn't part of the state, it belongs to whoever is playing the samples.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_tag(b"SPU ");
+        state.write_bytes(&self.ram);
+        for voice in self.voices.iter() {
+            voice.save_state(state);
+        }
+        self.reverb.save_state(state);
+
+        self.main_volume_left.save_state(state);
+        self.main_volume_right.save_state(state);
+        state.write_i16(self.cd_volume_left);
+        state.write_i16(self.cd_volume_right);
+        state.write_i16(self.external_volume_left);
+        state.write_i16(self.external_volume_right);
+
+        state.write_u16(self.control);
+        state.write_u16(self.status);
+
+        state.write_u32(self.pitch_modulation);
+        state.write_u32(self.noise_mode);
+        state.write_u32(self.reverb_mode);
+        state.write_u32(self.endx);
+
+        state.write_u16(self.irq_address);
+        state.write_bool(self.irq_pending);
+
+        state.write_u16(self.transfer_address);
+        state.write_u16(self.transfer_control);
+        state.write_u32(self.current_transfer_address);
+        state.write_u16s(&self.transfer_fifo.iter().cloned().collect::<Vec<u16>>());
+
+        state.write_u16(self.noise_level);
+        state.write_i32(self.noise_timer);
+        state.write_u32(self.capture_offset);
+        state.write_u16s(&self.registers);
+        state.write_u32(self.cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_tag(b"SPU ")?;
+        state.read_bytes_into(&mut self.ram)?;
+        for voice in self.voices.iter_mut() {
+            voice.load_state(state)?;
+        }
+        self.reverb.load_state(state)?;
+
+        self.main_volume_left.load_state(state)?;
+        self.main_volume_right.load_state(state)?;
+        self.cd_volume_left = state.read_i16()?;
+        self.cd_volume_right = state.read_i16()?;
+        self.external_volume_left = state.read_i16()?;
+        self.external_volume_right = state.read_i16()?;
+
+        self.control = state.read_u16()?;
+        self.status = state.read_u16()?;
+
+        self.pitch_modulation = state.read_u32()?;
+        self.noise_mode = state.read_u32()?;
+        self.reverb_mode = state.read_u32()?;
+        self.endx = state.read_u32()?;
+
+        self.irq_address = state.read_u16()?;
+        self.irq_pending = state.read_bool()?;
+
+        self.transfer_address = state.read_u16()?;
+        self.transfer_control = state.read_u16()?;
+        self.current_transfer_address = state.read_u32()?;
+        self.transfer_fifo = state.read_u16s()?.into_iter().collect();
+
+        self.noise_level = state.read_u16()?;
+        self.noise_timer = state.read_i32()?;
+        self.capture_offset = state.read_u32()?;
+        state.read_u16s_into(&mut self.registers)?;
+        self.cycles = state.read_u32()?;
+
+        self.output.clear();
+        Ok(())
+    }
+
+    fn key_on(&mut self, mask: u32) {
+        for index in 0..VOICE_COUNT {
+            if mask & (1 << index) != 0 {
+                self.voices[index].key_on(&self.ram);
+                self.endx &= !(1 << index);
+            }
+        }
+    }
+
+    fn key_off(&mut self, mask: u32) {
+        for index in 0..VOICE_COUNT {
+            if mask & (1 << index) != 0 {
+                self.voices[index].key_off();
+            }
+        }
+    }
+
+    fn check_irq(&mut self, address: u32) {
+        if self.control & CONTROL_IRQ_ENABLE != 0 && address & 0x7FFF8 == (self.irq_address as u32) * 8 {
+            self.status |= STATUS_IRQ;
+            self.irq_pending = true;
+        }
+    }
+
+    fn write_ram(&mut self, value: u16) {
+        let address = self.current_transfer_address as usize;
+
+        self.ram[address] = value as u8;
+        self.ram[address + 1] = (value >> 8) as u8;
+        self.check_irq(self.current_transfer_address);
+
+        self.current_transfer_address = (self.current_transfer_address + 2) & (SOUND_RAM_SIZE as u32 - 1);
+    }
+
+    fn read_ram(&mut self) -> u16 {
+        let address = self.current_transfer_address as usize;
+        let value = self.ram[address] as u16 | (self.ram[address + 1] as u16) << 8;
+
+        self.check_irq(self.current_transfer_address);
+        self.current_transfer_address = (self.current_transfer_address + 2) & (SOUND_RAM_SIZE as u32 - 1);
+
+        value
+    }
+
+    fn flush_transfer_fifo(&mut self) {
+        while let Some(value) = self.transfer_fifo.pop_front() {
+            self.write_ram(value);
+        }
+    }
+
+    // DMA channel 4 writes, one word at a time.
+    pub fn dma_write(&mut self, value: u32) {
+        self.write_ram(value as u16);
+        self.write_ram((value >> 16) as u16);
+    }
+
+    // DMA channel 4 reads, one word at a time.
+    pub fn dma_read(&mut self) -> u32 {
+        let low = self.read_ram();
+        let high = self.read_ram();
+
+        low as u32 | (high as u32) << 16
+    }
+
+    // Advances the SPU by some CPU cycles, producing one output sample every 768 of them.
+    // CD audio is pulled from the callback once per output sample.
+    pub fn tick<F: FnMut() -> (i16, i16)>(&mut self, cycles: u32, mut cd_audio: F) {
+        self.cycles += cycles;
+
+        while self.cycles >= CYCLES_PER_SAMPLE {
+            self.cycles -= CYCLES_PER_SAMPLE;
+
+            let sample = self.generate_sample(cd_audio());
+
+            if self.output.len() >= MAX_QUEUED_SAMPLES {
+                self.output.pop_front();
+            }
+            self.output.push_back(sample);
+        }
+    }
+
+    fn clock_noise(&mut self) {
+        let shift = ((self.control >> 10) & 0x0F) as i32;
+        let step = ((self.control >> 8) & 0x03) as i32 + 4;
+
+        self.noise_timer -= step;
+
+        if self.noise_timer < 0 {
+            let level = self.noise_level;
+            let parity = ((level >> 15) ^ (level >> 12) ^ (level >> 11) ^ (level >> 10) ^ 1) & 1;
+            self.noise_level = (level << 1) | parity;
+
+            self.noise_timer += 0x20000 >> shift;
+            if self.noise_timer < 0 {
+                self.noise_timer += 0x20000 >> shift;
+            }
+        }
+    }
+
+    fn generate_sample(&mut self, cd_sample: (i16, i16)) -> (i16, i16) {
+        let mut left = 0;
+        let mut right = 0;
+        let mut reverb_left = 0;
+        let mut reverb_right = 0;
+
+        self.clock_noise();
+
+        let mut previous_output = 0;
+
+        for index in 0..VOICE_COUNT {
+            let noise = self.noise_mode & (1 << index) != 0;
+            let modulated = index > 0 && self.pitch_modulation & (1 << index) != 0;
+
+            let voice = &mut self.voices[index];
+
+            let raw = if noise { self.noise_level as i16 } else { voice.interpolate(&GAUSS_TABLE) };
+            let output = ((raw as i32 * voice.adsr.level as i32) >> 15) as i16;
+
+            voice.adsr.clock();
+            voice.volume_left.clock();
+            voice.volume_right.clock();
+
+            let voice_left = (output as i32 * voice.volume_left.level as i32) >> 15;
+            let voice_right = (output as i32 * voice.volume_right.level as i32) >> 15;
+
+            left += voice_left;
+            right += voice_right;
+
+            if self.reverb_mode & (1 << index) != 0 {
+                reverb_left += voice_left;
+                reverb_right += voice_right;
+            }
+
+            let mut step = voice.pitch as u32;
+            if modulated {
+                let factor = previous_output as i32 + 0x8000;
+                step = (((step as i16 as i32) * factor) >> 15) as u32 & 0xFFFF;
+            }
+            let step = clamp_step(step);
+
+            voice.last_output = output;
+            previous_output = output;
+
+            let address_before = voice.current_address;
+            let reached_end = voice.advance(step, &self.ram);
+            let address_after = voice.current_address;
+
+            if reached_end {
+                self.endx |= 1 << index;
+            }
+
+            self.check_irq(address_before);
+            if address_after != address_before {
+                self.check_irq(address_after);
+            }
+
+            // Voices 1 and 3 are written to the capture buffers.
+            if index == 1 || index == 3 {
+                let buffer = if index == 1 { 0x800 } else { 0xC00 };
+                self.write_capture(buffer, output);
+            }
+        }
+
+        let (cd_left, cd_right) = cd_sample;
+        self.write_capture(0x000, cd_left);
+        self.write_capture(0x400, cd_right);
+        self.capture_offset = (self.capture_offset + 2) & 0x3FF;
+
+        if self.control & CONTROL_CD_ENABLE != 0 {
+            let cd_left = (cd_left as i32 * self.cd_volume_left as i32) >> 15;
+            let cd_right = (cd_right as i32 * self.cd_volume_right as i32) >> 15;
+
+            left += cd_left;
+            right += cd_right;
+
+            if self.control & CONTROL_CD_REVERB != 0 {
+                reverb_left += cd_left;
+                reverb_right += cd_right;
+            }
+        }
+
+        let write_enabled = self.control & CONTROL_REVERB_ENABLE != 0;
+        let (wet_left, wet_right) = self.reverb.process(&mut self.ram, (reverb_left, reverb_right), write_enabled);
+        left += wet_left;
+        right += wet_right;
+
+        self.main_volume_left.clock();
+        self.main_volume_right.clock();
+
+        if self.control & CONTROL_ENABLE == 0 || self.control & CONTROL_UNMUTE == 0 {
+            return (0, 0);
+        }
+
+        let left = (left.clamp(-0x8000, 0x7FFF) * self.main_volume_left.level as i32) >> 15;
+        let right = (right.clamp(-0x8000, 0x7FFF) * self.main_volume_right.level as i32) >> 15;
+
+        (left.clamp(-0x8000, 0x7FFF) as i16, right.clamp(-0x8000, 0x7FFF) as i16)
+    }
+
+    fn write_capture(&mut self, buffer: u32, value: i16) {
+        let address = (buffer + self.capture_offset) as usize;
+        self.ram[address] = value as u8;
+        self.ram[address + 1] = (value >> 8) as u8;
+    }
+}
+
+// The interpolation kernel of the voices, as dumped from the SPU by nocash and Mednafen.
+// The four taps of each position add up to 0x7F80, give or take one.
+const GAUSS_TABLE: [i32; 512] = [
+    -0x001, -0x001, -0x001, -0x001, -0x001, -0x001, -0x001, -0x001,
+    -0x001, -0x001, -0x001, -0x001, -0x001, -0x001, -0x001, -0x001,
+    0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0001,
+    0x0001, 0x0001, 0x0001, 0x0002, 0x0002, 0x0002, 0x0003, 0x0003,
+    0x0003, 0x0004, 0x0004, 0x0005, 0x0005, 0x0006, 0x0007, 0x0007,
+    0x0008, 0x0009, 0x0009, 0x000A, 0x000B, 0x000C, 0x000D, 0x000E,
+    0x000F, 0x0010, 0x0011, 0x0012, 0x0013, 0x0015, 0x0016, 0x0018,
+    0x0019, 0x001B, 0x001C, 0x001E, 0x0020, 0x0021, 0x0023, 0x0025,
+    0x0027, 0x0029, 0x002C, 0x002E, 0x0030, 0x0033, 0x0035, 0x0038,
+    0x003A, 0x003D, 0x0040, 0x0043, 0x0046, 0x0049, 0x004D, 0x0050,
+    0x0054, 0x0057, 0x005B, 0x005F, 0x0063, 0x0067, 0x006B, 0x006F,
+    0x0074, 0x0078, 0x007D, 0x0082, 0x0087, 0x008C, 0x0091, 0x0096,
+    0x009C, 0x00A1, 0x00A7, 0x00AD, 0x00B3, 0x00BA, 0x00C0, 0x00C7,
+    0x00CD, 0x00D4, 0x00DB, 0x00E3, 0x00EA, 0x00F2, 0x00FA, 0x0101,
+    0x010A, 0x0112, 0x011B, 0x0123, 0x012C, 0x0135, 0x013F, 0x0148,
+    0x0152, 0x015C, 0x0166, 0x0171, 0x017B, 0x0186, 0x0191, 0x019C,
+    0x01A8, 0x01B4, 0x01C0, 0x01CC, 0x01D9, 0x01E5, 0x01F2, 0x0200,
+    0x020D, 0x021B, 0x0229, 0x0237, 0x0246, 0x0255, 0x0264, 0x0273,
+    0x0283, 0x0293, 0x02A3, 0x02B4, 0x02C4, 0x02D6, 0x02E7, 0x02F9,
+    0x030B, 0x031D, 0x0330, 0x0343, 0x0356, 0x036A, 0x037E, 0x0392,
+    0x03A7, 0x03BC, 0x03D1, 0x03E7, 0x03FC, 0x0413, 0x042A, 0x0441,
+    0x0458, 0x0470, 0x0488, 0x04A0, 0x04B9, 0x04D2, 0x04EC, 0x0506,
+    0x0520, 0x053B, 0x0556, 0x0572, 0x058E, 0x05AA, 0x05C7, 0x05E4,
+    0x0601, 0x061F, 0x063E, 0x065C, 0x067C, 0x069B, 0x06BB, 0x06DC,
+    0x06FD, 0x071E, 0x0740, 0x0762, 0x0784, 0x07A7, 0x07CB, 0x07EF,
+    0x0813, 0x0838, 0x085D, 0x0883, 0x08A9, 0x08D0, 0x08F7, 0x091E,
+    0x0946, 0x096F, 0x0998, 0x09C1, 0x09EB, 0x0A16, 0x0A40, 0x0A6C,
+    0x0A98, 0x0AC4, 0x0AF1, 0x0B1E, 0x0B4C, 0x0B7A, 0x0BA9, 0x0BD8,
+    0x0C07, 0x0C38, 0x0C68, 0x0C99, 0x0CCB, 0x0CFD, 0x0D30, 0x0D63,
+    0x0D97, 0x0DCB, 0x0E00, 0x0E35, 0x0E6B, 0x0EA1, 0x0ED7, 0x0F0F,
+    0x0F46, 0x0F7F, 0x0FB7, 0x0FF1, 0x102A, 0x1065, 0x109F, 0x10DB,
+    0x1116, 0x1153, 0x118F, 0x11CD, 0x120B, 0x1249, 0x1288, 0x12C7,
+    0x1307, 0x1347, 0x1388, 0x13C9, 0x140B, 0x144D, 0x1490, 0x14D4,
+    0x1517, 0x155C, 0x15A0, 0x15E6, 0x162C, 0x1672, 0x16B9, 0x1700,
+    0x1747, 0x1790, 0x17D8, 0x1821, 0x186B, 0x18B5, 0x1900, 0x194B,
+    0x1996, 0x19E2, 0x1A2E, 0x1A7B, 0x1AC8, 0x1B16, 0x1B64, 0x1BB3,
+    0x1C02, 0x1C51, 0x1CA1, 0x1CF1, 0x1D42, 0x1D93, 0x1DE5, 0x1E37,
+    0x1E89, 0x1EDC, 0x1F2F, 0x1F82, 0x1FD6, 0x202A, 0x207F, 0x20D4,
+    0x2129, 0x217F, 0x21D5, 0x222C, 0x2282, 0x22DA, 0x2331, 0x2389,
+    0x23E1, 0x2439, 0x2492, 0x24EB, 0x2545, 0x259E, 0x25F8, 0x2653,
+    0x26AD, 0x2708, 0x2763, 0x27BE, 0x281A, 0x2876, 0x28D2, 0x292E,
+    0x298B, 0x29E7, 0x2A44, 0x2AA1, 0x2AFF, 0x2B5C, 0x2BBA, 0x2C18,
+    0x2C76, 0x2CD4, 0x2D33, 0x2D91, 0x2DF0, 0x2E4F, 0x2EAE, 0x2F0D,
+    0x2F6C, 0x2FCC, 0x302B, 0x308B, 0x30EA, 0x314A, 0x31AA, 0x3209,
+    0x3269, 0x32C9, 0x3329, 0x3389, 0x33E9, 0x3449, 0x34A9, 0x3509,
+    0x3569, 0x35C9, 0x3629, 0x3689, 0x36E8, 0x3748, 0x37A8, 0x3807,
+    0x3867, 0x38C6, 0x3926, 0x3985, 0x39E4, 0x3A43, 0x3AA2, 0x3B00,
+    0x3B5F, 0x3BBD, 0x3C1B, 0x3C79, 0x3CD7, 0x3D35, 0x3D92, 0x3DEF,
+    0x3E4C, 0x3EA9, 0x3F05, 0x3F62, 0x3FBD, 0x4019, 0x4074, 0x40D0,
+    0x412A, 0x4185, 0x41DF, 0x4239, 0x4292, 0x42EB, 0x4344, 0x439C,
+    0x43F4, 0x444C, 0x44A3, 0x44FA, 0x4550, 0x45A6, 0x45FC, 0x4651,
+    0x46A6, 0x46FA, 0x474E, 0x47A1, 0x47F4, 0x4846, 0x4898, 0x48E9,
+    0x493A, 0x498A, 0x49D9, 0x4A29, 0x4A77, 0x4AC5, 0x4B13, 0x4B5F,
+    0x4BAC, 0x4BF7, 0x4C42, 0x4C8D, 0x4CD7, 0x4D20, 0x4D68, 0x4DB0,
+    0x4DF7, 0x4E3E, 0x4E84, 0x4EC9, 0x4F0E, 0x4F52, 0x4F95, 0x4FD7,
+    0x5019, 0x505A, 0x509A, 0x50DA, 0x5118, 0x5156, 0x5194, 0x51D0,
+    0x520C, 0x5247, 0x5281, 0x52BA, 0x52F3, 0x532A, 0x5361, 0x5397,
+    0x53CC, 0x5401, 0x5434, 0x5467, 0x5499, 0x54CA, 0x54FA, 0x5529,
+    0x5558, 0x5585, 0x55B2, 0x55DE, 0x5609, 0x5632, 0x565B, 0x5684,
+    0x56AB, 0x56D1, 0x56F6, 0x571B, 0x573E, 0x5761, 0x5782, 0x57A3,
+    0x57C3, 0x57E2, 0x57FF, 0x581C, 0x5838, 0x5853, 0x586D, 0x5886,
+    0x589E, 0x58B5, 0x58CB, 0x58E0, 0x58F4, 0x5907, 0x5919, 0x592A,
+    0x593A, 0x5949, 0x5958, 0x5965, 0x5971, 0x597C, 0x5986, 0x598F,
+    0x5997, 0x599E, 0x59A4, 0x59A9, 0x59AD, 0x59B0, 0x59B2, 0x59B3,
+];
+
+// Steps past 0x3FFF play at 0x4000, four times the base rate, rather than just below it.
+fn clamp_step(step: u32) -> u32 {
+    if step > 0x3FFF { 0x4000 } else { step }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gauss_table_taps() {
+        assert_eq!(GAUSS_TABLE[0], -0x001);
+        assert_eq!(GAUSS_TABLE[0x0FF], 0x12C7);
+        assert_eq!(GAUSS_TABLE[0x100], 0x1307);
+        assert_eq!(GAUSS_TABLE[0x1FF], 0x59B3);
+
+        for fraction in 0..0x100 {
+            let sum = GAUSS_TABLE[0x0FF - fraction] + GAUSS_TABLE[0x1FF - fraction] + GAUSS_TABLE[0x100 + fraction] + GAUSS_TABLE[fraction];
+            assert!((0x7F7F..=0x7F81).contains(&sum), "taps at {:02X} add up to {:04X}", fraction, sum);
+        }
+    }
+
+    #[test]
+    fn steps_above_the_limit_become_0x4000() {
+        assert_eq!(clamp_step(0x1000), 0x1000);
+        assert_eq!(clamp_step(0x3FFF), 0x3FFF);
+        assert_eq!(clamp_step(0x4000), 0x4000);
+        assert_eq!(clamp_step(0xFFFF), 0x4000);
+    }
+}
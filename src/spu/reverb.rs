@@ -0,0 +1,451 @@
+use std::io;
+
+use super::super::savestate::{StateReader, StateWriter};
+
+// Half-band filter used to downsample reverb input to 22.05kHz and upsample its output back.
+const RESAMPLE_TABLE: [i32; 39] = [
+    -0x0001, 0, 0x0002, 0, -0x000A, 0, 0x0023, 0, -0x0067, 0, 0x010A, 0, -0x0268, 0, 0x0534, 0, -0x0B90, 0, 0x2806,
+    0x4000,
+    0x2806, 0, -0x0B90, 0, 0x0534, 0, -0x0268, 0, 0x010A, 0, -0x0067, 0, 0x0023, 0, -0x000A, 0, 0x0002, 0, -0x0001,
+];
+
+const HISTORY_SIZE: usize = 64;
+
+// Register offsets from the start of the SPU block.
+pub const OUTPUT_VOLUME_LEFT: u32 = 0x184;
+pub const OUTPUT_VOLUME_RIGHT: u32 = 0x186;
+pub const WORK_AREA_START: u32 = 0x1A2;
+pub const CONFIG_START: u32 = 0x1C0;
+pub const CONFIG_END: u32 = 0x1FE;
+
+// Ring of recent samples for one channel of the resamplers.
+struct History {
+    samples: [i16; HISTORY_SIZE],
+    position: usize,
+}
+
+impl History {
+    fn new() -> History {
+        History {
+            samples: [0; HISTORY_SIZE],
+            position: 0,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_i16s(&self.samples);
+        state.write_u32(self.position as u32);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_i16s_into(&mut self.samples)?;
+        self.position = state.read_u32()? as usize % HISTORY_SIZE;
+        Ok(())
+    }
+
+    fn push(&mut self, sample: i16) {
+        self.samples[self.position] = sample;
+        self.position = (self.position + 1) % HISTORY_SIZE;
+    }
+
+    fn filter(&self) -> i32 {
+        let mut sum = 0;
+
+        for (index, coefficient) in RESAMPLE_TABLE.iter().enumerate() {
+            let position = (self.position + HISTORY_SIZE - RESAMPLE_TABLE.len() + index) % HISTORY_SIZE;
+            sum += self.samples[position] as i32 * coefficient;
+        }
+
+        sum >> 15
+    }
+}
+
+pub struct Reverb {
+    pub output_volume_left: i16,
+    pub output_volume_right: i16,
+    pub work_area_start: u16,
+
+    // dAPF1, dAPF2, vIIR, vCOMB1-4, vWALL, vAPF1, vAPF2, mLSAME, mRSAME, mLCOMB1... vLIN, vRIN,
+    // in register order from 0x1F801DC0.
+    pub config: [u16; 32],
+
+    buffer_address: u32,
+    odd_step: bool,
+
+    downsample: [History; 2],
+    upsample: [History; 2],
+}
+
+// Indices into the configuration registers.
+const D_APF1: usize = 0;
+const D_APF2: usize = 1;
+const V_IIR: usize = 2;
+const V_COMB1: usize = 3;
+const V_COMB2: usize = 4;
+const V_COMB3: usize = 5;
+const V_COMB4: usize = 6;
+const V_WALL: usize = 7;
+const V_APF1: usize = 8;
+const V_APF2: usize = 9;
+const M_LSAME: usize = 10;
+const M_RSAME: usize = 11;
+const M_LCOMB1: usize = 12;
+const M_RCOMB1: usize = 13;
+const M_LCOMB2: usize = 14;
+const M_RCOMB2: usize = 15;
+const D_LSAME: usize = 16;
+const D_RSAME: usize = 17;
+const M_LDIFF: usize = 18;
+const M_RDIFF: usize = 19;
+const M_LCOMB3: usize = 20;
+const M_RCOMB3: usize = 21;
+const M_LCOMB4: usize = 22;
+const M_RCOMB4: usize = 23;
+const D_LDIFF: usize = 24;
+const D_RDIFF: usize = 25;
+const M_LAPF1: usize = 26;
+const M_RAPF1: usize = 27;
+const M_LAPF2: usize = 28;
+const M_RAPF2: usize = 29;
+const V_LIN: usize = 30;
+const V_RIN: usize = 31;
+
+impl Reverb {
+    pub fn new() -> Reverb {
+        Reverb {
+            output_volume_left: 0,
+            output_volume_right: 0,
+            work_area_start: 0,
+
+            config: [0; 32],
+
+            buffer_address: 0,
+            odd_step: false,
+
+            downsample: [History::new(), History::new()],
+            upsample: [History::new(), History::new()],
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_i16(self.output_volume_left);
+        state.write_i16(self.output_volume_right);
+        state.write_u16(self.work_area_start);
+        state.write_u16s(&self.config);
+
+        state.write_u32(self.buffer_address);
+        state.write_bool(self.odd_step);
+
+        for history in self.downsample.iter().chain(self.upsample.iter()) {
+            history.save_state(state);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.output_volume_left = state.read_i16()?;
+        self.output_volume_right = state.read_i16()?;
+        self.work_area_start = state.read_u16()?;
+        state.read_u16s_into(&mut self.config)?;
+
+        self.buffer_address = state.read_u32()?;
+        self.odd_step = state.read_bool()?;
+
+        for history in self.downsample.iter_mut().chain(self.upsample.iter_mut()) {
+            history.load_state(state)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_register(&self, offset: u32) -> u16 {
+        match offset {
+            OUTPUT_VOLUME_LEFT => self.output_volume_left as u16,
+            OUTPUT_VOLUME_RIGHT => self.output_volume_right as u16,
+            WORK_AREA_START => self.work_area_start,
+            _ => self.config[((offset - CONFIG_START) >> 1) as usize],
+        }
+    }
+
+    pub fn write_register(&mut self, offset: u32, value: u16) {
+        match offset {
+            OUTPUT_VOLUME_LEFT => self.output_volume_left = value as i16,
+            OUTPUT_VOLUME_RIGHT => self.output_volume_right = value as i16,
+            WORK_AREA_START => {
+                self.work_area_start = value;
+                self.buffer_address = (value as u32) * 8;
+            },
+            _ => self.config[((offset - CONFIG_START) >> 1) as usize] = value,
+        }
+    }
+
+    fn volume(&self, register: usize) -> i32 {
+        self.config[register] as i16 as i32
+    }
+
+    // Byte address in sound RAM of a work area offset, wrapping inside the area.
+    fn address(&self, register: usize, adjust: i32) -> usize {
+        let start = (self.work_area_start as u32) * 8;
+        let size = (0x80000 - start).max(2);
+        let offset = ((self.config[register] as u32) * 8) as i64 + adjust as i64 * 2;
+        let relative = (self.buffer_address.wrapping_sub(start) % size) as i64 + offset;
+
+        (start + relative.rem_euclid(size as i64) as u32) as usize & 0x7FFFE
+    }
+
+    fn load(&self, ram: &[u8], register: usize, adjust: i32) -> i32 {
+        let address = self.address(register, adjust);
+        (ram[address] as u16 | (ram[address + 1] as u16) << 8) as i16 as i32
+    }
+
+    fn store(&self, ram: &mut [u8], register: usize, value: i32) {
+        let address = self.address(register, 0);
+        let value = clamp16(value) as u16;
+        ram[address] = value as u8;
+        ram[address + 1] = (value >> 8) as u8;
+    }
+
+    // Runs one 44.1kHz step. Input is the sum of the reverb enabled voices, the output is
+    // already scaled by the output volume and ready to be mixed.
+    // Like the hardware, the left side is worked out on one step and the right side on the next,
+    // so each runs at 22.05kHz, and the buffer moves on once both are done.
+    // The work area is only written to when `write_enabled` (SPUCNT bit 7) is set.
+    pub fn process(&mut self, ram: &mut [u8], input: (i32, i32), write_enabled: bool) -> (i32, i32) {
+        self.downsample[0].push(clamp16(input.0));
+        self.downsample[1].push(clamp16(input.1));
+
+        self.odd_step = !self.odd_step;
+
+        let mut output = [0, 0];
+        if self.odd_step {
+            let input = self.downsample[0].filter();
+            output[0] = self.process_side(ram, Side::LEFT, input, write_enabled);
+        }
+        else {
+            let input = self.downsample[1].filter();
+            output[1] = self.process_side(ram, Side::RIGHT, input, write_enabled);
+
+            let start = (self.work_area_start as u32) * 8;
+            self.buffer_address = ((self.buffer_address + 2) & 0x7FFFE).max(start);
+        }
+
+        // Zero stuffing plus the half-band filter, doubled to make up for the inserted zeroes.
+        self.upsample[0].push(clamp16(output[0]));
+        self.upsample[1].push(clamp16(output[1]));
+
+        let left = clamp16(self.upsample[0].filter() * 2) as i32;
+        let right = clamp16(self.upsample[1].filter() * 2) as i32;
+
+        ((left * self.output_volume_left as i32) >> 15, (right * self.output_volume_right as i32) >> 15)
+    }
+
+    fn process_side(&mut self, ram: &mut [u8], side: &Side, input: i32, write_enabled: bool) -> i32 {
+        let input = mul(clamp16(input) as i32, self.volume(side.input_volume));
+        let iir = self.volume(V_IIR);
+        let wall = self.volume(V_WALL);
+
+        if write_enabled {
+            // Same side and different side reflections.
+            for (destination, source) in [(side.same, side.same_source), (side.different, side.different_source)].iter() {
+                let previous = self.load(ram, *destination, -1);
+                let value = clamp16(input + mul(self.load(ram, *source, 0), wall) - previous) as i32;
+                self.store(ram, *destination, mul(value, iir) + previous);
+            }
+        }
+
+        let mut output = side.combs.iter().zip([V_COMB1, V_COMB2, V_COMB3, V_COMB4].iter())
+            .map(|(comb, volume)| mul(self.volume(*volume), self.load(ram, *comb, 0)))
+            .sum();
+        output = clamp16(output) as i32;
+
+        // Two all pass filters.
+        for (address, delay, volume) in [(side.apf1, D_APF1, V_APF1), (side.apf2, D_APF2, V_APF2)].iter() {
+            let volume = self.volume(*volume);
+            let delay = -((self.config[*delay] as u32 * 8 / 2) as i32);
+
+            let delayed = self.load(ram, *address, delay);
+            let value = clamp16(output - mul(volume, delayed)) as i32;
+
+            if write_enabled {
+                self.store(ram, *address, value);
+            }
+
+            output = clamp16(mul(value, volume) + delayed) as i32;
+        }
+
+        output
+    }
+}
+
+// The registers one side of the reverb works with.
+struct Side {
+    input_volume: usize,
+    same: usize,
+    same_source: usize,
+    different: usize,
+    different_source: usize,
+    combs: [usize; 4],
+    apf1: usize,
+    apf2: usize,
+}
+
+impl Side {
+    const LEFT: &'static Side = &Side {
+        input_volume: V_LIN,
+        same: M_LSAME,
+        same_source: D_LSAME,
+        different: M_LDIFF,
+        different_source: D_RDIFF,
+        combs: [M_LCOMB1, M_LCOMB2, M_LCOMB3, M_LCOMB4],
+        apf1: M_LAPF1,
+        apf2: M_LAPF2,
+    };
+
+    const RIGHT: &'static Side = &Side {
+        input_volume: V_RIN,
+        same: M_RSAME,
+        same_source: D_RSAME,
+        different: M_RDIFF,
+        different_source: D_LDIFF,
+        combs: [M_RCOMB1, M_RCOMB2, M_RCOMB3, M_RCOMB4],
+        apf1: M_RAPF1,
+        apf2: M_RAPF2,
+    };
+}
+
+fn mul(a: i32, b: i32) -> i32 {
+    (a * b) >> 15
+}
+
+fn clamp16(value: i32) -> i16 {
+    value.clamp(-0x8000, 0x7FFF) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORK_AREA: usize = 0x70000;
+
+    fn reverb(config: &[(usize, u16)]) -> Reverb {
+        let mut reverb = Reverb::new();
+        reverb.write_register(WORK_AREA_START, (WORK_AREA / 8) as u16);
+        reverb.write_register(OUTPUT_VOLUME_LEFT, 0x7FFF);
+        reverb.write_register(OUTPUT_VOLUME_RIGHT, 0x7FFF);
+
+        for (register, value) in config {
+            reverb.write_register(CONFIG_START + *register as u32 * 2, *value);
+        }
+
+        reverb
+    }
+
+    fn read(ram: &[u8], address: usize) -> i16 {
+        (ram[address] as u16 | (ram[address + 1] as u16) << 8) as i16
+    }
+
+    // With both all pass volumes at zero, the core output is whatever the work area holds at the
+    // APF2 address. A constant 0x1000 comes out at 22.05kHz and goes through the upsampler: the
+    // centre tap gives 0x4000 * 2, the other phase the sum of the even taps, 0x3FFE * 2. The right
+    // side runs one step behind the left, so its phases are swapped.
+    #[test]
+    fn constant_work_area_through_the_upsampler() {
+        let mut ram = vec![0; 0x80000];
+        for address in (WORK_AREA..ram.len()).step_by(2) {
+            ram[address + 1] = 0x10;
+        }
+
+        let mut reverb = reverb(&[]);
+        let outputs: Vec<(i32, i32)> = (0..80).map(|_| reverb.process(&mut ram, (0, 0), false)).collect();
+
+        // Only the newest tap, -1, sees the first left sample. The right one comes a step later.
+        assert_eq!(outputs[0], (-2, 0));
+        assert_eq!(outputs[1], (0, -2));
+
+        for (index, output) in outputs.iter().enumerate().skip(40) {
+            let expected = if index & 1 == 0 { (4093, 4095) } else { (4095, 4093) };
+            assert_eq!(*output, expected, "step {}", index);
+        }
+
+        assert!(ram[WORK_AREA..].chunks(2).all(|value| value == [0x00, 0x10]));
+    }
+
+    // [mLSAME] = (Lin + [dLSAME] * vWALL - [mLSAME - 2]) * vIIR + [mLSAME - 2], where the previous
+    // halfword is what the last step stored. A constant 0x2000 input settles at 0x2000 * 0x7FFE >> 15
+    // through the downsampler and 8190 after vLIN, and vIIR = 0.5 halves the distance to it every step.
+    #[test]
+    fn same_side_reflection_iir() {
+        const EXPECTED: [i16; 16] = [4095, 6142, 7166, 7678, 7934, 8062, 8126, 8158, 8174, 8182, 8186, 8188, 8189, 8189, 8189, 8189];
+
+        let mut ram = vec![0; 0x80000];
+        let mut reverb = reverb(&[(V_LIN, 0x7FFF), (V_IIR, 0x4000), (M_LSAME, 0x10)]);
+
+        // Fill the downsampler without touching the work area, 20 steps at 22.05kHz.
+        for _ in 0..40 {
+            reverb.process(&mut ram, (0x2000, 0), false);
+        }
+
+        for _ in 0..EXPECTED.len() * 2 {
+            reverb.process(&mut ram, (0x2000, 0), true);
+        }
+
+        let start = WORK_AREA + 40 + 0x80;
+        let stored: Vec<i16> = (0..EXPECTED.len()).map(|step| read(&ram, start + step * 2)).collect();
+        assert_eq!(stored, EXPECTED);
+    }
+
+    // With vCOMB1 = 0.5 and the all pass volumes at zero, the value stored at mLAPF1 is half of
+    // what [mLCOMB1] holds. The left side is stored on the first step, the right one only on the
+    // next, and the buffer moves on after that.
+    #[test]
+    fn left_and_right_on_alternating_steps() {
+        let mut ram = vec![0; 0x80000];
+        for address in (WORK_AREA + 0x800..WORK_AREA + 0x900).step_by(2) {
+            ram[address + 1] = 0x20;
+        }
+        for address in (WORK_AREA + 0x2000..WORK_AREA + 0x2100).step_by(2) {
+            ram[address] = 0x77;
+            ram[address + 1] = 0x77;
+        }
+
+        let mut reverb = reverb(&[(V_COMB1, 0x4000), (M_LCOMB1, 0x100), (M_RCOMB1, 0x200), (M_LAPF1, 0x300), (M_RAPF1, 0x400)]);
+
+        reverb.process(&mut ram, (0, 0), true);
+        assert_eq!(read(&ram, WORK_AREA + 0x1800), 0x1000);
+        assert_eq!(read(&ram, WORK_AREA + 0x2000), 0x7777);
+
+        reverb.process(&mut ram, (0, 0), true);
+        assert_eq!(read(&ram, WORK_AREA + 0x2000), 0);
+        assert_eq!(read(&ram, WORK_AREA + 0x1802), 0);
+
+        reverb.process(&mut ram, (0, 0), true);
+        assert_eq!(read(&ram, WORK_AREA + 0x1802), 0x1000);
+        assert_eq!(read(&ram, WORK_AREA + 0x2002), 0x7777);
+    }
+
+    #[test]
+    fn addresses_wrap_inside_the_work_area() {
+        let mut reverb = Reverb::new();
+        reverb.write_register(WORK_AREA_START, 0xFFFE);
+        reverb.write_register(CONFIG_START + M_LSAME as u32 * 2, 0x3);
+
+        // 0x18 bytes into a 0x10 byte area.
+        assert_eq!(reverb.address(M_LSAME, 0), 0x7FFF8);
+        assert_eq!(reverb.address(M_LSAME, -5), 0x7FFFE);
+
+        // A buffer address below the start doesn't underflow.
+        reverb.buffer_address = 0x100;
+        assert!((0x7FFF0..0x80000).contains(&reverb.address(M_LSAME, 0)));
+    }
+
+    #[test]
+    fn work_area_writes_need_the_enable_bit() {
+        let mut ram = vec![0; 0x80000];
+        let mut reverb = reverb(&[(V_LIN, 0x7FFF), (V_IIR, 0x4000), (M_LSAME, 0x10)]);
+
+        for _ in 0..200 {
+            reverb.process(&mut ram, (0x2000, 0x2000), false);
+        }
+
+        assert!(ram.iter().all(|byte| *byte == 0));
+    }
+}
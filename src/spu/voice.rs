@@ -0,0 +1,216 @@
+use std::io;
+
+use super::envelope::{Adsr, Sweep};
+use super::super::savestate::{StateReader, StateWriter};
+
+const POS_ADPCM_TABLE: [i32; 5] = [0, 60, 115, 98, 122];
+const NEG_ADPCM_TABLE: [i32; 5] = [0, 0, -52, -55, -60];
+
+pub const SAMPLES_PER_BLOCK: usize = 28;
+pub const BLOCK_SIZE: u32 = 16;
+
+// ADPCM block flags.
+const FLAG_LOOP_END: u8 = 0x01;
+const FLAG_LOOP_REPEAT: u8 = 0x02;
+const FLAG_LOOP_START: u8 = 0x04;
+
+pub struct Voice {
+    pub volume_left: Sweep,
+    pub volume_right: Sweep,
+    pub pitch: u16,
+    pub start_address: u16,
+    pub repeat_address: u16,
+    pub adsr: Adsr,
+
+    // Byte address in sound RAM of the next block to decode.
+    pub current_address: u32,
+    pub counter: u32,
+
+    // Decoded samples of the current block, with the last three of the previous block in front for interpolation.
+    samples: [i16; SAMPLES_PER_BLOCK + 3],
+    old: i32,
+    older: i32,
+    block_flags: u8,
+
+    // Output after ADSR, before the volume. Feeds the next voice's pitch modulation.
+    pub last_output: i16,
+}
+
+impl Voice {
+    pub fn new() -> Voice {
+        Voice {
+            volume_left: Sweep::new(),
+            volume_right: Sweep::new(),
+            pitch: 0,
+            start_address: 0,
+            repeat_address: 0,
+            adsr: Adsr::new(),
+
+            current_address: 0,
+            counter: 0,
+
+            samples: [0; SAMPLES_PER_BLOCK + 3],
+            old: 0,
+            older: 0,
+            block_flags: 0,
+
+            last_output: 0,
+        }
+    }
+
+    pub fn key_on(&mut self, ram: &[u8]) {
+        self.current_address = (self.start_address as u32) * 8;
+        self.counter = 0;
+        self.old = 0;
+        self.older = 0;
+        self.samples = [0; SAMPLES_PER_BLOCK + 3];
+        self.adsr.key_on();
+        self.decode_block(ram);
+    }
+
+    pub fn key_off(&mut self) {
+        self.adsr.key_off();
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.volume_left.save_state(state);
+        self.volume_right.save_state(state);
+        state.write_u16(self.pitch);
+        state.write_u16(self.start_address);
+        state.write_u16(self.repeat_address);
+        self.adsr.save_state(state);
+
+        state.write_u32(self.current_address);
+        state.write_u32(self.counter);
+
+        state.write_i16s(&self.samples);
+        state.write_i32(self.old);
+        state.write_i32(self.older);
+        state.write_u8(self.block_flags);
+
+        state.write_i16(self.last_output);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.volume_left.load_state(state)?;
+        self.volume_right.load_state(state)?;
+        self.pitch = state.read_u16()?;
+        self.start_address = state.read_u16()?;
+        self.repeat_address = state.read_u16()?;
+        self.adsr.load_state(state)?;
+
+        self.current_address = state.read_u32()?;
+        self.counter = state.read_u32()?;
+
+        state.read_i16s_into(&mut self.samples)?;
+        self.old = state.read_i32()?;
+        self.older = state.read_i32()?;
+        self.block_flags = state.read_u8()?;
+
+        self.last_output = state.read_i16()?;
+        Ok(())
+    }
+
+    fn decode_block(&mut self, ram: &[u8]) {
+        let mut block = [0; BLOCK_SIZE as usize];
+        for (index, byte) in block.iter_mut().enumerate() {
+            *byte = ram[(self.current_address as usize + index) & (ram.len() - 1)];
+        }
+
+        let header = block[0];
+        let mut shift = (header & 0x0F) as u32;
+        let filter = (((header >> 4) & 0x07) as usize).min(4);
+        self.block_flags = block[1];
+
+        if shift > 12 {
+            shift = 9;
+        }
+
+        if self.block_flags & FLAG_LOOP_START != 0 {
+            self.repeat_address = (self.current_address / 8) as u16;
+        }
+
+        // Keep the tail of the previous block around for the interpolation.
+        self.samples.copy_within(SAMPLES_PER_BLOCK..SAMPLES_PER_BLOCK + 3, 0);
+
+        for index in 0..SAMPLES_PER_BLOCK {
+            let byte = block[2 + index / 2];
+            let nibble = if index & 1 != 0 { byte >> 4 } else { byte & 0x0F };
+            let raw = (((nibble as i16) << 12) >> shift) as i32;
+
+            let value = raw + ((self.old * POS_ADPCM_TABLE[filter] + self.older * NEG_ADPCM_TABLE[filter] + 32) >> 6);
+            let value = value.clamp(-0x8000, 0x7FFF);
+
+            self.older = self.old;
+            self.old = value;
+            self.samples[index + 3] = value as i16;
+        }
+    }
+
+    // Advances the pitch counter, decoding new blocks as needed.
+    // Returns true if the voice went past a block with the loop end flag.
+    pub fn advance(&mut self, step: u32, ram: &[u8]) -> bool {
+        let mut reached_end = false;
+
+        self.counter += step;
+
+        while (self.counter >> 12) as usize >= SAMPLES_PER_BLOCK {
+            self.counter -= (SAMPLES_PER_BLOCK as u32) << 12;
+
+            if self.block_flags & FLAG_LOOP_END != 0 {
+                reached_end = true;
+                self.current_address = (self.repeat_address as u32) * 8;
+
+                if self.block_flags & FLAG_LOOP_REPEAT == 0 {
+                    self.adsr.stop();
+                }
+            }
+            else {
+                self.current_address = (self.current_address + BLOCK_SIZE) & 0x7FFFF;
+            }
+
+            self.decode_block(ram);
+        }
+
+        reached_end
+    }
+
+    // 4-point gaussian interpolation of the sample under the pitch counter.
+    pub fn interpolate(&self, gauss: &[i32; 512]) -> i16 {
+        let index = (self.counter >> 12) as usize + 3;
+        let fraction = ((self.counter >> 4) & 0xFF) as usize;
+
+        let mut output = (gauss[0x0FF - fraction] * self.samples[index - 3] as i32) >> 15;
+        output += (gauss[0x1FF - fraction] * self.samples[index - 2] as i32) >> 15;
+        output += (gauss[0x100 + fraction] * self.samples[index - 1] as i32) >> 15;
+        output += (gauss[fraction] * self.samples[index] as i32) >> 15;
+
+        output.clamp(-0x8000, 0x7FFF) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::GAUSS_TABLE;
+
+    // Halfway between two samples the taps are 019C, 3DEF, 3E4C and 01A8, each product shifted
+    // down on its own: 12 - 968 + 1460 + 51.
+    #[test]
+    fn interpolation_between_samples() {
+        let mut voice = Voice::new();
+        voice.samples[..4].copy_from_slice(&[1000, -2000, 3000, 4000]);
+        voice.counter = 0x0800;
+
+        assert_eq!(voice.interpolate(&GAUSS_TABLE), 555);
+    }
+
+    // Right on a sample the taps add up to 0x7F80, so full scale loses a little.
+    #[test]
+    fn interpolation_on_a_sample() {
+        let mut voice = Voice::new();
+        voice.samples[..4].copy_from_slice(&[0x7FFF; 4]);
+
+        assert_eq!(voice.interpolate(&GAUSS_TABLE), 32637);
+    }
+}
@@ -0,0 +1,477 @@
+use std::io;
+use std::fs;
+use std::path::Path;
+use std::collections::HashMap;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::png;
+use super::wav;
+use super::audio;
+use super::mdec::Mdec;
+use super::cdrom;
+use super::cdrom::disc::{self, Disc};
+
+// Video sectors start with this header in their user data, followed by 2016 bytes of the frame.
+const VIDEO_MAGIC: u16 = 0x0160;
+const VIDEO_TYPE: u16 = 0x8001;
+const VIDEO_HEADER_SIZE: usize = 0x20;
+// Offset of the user data in a raw Mode 2 sector.
+const USER_DATA_OFFSET: usize = 24;
+
+// Largest frame the MDEC path can show, anything bigger (or empty) is a corrupted header.
+const MAX_WIDTH: usize = 640;
+const MAX_HEIGHT: usize = 480;
+
+const END_OF_BLOCK: u16 = 0xFE00;
+const MACROBLOCK_WORDS: usize = 16 * 16 * 3 / 4;
+
+// The quantization table every game uses for STR playback, in zigzag order.
+const QUANT_TABLE: [u8; 64] = [
+    0x02, 0x10, 0x10, 0x13, 0x10, 0x13, 0x16, 0x16, 0x16, 0x16, 0x16, 0x16, 0x1A, 0x18, 0x1A, 0x1B,
+    0x1B, 0x1B, 0x1A, 0x1A, 0x1A, 0x1A, 0x1B, 0x1B, 0x1B, 0x1D, 0x1D, 0x1D, 0x22, 0x22, 0x22, 0x1D,
+    0x1D, 0x1D, 0x1B, 0x1B, 0x1D, 0x1D, 0x20, 0x20, 0x22, 0x22, 0x25, 0x26, 0x25, 0x23, 0x23, 0x22,
+    0x23, 0x26, 0x26, 0x28, 0x28, 0x28, 0x30, 0x30, 0x2E, 0x2E, 0x38, 0x38, 0x3A, 0x45, 0x45, 0x53,
+];
+
+// The IDCT scale table the BIOS uploads.
+const SCALE_TABLE: [u16; 64] = [
+    0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82, 0x5A82,
+    0x7D8A, 0x6A6D, 0x471C, 0x18F8, 0xE707, 0xB8E3, 0x9592, 0x8275,
+    0x7641, 0x30FB, 0xCF04, 0x89BE, 0x89BE, 0xCF04, 0x30FB, 0x7641,
+    0x6A6D, 0xE707, 0x8275, 0xB8E3, 0x471C, 0x7D8A, 0x18F8, 0x9592,
+    0x5A82, 0xA57D, 0xA57D, 0x5A82, 0x5A82, 0xA57D, 0xA57D, 0x5A82,
+    0x471C, 0x8275, 0x18F8, 0x6A6D, 0x9592, 0xE707, 0x7D8A, 0xB8E3,
+    0x30FB, 0x89BE, 0x7641, 0xCF04, 0xCF04, 0x7641, 0x89BE, 0x30FB,
+    0x18F8, 0xB8E3, 0x6A6D, 0x8275, 0x7D8A, 0x9592, 0x471C, 0xE707,
+];
+
+// MPEG-1 AC coefficient codes (without the sign bit) and their run and level.
+// "10" ends a block and "000001" escapes to a raw 6-bit run and 10-bit level.
+const AC_CODES: [(&str, u16, u16); 111] = [
+    ("11", 0, 1), ("011", 1, 1), ("0100", 0, 2), ("0101", 2, 1), ("00101", 0, 3), ("00111", 3, 1),
+    ("00110", 4, 1), ("000110", 1, 2), ("000111", 5, 1), ("000101", 6, 1), ("000100", 7, 1),
+    ("0000110", 0, 4), ("0000100", 2, 2), ("0000111", 8, 1), ("0000101", 9, 1),
+    ("00100110", 0, 5), ("00100001", 0, 6), ("00100101", 1, 3), ("00100100", 3, 2), ("00100111", 10, 1),
+    ("00100011", 11, 1), ("00100010", 12, 1), ("00100000", 13, 1),
+    ("0000001010", 0, 7), ("0000001100", 1, 4), ("0000001011", 2, 3), ("0000001111", 4, 2),
+    ("0000001001", 5, 2), ("0000001110", 14, 1), ("0000001101", 15, 1), ("0000001000", 16, 1),
+    ("000000011101", 0, 8), ("000000011000", 0, 9), ("000000010011", 0, 10), ("000000010000", 0, 11),
+    ("000000011011", 1, 5), ("000000010100", 2, 4), ("000000011100", 3, 3), ("000000010010", 4, 3),
+    ("000000011110", 6, 2), ("000000010101", 7, 2), ("000000010001", 8, 2), ("000000011111", 17, 1),
+    ("000000011010", 18, 1), ("000000011001", 19, 1), ("000000010111", 20, 1), ("000000010110", 21, 1),
+    ("0000000011010", 0, 12), ("0000000011001", 0, 13), ("0000000011000", 0, 14), ("0000000010111", 0, 15),
+    ("0000000010110", 1, 6), ("0000000010101", 1, 7), ("0000000010100", 2, 5), ("0000000010011", 3, 4),
+    ("0000000010010", 5, 3), ("0000000010001", 9, 2), ("0000000010000", 10, 2), ("0000000011111", 22, 1),
+    ("0000000011110", 23, 1), ("0000000011101", 24, 1), ("0000000011100", 25, 1), ("0000000011011", 26, 1),
+    ("00000000011111", 0, 16), ("00000000011110", 0, 17), ("00000000011101", 0, 18), ("00000000011100", 0, 19),
+    ("00000000011011", 0, 20), ("00000000011010", 0, 21), ("00000000011001", 0, 22), ("00000000011000", 0, 23),
+    ("00000000010111", 0, 24), ("00000000010110", 0, 25), ("00000000010101", 0, 26), ("00000000010100", 0, 27),
+    ("00000000010011", 0, 28), ("00000000010010", 0, 29), ("00000000010001", 0, 30), ("00000000010000", 0, 31),
+    ("000000000011000", 0, 32), ("000000000010111", 0, 33), ("000000000010110", 0, 34), ("000000000010101", 0, 35),
+    ("000000000010100", 0, 36), ("000000000010011", 0, 37), ("000000000010010", 0, 38), ("000000000010001", 0, 39),
+    ("000000000010000", 0, 40), ("000000000011111", 1, 8), ("000000000011110", 1, 9), ("000000000011101", 1, 10),
+    ("000000000011100", 1, 11), ("000000000011011", 1, 12), ("000000000011010", 1, 13), ("000000000011001", 1, 14),
+    ("0000000000010011", 1, 15), ("0000000000010010", 1, 16), ("0000000000010001", 1, 17), ("0000000000010000", 1, 18),
+    ("0000000000010100", 6, 3), ("0000000000011010", 11, 2), ("0000000000011001", 12, 2), ("0000000000011000", 13, 2),
+    ("0000000000010111", 14, 2), ("0000000000010110", 15, 2), ("0000000000010101", 16, 2), ("0000000000011111", 27, 1),
+    ("0000000000011110", 28, 1), ("0000000000011101", 29, 1), ("0000000000011100", 30, 1), ("0000000000011011", 31, 1),
+];
+
+// Sizes of the differential DC values in v3 frames, for luma and chroma blocks.
+const LUMA_DC_SIZES: [&str; 9] = ["100", "00", "01", "101", "110", "1110", "11110", "111110", "1111110"];
+const CHROMA_DC_SIZES: [&str; 9] = ["00", "01", "10", "110", "1110", "11110", "111110", "1111110", "11111110"];
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Reads the frame bitstream, which is stored as little-endian halfwords read from the top bit down.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bit(&mut self) -> io::Result<u32> {
+        let word = self.position / 16 * 2;
+        if word + 1 >= self.data.len() {
+            return Err(invalid("the frame's bitstream ended early"));
+        }
+
+        let value = LittleEndian::read_u16(&self.data[word..word + 2]);
+        let bit = (value >> (15 - self.position % 16)) & 1;
+        self.position += 1;
+        Ok(bit as u32)
+    }
+
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.bit()?;
+        }
+        Ok(value)
+    }
+
+    // Reads bits until they match one of the codes, keyed by (length, value).
+    fn code<T: Copy>(&mut self, codes: &HashMap<(u32, u32), T>, max_length: u32) -> io::Result<T> {
+        let mut value = 0;
+
+        for length in 1..=max_length {
+            value = (value << 1) | self.bit()?;
+            if let Some(entry) = codes.get(&(length, value)) {
+                return Ok(*entry);
+            }
+        }
+
+        Err(invalid("unknown code in the frame's bitstream"))
+    }
+}
+
+fn code_key(code: &str) -> (u32, u32) {
+    (code.len() as u32, u32::from_str_radix(code, 2).unwrap())
+}
+
+#[derive(Clone, Copy)]
+enum AcCode {
+    EndOfBlock,
+    Escape,
+    Coefficient(u16, u16),
+}
+
+struct Tables {
+    ac: HashMap<(u32, u32), AcCode>,
+    luma_dc: HashMap<(u32, u32), u32>,
+    chroma_dc: HashMap<(u32, u32), u32>,
+}
+
+impl Tables {
+    fn new() -> Tables {
+        let mut ac: HashMap<(u32, u32), AcCode> = AC_CODES.iter().map(|(code, run, level)| (code_key(code), AcCode::Coefficient(*run, *level))).collect();
+        ac.insert(code_key("10"), AcCode::EndOfBlock);
+        ac.insert(code_key("000001"), AcCode::Escape);
+
+        Tables {
+            ac,
+            luma_dc: LUMA_DC_SIZES.iter().enumerate().map(|(size, code)| (code_key(code), size as u32)).collect(),
+            chroma_dc: CHROMA_DC_SIZES.iter().enumerate().map(|(size, code)| (code_key(code), size as u32)).collect(),
+        }
+    }
+}
+
+// Decodes a v2 or v3 frame into the run-length halfwords the MDEC takes.
+fn decode_bitstream(frame: &[u8], macroblocks: usize, tables: &Tables) -> io::Result<Vec<u16>> {
+    if frame.len() < 8 || LittleEndian::read_u16(&frame[2..4]) != 0x3800 {
+        return Err(invalid("not an STR frame"));
+    }
+
+    let quant_scale = LittleEndian::read_u16(&frame[4..6]) & 0x3F;
+    let version = LittleEndian::read_u16(&frame[6..8]);
+    if version != 2 && version != 3 {
+        return Err(invalid(&format!("unsupported frame version {}", version)));
+    }
+
+    let mut reader = BitReader { data: &frame[8..], position: 0 };
+    let mut output = Vec::new();
+    // v3 DC values are relative to the previous block of the same kind: Cr, Cb and Y.
+    let mut predictors = [0i32; 3];
+
+    for _ in 0..macroblocks {
+        for block in 0..6 {
+            let dc = if version == 2 {
+                reader.bits(10)? as i32
+            }
+            else {
+                let kind = block.min(2);
+                let size = if kind == 2 { reader.code(&tables.luma_dc, 7)? } else { reader.code(&tables.chroma_dc, 8)? };
+
+                let difference = if size == 0 {
+                    0
+                }
+                else {
+                    let value = reader.bits(size)? as i32;
+                    if value & (1 << (size - 1)) == 0 { value - ((1 << size) - 1) } else { value }
+                };
+
+                predictors[kind] += difference * 4;
+                predictors[kind]
+            };
+
+            output.push((quant_scale << 10) | (dc as u16 & 0x3FF));
+
+            loop {
+                match reader.code(&tables.ac, 16)? {
+                    AcCode::EndOfBlock => break,
+                    AcCode::Escape => {
+                        let run = reader.bits(6)? as u16;
+                        let level = reader.bits(10)? as u16;
+                        output.push((run << 10) | level);
+                    },
+                    AcCode::Coefficient(run, level) => {
+                        let level = if reader.bit()? != 0 { level.wrapping_neg() } else { level };
+                        output.push((run << 10) | (level & 0x3FF));
+                    },
+                }
+            }
+
+            output.push(END_OF_BLOCK);
+        }
+    }
+
+    Ok(output)
+}
+
+fn setup_mdec(mdec: &mut Mdec) {
+    mdec.write_control(0x80000000);
+
+    mdec.write_command(0x40000001);
+    // Luma and chroma use the same table.
+    for _ in 0..2 {
+        for chunk in QUANT_TABLE.chunks(4) {
+            mdec.write_command(LittleEndian::read_u32(chunk));
+        }
+    }
+
+    mdec.write_command(0x60000000);
+    for pair in SCALE_TABLE.chunks(2) {
+        mdec.write_command(pair[0] as u32 | (pair[1] as u32) << 16);
+    }
+}
+
+// Runs a frame through the MDEC and lays the 24-bit macroblocks out as an RGB image.
+// Macroblocks come in columns, top to bottom and then left to right.
+fn render_frame(mdec: &mut Mdec, data: &[u16], width: usize, height: usize) -> Vec<u8> {
+    let mut halfwords = data.to_vec();
+    if halfwords.len() & 1 != 0 {
+        halfwords.push(END_OF_BLOCK);
+    }
+
+    mdec.write_command(0x30000000 | (halfwords.len() / 2) as u32);
+    for pair in halfwords.chunks(2) {
+        mdec.write_command(pair[0] as u32 | (pair[1] as u32) << 16);
+    }
+
+    let columns = width.div_ceil(16);
+    let rows = height.div_ceil(16);
+    let mut image = vec![0; width * height * 3];
+
+    for macroblock in 0..columns * rows {
+        if mdec.output_len() < MACROBLOCK_WORDS {
+            break;
+        }
+
+        let mut pixels = Vec::with_capacity(MACROBLOCK_WORDS * 4);
+        for _ in 0..MACROBLOCK_WORDS {
+            pixels.extend_from_slice(&mdec.read_data().to_le_bytes());
+        }
+
+        let (left, top) = ((macroblock / rows) * 16, (macroblock % rows) * 16);
+        for y in 0..16 {
+            for x in 0..16 {
+                let (image_x, image_y) = (left + x, top + y);
+                if image_x < width && image_y < height {
+                    let source = (y * 16 + x) * 3;
+                    let destination = (image_y * width + image_x) * 3;
+                    image[destination..destination + 3].copy_from_slice(&pixels[source..source + 3]);
+                }
+            }
+        }
+    }
+
+    image
+}
+
+// Demuxes an STR file from a disc image, writing every video frame to `output_dir` as a PNG,
+// and the first XA channel found to audio.wav. Returns the number of frames written.
+pub fn dump_str(disc_path: &Path, file_path: &str, output_dir: &Path) -> io::Result<u32> {
+    let mut disc = Disc::open(disc_path)?;
+    let entry = disc.find_file(file_path)?;
+    let sector_count = entry.size.div_ceil(disc::DATA_SIZE as u32);
+
+    fs::create_dir_all(output_dir)?;
+
+    let tables = Tables::new();
+    let mut mdec = Mdec::new();
+    setup_mdec(&mut mdec);
+
+    let mut cd = cdrom::Cdrom::new();
+    cd.set_mode(cdrom::MODE_XA_ADPCM | cdrom::MODE_XA_FILTER);
+    let mut recording: Option<wav::WavWriter> = None;
+
+    let mut frame_data = Vec::new();
+    let mut frames = 0;
+
+    for lba in entry.lba..entry.lba + sector_count {
+        let sector = disc.read_raw_sector(lba)?;
+        let data = &sector[USER_DATA_OFFSET..USER_DATA_OFFSET + disc::DATA_SIZE];
+
+        if cdrom::audio::CdAudio::is_xa_sector(&sector) {
+            if recording.is_none() {
+                cd.set_filter(sector[16], sector[17]);
+                recording = Some(wav::WavWriter::create(&output_dir.join("audio.wav"), audio::SPU_SAMPLE_RATE)?);
+            }
+
+            cd.process_data_sector(&sector);
+            let samples: Vec<(i16, i16)> = cd.audio.output.drain(..).collect();
+            if let Some(writer) = recording.as_mut() {
+                writer.write_samples(&samples)?;
+            }
+            continue;
+        }
+
+        if LittleEndian::read_u16(&data[0..2]) != VIDEO_MAGIC || LittleEndian::read_u16(&data[2..4]) != VIDEO_TYPE {
+            continue;
+        }
+
+        let chunk = LittleEndian::read_u16(&data[4..6]);
+        let chunk_count = LittleEndian::read_u16(&data[6..8]);
+        let frame_size = LittleEndian::read_u32(&data[12..16]) as usize;
+        let width = LittleEndian::read_u16(&data[16..18]) as usize;
+        let height = LittleEndian::read_u16(&data[18..20]) as usize;
+
+        if chunk == 0 {
+            frame_data.clear();
+        }
+        frame_data.extend_from_slice(&data[VIDEO_HEADER_SIZE..]);
+
+        if chunk + 1 != chunk_count {
+            continue;
+        }
+
+        if width == 0 || height == 0 || width > MAX_WIDTH || height > MAX_HEIGHT {
+            println!("Skipping frame at sector {}: bad size {}x{}", lba, width, height);
+            continue;
+        }
+
+        frame_data.truncate(frame_size);
+        let macroblocks = (width.div_ceil(16)) * (height.div_ceil(16));
+
+        match decode_bitstream(&frame_data, macroblocks, &tables) {
+            Ok(halfwords) => {
+                let image = render_frame(&mut mdec, &halfwords, width, height);
+                frames += 1;
+                png::write_rgb(&output_dir.join(format!("frame_{:05}.png", frames)), width as u32, height as u32, &image)?;
+            },
+            Err(error) => println!("Skipping frame at sector {}: {}", lba, error),
+        }
+
+        // Anything a broken frame left behind would shift the next one.
+        setup_mdec(&mut mdec);
+    }
+
+    if let Some(writer) = recording {
+        writer.finish()?;
+    }
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cdrom::disc::tests::{build_image, write_image};
+
+    fn video_sector(width: u16, height: u16) -> Vec<u8> {
+        let mut data = vec![0; disc::DATA_SIZE];
+        LittleEndian::write_u16(&mut data[0..2], VIDEO_MAGIC);
+        LittleEndian::write_u16(&mut data[2..4], VIDEO_TYPE);
+        LittleEndian::write_u16(&mut data[6..8], 1);
+        LittleEndian::write_u32(&mut data[12..16], (disc::DATA_SIZE - VIDEO_HEADER_SIZE) as u32);
+        LittleEndian::write_u16(&mut data[16..18], width);
+        LittleEndian::write_u16(&mut data[18..20], height);
+        data
+    }
+
+    // Builds a frame from its header fields and a bitstream written as '0'/'1' characters, packed
+    // into little-endian halfwords from the top bit down. Spaces are only there for reading.
+    fn frame(quant_scale: u16, version: u16, bits: &str) -> Vec<u8> {
+        let bits: Vec<u16> = bits.chars().filter(|bit| *bit != ' ').map(|bit| (bit == '1') as u16).collect();
+
+        let mut frame = vec![0; 8];
+        LittleEndian::write_u16(&mut frame[2..4], 0x3800);
+        LittleEndian::write_u16(&mut frame[4..6], quant_scale);
+        LittleEndian::write_u16(&mut frame[6..8], version);
+
+        for chunk in bits.chunks(16) {
+            let halfword = chunk.iter().enumerate().fold(0, |value, (index, bit)| value | bit << (15 - index));
+            frame.extend_from_slice(&halfword.to_le_bytes());
+        }
+        frame
+    }
+
+    #[test]
+    fn v3_frame_with_dc_prediction_and_ac_codes() {
+        // Two macroblocks. DC sizes come from the luma and chroma tables, and each value is a
+        // difference from the previous block of the same kind, times four.
+        let bits = [
+            // Cr: size 2, +3, then run 0 level +1 ("11" "0").
+            "10 11 110 10",
+            // Cb: size 0.
+            "00 10",
+            // Y0: size 2, "00" is -3, then run 2 level -1 ("0101" "1").
+            "01 00 01011 10",
+            // Y1: size 0.
+            "100 10",
+            // Y2: size 1, +1.
+            "00 1 10",
+            // Y3: size 0.
+            "100 10",
+            // Cr: size 0, then an escape to run 2 level -2.
+            "00 000001 000010 1111111110 10",
+            // Cb: size 1, "0" is -1.
+            "01 0 10",
+            // Y0-Y3: size 0.
+            "100 10 100 10 100 10 100 10",
+        ].concat();
+
+        let halfwords = decode_bitstream(&frame(1, 3, &bits), 2, &Tables::new()).unwrap();
+
+        assert_eq!(halfwords, [
+            0x040C, 0x0001, 0xFE00, 0x0400, 0xFE00,
+            0x07F4, 0x0BFF, 0xFE00, 0x07F4, 0xFE00, 0x07F8, 0xFE00, 0x07F8, 0xFE00,
+            0x040C, 0x0BFE, 0xFE00, 0x07FC, 0xFE00,
+            0x07F8, 0xFE00, 0x07F8, 0xFE00, 0x07F8, 0xFE00, 0x07F8, 0xFE00,
+        ]);
+    }
+
+    // A 16x32 v2 frame: raw 10-bit DC values and no AC coefficients. The top macroblock has a luma
+    // DC of 64, which the first quantizer value (2) and the two IDCT passes over the DC basis
+    // (0x5A82 / 8 >> 13) turn into 128, 45 and then 16. The bottom one stays at 0, with no chroma.
+    #[test]
+    fn v2_frame_renders_macroblocks_top_to_bottom() {
+        let zero = "0000000000 10";
+        let luma = "0001000000 10";
+        let bits = [zero, zero, luma, luma, luma, luma, zero, zero, zero, zero, zero, zero].concat();
+
+        let halfwords = decode_bitstream(&frame(1, 2, &bits), 2, &Tables::new()).unwrap();
+        assert_eq!(halfwords[4..6], [0x0440, 0xFE00]);
+
+        let mut mdec = Mdec::new();
+        setup_mdec(&mut mdec);
+        let image = render_frame(&mut mdec, &halfwords, 16, 32);
+
+        // Unsigned output flips the sign bit.
+        let (top, bottom) = image.split_at(16 * 16 * 3);
+        assert!(top.iter().all(|value| *value == 16 ^ 0x80));
+        assert!(bottom.iter().all(|value| *value == 0x80));
+    }
+
+    #[test]
+    fn frames_with_bad_sizes_are_skipped() {
+        let mut movie = Vec::new();
+        for (width, height) in [(0, 240), (320, 0), (0xFFFF, 0xFFFF), (641, 480), (640, 481)].iter() {
+            movie.extend(video_sector(*width, *height));
+        }
+
+        let path = write_image("bad_sizes.bin", &build_image(&[("MOVIE.STR;1", &movie)], 32));
+        let output = std::env::temp_dir().join(format!("rusty_psx_{}_bad_sizes", std::process::id()));
+
+        let frames = dump_str(&path, "MOVIE.STR", &output);
+        fs::remove_file(&path).unwrap();
+        let written = fs::read_dir(&output).unwrap().count();
+        fs::remove_dir_all(&output).unwrap();
+
+        assert_eq!(frames.unwrap(), 0);
+        assert_eq!(written, 0);
+    }
+}
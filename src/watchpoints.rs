@@ -0,0 +1,161 @@
+// Watchpoints on reads and writes to ranges of the physical address space, checked by CpuMemory
+// for CPU accesses and DMA transfers alike.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Reads and writes.
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+pub struct Watchpoint {
+    // Physical addresses, both ends included.
+    pub start: u32,
+    pub end: u32,
+    pub kind: WatchKind,
+    pub enabled: bool,
+    pub hit_count: u32,
+}
+
+// The access that triggered a watchpoint.
+#[derive(Clone, Copy)]
+pub struct WatchHit {
+    pub access: Access,
+    pub address: u32,
+    // Bytes accessed.
+    pub size: u32,
+    pub value: u32,
+    // Channel of the DMA transfer that made the access, None for the CPU.
+    pub dma_channel: Option<usize>,
+    // Instruction that made the access, or started the transfer. Filled in by the CPU.
+    pub pc: u32,
+}
+
+impl WatchHit {
+    pub fn describe(&self) -> String {
+        let access = match self.access {
+            Access::Read => "Read",
+            Access::Write => "Write",
+        };
+        let source = match self.dma_channel {
+            Some(channel) => format!(" by DMA channel {}", channel),
+            None => String::new(),
+        };
+
+        format!("{} of {:0width$X} ({} bytes) at {:08X}{}, PC {:08X}", access, self.value, self.size, self.address, source, self.pc, width = self.size as usize * 2)
+    }
+}
+
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    // Set while any watchpoint is enabled. Memory accesses only look at the list when it is,
+    // which keeps them at one extra branch otherwise.
+    armed: bool,
+    // The first hit since the CPU last collected one.
+    pending: Option<WatchHit>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints {
+            watchpoints: Vec::new(),
+            armed: false,
+            pending: None,
+        }
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add(&mut self, start: u32, end: u32, kind: WatchKind) {
+        let (start, end) = (start & 0x1FFFFFFF, end & 0x1FFFFFFF);
+
+        self.watchpoints.push(Watchpoint {
+            start: start.min(end),
+            end: start.max(end),
+            kind,
+            enabled: true,
+            hit_count: 0,
+        });
+        self.update_armed();
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if index < self.watchpoints.len() {
+            self.watchpoints.remove(index);
+        }
+        self.update_armed();
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        if let Some(watchpoint) = self.watchpoints.get_mut(index) {
+            watchpoint.enabled = enabled;
+        }
+        self.update_armed();
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+        self.update_armed();
+    }
+
+    fn update_armed(&mut self) {
+        self.armed = self.watchpoints.iter().any(|watchpoint| watchpoint.enabled);
+        if !self.armed {
+            self.pending = None;
+        }
+    }
+
+    #[inline]
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    // Looks for watchpoints covering the access. Only called when armed.
+    pub fn check(&mut self, access: Access, address: u32, size: u32, value: u32, dma_channel: Option<usize>) {
+        let address = address & 0x1FFFFFFF;
+        let last = address + size - 1;
+        let mut triggered = false;
+
+        for watchpoint in self.watchpoints.iter_mut() {
+            if watchpoint.enabled && watchpoint.kind.matches(access) && address <= watchpoint.end && last >= watchpoint.start {
+                watchpoint.hit_count += 1;
+                triggered = true;
+            }
+        }
+
+        if triggered && self.pending.is_none() {
+            self.pending = Some(WatchHit {
+                access,
+                address,
+                size,
+                value,
+                dma_channel,
+                pc: 0,
+            });
+        }
+    }
+
+    // Hands over the hit since the last call, with the PC of the instruction that caused it.
+    pub fn take_hit(&mut self, pc: u32) -> Option<WatchHit> {
+        self.pending.take().map(|hit| WatchHit { pc, ..hit })
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::fs::File;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const HEADER_SIZE: u32 = 44;
+
+// Writes 16-bit stereo PCM to a .wav file, patching the sizes in the header when finished.
+pub struct WavWriter {
+    file: File,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut file = File::create(path)?;
+        let mut header = [0; HEADER_SIZE as usize];
+
+        let channels = 2;
+        let bits_per_sample = 16;
+        let block_align = channels * bits_per_sample / 8;
+
+        header[0..4].copy_from_slice(b"RIFF");
+        header[8..12].copy_from_slice(b"WAVE");
+        header[12..16].copy_from_slice(b"fmt ");
+        LittleEndian::write_u32(&mut header[16..20], 16);
+        LittleEndian::write_u16(&mut header[20..22], 1);
+        LittleEndian::write_u16(&mut header[22..24], channels);
+        LittleEndian::write_u32(&mut header[24..28], sample_rate);
+        LittleEndian::write_u32(&mut header[28..32], sample_rate * block_align as u32);
+        LittleEndian::write_u16(&mut header[32..34], block_align);
+        LittleEndian::write_u16(&mut header[34..36], bits_per_sample);
+        header[36..40].copy_from_slice(b"data");
+
+        file.write_all(&header)?;
+
+        Ok(WavWriter {
+            file,
+            data_size: 0,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[(i16, i16)]) -> io::Result<()> {
+        let mut data = vec![0; samples.len() * 4];
+
+        for (index, (left, right)) in samples.iter().enumerate() {
+            LittleEndian::write_i16(&mut data[index * 4..index * 4 + 2], *left);
+            LittleEndian::write_i16(&mut data[index * 4 + 2..index * 4 + 4], *right);
+        }
+
+        self.file.write_all(&data)?;
+        self.data_size += data.len() as u32;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let mut size = [0; 4];
+
+        LittleEndian::write_u32(&mut size, HEADER_SIZE - 8 + self.data_size);
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&size)?;
+
+        LittleEndian::write_u32(&mut size, self.data_size);
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&size)?;
+
+        self.file.flush()
+    }
+}